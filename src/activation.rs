@@ -0,0 +1,317 @@
+use log::debug;
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// How long the compositor gets to answer, so a stuck one can't hold up the launch.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+// Object ids are picked by the client, wl_display is always 1
+const DISPLAY: u32 = 1;
+const REGISTRY: u32 = 2;
+const CALLBACK: u32 = 3;
+const ACTIVATION: u32 = 4;
+const TOKEN: u32 = 5;
+
+const ACTIVATION_INTERFACE: &str = "xdg_activation_v1";
+
+/// Asks the compositor for an xdg-activation token to give a launched program,
+/// which lets its window take focus when it opens. `None` when uff isn't running
+/// under Wayland, the compositor doesn't support xdg-activation-v1 or it fails
+/// to answer, in which case the program is launched without one.
+pub fn request_token() -> Option<String> {
+    let Some(socket) = socket_path() else {
+        debug!("not running under Wayland, launching without an activation token");
+        return None;
+    };
+    match request_token_at(&socket) {
+        Ok(Some(token)) => Some(token),
+        Ok(None) => {
+            debug!("the compositor doesn't support {ACTIVATION_INTERFACE}");
+            None
+        }
+        Err(error) => {
+            debug!("failed to get an activation token: {error}");
+            None
+        }
+    }
+}
+
+/// `$WAYLAND_DISPLAY`, relative to `$XDG_RUNTIME_DIR` unless it's absolute.
+fn socket_path() -> Option<PathBuf> {
+    let display =
+        PathBuf::from(std::env::var_os("WAYLAND_DISPLAY").filter(|display| !display.is_empty())?);
+    if display.is_absolute() {
+        return Some(display);
+    }
+    Some(PathBuf::from(std::env::var_os("XDG_RUNTIME_DIR")?).join(display))
+}
+
+/// Binds xdg_activation_v1 if the compositor has it and commits a token
+/// request. Nothing is known about the program yet, so it's a bare request
+/// without a surface or serial.
+fn request_token_at(socket: &Path) -> io::Result<Option<String>> {
+    let mut connection = Connection::connect(socket)?;
+    connection.send(DISPLAY, 1, &[Arg::Uint(REGISTRY)])?; // get_registry
+    // The callback is done once every global has been announced
+    connection.send(DISPLAY, 0, &[Arg::Uint(CALLBACK)])?; // sync
+
+    let mut activation = None;
+    loop {
+        let mut event = connection.event()?;
+        match (event.object, event.opcode) {
+            (REGISTRY, 0) => {
+                let name = event.uint()?;
+                let interface = event.string()?;
+                let version = event.uint()?;
+                if interface == ACTIVATION_INTERFACE {
+                    activation = Some((name, version.min(1)));
+                }
+            }
+            (CALLBACK, 0) => break,
+            _ => {}
+        }
+    }
+    let Some((name, version)) = activation else {
+        return Ok(None);
+    };
+
+    connection.send(
+        REGISTRY,
+        0, // bind
+        &[
+            Arg::Uint(name),
+            Arg::Str(ACTIVATION_INTERFACE),
+            Arg::Uint(version),
+            Arg::Uint(ACTIVATION),
+        ],
+    )?;
+    connection.send(ACTIVATION, 1, &[Arg::Uint(TOKEN)])?; // get_activation_token
+    connection.send(TOKEN, 3, &[])?; // commit
+    let token = loop {
+        let mut event = connection.event()?;
+        if (event.object, event.opcode) == (TOKEN, 0) {
+            break event.string()?; // done
+        }
+    };
+    // The compositor cleans up after a closed connection anyway
+    let _ = connection.send(TOKEN, 4, &[]); // destroy
+    let _ = connection.send(ACTIVATION, 0, &[]); // destroy
+    Ok(Some(token))
+}
+
+enum Arg<'a> {
+    /// Also used for object ids.
+    Uint(u32),
+    Str(&'a str),
+}
+
+/// A message from the other end, with its arguments read in order.
+struct Event {
+    object: u32,
+    opcode: u16,
+    body: Vec<u8>,
+    offset: usize,
+}
+
+impl Event {
+    fn uint(&mut self) -> io::Result<u32> {
+        let bytes = self
+            .body
+            .get(self.offset..self.offset + 4)
+            .ok_or_else(|| invalid("event ended early"))?;
+        self.offset += 4;
+        Ok(u32::from_ne_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Strings are sent with their length, which counts a trailing nul, and
+    /// padded to 4 bytes.
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.uint()? as usize;
+        let bytes = self
+            .body
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| invalid("event ended early"))?;
+        self.offset += len.next_multiple_of(4);
+        let bytes = bytes.strip_suffix(b"\0").unwrap_or(bytes);
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("string is not utf-8"))
+    }
+}
+
+struct Connection {
+    stream: UnixStream,
+}
+
+impl Connection {
+    fn connect(socket: &Path) -> io::Result<Self> {
+        let stream = UnixStream::connect(socket)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(Self { stream })
+    }
+
+    /// Each message starts with the object id, then its size in the upper 16
+    /// bits and the opcode in the lower 16, all in native byte order.
+    fn send(&mut self, object: u32, opcode: u16, args: &[Arg]) -> io::Result<()> {
+        let mut body = Vec::new();
+        for arg in args {
+            match arg {
+                Arg::Uint(value) => body.extend_from_slice(&value.to_ne_bytes()),
+                Arg::Str(text) => {
+                    let len = text.len() + 1;
+                    body.extend_from_slice(&(len as u32).to_ne_bytes());
+                    body.extend_from_slice(text.as_bytes());
+                    body.resize(body.len() + len.next_multiple_of(4) - text.len(), 0);
+                }
+            }
+        }
+        let mut message = object.to_ne_bytes().to_vec();
+        message.extend_from_slice(
+            &((((body.len() + 8) as u32) << 16) | u32::from(opcode)).to_ne_bytes(),
+        );
+        message.extend_from_slice(&body);
+        self.stream.write_all(&message)
+    }
+
+    /// The next message, requests and events are framed the same way.
+    fn receive(&mut self) -> io::Result<Event> {
+        let mut header = [0; 8];
+        self.stream.read_exact(&mut header)?;
+        let object = u32::from_ne_bytes(header[..4].try_into().unwrap());
+        let size_and_opcode = u32::from_ne_bytes(header[4..].try_into().unwrap());
+        let size = (size_and_opcode >> 16) as usize;
+        let mut body = vec![
+            0;
+            size.checked_sub(8)
+                .ok_or_else(|| invalid("event is too short"))?
+        ];
+        self.stream.read_exact(&mut body)?;
+        Ok(Event {
+            object,
+            opcode: size_and_opcode as u16,
+            body,
+            offset: 0,
+        })
+    }
+
+    /// The next event, wl_display's error event is returned as an error.
+    fn event(&mut self) -> io::Result<Event> {
+        let mut event = self.receive()?;
+        if (event.object, event.opcode) == (DISPLAY, 0) {
+            let _object = event.uint()?;
+            let code = event.uint()?;
+            let message = event.string()?;
+            return Err(io::Error::other(format!(
+                "the compositor sent error {code}: {message}"
+            )));
+        }
+        Ok(event)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use tempfile::TempDir;
+
+    /// A fake compositor that announces the given globals and answers token
+    /// requests, returning the requests it got.
+    fn compositor(
+        socket: &Path,
+        globals: &'static [&'static str],
+    ) -> std::thread::JoinHandle<Vec<(u32, u16)>> {
+        let _ = std::fs::remove_file(socket);
+        let listener = UnixListener::bind(socket).unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = Connection { stream };
+            let mut requests = Vec::new();
+            while let Ok(mut request) = connection.receive() {
+                requests.push((request.object, request.opcode));
+                match (request.object, request.opcode) {
+                    (DISPLAY, 0) => {
+                        let callback = request.uint().unwrap();
+                        for (name, interface) in (1..).zip(globals) {
+                            let args = [Arg::Uint(name), Arg::Str(interface), Arg::Uint(1)];
+                            connection.send(REGISTRY, 0, &args).unwrap();
+                        }
+                        connection.send(callback, 0, &[Arg::Uint(0)]).unwrap();
+                    }
+                    (REGISTRY, 0) => {
+                        assert_eq!(request.uint().unwrap(), 2);
+                        assert_eq!(request.string().unwrap(), ACTIVATION_INTERFACE);
+                    }
+                    (TOKEN, 3) => {
+                        connection
+                            .send(TOKEN, 0, &[Arg::Str("token-1234")])
+                            .unwrap();
+                    }
+                    _ => {}
+                }
+            }
+            requests
+        })
+    }
+
+    #[test]
+    fn test_request_token() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("test-activation");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let socket = dir.join("supported");
+        let server = compositor(&socket, &["wl_seat", ACTIVATION_INTERFACE]);
+        assert_eq!(
+            request_token_at(&socket).unwrap().as_deref(),
+            Some("token-1234")
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                (DISPLAY, 1),
+                (DISPLAY, 0),
+                (REGISTRY, 0),
+                (ACTIVATION, 1),
+                (TOKEN, 3),
+                (TOKEN, 4),
+                (ACTIVATION, 0)
+            ]
+        );
+
+        let socket = dir.join("unsupported");
+        let server = compositor(&socket, &["wl_seat"]);
+        assert_eq!(request_token_at(&socket).unwrap(), None);
+        assert_eq!(server.join().unwrap(), [(DISPLAY, 1), (DISPLAY, 0)]);
+
+        assert!(request_token_at(&dir.join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_display_error() {
+        let temp = TempDir::new().unwrap();
+        let socket = temp.path().join("test-activation-error");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = Connection { stream };
+            // Answers get_registry, after sync has been sent too
+            connection.receive().unwrap();
+            connection.receive().unwrap();
+            let args = [Arg::Uint(DISPLAY), Arg::Uint(1), Arg::Str("invalid method")];
+            connection.send(DISPLAY, 0, &args).unwrap();
+        });
+        assert_eq!(
+            request_token_at(&socket).unwrap_err().to_string(),
+            "the compositor sent error 1: invalid method"
+        );
+        server.join().unwrap();
+    }
+}
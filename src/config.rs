@@ -1,31 +1,42 @@
 use bitcode::{Decode, Encode};
-use log::{error, info, warn};
-use miette::{Context, IntoDiagnostic, NamedSource, Result};
+use log::{debug, error, info, warn};
+use miette::{Context, IntoDiagnostic, LabeledSpan, NamedSource, Result, bail, ensure, miette};
 use sha2::{Digest, Sha256};
 use std::{
-    collections::VecDeque,
-    ffi::OsStr,
-    fs::File,
+    cell::RefCell,
+    ffi::{OsStr, OsString},
+    fs::{File, TryLockError},
     io::Write,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
-use walkdir::WalkDir;
 
-use crate::parser::{self, ItemContents, Menu};
+use crate::{
+    desktop, detach, glob,
+    icons::{self, IconCache, IconFinder, MissingIcon},
+    parser::{
+        self, AutoSize, Defaults, FileSort, GlobalConfig, IconDir, IconDirInheritance, IconFormat,
+        IconMatch, IconWalk, IoClass, Item, ItemContents, ItemOrder, LaunchMethod, Menu,
+        MultiSelect, OnEmpty, ParseOptions, PassVia, PowerAction, SortOrder, SymbolicIcons,
+    },
+    settings,
+};
 
-pub fn default_config_dir() -> PathBuf {
+pub fn default_config_dir() -> Result<PathBuf> {
     let mut path;
-    if let Ok(config_home) = std::env::var("XDG_CONFIG_HOME") {
+    if let Some(config_home) = env_var("XDG_CONFIG_HOME") {
         path = PathBuf::from(config_home);
     } else {
-        path = std::env::home_dir().unwrap();
+        path = PathBuf::from(home()?);
         path.push(".config");
     }
-    path
+    Ok(path)
 }
 
-pub fn default_config_path() -> PathBuf {
-    let mut path = default_config_dir();
+pub fn default_config_path(config_dir: &Path) -> PathBuf {
+    let mut path = config_dir.to_path_buf();
     path.push(env!("CARGO_BIN_NAME"));
     path.push("default.kdl");
     info!("using default config path");
@@ -34,16 +45,149 @@ pub fn default_config_path() -> PathBuf {
 
 #[derive(Encode, Decode, Debug)]
 pub struct ComputedConfig {
-    /// First 8 bytes of SHA256 digest of raw config file.
-    hash: [u8; 8],
+    /// `config_hash` of everything the config was built from that isn't checked
+    /// separately.
+    hash: [u8; 32],
+    /// The length of the config, checked along with `hash`.
+    config_len: u64,
+    /// When the config file was last changed and its size, so it isn't read
+    /// while they and `global_hash` are the same, see `ConfigSource::built`.
+    config_stamp: Option<(u64, u64)>,
+    /// `config_hash` of everything but the config.
+    global_hash: [u8; 32],
+    /// Glob patterns used as icon-dirs and the directories they expanded to.
+    icon_dir_globs: Vec<(String, Vec<String>)>,
+    /// Environment variables read while building it, along with any referenced
+    /// by the config, and their values then. See `env_var`.
+    env_vars: Vec<(String, Option<String>)>,
+    /// The programs tried by each `try` block and the index of the one found in `$PATH`.
+    command_choices: Vec<(Vec<String>, Option<usize>)>,
+    /// Desktop entries used by programs, the file each was found at and when it
+    /// was last changed.
+    desktop_entries: Vec<(String, String, Option<u64>)>,
+    /// The hostname `if-host` and `unless-host` were checked against, if the
+    /// config uses them.
+    hostname: Option<String>,
+    /// The desktop's icon theme, if the config doesn't set one, so switching
+    /// themes finds the icons again.
+    detected_icon_theme: Option<Option<String>>,
+    /// Where each icon was found, so the next build can skip searching again.
+    icons: IconCache,
+    /// Icons that weren't found when it was built.
+    missing_icons: Vec<MissingIcon>,
+    /// The fuzzel inis written for it, so deleted ones can be written again.
+    fuzzel_inis: Vec<FuzzelIni>,
+    /// Top-level menus that can be launched directly, by name or id, and their
+    /// index in `items`.
+    entries: Vec<(String, Option<String>, usize)>,
+    /// Whether the root menu only holds entries, so one has to be picked.
+    entries_only: bool,
+    /// Built with `--lenient`, so duplicate nodes may have been let through.
+    lenient: bool,
+    /// Whether launched programs get the `UFF_*` variables, from `export-env`.
+    pub export_env: bool,
+    /// Whether launched programs get an xdg-activation token, from `activation`.
+    pub activation: bool,
     pub initial_menu: ComputedMenu,
     pub items: Vec<ComputedItem>,
 }
 
+impl ComputedConfig {
+    /// The `--config` and `--cache` args are cached relative to the cache and
+    /// state dirs, so the cache doesn't depend on where they are. This makes
+    /// them absolute.
+    fn locate_fuzzel_files(&mut self, cache_dir: &Path, state_dir: &Path) {
+        let menus = self.items.iter_mut().filter_map(|item| match item {
+            ComputedItem::Menu(menu) => Some(&mut menu.args),
+            ComputedItem::DynamicMenu(dynamic_menu) => Some(&mut dynamic_menu.args),
+            ComputedItem::Pick(pick) => Some(&mut pick.args),
+            ComputedItem::MimeOpen(mime_open) => Some(&mut mime_open.args),
+            ComputedItem::Input(input) => Some(&mut input.args),
+            ComputedItem::FilesMenu(files_menu) => Some(&mut files_menu.args),
+            ComputedItem::RecentFiles(recent_files) => Some(&mut recent_files.args),
+            ComputedItem::Program(_) | ComputedItem::Back => None,
+        });
+        for args in std::iter::once(&mut self.initial_menu.args).chain(menus) {
+            // Users can't pass either in fuzzel-args, see `without_managed_fuzzel_args`
+            for index in 1..args.len() {
+                let dir = match args[index - 1].as_str() {
+                    "--config" => cache_dir,
+                    "--cache" => state_dir,
+                    _ => continue,
+                };
+                args[index] = dir.join(&args[index]).display().to_string();
+            }
+        }
+    }
+
+    /// The menu to open first, either the entry with that id or name, or the
+    /// root menu.
+    pub fn entry_menu(&self, entry: Option<&str>) -> Result<&ComputedMenu> {
+        let names = || {
+            self.entries
+                .iter()
+                .map(|(name, id, _)| format!("{:?}", id.as_ref().unwrap_or(name)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let Some(entry) = entry else {
+            ensure!(
+                !self.entries_only || self.entries.len() <= 1,
+                help = format!("available entries: {}", names()),
+                "this config has multiple entries, pick one with --entry or config.kdl:entry"
+            );
+            return Ok(&self.initial_menu);
+        };
+
+        // Ids are unique, so one matching is always the one meant
+        let index = if let Some((_, _, index)) = self
+            .entries
+            .iter()
+            .find(|(_, id, _)| id.as_deref() == Some(entry))
+        {
+            index
+        } else {
+            let mut matches = self.entries.iter().filter(|(name, _, _)| name == entry);
+            let Some((_, _, index)) = matches.next() else {
+                bail!(
+                    help = format!("available entries: {}", names()),
+                    "no entry named {entry:?}"
+                );
+            };
+            ensure!(
+                matches.next().is_none(),
+                help = "give them ids to tell them apart",
+                "there are multiple menus named {entry:?}"
+            );
+            index
+        };
+        match &self.items[*index] {
+            ComputedItem::Menu(menu) => Ok(menu),
+            _ => unreachable!("entries only point at menus"),
+        }
+    }
+}
+
 #[derive(Encode, Decode, Debug)]
 pub enum ComputedItem {
     Menu(ComputedMenu),
     Program(ComputedProgram),
+    Back,
+    DynamicMenu(ComputedDynamicMenu),
+    Pick(ComputedPick),
+    MimeOpen(ComputedMimeOpen),
+    Input(ComputedInput),
+    FilesMenu(ComputedFilesMenu),
+    RecentFiles(ComputedRecentFiles),
+}
+
+/// A fuzzel ini written for the defaults or a menu, `{preset}{id}.fuzzel.ini`.
+#[derive(Encode, Decode, Debug)]
+struct FuzzelIni {
+    id: usize,
+    /// The ini it includes, or the user's own when it's `None`.
+    inherit_id: Option<usize>,
+    pairs: Vec<(String, String)>,
 }
 
 #[derive(Encode, Decode, Debug)]
@@ -51,11 +195,253 @@ pub struct ComputedMenu {
     pub args: Vec<String>,
     pub input: Vec<u8>,
     pub items_offset: usize,
+    /// Return to the previous menu when fuzzel is cancelled instead of failing.
+    pub back_on_cancel: bool,
+    /// Reopen this menu after launching one of its programs.
+    pub stay_open: bool,
+    pub actions: Vec<ComputedAction>,
+    /// Lines of `input` with a `{}` that's filled in each time the menu is shown.
+    pub labels: Vec<ComputedLabel>,
+    pub hooks: ComputedHooks,
+    /// Item names in input order, passed to the hooks.
+    pub names: Vec<String>,
+    /// The ids of the same items, if they have one.
+    pub ids: Vec<Option<String>>,
+    pub multi_select: Option<ComputedMultiSelect>,
+}
+
+/// What happens to the items picked in a multi-select menu.
+#[derive(Encode, Decode, Debug, Clone)]
+pub enum ComputedMultiSelect {
+    /// Launch each one in order.
+    Each,
+    /// Run this once with their names appended.
+    Args(Box<ComputedProgram>),
+}
+
+impl ComputedMenu {
+    /// The name of the item on the line, empty for menus built without names.
+    pub fn item_name(&self, index: usize) -> &str {
+        self.names.get(index).map_or("", String::as_str)
+    }
+
+    /// The id of the item on the line, or its name if it has none.
+    pub fn item_id(&self, index: usize) -> &str {
+        match self.ids.get(index) {
+            Some(Some(id)) => id,
+            _ => self.item_name(index),
+        }
+    }
 }
 
+/// Commands run around each launch from a menu.
+#[derive(Encode, Decode, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ComputedHooks {
+    pub pre_launch: Vec<String>,
+    pub post_launch: Vec<String>,
+    /// Ids or names of the submenus leading to the menu, joined with `/`.
+    pub menu_path: String,
+}
+
+/// A program run when a custom key is pressed on an item.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ComputedAction {
+    /// Index of the item within its menu.
+    pub item: usize,
+    /// Which of fuzzel's custom key bindings runs it, starting at 0 for custom-1.
+    pub bind: usize,
+    pub program: ComputedProgram,
+}
+
+/// An item whose name includes the output of a command.
 #[derive(Encode, Decode, Debug, Clone)]
+pub struct ComputedLabel {
+    /// Index of the item within its menu.
+    pub item: usize,
+    pub command: Vec<String>,
+    /// The output needs escaping, since the menu uses pango markup.
+    pub markup: bool,
+}
+
+#[derive(Encode, Decode, Debug, Clone, Default)]
 pub struct ComputedProgram {
     pub command: Vec<String>,
+    /// Seconds to wait for the command to fail before letting it run unwatched.
+    pub watch_exit: Option<u64>,
+    pub notify: Option<ComputedNotification>,
+    /// Commands run in order instead of `command`, waiting for each one.
+    pub chain: Vec<Vec<String>>,
+    pub continue_on_error: bool,
+    /// Written to the command's stdin.
+    pub stdin: Option<String>,
+    /// From the enclosing menus, with the program's own over them. Runtime
+    /// items keep their menus' to give each program they build.
+    pub env: Vec<(String, String)>,
+    /// The command holds a secret, so only its name is ever logged.
+    pub redacted: bool,
+    pub nice: Option<i8>,
+    pub ionice: Option<ComputedIoPriority>,
+    /// Runs in a systemd user scope named after this, see `unit_slug`.
+    pub systemd_scope: Option<String>,
+    pub launch_method: ComputedLaunchMethod,
+    /// Window rules put in front of the command when it's handed to Hyprland.
+    pub hypr_rules: Vec<String>,
+    /// Stays in uff's session with its stdio instead of being detached.
+    pub attached: bool,
+    /// Replaces uff instead of being spawned.
+    pub exec: bool,
+    /// Only set if the config chose, otherwise they follow `attached`.
+    pub stdout: Option<ComputedOutput>,
+    pub stderr: Option<ComputedOutput>,
+}
+
+/// The launch options from a menu's `defaults`, for the programs a runtime item
+/// builds once it's opened.
+#[derive(Encode, Decode, Debug, Clone, Default)]
+pub struct ComputedDefaults {
+    pub systemd_scope: Option<String>,
+    pub launch_method: ComputedLaunchMethod,
+    pub stdout: Option<ComputedOutput>,
+    pub stderr: Option<ComputedOutput>,
+}
+
+impl ComputedDefaults {
+    /// A program with nothing but the defaults set, to fill in the rest of.
+    pub fn program(&self) -> ComputedProgram {
+        ComputedProgram {
+            systemd_scope: self.systemd_scope.clone(),
+            launch_method: self.launch_method,
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub enum ComputedOutput {
+    Null,
+    Inherit,
+    File { path: String, append: bool },
+}
+
+/// Whether to start the program through the compositor, see `launch::Wrappers`.
+#[derive(Encode, Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ComputedLaunchMethod {
+    #[default]
+    Direct,
+    Swaymsg,
+    Hyprctl,
+    /// Checked for each launch, so the cache doesn't depend on the session.
+    Auto,
+}
+
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComputedIoPriority {
+    /// `ionice`'s number for the class: 1 is realtime, 2 best-effort and 3 idle.
+    pub class: u8,
+    pub level: Option<u8>,
+}
+
+impl ComputedProgram {
+    /// The command as it's shown in logs and notifications.
+    pub fn describe(&self) -> String {
+        if self.redacted {
+            format!("{} (arguments hidden)", self.command[0])
+        } else {
+            self.command.join(" ")
+        }
+    }
+}
+
+/// The entries are only known once the menu is opened, see `dynamic.rs`.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ComputedDynamicMenu {
+    pub args: Vec<String>,
+    pub source_command: Vec<String>,
+    pub run_command: Vec<String>,
+    pub on_empty: ComputedOnEmpty,
+    pub env: Vec<(String, String)>,
+    pub defaults: ComputedDefaults,
+}
+
+/// What happens when the source command prints nothing, see `dynamic::outcome`.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub enum ComputedOnEmpty {
+    Message,
+    Skip,
+    Run(Vec<String>),
+}
+
+/// The lines are listed each time it's picked, see `dynamic.rs`.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ComputedPick {
+    pub args: Vec<String>,
+    pub list_command: Vec<String>,
+    pub run_command: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub defaults: ComputedDefaults,
+}
+
+/// The file's applications are only looked up once it's picked, see `mime.rs`.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ComputedMimeOpen {
+    pub args: Vec<String>,
+    pub file: String,
+    /// Put in front of applications that run in a terminal.
+    pub terminal: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub defaults: ComputedDefaults,
+}
+
+/// The directory is only listed once the menu is opened, see `files.rs`.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ComputedFilesMenu {
+    pub args: Vec<String>,
+    pub dir: String,
+    pub pattern: Option<String>,
+    pub newest_first: bool,
+    pub limit: Option<usize>,
+    pub open_with: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub defaults: ComputedDefaults,
+}
+
+/// The recently used files are only read once the menu is opened, see `recent.rs`.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ComputedRecentFiles {
+    pub args: Vec<String>,
+    pub limit: usize,
+    pub open_with: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub defaults: ComputedDefaults,
+}
+
+/// The text is only known once the item is selected, see `input.rs`.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ComputedInput {
+    pub args: Vec<String>,
+    pub command: Vec<String>,
+    pub url_encode: bool,
+    pub password: bool,
+    pub pass_via: ComputedPassVia,
+    pub env: Vec<(String, String)>,
+    pub defaults: ComputedDefaults,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub enum ComputedPassVia {
+    Arg,
+    Stdin,
+    Env(String),
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ComputedNotification {
+    pub summary: String,
+    pub body: Option<String>,
+    /// Resolved icon path of the item that was launched.
+    pub icon: Option<String>,
 }
 
 struct IdGenerator {
@@ -76,27 +462,85 @@ impl IdGenerator {
 
 #[derive(Clone)]
 struct InheritanceFrame {
-    icon_dirs: Vec<PathBuf>,
+    fuzzel_args: Vec<String>,
+    icon_dirs: Vec<IconDir>,
+    /// How far up the stack this menu's children keep looking for icon dirs.
+    inherit_icon_dirs: IconDirInheritance,
+    /// The ini this menu used, its own or the one it inherited.
     fuzzel_config_id: Option<usize>,
+    /// Keys set in that ini and the ones it includes.
+    fuzzel_config_keys: Vec<String>,
+    opener: Vec<String>,
+    placeholder: Option<String>,
+    description_separator: String,
+    markup: bool,
+    auto_size: Option<AutoSize>,
+    binds: Vec<(String, String)>,
+    use_fuzzel_cache: bool,
+    icon_size: Option<u32>,
+    prefer_icon_format: IconFormat,
+    icon_match: IconMatch,
+    symbolic: SymbolicIcons,
+    /// From the config's `defaults` block, the same in every frame.
+    icon_dir_walk: IconWalk,
+    /// From the `icon-theme` in the menu's ini, or the one it inherited.
+    icon_theme: Option<String>,
+    /// Only set in the root frame, from the config's `power-commands` block.
+    power_commands: Vec<(PowerAction, Vec<String>)>,
+    /// The menu's hooks, with `menu_path` set to the path of the child menu.
+    hooks: ComputedHooks,
+    /// From the config's `defaults` block, the same in every frame.
+    program_defaults: ProgramDefaults,
+    /// Variables from this menu's `env` block and its parents', each with the
+    /// menu that set it.
+    env: Vec<(String, String, String)>,
+}
+
+/// What programs get when they don't set these themselves.
+#[derive(Debug, Clone, Default)]
+struct ProgramDefaults {
+    systemd_scope: bool,
+    launch_method: Option<LaunchMethod>,
+    /// Put in front of commands from desktop entries that run in a terminal.
+    terminal: Vec<String>,
+    stdout: Option<parser::Output>,
+    stderr: Option<parser::Output>,
 }
 
 // Intermediate tree structure that holds fully resolved data
-#[derive(Debug)]
+#[derive(Debug, Default, Clone)]
 struct ResolvedMenu {
     args: Vec<String>,
     input: Vec<u8>,
     items: Vec<ResolvedItem>,
+    back_on_cancel: bool,
+    stay_open: bool,
+    actions: Vec<ComputedAction>,
+    labels: Vec<ComputedLabel>,
+    hooks: ComputedHooks,
+    names: Vec<String>,
+    ids: Vec<Option<String>>,
+    multi_select: Option<ComputedMultiSelect>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum ResolvedItem {
     Menu(ResolvedMenu),
     Program(ComputedProgram),
+    Back,
+    DynamicMenu(ComputedDynamicMenu),
+    Pick(ComputedPick),
+    MimeOpen(ComputedMimeOpen),
+    Input(ComputedInput),
+    FilesMenu(ComputedFilesMenu),
+    RecentFiles(ComputedRecentFiles),
+    /// The path to the item an alias points at, see `alias_target`.
+    Alias(Vec<usize>),
 }
 
 impl InheritanceFrame {
     fn default() -> Self {
-        let mut data_dirs = std::env::var("XDG_DATA_DIRS").unwrap_or_default();
+        let mut data_dirs = env_var("XDG_DATA_DIRS").unwrap_or_default();
         if data_dirs.is_empty() {
             data_dirs = "/usr/local/share/:/usr/share/".to_string();
             info!("XDG_DATA_DIRS is empty, using {data_dirs} as default");
@@ -104,605 +548,7592 @@ impl InheritanceFrame {
 
         let mut icon_dirs: Vec<PathBuf> = std::env::split_paths(&data_dirs).collect();
 
-        let mut data_home = std::env::var("XDG_DATA_HOME").unwrap_or_default();
+        let mut data_home = env_var("XDG_DATA_HOME").unwrap_or_default();
         if data_home.is_empty() {
-            let home = std::env::home_dir().unwrap();
-            data_home = format!("{}/.local/share/", home.display());
-            warn!("XDG_DATA_HOME is empty, using {data_home} as default");
+            match home() {
+                Ok(home) => {
+                    data_home = format!("{home}/.local/share/");
+                    warn!("XDG_DATA_HOME is empty, using {data_home} as default");
+                }
+                Err(error) => warn!("XDG_DATA_HOME is empty and {error}"),
+            }
+        }
+        if !data_home.is_empty() {
+            icon_dirs.push(PathBuf::from(data_home));
         }
-        icon_dirs.push(PathBuf::from(data_home));
 
         Self {
-            icon_dirs,
+            fuzzel_args: Vec::new(),
+            icon_dirs: icon_dirs
+                .into_iter()
+                .map(|path| IconDir {
+                    path,
+                    walk: IconWalk::default(),
+                })
+                .collect(),
+            inherit_icon_dirs: IconDirInheritance::All,
             fuzzel_config_id: None,
+            fuzzel_config_keys: Vec::new(),
+            opener: vec!["xdg-open".to_string()],
+            placeholder: None,
+            description_separator: " — ".to_string(),
+            markup: false,
+            auto_size: None,
+            binds: Vec::new(),
+            use_fuzzel_cache: true,
+            icon_size: None,
+            prefer_icon_format: IconFormat::default(),
+            icon_match: IconMatch::default(),
+            symbolic: SymbolicIcons::default(),
+            icon_dir_walk: IconWalk::default(),
+            icon_theme: None,
+            power_commands: Vec::new(),
+            hooks: ComputedHooks::default(),
+            program_defaults: ProgramDefaults::default(),
+            env: Vec::new(),
         }
     }
 }
 
-pub fn get_computed_config(path: &Path) -> Result<ComputedConfig> {
-    let config_string = std::fs::read_to_string(path)
-        .into_diagnostic()
-        .with_context(|| format!("failed to read config file: {}", path.display()))?;
-    let actual_hash = Sha256::digest(&config_string);
+/// A config to build, and what it's built with besides the file itself.
+pub struct ConfigBuild<'a> {
+    pub dirs: &'a Dirs,
+    pub path: &'a Path,
+    /// From `--profile`, which keeps only its items and untagged ones.
+    pub profile: Option<&'a str>,
+    pub options: ParseOptions,
+    pub global: &'a GlobalConfig,
+    /// config.kdl as it was read, which every preset's cache depends on.
+    pub global_source: &'a str,
+}
 
-    let preset_name = path
-        .file_stem()
-        .unwrap()
-        .to_str()
-        .context("preset name contains non-utf8 characters")?;
-    let cache_path = make_cache_path(preset_name);
-    let maybe_cached_config = read_cached_config(&cache_path);
+/// The config, from its cache when nothing it depends on has changed.
+/// `refresh_icons` looks for the icons that weren't found again, even if
+/// their dirs haven't changed. With `rebuild_stale`, uff itself, a stale cache
+/// is used this time and rebuilt in the background, see `rebuild_in_background`.
+pub fn get_computed_config(
+    build: &ConfigBuild,
+    refresh_icons: bool,
+    rebuild_stale: Option<&Path>,
+) -> Result<ComputedConfig> {
+    let &ConfigBuild {
+        dirs,
+        path,
+        profile,
+        options,
+        global,
+        global_source,
+    } = build;
+    let mut source = ConfigSource::new(path, global_source)?;
+    let preset_name = &preset_name(path, profile)?;
+    let cache_path = make_cache_path(dirs, preset_name);
+    // Checks only happen while building, so --strict never uses the cache
+    let maybe_cached_config = if options.strict {
+        None
+    } else {
+        read_cached_config(&cache_path)
+    };
 
+    let mut previous_icons = IconCache::default();
     match maybe_cached_config {
         Some(cached_config) => {
-            if cached_config.hash == actual_hash[..8] {
-                info!("using cached config");
-                return Ok(cached_config);
+            let cached_at = desktop::modified(&cache_path);
+            if cached_config_is_fresh(
+                &cached_config,
+                &mut source,
+                cached_at,
+                dirs,
+                options,
+                refresh_icons,
+            )? {
+                return use_cached_config(cached_config, dirs, preset_name);
+            }
+            // Unless it may not have been checked like this build would be
+            if let Some(exe) = rebuild_stale
+                && !refresh_icons
+                && (options.lenient || !cached_config.lenient)
+            {
+                info!("using the stale cached config this time, rebuilding it in the background");
+                rebuild_in_background(exe, path, profile, options);
+                return use_cached_config(cached_config, dirs, preset_name);
+            }
+            // Icons in dirs that didn't change are where they were
+            previous_icons = cached_config.icons;
+            if refresh_icons {
+                previous_icons.forget_missing();
             }
-            info!("cached config is stale, rebuilding");
         }
         None => {
             info!("no cached config, building from scratch");
+            // Likely the first build since it was named after the path too
+            migrate_legacy_files(dirs, &legacy_preset_name(path, profile)?, preset_name);
         }
     }
 
-    let computed_config = compute_config(&config_string, &actual_hash, preset_name, path)?;
-    cache_config(&cache_path, &computed_config);
+    let lock = lock_cache(&dirs.cache, preset_name, CACHE_LOCK_TIMEOUT);
+    // Another uff was building it, which is likely what this one would build
+    if lock.as_ref().is_some_and(|lock| lock.waited)
+        && !options.strict
+        && let Some(cached_config) = read_cached_config(&cache_path)
+        && cached_config_is_fresh(
+            &cached_config,
+            &mut source,
+            desktop::modified(&cache_path),
+            dirs,
+            options,
+            refresh_icons,
+        )?
+    {
+        return use_cached_config(cached_config, dirs, preset_name);
+    }
+
+    let (config_string, actual_hash) = source.read()?;
+    let mut computed_config = compute_config(
+        config_string,
+        actual_hash,
+        dirs,
+        preset_name,
+        profile,
+        options,
+        global,
+        path,
+        previous_icons,
+    )?;
+    computed_config.config_stamp = source.stamp;
+    computed_config.global_hash = source.global_hash;
+    cache_config(
+        &cache_path,
+        &computed_config,
+        global.settings.cache_compression.unwrap_or(true),
+    );
+    computed_config.locate_fuzzel_files(&dirs.cache, &dirs.state);
     Ok(computed_config)
 }
 
-fn make_cache_path(preset_name: &str) -> PathBuf {
-    let mut cache_path = get_cache_dir();
-    cache_path.push(preset_name);
-    cache_path.set_extension("cache");
-    cache_path
+/// The config file, which isn't read while its cache can be used without it.
+struct ConfigSource<'a> {
+    path: &'a Path,
+    global_source: &'a str,
+    /// When the file was last changed, in nanoseconds since the epoch, and its
+    /// size, from before it was read.
+    stamp: Option<(u64, u64)>,
+    /// `config_hash` of everything but the config.
+    global_hash: [u8; 32],
+    /// The config and its `config_hash`, once it's read.
+    read: Option<(String, [u8; 32])>,
 }
 
-fn make_fuzzel_config_path(id: usize, preset_name: &str) -> PathBuf {
-    let mut config_path = get_cache_dir();
-    config_path.push(format!("{preset_name}{id}"));
-    config_path.set_extension("fuzzel.ini");
-    config_path
+impl<'a> ConfigSource<'a> {
+    fn new(path: &'a Path, global_source: &'a str) -> Result<Self> {
+        // Pipes like /dev/stdin have a stamp that doesn't change with what's in them
+        let stamp = std::fs::metadata(path)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .and_then(|metadata| {
+                let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+                Some((u64::try_from(modified.as_nanos()).ok()?, metadata.len()))
+            });
+        let mut source = Self {
+            path,
+            global_source,
+            stamp,
+            global_hash: config_hash(env!("CARGO_PKG_VERSION"), global_source, ""),
+            read: None,
+        };
+        // Without a stamp it's read right away, so a missing or unreadable
+        // file reports its error here
+        if source.stamp.is_none() {
+            source.read()?;
+        }
+        Ok(source)
+    }
+
+    fn read(&mut self) -> Result<(&str, &[u8; 32])> {
+        if self.read.is_none() {
+            self.read = Some(read_config(self.path, self.global_source)?);
+        }
+        let (config_string, hash) = self.read.as_ref().unwrap();
+        Ok((config_string, hash))
+    }
+
+    /// Whether `cached_config` was built from this config. It isn't read if it
+    /// has the stamp it had then and was last changed before the cache was
+    /// written at `cached_at`, since a change right after being read could
+    /// leave the same stamp.
+    fn built(&mut self, cached_config: &ComputedConfig, cached_at: Option<u64>) -> Result<bool> {
+        if let Some((modified, _)) = self.stamp
+            && cached_config.config_stamp == self.stamp
+            && cached_config.global_hash == self.global_hash
+            && cached_at.is_some_and(|cached_at| modified < cached_at)
+        {
+            debug!("config file unchanged since it was cached, not reading it");
+            return Ok(true);
+        }
+        let (config_string, hash) = self.read()?;
+        Ok(cached_config.hash == *hash && cached_config.config_len == config_string.len() as u64)
+    }
 }
 
-fn make_fuzzel_cache_path(id: usize, preset_name: &str) -> PathBuf {
-    let mut cache_path = get_cache_dir();
-    cache_path.push(format!("{preset_name}{id}"));
-    cache_path.set_extension("fuzzel.cache");
-    cache_path
+/// Starts `exe --warm` for the config, detached, so the next launch can use a
+/// fresh cache without waiting for it.
+fn rebuild_in_background(exe: &Path, path: &Path, profile: Option<&str>, options: ParseOptions) {
+    let mut command = Command::new(exe);
+    command.args(rebuild_args(path, profile, options));
+    detach::detach(&mut command);
+    match command.spawn() {
+        Ok(child) => detach::reap(child),
+        Err(error) => warn!("failed to start rebuilding the cache: {error}"),
+    }
 }
 
-fn default_fuzzel_config_path() -> PathBuf {
-    if cfg!(test) {
-        PathBuf::from("placeholder.fuzzel.ini")
-    } else {
-        let mut path = default_config_dir();
-        path.push("fuzzel");
-        path.push("fuzzel.ini");
-        path
+fn rebuild_args(path: &Path, profile: Option<&str>, options: ParseOptions) -> Vec<OsString> {
+    let mut args = vec![OsString::from("--warm")];
+    if let Some(profile) = profile {
+        args.extend(["--profile".into(), profile.into()]);
     }
+    if options.lenient {
+        args.push("--lenient".into());
+    }
+    args.push(path.into());
+    args
 }
 
-fn create_fuzzel_config(
-    pairs: &[(String, String)],
-    id: usize,
-    inherit_id: Option<usize>,
+/// A cache may have been written to another cache dir, or had its inis
+/// deleted since, so they're written for the current one.
+fn use_cached_config(
+    mut cached_config: ComputedConfig,
+    dirs: &Dirs,
     preset_name: &str,
-) -> PathBuf {
-    let config_path = make_fuzzel_config_path(id, preset_name);
+) -> Result<ComputedConfig> {
+    restore_fuzzel_inis(&cached_config.fuzzel_inis, dirs, preset_name)?;
+    cached_config.locate_fuzzel_files(&dirs.cache, &dirs.state);
+    Ok(cached_config)
+}
 
-    // Create the directory if it doesn't exist
-    if let Some(parent) = config_path.parent() {
-        let _ = std::fs::create_dir_all(parent);
+/// Whether a cached config can be used as it is, logging why not.
+fn cached_config_is_fresh(
+    cached_config: &ComputedConfig,
+    source: &mut ConfigSource,
+    cached_at: Option<u64>,
+    dirs: &Dirs,
+    options: ParseOptions,
+    refresh_icons: bool,
+) -> Result<bool> {
+    if !source.built(cached_config, cached_at)? {
+        info!("cached config is stale, rebuilding");
+    } else if !icon_dir_globs_unchanged(&cached_config.icon_dir_globs) {
+        info!("icon-dir patterns match different directories, rebuilding");
+    } else if !env_vars_unchanged(&cached_config.env_vars) {
+        info!("environment variables used by the config changed, rebuilding");
+    } else if !command_choices_unchanged(&cached_config.command_choices, std::env::var_os("PATH")) {
+        info!("programs found in PATH changed, rebuilding");
+    } else if !desktop_entries_unchanged(
+        &cached_config.desktop_entries,
+        &desktop::applications_dirs(),
+    ) {
+        info!("desktop entries used by the config changed, rebuilding");
+    } else if cached_config
+        .hostname
+        .as_ref()
+        .is_some_and(|cached| *cached != hostname().unwrap_or_default())
+    {
+        info!("hostname changed, rebuilding");
+    } else if cached_config
+        .detected_icon_theme
+        .as_ref()
+        .is_some_and(|cached| *cached != icons::detect_theme(&dirs.config))
+    {
+        info!("icon theme changed, rebuilding");
+    } else if cached_config.lenient && !options.lenient {
+        info!("cached config was built with --lenient, rebuilding");
+    } else if !cached_config.icons.unchanged() {
+        info!("icon dirs changed, rebuilding");
+    } else if refresh_icons && cached_config.icons.has_missing() {
+        info!("looking for missing icons again, rebuilding");
+    } else {
+        info!("using cached config");
+        return Ok(true);
     }
+    Ok(false)
+}
 
-    let mut config_file = File::create(&config_path).unwrap();
+/// How long to wait for another uff building the same preset.
+const CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
 
-    let inherit_path = inherit_id.map_or_else(default_fuzzel_config_path, |inherit_id| {
-        make_fuzzel_config_path(inherit_id, preset_name)
-    });
-    writeln!(&mut config_file, "include={}", inherit_path.display()).unwrap();
+/// Held while a preset's files are built and written, so two uffs started at
+/// once don't write them at the same time. Unlocked when dropped.
+struct CacheLock {
+    _file: File,
+    /// Another uff held it first, so the cache may have just been written.
+    waited: bool,
+}
 
-    for (key, value) in pairs {
-        writeln!(&mut config_file, "{key}={value}").unwrap();
+/// Locks `{preset}.lock` in the cache dir, waiting up to `timeout` for another
+/// uff to finish. Launching never depends on it: when it can't be locked, this
+/// warns and the preset is built without it.
+fn lock_cache(cache_dir: &Path, preset_name: &str, timeout: Duration) -> Option<CacheLock> {
+    let lock_path = cache_dir.join(format!("{preset_name}.lock"));
+    let _ = std::fs::create_dir_all(cache_dir);
+    let file = match File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+    {
+        Ok(file) => file,
+        Err(error) => {
+            warn!(
+                "failed to open {}, building without a lock: {error}",
+                lock_path.display()
+            );
+            return None;
+        }
+    };
+    let start = Instant::now();
+    let mut waited = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => {
+                return Some(CacheLock {
+                    _file: file,
+                    waited,
+                });
+            }
+            Err(TryLockError::WouldBlock) if start.elapsed() < timeout => {
+                if !waited {
+                    info!("another uff is building {preset_name}, waiting for it");
+                    waited = true;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(TryLockError::WouldBlock) => {
+                warn!("another uff is still building {preset_name}, building without a lock");
+                return None;
+            }
+            Err(TryLockError::Error(error)) => {
+                warn!(
+                    "failed to lock {}, building without a lock: {error}",
+                    lock_path.display()
+                );
+                return None;
+            }
+        }
     }
+}
 
-    config_path
+/// Resolves the config to find every icon that can't be found, without using or
+/// replacing its cache or writing its files, so icons added since the last
+/// build are looked for too.
+pub fn check_icons(build: &ConfigBuild) -> Result<Vec<MissingIcon>> {
+    let &ConfigBuild {
+        dirs,
+        path,
+        profile,
+        options,
+        global,
+        global_source,
+    } = build;
+    let (config_string, hash) = read_config(path, global_source)?;
+    let preset_name = &preset_name(path, profile)?;
+    let resolved = resolve_config(
+        &config_string,
+        &hash,
+        dirs,
+        preset_name,
+        profile,
+        options,
+        global,
+        path,
+        IconCache::default(),
+    )?;
+    Ok(resolved.computed.missing_icons)
 }
 
-fn get_cache_dir() -> PathBuf {
-    if cfg!(test) {
-        PathBuf::from("./target/test-cache")
-    } else {
-        let mut path;
-        if let Ok(config_home) = std::env::var("XDG_CACHE_HOME") {
-            path = PathBuf::from(config_home);
-        } else {
-            path = std::env::home_dir().unwrap();
-            path.push(".cache");
-        }
-        path.push(env!("CARGO_BIN_NAME"));
-        path
-    }
+/// The config file and its hash.
+fn read_config(path: &Path, global_source: &str) -> Result<(String, [u8; 32])> {
+    let config_string = std::fs::read_to_string(path)
+        .into_diagnostic()
+        .with_context(|| format!("failed to read config file: {}", path.display()))?;
+    let hash = config_hash(env!("CARGO_PKG_VERSION"), global_source, &config_string);
+    Ok((config_string, hash))
 }
 
-fn read_cached_config(path: &Path) -> Option<ComputedConfig> {
-    let bytes = std::fs::read(path).ok()?;
-    let decoded = bitcode::decode(&bytes);
-    match decoded {
-        Ok(decoded) => Some(decoded),
-        Err(error) => {
-            error!("failed to decode cached config: {error}");
-            None
-        }
-    }
+/// The SHA256 of uff's version, the config version it reads, config.kdl and
+/// the config, each but the last after its length. Upgrading uff rebuilds
+/// every cache once, since a new version can build the same config
+/// differently, and changing config.kdl rebuilds every preset.
+fn config_hash(uff_version: &str, global_source: &str, config_string: &str) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(uff_version.len().to_le_bytes())
+        .chain_update(uff_version)
+        .chain_update(parser::CONFIG_VERSIONS.end().to_le_bytes())
+        .chain_update(global_source.len().to_le_bytes())
+        .chain_update(global_source)
+        .chain_update(config_string)
+        .finalize()
+        .into()
 }
 
-fn cache_config(path: &Path, computed_config: &ComputedConfig) {
-    let bytes = bitcode::encode(computed_config);
-    if let Err(error) = std::fs::create_dir_all(path.parent().unwrap()) {
-        error!("failed to create cache directory: {error}");
+/// What the config's cache and generated fuzzel files are named after: its
+/// stem and a short hash of where it is, like `default-3fa2b1`, so configs with
+/// the same name in different dirs don't share them.
+fn preset_name(path: &Path, profile: Option<&str>) -> Result<String> {
+    let canonical_path = std::fs::canonicalize(path)
+        .into_diagnostic()
+        .with_context(|| format!("failed to resolve config path: {}", path.display()))?;
+    let digest = Sha256::digest(canonical_path.as_os_str().as_encoded_bytes());
+    let hash: String = digest[..3]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let stem = legacy_preset_name(path, None)?;
+    // Each profile gets its own cache and generated fuzzel files
+    Ok(match profile {
+        Some(profile) => format!("{stem}-{hash}@{profile}"),
+        None => format!("{stem}-{hash}"),
+    })
+}
+
+/// What uff named the config's files before `preset_name` included its path.
+fn legacy_preset_name(path: &Path, profile: Option<&str>) -> Result<String> {
+    let preset_name = path
+        .file_stem()
+        .context("config path has no file name")?
+        .to_str()
+        .context("preset name contains non-utf8 characters")?;
+    Ok(match profile {
+        Some(profile) => format!("{preset_name}@{profile}"),
+        None => preset_name.to_string(),
+    })
+}
+
+/// Moves the fuzzel usage counts kept under the config's legacy name to the
+/// state dir under its new one, and deletes the cache, fuzzel configs and
+/// scripts, which are written again.
+fn migrate_legacy_files(dirs: &Dirs, legacy_name: &str, preset_name: &str) {
+    let cache_dir = &dirs.cache;
+    let cache_path = cache_dir.join(format!("{legacy_name}.cache"));
+    if cache_path.exists() {
+        info!("removing cache from an older uff: {}", cache_path.display());
+        let _ = std::fs::remove_file(&cache_path);
     }
-    if let Err(error) = std::fs::write(path, bytes) {
-        error!("failed to write cache file: {error}");
+    move_fuzzel_caches(cache_dir, &dirs.state, legacy_name, preset_name);
+    // Usage counts in the state dir all have new names, so only the inis go
+    remove_stale_fuzzel_files(cache_dir, &dirs.state, legacy_name, 0);
+    let scripts_dir = cache_dir.join("scripts").join(legacy_name);
+    if scripts_dir.exists() {
+        info!(
+            "removing scripts from an older uff: {}",
+            scripts_dir.display()
+        );
+        let _ = std::fs::remove_dir_all(&scripts_dir);
     }
 }
 
-fn compute_config(
-    config_string: &str,
-    hash: &[u8],
-    preset_name: &str,
-    path: &Path,
-) -> Result<ComputedConfig> {
-    let config = parser::parse_config(config_string)
-        .wrap_err("failed to parse config")
-        .map_err(|report| {
-            report.with_source_code(NamedSource::new(
-                path.display().to_string(),
-                config_string.to_string(),
-            ))
-        })?;
-    let inheritance_stack = vec![InheritanceFrame::default()];
-    let mut id_gen = IdGenerator::new();
+/// An item's path from the root menu, given the path of the menu it's in.
+fn item_path(menu_path: &str, name: &str) -> String {
+    if menu_path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{menu_path}/{name}")
+    }
+}
 
-    // Build phase: create fully resolved tree with inheritance applied
-    let resolved_menu = build_resolved_menu(&config, &inheritance_stack, &mut id_gen, preset_name);
+fn make_cache_path(dirs: &Dirs, preset_name: &str) -> PathBuf {
+    let mut cache_path = dirs.cache.clone();
+    cache_path.push(preset_name);
+    cache_path.set_extension("cache");
+    cache_path
+}
 
-    let mut items = Vec::new();
-    // Flatten phase: convert tree to a flat list
-    let initial_menu = flatten_resolved_menu(&resolved_menu, &mut items);
+fn make_fuzzel_config_path(dirs: &Dirs, id: usize, preset_name: &str) -> PathBuf {
+    dirs.cache.join(fuzzel_config_name(id, preset_name))
+}
 
-    Ok(ComputedConfig {
-        hash: std::array::from_fn(|i| hash[i]),
-        initial_menu,
-        items,
-    })
+/// Cached args name the ini by this, see `ComputedConfig::locate_fuzzel_files`.
+fn fuzzel_config_name(id: usize, preset_name: &str) -> String {
+    format!("{preset_name}{id}.fuzzel.ini")
 }
 
-fn build_resolved_menu(
-    menu: &Menu,
-    inheritance_stack: &[InheritanceFrame],
-    id_gen: &mut IdGenerator,
-    preset_name: &str,
-) -> ResolvedMenu {
-    let id = id_gen.next_id();
+/// Cached args name fuzzel's cache by this, see `ComputedConfig::locate_fuzzel_files`.
+fn fuzzel_cache_name(id: usize, preset_name: &str) -> String {
+    format!("{preset_name}{id}.fuzzel.cache")
+}
 
-    let mut args = menu.fuzzel_args.clone();
+/// Programs run scripts by their path, so a config with them depends on
+/// `Dirs::cache_env` and is rebuilt when the cache dir moves.
+fn make_scripts_dir(dirs: &Dirs, preset_name: &str) -> PathBuf {
+    let mut scripts_dir = dirs.cache.clone();
+    scripts_dir.push("scripts");
+    scripts_dir.push(preset_name);
+    scripts_dir
+}
 
-    let last_config = inheritance_stack
-        .iter()
-        .filter_map(|frame| frame.fuzzel_config_id)
-        .next_back();
-
-    if menu.fuzzel_config.is_empty() {
-        if let Some(last_config) = last_config {
-            args.push("--config".to_string());
-            args.push(
-                make_fuzzel_config_path(last_config, preset_name)
-                    .display()
-                    .to_string(),
-            );
-        }
-    } else {
-        args.push("--config".to_string());
-        args.push(
-            create_fuzzel_config(&menu.fuzzel_config, id, last_config, preset_name)
-                .display()
-                .to_string(),
-        );
+fn create_fuzzel_config(ini: &FuzzelIni, dirs: &Dirs, preset_name: &str) -> Result<PathBuf> {
+    let config_path = make_fuzzel_config_path(dirs, ini.id, preset_name);
+
+    // Create the directory if it doesn't exist
+    if let Some(parent) = config_path.parent() {
+        let _ = std::fs::create_dir_all(parent);
     }
 
-    // Add unique cache path for this menu
-    args.push("--cache".to_string());
-    args.push(
-        make_fuzzel_cache_path(id, preset_name)
-            .display()
-            .to_string(),
+    std::fs::write(&config_path, fuzzel_config_contents(ini, dirs, preset_name))
+        .into_diagnostic()
+        .with_context(|| format!("failed to write fuzzel config {}", config_path.display()))?;
+
+    Ok(config_path)
+}
+
+/// What `create_fuzzel_config` writes, which includes the ini's parent or
+/// fuzzel's own config by their paths.
+fn fuzzel_config_contents(ini: &FuzzelIni, dirs: &Dirs, preset_name: &str) -> Vec<u8> {
+    let inherit_path = ini.inherit_id.map_or_else(
+        || dirs.fuzzel_config.clone(),
+        |inherit_id| make_fuzzel_config_path(dirs, inherit_id, preset_name),
     );
+    let mut contents = Vec::new();
+    writeln!(&mut contents, "include={}", inherit_path.display()).unwrap();
+    write_fuzzel_config_pairs(&mut contents, &ini.pairs);
+    contents
+}
+
+/// Writes the inis of a cached config again if they were deleted, since
+/// fuzzel would otherwise start without them, or if they include files from
+/// another cache dir or fuzzel config, like when the cache dir was copied.
+/// Parents included by another ini are checked too, which looking at the
+/// menus' `--config` wouldn't find.
+fn restore_fuzzel_inis(inis: &[FuzzelIni], dirs: &Dirs, preset_name: &str) -> Result<()> {
+    for ini in inis {
+        let path = make_fuzzel_config_path(dirs, ini.id, preset_name);
+        match std::fs::read(&path) {
+            Ok(contents) if contents == fuzzel_config_contents(ini, dirs, preset_name) => {}
+            Ok(_) => {
+                info!(
+                    "fuzzel config {} is out of date, writing it again",
+                    path.display()
+                );
+                create_fuzzel_config(ini, dirs, preset_name)?;
+            }
+            Err(_) => {
+                info!(
+                    "fuzzel config {} is missing, writing it again",
+                    path.display()
+                );
+                create_fuzzel_config(ini, dirs, preset_name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An inline script's path and contents. It's named after its contents, so
+/// changing the body creates a new file instead of editing one that might be
+/// running.
+fn script_file(script: &parser::Script, dirs: &Dirs, preset_name: &str) -> (PathBuf, String) {
+    let mut contents = String::new();
+    if let Some(shebang) = &script.shebang {
+        contents.push_str("#!");
+        contents.push_str(shebang.trim_start_matches("#!"));
+        contents.push('\n');
+    }
+    contents.push_str(&script.body);
+    if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
 
-    // Build icon dirs with inheritance
-    let icon_dirs: VecDeque<&Path> = menu
-        .icon_dirs
+    let digest = Sha256::digest(&contents);
+    let name: String = digest[..8]
         .iter()
-        .map(PathBuf::as_path)
-        .chain(
-            inheritance_stack
-                .iter()
-                .rev()
-                .flat_map(|frame| frame.icon_dirs.iter().map(PathBuf::as_path)),
-        )
+        .map(|byte| format!("{byte:02x}"))
         .collect();
+    let mut script_path = make_scripts_dir(dirs, preset_name);
+    script_path.push(name);
+    script_path.set_extension("sh");
+    (script_path, contents)
+}
 
-    // Build fuzzel input format: {NAME}\0icon\x1f{ICON_PATH}\n
-    let mut input = Vec::new();
+/// Every inline script in the menu and its submenus, see `script_file`.
+fn script_files(menu: &Menu, dirs: &Dirs, preset_name: &str) -> Vec<(PathBuf, String)> {
+    let mut found = Vec::new();
     for item in &menu.items {
-        write!(&mut input, "{}", item.name).unwrap();
-        if let Some(icon) = &item.icon {
-            let mut item_icon_dirs = icon_dirs.clone();
-            if let ItemContents::Menu(menu) = &item.contents {
-                for icon_dir in &menu.icon_dirs {
-                    item_icon_dirs.push_front(icon_dir);
-                }
-            }
-
-            let icon_path = search_for_icon(icon, item_icon_dirs).map_or_else(
-                || icon.replace('~', &home()),
-                |path| path.display().to_string(),
-            );
-            write!(&mut input, "\0icon\x1f{icon_path}").unwrap();
+        match &item.contents {
+            ItemContents::Menu(submenu) => found.extend(script_files(submenu, dirs, preset_name)),
+            ItemContents::Script(script) => found.push(script_file(script, dirs, preset_name)),
+            _ => {}
         }
-        writeln!(&mut input).unwrap();
     }
+    found
+}
 
-    // Build child inheritance frame for recursive calls
-    let child_frame = InheritanceFrame {
-        icon_dirs: menu.icon_dirs.clone(),
-        fuzzel_config_id: if menu.fuzzel_config.is_empty() {
-            None
-        } else {
-            Some(id)
-        },
+fn write_script(script_path: &Path, contents: &str) {
+    if script_path.exists() {
+        return;
+    }
+
+    if let Some(parent) = script_path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    if let Err(error) = std::fs::write(script_path, contents) {
+        error!("failed to write script {}: {error}", script_path.display());
+    }
+    if let Err(error) =
+        std::fs::set_permissions(script_path, std::fs::Permissions::from_mode(0o755))
+    {
+        error!("failed to make script executable: {error}");
+    }
+}
+
+/// Deletes scripts that are no longer used by any program in this preset.
+fn remove_stale_scripts(dirs: &Dirs, preset_name: &str, items: &[ComputedItem]) {
+    let Ok(entries) = std::fs::read_dir(make_scripts_dir(dirs, preset_name)) else {
+        return;
     };
+    for entry in entries.flatten() {
+        let path = entry.path().display().to_string();
+        let in_use = items.iter().any(|item| {
+            matches!(item, ComputedItem::Program(program) if program.command.contains(&path))
+        });
+        if !in_use {
+            info!("removing unused script: {path}");
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
 
-    // Recursively build resolved items
-    let mut resolved_items = Vec::new();
-    for item in &menu.items {
-        match &item.contents {
-            ItemContents::Menu(child_menu) => {
-                let mut child_inheritance_stack = inheritance_stack.to_vec();
-                child_inheritance_stack.push(child_frame.clone());
-                let resolved_child =
-                    build_resolved_menu(child_menu, &child_inheritance_stack, id_gen, preset_name);
-                resolved_items.push(ResolvedItem::Menu(resolved_child));
+/// The id of one of this preset's fuzzel files, named `{preset}{id}{extension}`.
+/// Another preset's files can have the same prefix (`work12` may be `work1`'s
+/// menu 2), so there's none whenever such a preset has a cache in `cache_dir`.
+fn fuzzel_file_id(
+    file_name: &OsStr,
+    extension: &str,
+    preset_name: &str,
+    cache_dir: &Path,
+) -> Option<usize> {
+    let digits = file_name
+        .to_str()?
+        .strip_suffix(extension)?
+        .strip_prefix(preset_name)?;
+    // Ids are written without leading zeros
+    if digits.is_empty()
+        || !digits.bytes().all(|byte| byte.is_ascii_digit())
+        || (digits.len() > 1 && digits.starts_with('0'))
+    {
+        return None;
+    }
+    let other_preset = (1..digits.len()).any(|split| {
+        cache_dir
+            .join(format!("{preset_name}{}.cache", &digits[..split]))
+            .exists()
+    });
+    if other_preset {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// Deletes the fuzzel inis and caches of this preset that no menu uses anymore.
+/// Ids are handed out in order, so every id from `ids` on is stale.
+fn remove_stale_fuzzel_files(cache_dir: &Path, state_dir: &Path, preset_name: &str, ids: usize) {
+    for (dir, extension) in [(cache_dir, ".fuzzel.ini"), (state_dir, ".fuzzel.cache")] {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let id = fuzzel_file_id(&entry.file_name(), extension, preset_name, cache_dir);
+            if id.is_none_or(|id| id < ids) {
+                continue;
             }
-            ItemContents::Program(program) => {
-                resolved_items.push(ResolvedItem::Program(ComputedProgram {
-                    command: program.command.clone(),
-                }));
+            let path = entry.path();
+            info!("removing unused fuzzel file: {}", path.display());
+            // Another uff may be cleaning up at the same time
+            match std::fs::remove_file(&path) {
+                Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                    warn!("failed to remove {}: {error}", path.display());
+                }
+                _ => {}
             }
         }
     }
+}
 
-    ResolvedMenu {
-        args,
-        input,
-        items: resolved_items,
+/// Moves the fuzzel caches named after `old_name` to the state dir from the
+/// cache dir, where they were kept before, naming them after `preset_name`.
+/// Ones already in the state dir are newer.
+fn move_fuzzel_caches(cache_dir: &Path, state_dir: &Path, old_name: &str, preset_name: &str) {
+    let Ok(entries) = std::fs::read_dir(cache_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Some(id) = fuzzel_file_id(&entry.file_name(), ".fuzzel.cache", old_name, cache_dir)
+        else {
+            continue;
+        };
+        let old_path = entry.path();
+        let new_path = state_dir.join(format!("{preset_name}{id}.fuzzel.cache"));
+        if new_path.exists() {
+            let _ = std::fs::remove_file(&old_path);
+            continue;
+        }
+        info!(
+            "moving fuzzel cache {} to {}",
+            old_path.display(),
+            new_path.display()
+        );
+        let _ = std::fs::create_dir_all(state_dir);
+        // Renaming fails when they're on different filesystems
+        if std::fs::rename(&old_path, &new_path).is_err() {
+            match std::fs::copy(&old_path, &new_path) {
+                Ok(_) => {
+                    let _ = std::fs::remove_file(&old_path);
+                }
+                Err(error) => warn!("failed to move {}: {error}", old_path.display()),
+            }
+        }
     }
 }
 
-fn flatten_resolved_menu(
-    resolved_menu: &ResolvedMenu,
-    items: &mut Vec<ComputedItem>,
-) -> ComputedMenu {
-    let items_offset = items.len();
-
-    // First pass: add all direct children to maintain adjacency
-    for resolved_item in &resolved_menu.items {
-        match resolved_item {
-            ResolvedItem::Menu(child_menu) => {
-                // Add placeholder menu item - we'll update its offset in second pass
-                items.push(ComputedItem::Menu(ComputedMenu {
-                    args: child_menu.args.clone(),
-                    input: child_menu.input.clone(),
-                    items_offset: 0, // Will be updated below
-                }));
-            }
-            ResolvedItem::Program(program) => {
-                items.push(ComputedItem::Program(program.clone()));
+/// Writes keys in the order they were given, with `section.key` pairs grouped
+/// under `[section]` headers after the unsectioned ones. Repeated keys are kept.
+fn write_fuzzel_config_pairs(writer: &mut impl Write, pairs: &[(String, String)]) {
+    let mut sections: Vec<&str> = Vec::new();
+    for (key, value) in pairs {
+        match key.split_once('.') {
+            None => writeln!(writer, "{key}={value}").unwrap(),
+            Some((section, _)) => {
+                if !sections.contains(&section) {
+                    sections.push(section);
+                }
             }
         }
     }
 
-    // Second pass: recursively flatten submenus and update their offsets
-    let mut current_index = items_offset;
-    for resolved_item in &resolved_menu.items {
-        if let ResolvedItem::Menu(child_menu) = resolved_item {
-            let child_offset = items.len();
-            // Update the offset for this menu item
-            if let ComputedItem::Menu(computed_menu) = &mut items[current_index] {
-                computed_menu.items_offset = child_offset;
+    for section in sections {
+        writeln!(writer, "[{section}]").unwrap();
+        for (key, value) in pairs {
+            if let Some((key_section, key)) = key.split_once('.')
+                && key_section == section
+            {
+                writeln!(writer, "{key}={value}").unwrap();
             }
-            // Recursively flatten the child menu
-            flatten_resolved_menu(child_menu, items);
         }
-        current_index += 1;
     }
+}
 
-    ComputedMenu {
-        args: resolved_menu.args.clone(),
-        input: resolved_menu.input.clone(),
-        items_offset,
-    }
+/// Where uff reads and writes its files, found once when it starts.
+pub struct Dirs {
+    /// Where the caches and generated files go. Cached configs don't depend
+    /// on it (see `ComputedConfig::locate_fuzzel_files`), unless scripts are
+    /// written there.
+    pub cache: PathBuf,
+    /// Where fuzzel's usage counts for each menu are kept, which can't be built
+    /// again like the cache, so they're safe from clearing it. Like the cache
+    /// dir, cached configs don't depend on it.
+    pub state: PathBuf,
+    /// `$XDG_CONFIG_HOME`, which has uff's presets and the icon theme settings.
+    pub config: PathBuf,
+    /// fuzzel's own config, at the bottom of every generated ini's include chain.
+    pub fuzzel_config: PathBuf,
+    /// The variables `cache` was found from and their values, which a config
+    /// with scripts depends on, see `make_scripts_dir`.
+    cache_env: EnvVars,
+    /// The variables `config` and `fuzzel_config` were found from and their
+    /// values, which every config depends on since its inis include fuzzel's.
+    config_env: EnvVars,
 }
 
-pub fn home() -> String {
-    let home_path = std::env::home_dir().unwrap();
-    home_path.to_string_lossy().to_string()
+impl Dirs {
+    pub fn from_env() -> Result<Self> {
+        without_env_reads(|| {
+            let cache_reads = EnvReads::start();
+            let cache = cache_dir()?;
+            let cache_env = cache_reads.finish();
+            let config_reads = EnvReads::start();
+            let config = default_config_dir()?;
+            let config_env = config_reads.finish();
+            Ok(Self {
+                cache,
+                state: state_dir(env_var("XDG_STATE_HOME"), &home()?),
+                fuzzel_config: config.join("fuzzel").join("fuzzel.ini"),
+                config,
+                cache_env,
+                config_env,
+            })
+        })
+    }
 }
 
-fn search_for_icon<'a>(name: &str, dirs: impl IntoIterator<Item = &'a Path>) -> Option<PathBuf> {
-    if name.contains('/') {
-        info!("icon name contains a '/', treating as full path: {name}");
-        return None; // probably a full path
+/// `$XDG_CACHE_HOME/uff`, or `~/.cache/uff`.
+fn cache_dir() -> Result<PathBuf> {
+    let mut path;
+    if let Some(cache_home) = env_var("XDG_CACHE_HOME") {
+        path = PathBuf::from(cache_home);
+    } else {
+        path = PathBuf::from(home()?);
+        path.push(".cache");
     }
+    path.push(env!("CARGO_BIN_NAME"));
+    Ok(path)
+}
 
-    for dir in dirs {
-        for entry in WalkDir::new(dir).into_iter().filter_map(Result::ok) {
-            if entry.path().file_stem() == Some(OsStr::new(name))
-                && (entry.path().extension() == Some(OsStr::new("png"))
-                    || entry.path().extension() == Some(OsStr::new("svg")))
-            {
-                return Some(entry.into_path());
+/// `$XDG_STATE_HOME/uff`, or `~/.local/state/uff` if it isn't an absolute path.
+fn state_dir(state_home: Option<String>, home: &str) -> PathBuf {
+    let mut path = state_home
+        .map(PathBuf::from)
+        .filter(|state_home| state_home.is_absolute())
+        .unwrap_or_else(|| Path::new(home).join(".local/state"));
+    path.push(env!("CARGO_BIN_NAME"));
+    path
+}
+
+/// Written before the encoded config in its cache. Bump it whenever anything
+/// encoded in `ComputedConfig` changes, so a cache written by a uff that
+/// encoded it differently is rebuilt instead of decoded into the wrong fields.
+const CACHE_SCHEMA_VERSION: u32 = 7;
+
+/// Follows the schema version, saying how the encoded config after it is stored.
+const CACHE_UNCOMPRESSED: u8 = 0;
+/// zlib, whose checksum catches a cache that was damaged on disk.
+const CACHE_ZLIB: u8 = 1;
+
+fn read_cached_config(path: &Path) -> Option<ComputedConfig> {
+    let bytes = std::fs::read(path).ok()?;
+    let Some((version, rest)) = bytes.split_first_chunk() else {
+        info!("cached config is empty");
+        return None;
+    };
+    let version = u32::from_le_bytes(*version);
+    if version != CACHE_SCHEMA_VERSION {
+        info!("cached config has schema version {version}, this uff writes {CACHE_SCHEMA_VERSION}");
+        return None;
+    }
+    let inflated;
+    let payload = match rest.split_first() {
+        Some((&CACHE_UNCOMPRESSED, payload)) => payload,
+        Some((&CACHE_ZLIB, compressed)) => {
+            match miniz_oxide::inflate::decompress_to_vec_zlib(compressed) {
+                Ok(payload) => {
+                    inflated = payload;
+                    &inflated
+                }
+                Err(error) => {
+                    error!("failed to decompress cached config: {error}");
+                    return None;
+                }
             }
         }
+        Some((format, _)) => {
+            info!("cached config is stored in unknown format {format}");
+            return None;
+        }
+        None => {
+            info!("cached config is empty");
+            return None;
+        }
+    };
+    let decoded = bitcode::decode(payload);
+    match decoded {
+        Ok(decoded) => Some(decoded),
+        Err(error) => {
+            error!("failed to decode cached config: {error}");
+            None
+        }
     }
-    error!("icon '{name}' not found in specified directories");
-    None
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::parser::{Item, ItemContents, Menu, Program};
+/// Compressing makes large configs' caches many times smaller, for a little
+/// longer to read them, see `Settings::cache_compression`.
+fn cache_config(path: &Path, computed_config: &ComputedConfig, compress: bool) {
+    let mut bytes = CACHE_SCHEMA_VERSION.to_le_bytes().to_vec();
+    let payload = bitcode::encode(computed_config);
+    if compress {
+        bytes.push(CACHE_ZLIB);
+        bytes.extend(miniz_oxide::deflate::compress_to_vec_zlib(&payload, 6));
+    } else {
+        bytes.push(CACHE_UNCOMPRESSED);
+        bytes.extend(payload);
+    }
+    if let Err(error) = std::fs::create_dir_all(path.parent().unwrap()) {
+        error!("failed to create cache directory: {error}");
+    }
+    if let Err(error) = std::fs::write(path, bytes) {
+        error!("failed to write cache file: {error}");
+    }
+}
 
-    #[test]
-    fn test_build_phase_comprehensive() {
-        // Test simple menu building
-        let simple_menu = Menu {
-            fuzzel_args: vec!["--arg1".to_string()],
-            fuzzel_config: vec![],
-            icon_dirs: vec![],
-            items: vec![Item {
-                name: "Item1".to_string(),
-                icon: None,
-                contents: ItemContents::Program(Program {
-                    command: vec!["cmd1".to_string()],
-                }),
-            }],
-        };
-        let inheritance_stack = vec![InheritanceFrame::default()];
-        let mut id_gen = IdGenerator::new();
-        let simple_result =
-            build_resolved_menu(&simple_menu, &inheritance_stack, &mut id_gen, "testsimple");
-        assert_eq!(
-            simple_result.args,
-            vec![
-                "--arg1",
-                "--cache",
-                "./target/test-cache/testsimple0.fuzzel.cache"
-            ]
-        );
-        assert_eq!(simple_result.input, b"Item1\n");
-        assert_eq!(simple_result.items.len(), 1);
+/// A computed config with the files it needs, which `write_config_files`
+/// writes to the cache dir.
+struct ResolvedConfig {
+    computed: ComputedConfig,
+    /// The inline scripts' paths and contents, see `script_file`.
+    scripts: Vec<(PathBuf, String)>,
+    /// How many fuzzel inis and caches the menus' ids leave room for.
+    id_count: usize,
+}
 
-        // Test menu with config file generation
-        let menu_with_config = Menu {
-            fuzzel_args: vec![],
-            fuzzel_config: vec![("width".to_string(), "12".to_string())],
-            icon_dirs: vec![],
-            items: vec![Item {
-                name: "Item1".to_string(),
-                icon: None,
-                contents: ItemContents::Program(Program {
-                    command: vec!["cmd1".to_string()],
-                }),
-            }],
-        };
-        let config_result = build_resolved_menu(
-            &menu_with_config,
-            &inheritance_stack,
-            &mut id_gen,
-            "testconfig",
-        );
-        assert_eq!(
-            config_result.args,
-            vec![
-                "--config",
-                "./target/test-cache/testconfig1.fuzzel.ini",
-                "--cache",
-                "./target/test-cache/testconfig1.fuzzel.cache"
-            ]
-        );
+/// Resolves the config and writes its scripts and fuzzel files.
+#[allow(clippy::too_many_arguments)]
+fn compute_config(
+    config_string: &str,
+    hash: &[u8; 32],
+    dirs: &Dirs,
+    preset_name: &str,
+    profile: Option<&str>,
+    options: ParseOptions,
+    global: &GlobalConfig,
+    path: &Path,
+    previous_icons: IconCache,
+) -> Result<ComputedConfig> {
+    let resolved = resolve_config(
+        config_string,
+        hash,
+        dirs,
+        preset_name,
+        profile,
+        options,
+        global,
+        path,
+        previous_icons,
+    )?;
+    write_config_files(&resolved, dirs, preset_name)?;
+    Ok(resolved.computed)
+}
 
-        // Verify config file was created with correct content
-        let config_content =
-            std::fs::read_to_string("./target/test-cache/testconfig1.fuzzel.ini").unwrap();
-        assert_eq!(config_content, "include=placeholder.fuzzel.ini\nwidth=12\n");
+/// Writes the scripts and fuzzel inis the config uses, and removes the ones
+/// an earlier version of it left behind.
+fn write_config_files(resolved: &ResolvedConfig, dirs: &Dirs, preset_name: &str) -> Result<()> {
+    for (script_path, contents) in &resolved.scripts {
+        write_script(script_path, contents);
+    }
+    for ini in &resolved.computed.fuzzel_inis {
+        create_fuzzel_config(ini, dirs, preset_name)?;
+    }
+    remove_stale_scripts(dirs, preset_name, &resolved.computed.items);
+    move_fuzzel_caches(&dirs.cache, &dirs.state, preset_name, preset_name);
+    remove_stale_fuzzel_files(&dirs.cache, &dirs.state, preset_name, resolved.id_count);
+    Ok(())
+}
 
-        // Test nested menu with inheritance
-        let nested_menu = Menu {
-            fuzzel_args: vec!["--base-arg".to_string()],
-            fuzzel_config: vec![("base_key".to_string(), "base_value".to_string())],
-            icon_dirs: vec![],
-            items: vec![
-                Item {
-                    name: "Item1".to_string(),
-                    icon: None,
-                    contents: ItemContents::Program(Program {
-                        command: vec!["cmd1".to_string()],
-                    }),
-                },
-                Item {
-                    name: "Submenu1".to_string(),
-                    icon: None,
-                    contents: ItemContents::Menu(Menu {
-                        fuzzel_args: vec![],
-                        fuzzel_config: vec![("sub_key".to_string(), "sub_value".to_string())],
-                        icon_dirs: vec![],
-                        items: vec![Item {
-                            name: "Item2".to_string(),
-                            icon: None,
-                            contents: ItemContents::Program(Program {
-                                command: vec!["cmd2".to_string()],
-                            }),
-                        }],
-                    }),
-                },
-            ],
-        };
-        let nested_result =
-            build_resolved_menu(&nested_menu, &inheritance_stack, &mut id_gen, "testnested");
+/// Everything `compute_config` does short of touching the disk.
+#[allow(clippy::too_many_arguments)]
+fn resolve_config(
+    config_string: &str,
+    hash: &[u8; 32],
+    dirs: &Dirs,
+    preset_name: &str,
+    profile: Option<&str>,
+    options: ParseOptions,
+    global: &GlobalConfig,
+    path: &Path,
+    previous_icons: IconCache,
+) -> Result<ResolvedConfig> {
+    let env_reads = EnvReads::start();
+    let with_source = |report: miette::Report| {
+        report.with_source_code(NamedSource::new(
+            path.display().to_string(),
+            config_string.to_string(),
+        ))
+    };
+    let mut config = parser::parse_config(config_string, options)
+        .wrap_err("failed to parse config")
+        .map_err(with_source)?;
+    // Before anything is filtered out, so a target is never missing only on some hosts
+    check_aliases(&config).map_err(with_source)?;
+    check_multi_select_aliases(&config, &config).map_err(with_source)?;
+    let mut missing_icons = Vec::new();
+    // Before desktop entries fill in icons, which aren't relative to the config
+    let config_dir = std::path::absolute(path)
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+    resolve_icon_paths(&mut config, &config_dir, "", options, &mut missing_icons)?;
+    apply_global_config(&mut config, global);
+    if let Some(profile) = profile {
+        if !profile_is_used(&config, profile) {
+            warn!("no items use profile '{profile}', only showing untagged items");
+        }
+        filter_by_profile(&mut config, profile);
+    }
+    let hostname = uses_host_conditions(&config).then(|| {
+        let hostname = hostname().unwrap_or_default();
+        if hostname.is_empty() {
+            warn!("couldn't read the hostname, if-host items are hidden");
+        }
+        filter_by_host(&mut config, &hostname);
+        hostname
+    });
+    let mut desktop_entries = Vec::new();
+    let terminal = terminal_command(&config.defaults);
+    apply_desktop_entries(
+        &mut config,
+        &desktop::applications_dirs(),
+        &terminal,
+        &mut desktop_entries,
+    )?;
+    let mut command_choices = Vec::new();
+    choose_commands(&mut config, std::env::var_os("PATH"), &mut command_choices)?;
+    check_commands(&config)?;
+    let mut icon_dir_globs = Vec::new();
+    expand_icon_dir_globs(&mut config, &mut icon_dir_globs);
+    resolve_aliases(&mut config);
 
-        // Check top-level menu
-        assert_eq!(
-            nested_result.args,
-            vec![
-                "--base-arg",
-                "--config",
-                "./target/test-cache/testnested2.fuzzel.ini",
-                "--cache",
-                "./target/test-cache/testnested2.fuzzel.cache"
-            ]
-        );
-        assert_eq!(nested_result.input, b"Item1\nSubmenu1\n");
-        assert_eq!(nested_result.items.len(), 2);
+    let mut id_gen = IdGenerator::new();
+    let mut fuzzel_inis = Vec::new();
+    let mut root = root_frame(&config.defaults, &mut id_gen, &mut fuzzel_inis);
+    // Only when the config doesn't say, so the cache only depends on it then
+    let detected_icon_theme = root.icon_theme.is_none().then(|| {
+        let theme = icons::detect_theme(&dirs.config);
+        root.icon_theme.clone_from(&theme);
+        theme
+    });
+    let inheritance_stack = vec![root];
 
-        // Check nested submenu
-        if let ResolvedItem::Menu(ref submenu) = nested_result.items[1] {
-            assert_eq!(
-                submenu.args,
-                vec![
-                    "--config",
-                    "./target/test-cache/testnested3.fuzzel.ini",
-                    "--cache",
-                    "./target/test-cache/testnested3.fuzzel.cache"
-                ]
-            );
-            assert_eq!(submenu.input, b"Item2\n");
-        } else {
-            panic!("Expected nested menu");
-        }
+    // Build phase: create fully resolved tree with inheritance applied
+    let mut icons = IconFinder::new(previous_icons);
+    let resolved_menu = build_resolved_menu(
+        &config,
+        &inheritance_stack,
+        &mut id_gen,
+        &mut fuzzel_inis,
+        &mut icons,
+        dirs,
+        preset_name,
+    );
 
-        // Verify inheritance in config files
-        let base_config =
-            std::fs::read_to_string("./target/test-cache/testnested2.fuzzel.ini").unwrap();
-        assert_eq!(
-            base_config,
-            "include=placeholder.fuzzel.ini\nbase_key=base_value\n"
-        );
+    let mut items = Vec::new();
+    // Flatten phase: convert tree to a flat list
+    let initial_menu = flatten_resolved_menu(resolved_menu, &mut items);
+    let (icons, not_found) = icons.finish();
+    missing_icons.extend(not_found);
 
-        let sub_config =
-            std::fs::read_to_string("./target/test-cache/testnested3.fuzzel.ini").unwrap();
-        assert_eq!(
-            sub_config,
-            "include=./target/test-cache/testnested2.fuzzel.ini\nsub_key=sub_value\n"
-        );
+    // Names are taken from the whole file, which may include some that aren't expanded
+    for name in env_var_names(config_string) {
+        env_var(&name);
+    }
+    let mut env_vars = env_reads.finish();
+    // The inis include fuzzel's config, see `create_fuzzel_config`
+    add_env_vars(&mut env_vars, &dirs.config_env);
+    // Scripts are run from the cache dir, see `make_scripts_dir`
+    if uses_scripts(&config) {
+        add_env_vars(&mut env_vars, &dirs.cache_env);
     }
 
-    #[test]
-    fn test_flatten_phase_comprehensive() {
-        // Test simple menu flattening
-        let simple_resolved = ResolvedMenu {
-            args: vec!["--arg1".to_string()],
-            input: b"Item1\n".to_vec(),
-            items: vec![ResolvedItem::Program(ComputedProgram {
-                command: vec!["cmd1".to_string()],
-            })],
-        };
-        let mut simple_items = Vec::new();
-        let simple_flattened = flatten_resolved_menu(&simple_resolved, &mut simple_items);
+    // Entries are the root's submenus, so they're all built and cached together
+    let entries = (initial_menu.items_offset..)
+        .zip(sorted_items(&config))
+        .filter(|(_, item)| matches!(item.contents, ItemContents::Menu(_)))
+        .map(|(index, item)| (item.name.clone(), item.id.clone(), index))
+        .collect();
 
-        assert_eq!(simple_flattened.args, vec!["--arg1"]);
-        assert_eq!(simple_flattened.input, b"Item1\n");
-        assert_eq!(simple_flattened.items_offset, 0);
-        assert_eq!(simple_items.len(), 1);
-        if let ComputedItem::Program(ref prog) = simple_items[0] {
-            assert_eq!(prog.command, vec!["cmd1"]);
+    let computed = ComputedConfig {
+        hash: *hash,
+        config_len: config_string.len() as u64,
+        // Only the cache needs them, see `get_computed_config`
+        config_stamp: None,
+        global_hash: [0; 32],
+        icon_dir_globs,
+        env_vars,
+        command_choices,
+        desktop_entries,
+        hostname,
+        detected_icon_theme,
+        icons,
+        missing_icons,
+        fuzzel_inis,
+        entries,
+        entries_only: config.entries_only,
+        lenient: options.lenient,
+        export_env: config.defaults.export_env.unwrap_or(true),
+        activation: config.defaults.activation.unwrap_or(true),
+        initial_menu,
+        items,
+    };
+    Ok(ResolvedConfig {
+        computed,
+        scripts: script_files(&config, dirs, preset_name),
+        id_count: id_gen.counter,
+    })
+}
+
+fn expand_icon_dir_glob(pattern: &Path) -> Vec<PathBuf> {
+    glob::expand(pattern)
+        .into_iter()
+        .filter(|path| path.is_dir())
+        .collect()
+}
+
+// Replaces icon-dir patterns with the directories they match, recording each
+// expansion so the cache can be invalidated when the matches change.
+fn expand_icon_dir_globs(menu: &mut Menu, expansions: &mut Vec<(String, Vec<String>)>) {
+    expand_globs_in(&mut menu.icon_dirs, expansions);
+    for item in &mut menu.items {
+        expand_globs_in(&mut item.icon_dirs, expansions);
+        if let ItemContents::Menu(child_menu) = &mut item.contents {
+            expand_icon_dir_globs(child_menu, expansions);
+        }
+    }
+}
+
+fn expand_globs_in(dirs: &mut Vec<IconDir>, expansions: &mut Vec<(String, Vec<String>)>) {
+    let mut icon_dirs = Vec::new();
+    for dir in std::mem::take(dirs) {
+        let pattern = dir.path.display().to_string();
+        if !glob::is_pattern(&pattern) {
+            icon_dirs.push(dir);
+            continue;
+        }
+
+        let matches = expand_icon_dir_glob(&dir.path);
+        if matches.is_empty() {
+            warn!("icon-dir pattern '{pattern}' did not match any directories");
+        }
+        expansions.push((
+            pattern,
+            matches
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect(),
+        ));
+        // Each match is walked the way the pattern's icon-dir says
+        icon_dirs.extend(matches.into_iter().map(|path| IconDir {
+            path,
+            walk: dir.walk,
+        }));
+    }
+    *dirs = icon_dirs;
+}
+
+/// What `gethostname` returns, with `/etc/hostname` as a fallback.
+fn hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .into_iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|contents| contents.trim().to_owned())
+        .find(|hostname| !hostname.is_empty())
+}
+
+/// Environment variables and their values, `None` for ones that aren't set.
+type EnvVars = Vec<(String, Option<String>)>;
+
+thread_local! {
+    /// The variables read with `env_var` so far, while a config is being built.
+    static ENV_READS: RefCell<Option<EnvVars>> = const { RefCell::new(None) };
+}
+
+/// An environment variable, remembered with its value while a config is being
+/// built so its cache is rebuilt when it changes. Anything the built config
+/// depends on should be read with this.
+pub fn env_var(name: &str) -> Option<String> {
+    let value = std::env::var(name).ok();
+    ENV_READS.with_borrow_mut(|reads| {
+        if let Some(reads) = reads
+            && !reads.iter().any(|(seen, _)| seen == name)
+        {
+            reads.push((name.to_string(), value.clone()));
+        }
+    });
+    value
+}
+
+/// Remembers the variables read with `env_var` on this thread until it's
+/// finished or dropped.
+struct EnvReads;
+
+impl EnvReads {
+    fn start() -> Self {
+        ENV_READS.set(Some(Vec::new()));
+        Self
+    }
+
+    /// Each variable read, in the order they were first read.
+    fn finish(self) -> EnvVars {
+        ENV_READS.take().unwrap_or_default()
+    }
+}
+
+impl Drop for EnvReads {
+    fn drop(&mut self) {
+        ENV_READS.set(None);
+    }
+}
+
+/// Runs `read` without remembering the variables it reads with `env_var`.
+fn without_env_reads<T>(read: impl FnOnce() -> T) -> T {
+    let reads = ENV_READS.take();
+    let value = read();
+    ENV_READS.set(reads);
+    value
+}
+
+/// Adds the variables in `more` that aren't in `env_vars` yet.
+fn add_env_vars(env_vars: &mut EnvVars, more: &EnvVars) {
+    for (name, value) in more {
+        if !env_vars.iter().any(|(seen, _)| seen == name) {
+            env_vars.push((name.clone(), value.clone()));
+        }
+    }
+}
+
+fn env_vars_unchanged(env_vars: &[(String, Option<String>)]) -> bool {
+    env_vars
+        .iter()
+        .all(|(name, value)| std::env::var(name).ok() == *value)
+}
+
+fn command_choices_unchanged(
+    choices: &[(Vec<String>, Option<usize>)],
+    search_path: Option<OsString>,
+) -> bool {
+    choices
+        .iter()
+        .all(|(candidates, chosen)| find_command(candidates, search_path.clone()) == *chosen)
+}
+
+fn desktop_entries_unchanged(entries: &[(String, String, Option<u64>)], dirs: &[PathBuf]) -> bool {
+    entries.iter().all(|(name, path, modified)| {
+        desktop::locate(name, dirs).is_some_and(|current| {
+            current.display().to_string() == *path && desktop::modified(&current) == *modified
+        })
+    })
+}
+
+fn icon_dir_globs_unchanged(expansions: &[(String, Vec<String>)]) -> bool {
+    expansions.iter().all(|(pattern, matches)| {
+        let current = expand_icon_dir_glob(Path::new(pattern));
+        current.len() == matches.len()
+            && current
+                .iter()
+                .zip(matches)
+                .all(|(path, cached)| path.display().to_string() == *cached)
+    })
+}
+
+/// The letters of fuzzel's short options, like `-l` for `--lines`.
+const FUZZEL_SHORT_OPTIONS: &str = "abBcCdDfFhiIlmMoOpPrRsStTvwxy";
+
+/// Whether the arg starts an option, as opposed to being a value that starts
+/// with a dash, like the prompt in `--prompt "-> "`.
+fn is_fuzzel_option(arg: &str) -> bool {
+    if let Some(name) = arg.strip_prefix("--") {
+        name.starts_with(|c: char| c.is_ascii_alphabetic())
+    } else if let Some(short) = arg.strip_prefix('-') {
+        short.starts_with(|c| FUZZEL_SHORT_OPTIONS.contains(c))
+    } else {
+        false
+    }
+}
+
+/// Splits fuzzel args into options, each being a flag followed by its values.
+fn group_fuzzel_args(args: &[String]) -> Vec<&[String]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for (i, arg) in args.iter().enumerate().skip(1) {
+        if is_fuzzel_option(arg) {
+            groups.push(&args[start..i]);
+            start = i;
+        }
+    }
+    if start < args.len() {
+        groups.push(&args[start..]);
+    }
+    groups
+}
+
+/// The long options that fuzzel's short ones stand for. Letters that aren't
+/// here are only the same option as themselves.
+fn fuzzel_long_option(letter: char) -> Option<&'static str> {
+    Some(match letter {
+        'a' => "--anchor",
+        'b' => "--background-color",
+        'B' => "--border-width",
+        'C' => "--border-color",
+        'd' => "--dmenu",
+        'D' => "--dpi-aware",
+        'f' => "--font",
+        'F' => "--fields",
+        'h' => "--help",
+        'I' => "--no-icons",
+        'l' => "--lines",
+        'm' => "--match-color",
+        'M' => "--selection-match-color",
+        'o' => "--output",
+        'p' => "--prompt",
+        'P' => "--horizontal-pad",
+        'r' => "--border-radius",
+        'R' => "--no-run-if-empty",
+        's' => "--selection-color",
+        'S' => "--selection-text-color",
+        't' => "--text-color",
+        'T' => "--terminal",
+        'v' => "--version",
+        'w' => "--width",
+        'x' => "--x-margin",
+        'y' => "--y-margin",
+        _ => return None,
+    })
+}
+
+/// The option a group sets, by its long name when it's given by a short one,
+/// so `-l3` and `--lines=5` are the same option.
+fn fuzzel_option_name(group: &[String]) -> &str {
+    let arg = &group[0];
+    if !arg.starts_with("--")
+        && is_fuzzel_option(arg)
+        && let Some(letter) = arg[1..].chars().next()
+    {
+        return fuzzel_long_option(letter).unwrap_or(&arg[..2]);
+    }
+    arg.split_once('=').map_or(arg, |(name, _)| name)
+}
+
+/// Appends `extra` to `base`, dropping any earlier occurrence of an option
+/// that is given again later so the most specific value wins.
+fn merge_fuzzel_args(base: &[String], extra: &[String]) -> Vec<String> {
+    let combined: Vec<String> = base.iter().chain(extra).cloned().collect();
+    let groups = group_fuzzel_args(&combined);
+
+    let mut merged = Vec::new();
+    for (i, group) in groups.iter().enumerate() {
+        let name = fuzzel_option_name(group);
+        let overridden = groups[i + 1..]
+            .iter()
+            .any(|later| fuzzel_option_name(later) == name);
+        if !overridden {
+            merged.extend_from_slice(group);
+        }
+    }
+    merged
+}
+
+/// The `icon-theme` fuzzel would use from these ini keys, the last one.
+fn icon_theme(fuzzel_config: &[(String, String)]) -> Option<String> {
+    fuzzel_config
+        .iter()
+        .rev()
+        .find(|(key, _)| key == "icon-theme")
+        .map(|(_, theme)| theme.clone())
+}
+
+/// Removes options that uff passes itself, so they are never duplicated.
+fn without_managed_fuzzel_args(args: Vec<String>) -> Vec<String> {
+    let mut kept = Vec::new();
+    for group in group_fuzzel_args(&args) {
+        let name = fuzzel_option_name(group);
+        if name == "--config" || name == "--cache" {
+            warn!("{name} is managed by uff, ignoring it in fuzzel-args");
         } else {
-            panic!("Expected program item");
+            kept.extend_from_slice(group);
+        }
+    }
+    kept
+}
+
+/// The line shown for an item, its name (and count, for submenus) followed by the
+/// description if it has one. Selections are read back by index, so nothing added
+/// to the name ever has to be stripped.
+fn item_label(item: &Item, count: Option<usize>, separator: &str, markup: bool) -> String {
+    let name = match count {
+        Some(count) => format!("{} ({count})", item.name),
+        None => item.name.clone(),
+    };
+    let Some(description) = &item.description else {
+        return if markup { escape_markup(&name) } else { name };
+    };
+    if markup {
+        format!(
+            "{}{}<span alpha=\"60%\">{}</span>",
+            escape_markup(&name),
+            escape_markup(separator),
+            escape_markup(description)
+        )
+    } else {
+        format!("{name}{separator}{description}")
+    }
+}
+
+/// `--lines` to show every entry and `--width` to fit the widest one, both up to
+/// the limits. Either is left out when the user sets it in fuzzel-args or
+/// fuzzel-config, which always wins.
+fn auto_size_args(
+    auto_size: AutoSize,
+    entries: usize,
+    longest: usize,
+    args: &[String],
+    fuzzel_config_keys: &[String],
+) -> Vec<String> {
+    let is_set = |long: &str, key: &str| {
+        group_fuzzel_args(args)
+            .iter()
+            .any(|group| fuzzel_option_name(group) == long)
+            || fuzzel_config_keys
+                .iter()
+                .any(|set| set == key || set.strip_prefix("main.") == Some(key))
+    };
+    let mut size_args = Vec::new();
+    if !is_set("--lines", "lines") {
+        let lines = entries.clamp(1, auto_size.max_lines as usize);
+        size_args.push(format!("--lines={lines}"));
+    }
+    if !is_set("--width", "width") {
+        let width = (longest + auto_size.padding as usize).min(auto_size.max_width as usize);
+        size_args.push(format!("--width={width}"));
+    }
+    size_args
+}
+
+/// Roughly how many columns the text takes up: characters from East Asian
+/// scripts and most emoji are two columns wide and combining marks take none.
+fn display_width(text: &str) -> usize {
+    text.chars()
+        .map(|character| match u32::from(character) {
+            0x0300..=0x036F | 0x200B..=0x200F | 0xFE00..=0xFE0F => 0,
+            0x1100..=0x115F
+            | 0x2E80..=0xA4CF
+            | 0xAC00..=0xD7A3
+            | 0xF900..=0xFAFF
+            | 0xFE30..=0xFE4F
+            | 0xFF00..=0xFF60
+            | 0xFFE0..=0xFFE6
+            | 0x1F300..=0x1FAFF
+            | 0x20000..=0x3FFFD => 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// How many items a menu shows, not counting back items. Submenus count as
+/// their own items instead when `recursive`.
+fn count_items(menu: &Menu, recursive: bool) -> usize {
+    menu.items
+        .iter()
+        .map(|item| match &item.contents {
+            ItemContents::Back => 0,
+            ItemContents::Menu(submenu) if recursive => count_items(submenu, true),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Makes text from a command or the filesystem safe to put in fuzzel's input.
+/// fuzzel would read anything after `\0` or `\x1f` as icon options, and a
+/// newline would split the entry in two.
+pub fn sanitize_entry(text: &str) -> String {
+    text.replace(['\0', '\x1f'], "\u{FFFD}")
+        .replace('\n', parser::NEWLINE_SYMBOL)
+}
+
+pub fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Icon dirs from the menus above, one tier for each menu, nearest first, and
+/// stopping at the first menu that opted out. The XDG data dirs come last.
+fn inherited_icon_dirs(
+    mut inheritance: IconDirInheritance,
+    inheritance_stack: &[InheritanceFrame],
+) -> Vec<&[IconDir]> {
+    let mut icon_dirs = Vec::new();
+    for frame in inheritance_stack.iter().rev() {
+        match inheritance {
+            IconDirInheritance::All => {}
+            // The root frame holds the XDG dirs
+            IconDirInheritance::Xdg => {
+                icon_dirs.push(inheritance_stack[0].icon_dirs.as_slice());
+                break;
+            }
+            IconDirInheritance::None => break,
+        }
+        icon_dirs.push(frame.icon_dirs.as_slice());
+        inheritance = frame.inherit_icon_dirs;
+    }
+    icon_dirs
+}
+
+/// From `terminal` in the defaults, or `xdg-terminal-exec` to start the user's
+/// preferred terminal.
+fn terminal_command(defaults: &Defaults) -> Vec<String> {
+    defaults
+        .terminal
+        .clone()
+        .unwrap_or_else(|| vec!["xdg-terminal-exec".to_string()])
+}
+
+/// The frame every menu inherits from, with the defaults ini at the bottom of
+/// every include chain.
+fn root_frame(
+    defaults: &Defaults,
+    id_gen: &mut IdGenerator,
+    fuzzel_inis: &mut Vec<FuzzelIni>,
+) -> InheritanceFrame {
+    let mut frame = InheritanceFrame::default();
+    frame.fuzzel_args.clone_from(&defaults.fuzzel_args);
+    frame.power_commands.clone_from(&defaults.power_commands);
+    frame.use_fuzzel_cache = defaults.use_fuzzel_cache.unwrap_or(true);
+    frame.icon_size = defaults.icon_size;
+    frame.prefer_icon_format = defaults.prefer_icon_format.unwrap_or_default();
+    frame.icon_match = defaults.icon_match.unwrap_or_default();
+    frame.symbolic = defaults.symbolic.unwrap_or_default();
+    frame.icon_dir_walk = defaults.icon_dir_walk;
+    frame.icon_theme = icon_theme(&defaults.fuzzel_config);
+    frame.program_defaults = ProgramDefaults {
+        systemd_scope: defaults.systemd_scope.unwrap_or(false),
+        launch_method: defaults.launch_method,
+        terminal: terminal_command(defaults),
+        stdout: defaults.stdout.clone(),
+        stderr: defaults.stderr.clone(),
+    };
+    if !defaults.fuzzel_config.is_empty() {
+        let id = id_gen.next_id();
+        let ini = FuzzelIni {
+            id,
+            inherit_id: None,
+            pairs: defaults.fuzzel_config.clone(),
+        };
+        fuzzel_inis.push(ini);
+        frame.fuzzel_config_id = Some(id);
+        frame.fuzzel_config_keys = defaults
+            .fuzzel_config
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+    }
+    frame
+}
+
+fn build_resolved_menu(
+    menu: &Menu,
+    inheritance_stack: &[InheritanceFrame],
+    id_gen: &mut IdGenerator,
+    fuzzel_inis: &mut Vec<FuzzelIni>,
+    icons: &mut IconFinder,
+    dirs: &Dirs,
+    preset_name: &str,
+) -> ResolvedMenu {
+    let id = id_gen.next_id();
+
+    // Opting out of inheritance still keeps the defaults from the root frame
+    let parent_frame = if menu.inherit_fuzzel_args {
+        inheritance_stack.last()
+    } else {
+        inheritance_stack.first()
+    };
+    let parent_args = parent_frame.map_or(&[][..], |frame| &frame.fuzzel_args);
+    let own_args = without_managed_fuzzel_args(merge_fuzzel_args(
+        &merge_fuzzel_args(parent_args, &menu.fuzzel_args),
+        &menu.extra_fuzzel_args,
+    ));
+    let mut args = own_args.clone();
+
+    let placeholder = menu.placeholder.clone().or_else(|| {
+        inheritance_stack
+            .last()
+            .and_then(|frame| frame.placeholder.clone())
+    });
+    if let Some(placeholder) = &placeholder {
+        let own_fuzzel_args = menu.fuzzel_args.iter().chain(&menu.extra_fuzzel_args);
+        if group_fuzzel_args(&own_fuzzel_args.cloned().collect::<Vec<_>>())
+            .iter()
+            .any(|group| fuzzel_option_name(group) == "--placeholder")
+        {
+            warn!("--placeholder in fuzzel-args is replaced by the placeholder node");
+        }
+        args = merge_fuzzel_args(&args, &["--placeholder".to_string(), placeholder.clone()]);
+    }
+
+    // Opting out still keeps the defaults ini from the root frame
+    let config_frame = if menu.inherit_fuzzel_config {
+        inheritance_stack.last()
+    } else {
+        inheritance_stack.first()
+    };
+    let last_config = config_frame.and_then(|frame| frame.fuzzel_config_id);
+    let mut fuzzel_config_keys = config_frame
+        .map(|frame| frame.fuzzel_config_keys.clone())
+        .unwrap_or_default();
+    fuzzel_config_keys.extend(menu.fuzzel_config.iter().map(|(key, _)| key.clone()));
+    let icon_theme = icon_theme(&menu.fuzzel_config)
+        .or_else(|| config_frame.and_then(|frame| frame.icon_theme.clone()));
+
+    let binds = if menu.binds.is_empty() {
+        inheritance_stack
+            .last()
+            .map(|frame| frame.binds.clone())
+            .unwrap_or_default()
+    } else {
+        menu.binds.clone()
+    };
+    // Inherited binds are already in the parent's ini, unless this menu opted out of it
+    let needs_binds = !menu.binds.is_empty() || (!binds.is_empty() && !menu.inherit_fuzzel_config);
+
+    let config_id = if menu.fuzzel_config.is_empty() && !needs_binds {
+        last_config
+    } else {
+        let mut pairs = menu.fuzzel_config.clone();
+        pairs.extend(binds.iter().enumerate().map(|(index, (key, _))| {
+            (
+                format!("key-bindings.custom-{}", index + 1),
+                fuzzel_key_name(key),
+            )
+        }));
+        let ini = FuzzelIni {
+            id,
+            inherit_id: last_config,
+            pairs,
+        };
+        fuzzel_inis.push(ini);
+        Some(id)
+    };
+    if let Some(config_id) = config_id {
+        args.push("--config".to_string());
+        args.push(fuzzel_config_name(config_id, preset_name));
+    }
+
+    let use_fuzzel_cache = menu.use_fuzzel_cache.unwrap_or_else(|| {
+        inheritance_stack
+            .last()
+            .is_none_or(|frame| frame.use_fuzzel_cache)
+    });
+    // Add unique cache path for this menu
+    args.extend(fuzzel_cache_args(use_fuzzel_cache, id, preset_name));
+
+    // The menu's own icon dirs come before the ones it inherits
+    let icon_dirs: Vec<&[IconDir]> = std::iter::once(menu.icon_dirs.as_slice())
+        .chain(inherited_icon_dirs(
+            menu.inherit_icon_dirs,
+            inheritance_stack,
+        ))
+        .collect();
+
+    let items = sorted_items(menu);
+
+    let icon_size = menu
+        .icon_size
+        .or_else(|| inheritance_stack.last().and_then(|frame| frame.icon_size));
+    let prefer_icon_format = menu.prefer_icon_format.unwrap_or_else(|| {
+        inheritance_stack
+            .last()
+            .map_or_else(IconFormat::default, |frame| frame.prefer_icon_format)
+    });
+    let icon_match = menu.icon_match.unwrap_or_else(|| {
+        inheritance_stack
+            .last()
+            .map_or_else(IconMatch::default, |frame| frame.icon_match)
+    });
+    let symbolic = menu.symbolic.unwrap_or_else(|| {
+        inheritance_stack
+            .last()
+            .map_or_else(SymbolicIcons::default, |frame| frame.symbolic)
+    });
+    let icon_dir_walk = inheritance_stack
+        .first()
+        .map_or_else(IconWalk::default, |frame| frame.icon_dir_walk);
+    let lookup = icons::Lookup {
+        theme: icon_theme.as_deref(),
+        size: icon_size,
+        prefer: prefer_icon_format,
+        matching: icon_match,
+        symbolic,
+        walk: icon_dir_walk,
+    };
+    let menu_path = inheritance_stack
+        .last()
+        .map_or("", |frame| frame.hooks.menu_path.as_str());
+    let icon_paths: Vec<Option<String>> = items
+        .iter()
+        .map(|item| {
+            let icon = item.icon.as_ref()?;
+            // A program's icon-dirs come before all the others, then a
+            // submenu's icon can come from its own icon dirs
+            let own_dirs = match &item.contents {
+                ItemContents::Menu(menu) => menu.icon_dirs.as_slice(),
+                _ => &[],
+            };
+            let tiers: Vec<&[IconDir]> = [item.icon_dirs.as_slice(), own_dirs]
+                .into_iter()
+                .chain(icon_dirs.iter().copied())
+                .filter(|tier| !tier.is_empty())
+                .collect();
+
+            Some(
+                icons
+                    .find(&item_path(menu_path, &item.name), icon, &tiers, &lookup)
+                    .map_or_else(|| expand_tilde(icon), |path| path.display().to_string()),
+            )
+        })
+        .collect();
+
+    let parent_frame = inheritance_stack.last();
+    let description_separator = menu.description_separator.clone().unwrap_or_else(|| {
+        parent_frame.map_or_else(String::new, |frame| frame.description_separator.clone())
+    });
+    let markup = menu
+        .markup
+        .unwrap_or_else(|| parent_frame.is_some_and(|frame| frame.markup));
+    let auto_size = menu
+        .auto_size
+        .unwrap_or_else(|| parent_frame.and_then(|frame| frame.auto_size));
+
+    // Build fuzzel input format: {NAME}\0icon\x1f{ICON_PATH}\n
+    let mut input = Vec::new();
+    // The widest line as it's shown, for auto-size
+    let mut longest = 0;
+    for (index, (item, icon_path)) in items.iter().zip(&icon_paths).enumerate() {
+        let mut width = 0;
+        if let Some(separator) = &menu.show_indices {
+            width += display_width(&format!("{}{separator}", index + 1));
+            let separator = if markup {
+                escape_markup(separator)
+            } else {
+                separator.clone()
+            };
+            write!(&mut input, "{}{separator}", index + 1).unwrap();
+        }
+        // Profiles were filtered out before building, so they aren't counted
+        let count = match &item.contents {
+            ItemContents::Menu(submenu) => menu
+                .show_counts
+                .map(|recursive| count_items(submenu, recursive)),
+            _ => None,
+        };
+        width += display_width(&item_label(item, count, &description_separator, false));
+        longest = longest.max(width);
+        let label = item_label(item, count, &description_separator, markup);
+        write!(&mut input, "{label}").unwrap();
+        if let Some(icon_path) = icon_path {
+            write!(&mut input, "\0icon\x1f{icon_path}").unwrap();
+        }
+        writeln!(&mut input).unwrap();
+    }
+
+    if let Some(auto_size) = auto_size {
+        let size_args = auto_size_args(auto_size, items.len(), longest, &args, &fuzzel_config_keys);
+        args = merge_fuzzel_args(&args, &size_args);
+    }
+
+    let mut labels = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        let Some(command) = &item.label_command else {
+            continue;
+        };
+        if !item.name.contains("{}") {
+            warn!(
+                "'{}' has a label-command but no {{}} in its name",
+                item.name
+            );
+            continue;
+        }
+        labels.push(ComputedLabel {
+            item: index,
+            command: expand_command(command),
+            markup,
+        });
+    }
+
+    // Each hook is inherited unless the menu sets its own
+    let parent_hooks = inheritance_stack
+        .last()
+        .map(|frame| frame.hooks.clone())
+        .unwrap_or_default();
+    let hooks = ComputedHooks {
+        pre_launch: menu
+            .hooks
+            .pre_launch
+            .as_deref()
+            .map_or(parent_hooks.pre_launch, expand_command),
+        post_launch: menu
+            .hooks
+            .post_launch
+            .as_deref()
+            .map_or(parent_hooks.post_launch, expand_command),
+        menu_path: parent_hooks.menu_path,
+    };
+
+    // Build child inheritance frame for recursive calls
+    let opener = menu.opener.clone().unwrap_or_else(|| {
+        inheritance_stack
+            .last()
+            .map(|frame| frame.opener.clone())
+            .unwrap_or_default()
+    });
+
+    let program_defaults = inheritance_stack
+        .last()
+        .map(|frame| frame.program_defaults.clone())
+        .unwrap_or_default();
+
+    let env = merge_env(
+        inheritance_stack.last().map_or(&[][..], |frame| &frame.env),
+        &menu.env,
+        &if hooks.menu_path.is_empty() {
+            "the root menu".to_string()
+        } else {
+            format!("menu '{}'", hooks.menu_path)
+        },
+    );
+
+    let program_env = unsourced_env(&env);
+
+    let child_frame = InheritanceFrame {
+        fuzzel_args: own_args,
+        icon_dirs: menu.icon_dirs.clone(),
+        inherit_icon_dirs: menu.inherit_icon_dirs,
+        fuzzel_config_id: config_id,
+        fuzzel_config_keys,
+        opener: opener.clone(),
+        placeholder,
+        description_separator,
+        markup,
+        auto_size,
+        binds: binds.clone(),
+        use_fuzzel_cache,
+        icon_size,
+        prefer_icon_format,
+        icon_match,
+        symbolic,
+        icon_dir_walk,
+        icon_theme,
+        power_commands: Vec::new(),
+        hooks: hooks.clone(),
+        program_defaults: program_defaults.clone(),
+        env: env.clone(),
+    };
+
+    let mut actions = Vec::new();
+    for ((index, item), icon_path) in items.iter().enumerate().zip(&icon_paths) {
+        for (action, program) in &item.actions {
+            let mut bound = false;
+            for (bind, _) in binds
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, name))| name == action)
+            {
+                bound = true;
+                actions.push(ComputedAction {
+                    item: index,
+                    bind,
+                    program: compute_program(
+                        program,
+                        icon_path.clone(),
+                        item.id.as_ref().unwrap_or(&item.name),
+                        &program_defaults,
+                        &env,
+                    ),
+                });
+            }
+            if !bound {
+                warn!("no key is bound to the {action} action of '{}'", item.name);
+            }
+        }
+    }
+
+    // Recursively build resolved items
+    let mut resolved_items = Vec::new();
+    for (item, icon_path) in items.iter().zip(icon_paths) {
+        match &item.contents {
+            ItemContents::Menu(child_menu) => {
+                let mut child_inheritance_stack = inheritance_stack.to_vec();
+                child_inheritance_stack.push(child_frame.clone());
+                // Ids keep the path the same when a menu is renamed
+                let segment = item.id.as_ref().unwrap_or(&item.name);
+                child_inheritance_stack.last_mut().unwrap().hooks.menu_path =
+                    item_path(&hooks.menu_path, segment);
+                let resolved_child = build_resolved_menu(
+                    child_menu,
+                    &child_inheritance_stack,
+                    id_gen,
+                    fuzzel_inis,
+                    icons,
+                    dirs,
+                    preset_name,
+                );
+                resolved_items.push(ResolvedItem::Menu(resolved_child));
+            }
+            ItemContents::Program(program) => {
+                if program.exec && menu.stay_open {
+                    warn!(
+                        "'{}' replaces uff when launched, so its menu can't stay open",
+                        item.name
+                    );
+                }
+                let computed_program = compute_program(
+                    program,
+                    icon_path,
+                    item.id.as_ref().unwrap_or(&item.name),
+                    &program_defaults,
+                    &env,
+                );
+                if let Some(question) = &program.confirm {
+                    resolved_items.push(ResolvedItem::Menu(build_confirmation_menu(
+                        question,
+                        &args,
+                        computed_program,
+                    )));
+                } else {
+                    resolved_items.push(ResolvedItem::Program(computed_program));
+                }
+            }
+            ItemContents::Power(power) => {
+                let command = inheritance_stack
+                    .first()
+                    .and_then(|frame| {
+                        frame
+                            .power_commands
+                            .iter()
+                            .find(|(action, _)| *action == power.action)
+                    })
+                    .map_or_else(
+                        || {
+                            power
+                                .action
+                                .command()
+                                .iter()
+                                .map(|&arg| arg.to_owned())
+                                .collect()
+                        },
+                        |(_, command)| expand_command(command),
+                    );
+                let program = default_program(
+                    command,
+                    item.id.as_ref().unwrap_or(&item.name),
+                    &program_defaults,
+                    &program_env,
+                );
+                if let Some(question) = &power.confirm {
+                    resolved_items.push(ResolvedItem::Menu(build_confirmation_menu(
+                        question, &args, program,
+                    )));
+                } else {
+                    resolved_items.push(ResolvedItem::Program(program));
+                }
+            }
+            ItemContents::Open(target) => {
+                let mut command = expand_command(&opener);
+                command.push(resolve_open_target(target));
+                resolved_items.push(ResolvedItem::Program(default_program(
+                    command,
+                    item.id.as_ref().unwrap_or(&item.name),
+                    &program_defaults,
+                    &program_env,
+                )));
+            }
+            ItemContents::Script(script) => {
+                let path = script_file(script, dirs, preset_name)
+                    .0
+                    .display()
+                    .to_string();
+                let command = if script.shebang.is_some() {
+                    vec![path]
+                } else {
+                    vec!["sh".to_string(), path]
+                };
+                resolved_items.push(ResolvedItem::Program(default_program(
+                    command,
+                    item.id.as_ref().unwrap_or(&item.name),
+                    &program_defaults,
+                    &program_env,
+                )));
+            }
+            ItemContents::DynamicMenu(dynamic_menu) => {
+                // Shown with this menu's args, but its own usage counts
+                let cache_args = fuzzel_cache_args(use_fuzzel_cache, id_gen.next_id(), preset_name);
+                resolved_items.push(ResolvedItem::DynamicMenu(ComputedDynamicMenu {
+                    args: merge_fuzzel_args(&args, &cache_args),
+                    source_command: expand_command(&dynamic_menu.source_command),
+                    run_command: expand_command(&dynamic_menu.run_command),
+                    on_empty: match &dynamic_menu.on_empty {
+                        OnEmpty::Message => ComputedOnEmpty::Message,
+                        OnEmpty::Skip => ComputedOnEmpty::Skip,
+                        OnEmpty::Run(command) => ComputedOnEmpty::Run(expand_command(command)),
+                    },
+                    env: program_env.clone(),
+                    defaults: computed_defaults(
+                        &program_defaults,
+                        item.id.as_ref().unwrap_or(&item.name),
+                    ),
+                }));
+            }
+            ItemContents::MimeOpen(file) => {
+                let file = expand_tilde(file);
+                if !Path::new(&file).exists() {
+                    warn!("mime-open file does not exist: {file}");
+                }
+                let cache_args = fuzzel_cache_args(use_fuzzel_cache, id_gen.next_id(), preset_name);
+                resolved_items.push(ResolvedItem::MimeOpen(ComputedMimeOpen {
+                    args: merge_fuzzel_args(&args, &cache_args),
+                    file,
+                    terminal: program_defaults.terminal.clone(),
+                    env: program_env.clone(),
+                    defaults: computed_defaults(
+                        &program_defaults,
+                        item.id.as_ref().unwrap_or(&item.name),
+                    ),
+                }));
+            }
+            ItemContents::Pick(pick) => {
+                let cache_args = fuzzel_cache_args(use_fuzzel_cache, id_gen.next_id(), preset_name);
+                resolved_items.push(ResolvedItem::Pick(ComputedPick {
+                    args: merge_fuzzel_args(&args, &cache_args),
+                    list_command: expand_command(&pick.list_command),
+                    run_command: expand_command(&pick.run_command),
+                    env: program_env.clone(),
+                    defaults: computed_defaults(
+                        &program_defaults,
+                        item.id.as_ref().unwrap_or(&item.name),
+                    ),
+                }));
+            }
+            ItemContents::FilesMenu(files_menu) => {
+                let cache_args = fuzzel_cache_args(use_fuzzel_cache, id_gen.next_id(), preset_name);
+                resolved_items.push(ResolvedItem::FilesMenu(ComputedFilesMenu {
+                    args: merge_fuzzel_args(&args, &cache_args),
+                    dir: expand_tilde(&files_menu.dir),
+                    pattern: files_menu.pattern.clone(),
+                    newest_first: files_menu.sort == FileSort::Mtime,
+                    limit: files_menu.limit,
+                    open_with: expand_command(files_menu.open_with.as_ref().unwrap_or(&opener)),
+                    env: program_env.clone(),
+                    defaults: computed_defaults(
+                        &program_defaults,
+                        item.id.as_ref().unwrap_or(&item.name),
+                    ),
+                }));
+            }
+            ItemContents::RecentFiles(recent_files) => {
+                let cache_args = fuzzel_cache_args(use_fuzzel_cache, id_gen.next_id(), preset_name);
+                resolved_items.push(ResolvedItem::RecentFiles(ComputedRecentFiles {
+                    args: merge_fuzzel_args(&args, &cache_args),
+                    limit: recent_files.limit,
+                    open_with: expand_command(recent_files.open_with.as_ref().unwrap_or(&opener)),
+                    env: program_env.clone(),
+                    defaults: computed_defaults(
+                        &program_defaults,
+                        item.id.as_ref().unwrap_or(&item.name),
+                    ),
+                }));
+            }
+            ItemContents::Input(input) => {
+                let prompt = input
+                    .prompt
+                    .clone()
+                    .unwrap_or_else(|| format!("{}: ", item.name));
+                // fuzzel would count what's typed as a use of the menu's entries
+                let mut input_args = vec![
+                    format!("--prompt-only={prompt}"),
+                    "--cache".to_string(),
+                    "/dev/null".to_string(),
+                ];
+                if input.password {
+                    input_args.push("--password".to_string());
+                }
+                // Secrets stay out of argv unless asked for
+                let pass_via = match &input.pass_via {
+                    Some(PassVia::Arg) => ComputedPassVia::Arg,
+                    Some(PassVia::Stdin) => ComputedPassVia::Stdin,
+                    Some(PassVia::Env(name)) => ComputedPassVia::Env(name.clone()),
+                    None if input.password => ComputedPassVia::Stdin,
+                    None => ComputedPassVia::Arg,
+                };
+                resolved_items.push(ResolvedItem::Input(ComputedInput {
+                    args: merge_fuzzel_args(&args, &input_args),
+                    command: expand_command(&input.command),
+                    url_encode: input.url_encode,
+                    password: input.password,
+                    pass_via,
+                    env: program_env.clone(),
+                    defaults: computed_defaults(
+                        &program_defaults,
+                        item.id.as_ref().unwrap_or(&item.name),
+                    ),
+                }));
+            }
+            ItemContents::Back => {
+                resolved_items.push(ResolvedItem::Back);
+            }
+            ItemContents::Alias(alias) => {
+                resolved_items.push(ResolvedItem::Alias(alias.path.clone()));
+            }
         }
+    }
+
+    let multi_select = menu
+        .multi_select
+        .as_ref()
+        .map(|multi_select| match multi_select {
+            MultiSelect::Each => ComputedMultiSelect::Each,
+            MultiSelect::Args(command) => ComputedMultiSelect::Args(Box::new(default_program(
+                expand_command(command),
+                &hooks.menu_path,
+                &program_defaults,
+                &program_env,
+            ))),
+        });
+    ResolvedMenu {
+        args,
+        input,
+        items: resolved_items,
+        back_on_cancel: false,
+        stay_open: menu.stay_open,
+        actions,
+        labels,
+        names: items.iter().map(|item| item.name.clone()).collect(),
+        ids: items.iter().map(|item| item.id.clone()).collect(),
+        hooks,
+        multi_select,
+    }
+}
+
+/// `--cache` with the menu's own usage counts, or nothing so entries keep their order.
+fn fuzzel_cache_args(enabled: bool, id: usize, preset_name: &str) -> Vec<String> {
+    if !enabled {
+        return Vec::new();
+    }
+    vec!["--cache".to_string(), fuzzel_cache_name(id, preset_name)]
+}
+
+fn compute_program(
+    program: &parser::Program,
+    icon_path: Option<String>,
+    name: &str,
+    defaults: &ProgramDefaults,
+    env: &[(String, String, String)],
+) -> ComputedProgram {
+    ComputedProgram {
+        command: expand_command(&program.command),
+        watch_exit: program.watch_exit,
+        notify: program
+            .notify
+            .as_ref()
+            .map(|notification| ComputedNotification {
+                summary: notification.summary.clone(),
+                body: notification.body.clone(),
+                icon: icon_path,
+            }),
+        chain: program.chain.as_ref().map_or_else(Vec::new, |chain| {
+            chain
+                .commands
+                .iter()
+                .map(|command| expand_command(command))
+                .collect()
+        }),
+        continue_on_error: program
+            .chain
+            .as_ref()
+            .is_some_and(|chain| chain.continue_on_error),
+        nice: program.nice,
+        ionice: program.ionice.map(|priority| ComputedIoPriority {
+            class: match priority.class {
+                IoClass::Realtime => 1,
+                IoClass::BestEffort => 2,
+                IoClass::Idle => 3,
+            },
+            level: priority.level,
+        }),
+        systemd_scope: program
+            .systemd_scope
+            .unwrap_or(defaults.systemd_scope)
+            .then(|| unit_slug(name)),
+        launch_method: compute_launch_method(program.launch_method.or(defaults.launch_method)),
+        hypr_rules: program.hypr_rules.clone(),
+        attached: program.attached,
+        exec: program.exec,
+        stdout: program
+            .stdout
+            .as_ref()
+            .or(defaults.stdout.as_ref())
+            .map(compute_output),
+        stderr: program
+            .stderr
+            .as_ref()
+            .or(defaults.stderr.as_ref())
+            .map(compute_output),
+        env: unsourced_env(&merge_env(env, &program.env, &format!("'{name}'"))),
+        ..Default::default()
+    }
+}
+
+/// The program for an item with no launch options of its own, like `power`,
+/// `open` or `script`, which runs its command with the menu's defaults.
+fn default_program(
+    command: Vec<String>,
+    name: &str,
+    defaults: &ProgramDefaults,
+    env: &[(String, String)],
+) -> ComputedProgram {
+    ComputedProgram {
+        command,
+        env: env.to_vec(),
+        ..computed_defaults(defaults, name).program()
+    }
+}
+
+/// The defaults for the programs of the item called `name`, which names their
+/// systemd scope.
+fn computed_defaults(defaults: &ProgramDefaults, name: &str) -> ComputedDefaults {
+    ComputedDefaults {
+        systemd_scope: defaults.systemd_scope.then(|| unit_slug(name)),
+        launch_method: compute_launch_method(defaults.launch_method),
+        stdout: defaults.stdout.as_ref().map(compute_output),
+        stderr: defaults.stderr.as_ref().map(compute_output),
+    }
+}
+
+fn compute_launch_method(launch_method: Option<LaunchMethod>) -> ComputedLaunchMethod {
+    match launch_method {
+        None | Some(LaunchMethod::Direct) => ComputedLaunchMethod::Direct,
+        Some(LaunchMethod::Swaymsg) => ComputedLaunchMethod::Swaymsg,
+        Some(LaunchMethod::Hyprctl) => ComputedLaunchMethod::Hyprctl,
+        Some(LaunchMethod::Auto) => ComputedLaunchMethod::Auto,
+    }
+}
+
+/// The inherited variables with `own` set by `origin` over them, logging each
+/// one it overrides.
+fn merge_env(
+    inherited: &[(String, String, String)],
+    own: &[(String, String)],
+    origin: &str,
+) -> Vec<(String, String, String)> {
+    let mut env = inherited.to_vec();
+    for (name, value) in own {
+        if let Some(index) = env.iter().position(|(inherited, ..)| inherited == name) {
+            let (_, previous, previous_origin) = env.remove(index);
+            debug!(
+                "{name}={value:?} from {origin} overrides {name}={previous:?} from {previous_origin}"
+            );
+        }
+        env.push((name.clone(), value.clone(), origin.to_string()));
+    }
+    env
+}
+
+/// The variables without where each one was set, as programs get them.
+fn unsourced_env(env: &[(String, String, String)]) -> Vec<(String, String)> {
+    env.iter()
+        .map(|(name, value, _)| (name.clone(), value.clone()))
+        .collect()
+}
+
+fn compute_output(output: &parser::Output) -> ComputedOutput {
+    match output {
+        parser::Output::Null => ComputedOutput::Null,
+        parser::Output::Inherit => ComputedOutput::Inherit,
+        parser::Output::File { path, append } => ComputedOutput::File {
+            path: expand_tilde(path),
+            append: *append,
+        },
+    }
+}
+
+/// The item name cut down to what's allowed in a systemd unit name: lowercase
+/// ascii letters and digits, with anything else between them turned into a dash.
+fn unit_slug(name: &str) -> String {
+    let mut slug = String::new();
+    for character in name.chars() {
+        if character.is_ascii_alphanumeric() {
+            slug.push(character.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(32);
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "program".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// Turns a key like `ctrl+shift+d` into fuzzel's `Control+Shift+d`.
+fn fuzzel_key_name(key: &str) -> String {
+    let mut parts: Vec<&str> = key.split('+').collect();
+    let last = parts.pop().unwrap_or_default();
+    let mut name = String::new();
+    for modifier in parts {
+        name.push_str(match modifier.to_lowercase().as_str() {
+            "ctrl" | "control" => "Control",
+            "alt" | "mod1" => "Mod1",
+            "shift" => "Shift",
+            "super" | "mod4" => "Mod4",
+            _ => modifier,
+        });
+        name.push('+');
+    }
+    name.push_str(last);
+    name
+}
+
+/// Expands `~` in icons given as paths and makes relative ones relative to the
+/// config's dir. One that doesn't exist is warned about, or is an error under
+/// `--strict`, and is kept in `missing` for `--check-icons`.
+fn resolve_icon_paths(
+    menu: &mut Menu,
+    config_dir: &Path,
+    menu_path: &str,
+    options: ParseOptions,
+    missing: &mut Vec<MissingIcon>,
+) -> Result<()> {
+    for item in &mut menu.items {
+        if let Some(icon) = &mut item.icon
+            && icon.contains('/')
+        {
+            // Joining an absolute path replaces the dir, and ./ is dropped
+            let path: PathBuf = config_dir.join(expand_tilde(icon)).components().collect();
+            *icon = path.display().to_string();
+            if !path.exists() {
+                let item = item_path(menu_path, &item.name);
+                parser::lint(
+                    options,
+                    miette!(
+                        help = "icons with a / are paths, relative ones are next to the config",
+                        "the icon of {item} doesn't exist: {icon}"
+                    ),
+                )?;
+                missing.push(MissingIcon {
+                    item,
+                    name: icon.clone(),
+                    dirs: Vec::new(),
+                    similar: Vec::new(),
+                });
+            }
+        }
+        if let ItemContents::Menu(submenu) = &mut item.contents {
+            let segment = item.id.as_ref().unwrap_or(&item.name);
+            resolve_icon_paths(
+                submenu,
+                config_dir,
+                &item_path(menu_path, segment),
+                options,
+                missing,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Makes sure every program has something to run before it's launched, which the
+/// parser should already have caught.
+fn check_commands(menu: &Menu) -> Result<()> {
+    for item in &menu.items {
+        let mut commands: Vec<&[String]> = match &item.contents {
+            ItemContents::Menu(submenu) => {
+                check_commands(submenu)?;
+                Vec::new()
+            }
+            ItemContents::Program(program) => program_commands(program),
+            ItemContents::DynamicMenu(dynamic_menu) => {
+                let mut commands: Vec<&[String]> =
+                    vec![&dynamic_menu.source_command, &dynamic_menu.run_command];
+                if let OnEmpty::Run(command) = &dynamic_menu.on_empty {
+                    commands.push(command);
+                }
+                commands
+            }
+            ItemContents::Pick(pick) => vec![&pick.list_command, &pick.run_command],
+            ItemContents::Input(input) => vec![&input.command],
+            _ => Vec::new(),
+        };
+        for (_, program) in &item.actions {
+            commands.extend(program_commands(program));
+        }
+        for command in commands {
+            ensure!(
+                command.first().is_some_and(|program| !program.is_empty()),
+                "{} has no command to run",
+                item.name
+            );
+        }
+    }
+    Ok(())
+}
+
+// Replaces each `try` block with the first of its commands found in
+// `search_path`, recording the choice so the cache can be invalidated when a
+// different one would be found. Items and actions without one are dropped.
+fn choose_commands(
+    menu: &mut Menu,
+    search_path: Option<OsString>,
+    choices: &mut Vec<(Vec<String>, Option<usize>)>,
+) -> Result<()> {
+    let mut items = Vec::new();
+    for mut item in std::mem::take(&mut menu.items) {
+        let found = match &mut item.contents {
+            ItemContents::Menu(submenu) => {
+                choose_commands(submenu, search_path.clone(), choices)?;
+                true
+            }
+            ItemContents::Program(program) => {
+                choose_command(program, &item.name, search_path.clone(), choices)?
+            }
+            _ => true,
+        };
+        let mut actions = Vec::new();
+        for (action, mut program) in std::mem::take(&mut item.actions) {
+            let name = format!("the {action} action of {}", item.name);
+            if choose_command(&mut program, &name, search_path.clone(), choices)? {
+                actions.push((action, program));
+            }
+        }
+        item.actions = actions;
+        if found {
+            items.push(item);
+        }
+    }
+    menu.items = items;
+    Ok(())
+}
+
+// Fills in the command and icon of each program with a desktop entry where
+// they aren't set, recording the file read so the cache can be invalidated when
+// it changes or another one takes its place.
+fn apply_desktop_entries(
+    menu: &mut Menu,
+    dirs: &[PathBuf],
+    terminal: &[String],
+    entries: &mut Vec<(String, String, Option<u64>)>,
+) -> Result<()> {
+    for item in &mut menu.items {
+        match &mut item.contents {
+            ItemContents::Menu(submenu) => {
+                apply_desktop_entries(submenu, dirs, terminal, entries)?;
+            }
+            ItemContents::Program(program) => {
+                let icon = apply_desktop_entry(program, &item.name, dirs, terminal, entries)?;
+                if item.icon.is_none() {
+                    item.icon = icon;
+                }
+            }
+            _ => {}
+        }
+        for (action, program) in &mut item.actions {
+            let name = format!("the {action} action of {}", item.name);
+            apply_desktop_entry(program, &name, dirs, terminal, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the program's command from its desktop entry unless it has its own,
+/// returning the entry's icon.
+fn apply_desktop_entry(
+    program: &mut parser::Program,
+    name: &str,
+    dirs: &[PathBuf],
+    terminal: &[String],
+    entries: &mut Vec<(String, String, Option<u64>)>,
+) -> Result<Option<String>> {
+    let Some(desktop) = program.desktop.take() else {
+        return Ok(None);
+    };
+    let Some(path) = desktop::locate(&desktop, dirs) else {
+        if desktop.contains('/') {
+            bail!("the desktop entry {desktop} for {name} doesn't exist");
+        }
+        let searched: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+        bail!(
+            help = format!("searched {}", searched.join(", ")),
+            "the desktop entry {desktop} for {name} was not found"
+        );
+    };
+    entries.push((
+        desktop,
+        path.display().to_string(),
+        desktop::modified(&path),
+    ));
+    let contents = std::fs::read_to_string(&path)
+        .into_diagnostic()
+        .with_context(|| format!("failed to read desktop entry: {}", path.display()))?;
+    let entry = desktop::parse(&contents)
+        .map_err(|error| miette!("invalid desktop entry {}: {error}", path.display()))?;
+
+    if program.command.is_empty() && program.chain.is_none() && program.fallbacks.is_none() {
+        let Some(command) = entry.command(None) else {
+            bail!(
+                help = "give it a command node",
+                "{} has no Exec, so {name} needs a command of its own",
+                path.display()
+            );
+        };
+        program.command = if entry.terminal {
+            terminal.iter().cloned().chain(command).collect()
+        } else {
+            command
+        };
+    }
+    Ok(entry.icon)
+}
+
+/// Sets the program's command from its `try` block, if it has one. Returns
+/// false if none of the commands were found and the block isn't `required`.
+fn choose_command(
+    program: &mut parser::Program,
+    name: &str,
+    search_path: Option<OsString>,
+    choices: &mut Vec<(Vec<String>, Option<usize>)>,
+) -> Result<bool> {
+    let Some(mut fallbacks) = program.fallbacks.take() else {
+        return Ok(true);
+    };
+    let candidates: Vec<String> = fallbacks
+        .commands
+        .iter()
+        .map(|command| command.first().cloned().unwrap_or_default())
+        .collect();
+    let chosen = find_command(&candidates, search_path);
+    choices.push((candidates.clone(), chosen));
+    match chosen {
+        Some(index) => {
+            debug!("using {} for {name}", candidates[index]);
+            program.command = fallbacks.commands.swap_remove(index);
+            Ok(true)
+        }
+        None if fallbacks.required => Err(miette!(
+            help = "install one of them, or remove required=#true to drop the item instead",
+            "none of the commands for {name} were found in PATH: {}",
+            candidates.join(", ")
+        )),
+        None => {
+            warn!(
+                "dropping {name}, none of its commands were found in PATH: {}",
+                candidates.join(", ")
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// The index of the first program that's found in `search_path`.
+fn find_command(candidates: &[String], search_path: Option<OsString>) -> Option<usize> {
+    candidates
+        .iter()
+        .position(|name| settings::find_executable(name, search_path.clone()).is_ok())
+}
+
+/// A program runs either its command or each step of its chain.
+fn program_commands(program: &parser::Program) -> Vec<&[String]> {
+    program.chain.as_ref().map_or_else(
+        || vec![program.command.as_slice()],
+        |chain| chain.commands.iter().map(Vec::as_slice).collect(),
+    )
+}
+
+/// Puts the global config's defaults and settings under the preset's own.
+fn apply_global_config(config: &mut Menu, global: &GlobalConfig) {
+    let defaults = &mut config.defaults;
+    defaults.fuzzel_args = merge_fuzzel_args(&global.defaults.fuzzel_args, &defaults.fuzzel_args);
+    // Later keys win, so the preset's come last
+    defaults
+        .fuzzel_config
+        .splice(0..0, global.defaults.fuzzel_config.iter().cloned());
+    defaults.use_fuzzel_cache = defaults
+        .use_fuzzel_cache
+        .or(global.defaults.use_fuzzel_cache);
+    defaults.icon_size = defaults.icon_size.or(global.defaults.icon_size);
+    defaults.prefer_icon_format = defaults
+        .prefer_icon_format
+        .or(global.defaults.prefer_icon_format);
+    defaults.icon_match = defaults.icon_match.or(global.defaults.icon_match);
+    defaults.symbolic = defaults.symbolic.or(global.defaults.symbolic);
+    defaults.icon_dir_walk = defaults.icon_dir_walk.or(global.defaults.icon_dir_walk);
+    defaults.systemd_scope = defaults.systemd_scope.or(global.defaults.systemd_scope);
+    defaults.launch_method = defaults.launch_method.or(global.defaults.launch_method);
+    defaults.export_env = defaults.export_env.or(global.defaults.export_env);
+    defaults.activation = defaults.activation.or(global.defaults.activation);
+    if defaults.terminal.is_none() {
+        defaults.terminal.clone_from(&global.defaults.terminal);
+    }
+    if defaults.stdout.is_none() {
+        defaults.stdout.clone_from(&global.defaults.stdout);
+    }
+    if defaults.stderr.is_none() {
+        defaults.stderr.clone_from(&global.defaults.stderr);
+    }
+    if config.opener.is_none() {
+        config.opener.clone_from(&global.settings.opener);
+    }
+}
+
+fn profile_is_used(menu: &Menu, profile: &str) -> bool {
+    menu.items.iter().any(|item| {
+        item.profiles.iter().any(|name| name == profile)
+            || matches!(&item.contents, ItemContents::Menu(submenu) if profile_is_used(submenu, profile))
+    })
+}
+
+/// Removes items tagged with other profiles, along with submenus that are left empty.
+fn filter_by_profile(menu: &mut Menu, profile: &str) {
+    filter_items(
+        menu,
+        &|item| item.profiles.is_empty() || item.profiles.iter().any(|name| name == profile),
+        &format!("profile {profile}"),
+    );
+}
+
+fn uses_host_conditions(menu: &Menu) -> bool {
+    menu.items.iter().any(|item| {
+        !item.if_host.is_empty()
+            || !item.unless_host.is_empty()
+            || matches!(&item.contents, ItemContents::Menu(submenu) if uses_host_conditions(submenu))
+    })
+}
+
+/// Whether the menu or any submenu has a script, which is written to the cache dir.
+fn uses_scripts(menu: &Menu) -> bool {
+    menu.items.iter().any(|item| match &item.contents {
+        ItemContents::Script(_) => true,
+        ItemContents::Menu(submenu) => uses_scripts(submenu),
+        _ => false,
+    })
+}
+
+/// Removes items whose `if-host` patterns all miss the hostname, or one of
+/// whose `unless-host` patterns matches it.
+fn filter_by_host(menu: &mut Menu, hostname: &str) {
+    let matches = |patterns: &[String]| {
+        patterns
+            .iter()
+            .any(|pattern| glob::matches(pattern, hostname))
+    };
+    filter_items(
+        menu,
+        &|item| (item.if_host.is_empty() || matches(&item.if_host)) && !matches(&item.unless_host),
+        &format!("host {hostname}"),
+    );
+}
+
+/// Keeps the items `keep` accepts, dropping submenus that end up with nothing
+/// but back items. `reason` is what hid them, for the debug log.
+fn filter_items(menu: &mut Menu, keep: &dyn Fn(&Item) -> bool, reason: &str) {
+    menu.items.retain_mut(|item| {
+        if !keep(item) {
+            return false;
+        }
+        if let ItemContents::Menu(submenu) = &mut item.contents
+            && !submenu.items.is_empty()
+        {
+            filter_items(submenu, keep, reason);
+            // A lone back item doesn't make a submenu worth showing
+            let has_entries = submenu
+                .items
+                .iter()
+                .any(|item| !matches!(item.contents, ItemContents::Back));
+            if !has_entries {
+                debug!("dropping menu '{}', {reason} left it empty", item.name);
+            }
+            return has_entries;
+        }
+        true
+    });
+}
+
+/// Every alias in the menu and its submenus, in config order.
+fn aliases(menu: &Menu) -> Vec<(&Item, &parser::Alias)> {
+    let mut found = Vec::new();
+    for item in &menu.items {
+        match &item.contents {
+            ItemContents::Menu(submenu) => found.extend(aliases(submenu)),
+            ItemContents::Alias(alias) => found.push((item, alias)),
+            _ => {}
+        }
+    }
+    found
+}
+
+/// The items an alias's target matches, with their index in each menu's sorted
+/// items on the way to them. Ids are looked for in the whole config, and then
+/// the target is read as a path of ids or names from the root menu.
+fn find_alias_targets<'a>(root: &'a Menu, target: &str) -> Vec<(Vec<usize>, &'a Item)> {
+    let mut found = Vec::new();
+    find_by_id(root, target, &mut Vec::new(), &mut found);
+    if found.is_empty() {
+        let segments: Vec<&str> = target.split('/').collect();
+        find_by_path(root, &segments, &mut Vec::new(), &mut found);
+    }
+    found
+}
+
+fn find_by_id<'a>(
+    menu: &'a Menu,
+    id: &str,
+    path: &mut Vec<usize>,
+    found: &mut Vec<(Vec<usize>, &'a Item)>,
+) {
+    for (index, item) in sorted_items(menu).into_iter().enumerate() {
+        path.push(index);
+        if item.id.as_deref() == Some(id) {
+            found.push((path.clone(), item));
+        }
+        if let ItemContents::Menu(submenu) = &item.contents {
+            find_by_id(submenu, id, path, found);
+        }
+        path.pop();
+    }
+}
+
+fn find_by_path<'a>(
+    menu: &'a Menu,
+    segments: &[&str],
+    path: &mut Vec<usize>,
+    found: &mut Vec<(Vec<usize>, &'a Item)>,
+) {
+    let Some((segment, rest)) = segments.split_first() else {
+        return;
+    };
+    for (index, item) in sorted_items(menu).into_iter().enumerate() {
+        if item.id.as_deref() != Some(*segment) && item.name != *segment {
+            continue;
+        }
+        path.push(index);
+        if rest.is_empty() {
+            found.push((path.clone(), item));
+        } else if let ItemContents::Menu(submenu) = &item.contents {
+            find_by_path(submenu, rest, path, found);
+        }
+        path.pop();
+    }
+}
+
+/// Every alias in the menu and its submenus, with its index in each menu's
+/// sorted items on the way to it.
+fn alias_paths<'a>(
+    menu: &'a Menu,
+    path: &mut Vec<usize>,
+    found: &mut Vec<(Vec<usize>, &'a Item, &'a parser::Alias)>,
+) {
+    for (index, item) in sorted_items(menu).into_iter().enumerate() {
+        path.push(index);
+        match &item.contents {
+            ItemContents::Menu(submenu) => alias_paths(submenu, path, found),
+            ItemContents::Alias(alias) => found.push((path.clone(), item, alias)),
+            _ => {}
+        }
+        path.pop();
+    }
+}
+
+/// Checks that each alias points at exactly one item, which isn't another alias
+/// and doesn't hold the alias, even through the aliases inside it.
+fn check_aliases(root: &Menu) -> Result<()> {
+    let mut found = Vec::new();
+    alias_paths(root, &mut Vec::new(), &mut found);
+    let mut targets = Vec::new();
+    for (path, item, alias) in &found {
+        let error = |label: &str, help: &str, message: String| {
+            miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some(label.to_string()),
+                    alias.span
+                )],
+                help = help.to_string(),
+                "{message}",
+            )
+        };
+        match find_alias_targets(root, &alias.target).as_slice() {
+            [] => {
+                return Err(error(
+                    "no item has this id or path",
+                    "use an item's id, or its path from the root menu like \"Apps/Firefox\"",
+                    format!("alias {} points at nothing: {}", item.name, alias.target),
+                ));
+            }
+            [(_, target)] if matches!(target.contents, ItemContents::Alias(_)) => {
+                return Err(error(
+                    "this is an alias",
+                    "point it at the item the other alias points at",
+                    format!("alias {} points at another alias", item.name),
+                ));
+            }
+            [(target, _)] if path.starts_with(target) => {
+                return Err(error(
+                    "this menu holds the alias",
+                    "point it at an item outside of its own menus",
+                    format!("alias {} points at a menu it's in", item.name),
+                ));
+            }
+            [(target, _)] => targets.push(target.clone()),
+            _ => {
+                return Err(error(
+                    "more than one item is here",
+                    "give the one you mean an id and point the alias at that",
+                    format!("alias {} could point at several items", item.name),
+                ));
+            }
+        }
+    }
+
+    // An alias copies the aliases in its target too, so follow them to make
+    // sure none of them lead back to a menu holding the first one
+    for (start, (path, item, alias)) in found.iter().enumerate() {
+        let mut seen = vec![false; found.len()];
+        let mut next = vec![start];
+        while let Some(current) = next.pop() {
+            for (other, (other_path, ..)) in found.iter().enumerate() {
+                if seen[other] || !other_path.starts_with(&targets[current]) {
+                    continue;
+                }
+                if path.starts_with(&targets[other]) {
+                    return Err(miette!(
+                        labels = vec![LabeledSpan::new_primary_with_span(
+                            Some("this menu holds an alias that leads back here".to_string()),
+                            alias.span
+                        )],
+                        help = "point one of the aliases at an item outside of the other's target",
+                        "alias {} leads back to a menu it's in",
+                        item.name,
+                    ));
+                }
+                seen[other] = true;
+                next.push(other);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the aliases in multi-select menus point at programs that don't
+/// ask for confirmation, which the parser can only check for the other items.
+fn check_multi_select_aliases(root: &Menu, menu: &Menu) -> Result<()> {
+    for item in &menu.items {
+        let alias = match &item.contents {
+            ItemContents::Menu(submenu) => {
+                check_multi_select_aliases(root, submenu)?;
+                continue;
+            }
+            ItemContents::Alias(alias) if menu.multi_select.is_some() => alias,
+            _ => continue,
+        };
+        // check_aliases made sure there's exactly one
+        let (_, target) = find_alias_targets(root, &alias.target).swap_remove(0);
+        let (label, help) = match &target.contents {
+            ItemContents::Program(program) if program.confirm.is_some() => (
+                "this asks for confirmation",
+                "point it at a program without confirm, or move it out of the multi-select menu",
+            ),
+            ItemContents::Power(power) if power.confirm.is_some() => (
+                "this asks for confirmation",
+                "add confirm #false to it, or move the alias out of the multi-select menu",
+            ),
+            ItemContents::Program(_)
+            | ItemContents::Power(_)
+            | ItemContents::Open(_)
+            | ItemContents::Script(_) => continue,
+            _ => (
+                "this isn't a program",
+                "point it at a program, or move it out of the multi-select menu",
+            ),
+        };
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some(label.to_string()),
+                alias.span
+            )],
+            help = help.to_string(),
+            "alias {} in a multi-select menu can't point at {}",
+            item.name,
+            target.name,
+        ));
+    }
+    Ok(())
+}
+
+/// Points each alias at its target's place among the sorted items, and gives
+/// it the target's icon if it has none of its own. An alias is dropped along
+/// with its target when a profile, host or missing command hid the target.
+fn resolve_aliases(root: &mut Menu) {
+    // Dropping aliases can leave a menu empty, and so hide another alias's target
+    loop {
+        let missing: Vec<String> = aliases(root)
+            .into_iter()
+            .filter(|(_, alias)| find_alias_targets(root, &alias.target).is_empty())
+            .map(|(_, alias)| alias.target.clone())
+            .collect();
+        if missing.is_empty() {
+            break;
+        }
+        filter_items(
+            root,
+            &|item| !matches!(&item.contents, ItemContents::Alias(alias) if missing.contains(&alias.target)),
+            "hiding the target of an alias",
+        );
+    }
+
+    let targets: Vec<AliasTarget> = aliases(root)
+        .into_iter()
+        .map(|(_, alias)| {
+            let (path, target) = find_alias_targets(root, &alias.target).swap_remove(0);
+            (path, target.icon.clone(), target.icon_dirs.clone())
+        })
+        .collect();
+    let mut targets = targets.into_iter();
+    set_alias_targets(root, &mut || targets.next().unwrap());
+}
+
+/// The path to an alias's target, and its icon with the dirs to find it in.
+type AliasTarget = (Vec<usize>, Option<String>, Vec<IconDir>);
+
+/// Visits the aliases in the same order as `aliases`.
+fn set_alias_targets(menu: &mut Menu, next_target: &mut dyn FnMut() -> AliasTarget) {
+    for item in &mut menu.items {
+        match &mut item.contents {
+            ItemContents::Menu(submenu) => set_alias_targets(submenu, next_target),
+            ItemContents::Alias(alias) => {
+                let (path, icon, icon_dirs) = next_target();
+                alias.path = path;
+                if item.icon.is_none() {
+                    item.icon = icon;
+                    item.icon_dirs = icon_dirs;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Orders a menu's items into the groups from its `order` option, then by weight
+/// in each group, heaviest first, and then by its `sort` option. Names are
+/// compared case-insensitively. Items with equal names keep their order from
+/// the config.
+fn sorted_items(menu: &Menu) -> Vec<&Item> {
+    let mut items: Vec<&Item> = menu.items.iter().collect();
+    match menu.sort {
+        SortOrder::None => {}
+        SortOrder::Alphabetical => {
+            items.sort_by_cached_key(|item| item.name.to_lowercase());
+        }
+        SortOrder::Reverse => {
+            items.sort_by_cached_key(|item| std::cmp::Reverse(item.name.to_lowercase()));
+        }
+    }
+    // Stable, so items of equal weight stay in the order above
+    items.sort_by_key(|item| (item_group(menu.order, item), std::cmp::Reverse(item.weight)));
+    items
+}
+
+/// Where the item goes for the menu's `order`, lower groups first. Back items
+/// stay in front of both groups, like `..` in a file manager.
+fn item_group(order: ItemOrder, item: &Item) -> u8 {
+    let opens_list = matches!(
+        item.contents,
+        ItemContents::Menu(_)
+            | ItemContents::DynamicMenu(_)
+            | ItemContents::Pick(_)
+            | ItemContents::FilesMenu(_)
+            | ItemContents::RecentFiles(_)
+    );
+    match (order, &item.contents) {
+        (ItemOrder::Config, _) | (_, ItemContents::Back) => 0,
+        (ItemOrder::MenusFirst, _) => 2 - u8::from(opens_list),
+        (ItemOrder::ProgramsFirst, _) => 1 + u8::from(opens_list),
+    }
+}
+
+// A hidden Yes/No menu in front of a program, cancelling it counts as "No"
+fn build_confirmation_menu(
+    question: &str,
+    parent_args: &[String],
+    program: ComputedProgram,
+) -> ResolvedMenu {
+    // Usage counts would reorder the answers, so they aren't recorded
+    let confirm_args = [
+        "--prompt".to_string(),
+        format!("{question} "),
+        "--cache".to_string(),
+        "/dev/null".to_string(),
+    ];
+    ResolvedMenu {
+        args: merge_fuzzel_args(parent_args, &confirm_args),
+        input: b"Yes\nNo\n".to_vec(),
+        items: vec![ResolvedItem::Program(program), ResolvedItem::Back],
+        back_on_cancel: true,
+        stay_open: false,
+        actions: Vec::new(),
+        labels: Vec::new(),
+        hooks: ComputedHooks::default(),
+        names: Vec::new(),
+        ids: Vec::new(),
+        multi_select: None,
+    }
+}
+
+fn flatten_resolved_menu(
+    mut resolved_menu: ResolvedMenu,
+    items: &mut Vec<ComputedItem>,
+) -> ComputedMenu {
+    copy_alias_targets(&mut resolved_menu);
+    flatten_menu(resolved_menu, items)
+}
+
+/// Replaces every alias with a copy of the item it points at, so the tree can
+/// be flattened by moving out of it. Only aliases are replaced, so the paths of
+/// the others stay valid, and copied menus may hold aliases of their own.
+fn copy_alias_targets(root: &mut ResolvedMenu) {
+    loop {
+        let mut copies = Vec::new();
+        collect_alias_copies(root, root, &mut Vec::new(), &mut copies);
+        if copies.is_empty() {
+            return;
+        }
+        for (path, copy) in copies {
+            let (last, menus) = path.split_last().unwrap();
+            let mut menu = &mut *root;
+            for &index in menus {
+                let ResolvedItem::Menu(submenu) = &mut menu.items[index] else {
+                    unreachable!("alias copies are only collected in menus");
+                };
+                menu = submenu;
+            }
+            menu.items[*last] = copy;
+        }
+    }
+}
+
+fn collect_alias_copies(
+    menu: &ResolvedMenu,
+    root: &ResolvedMenu,
+    path: &mut Vec<usize>,
+    copies: &mut Vec<(Vec<usize>, ResolvedItem)>,
+) {
+    for (index, item) in menu.items.iter().enumerate() {
+        path.push(index);
+        match item {
+            // Copying a menu the alias is in would copy the alias again, forever
+            ResolvedItem::Alias(target) if path.starts_with(target) => {}
+            ResolvedItem::Alias(_) => copies.push((path.clone(), alias_target(item, root).clone())),
+            ResolvedItem::Menu(submenu) => collect_alias_copies(submenu, root, path, copies),
+            _ => {}
+        }
+        path.pop();
+    }
+}
+
+fn flatten_menu(resolved_menu: ResolvedMenu, items: &mut Vec<ComputedItem>) -> ComputedMenu {
+    let ResolvedMenu {
+        args,
+        input,
+        items: resolved_items,
+        back_on_cancel,
+        stay_open,
+        actions,
+        labels,
+        hooks,
+        names,
+        ids,
+        multi_select,
+    } = resolved_menu;
+    let items_offset = items.len();
+
+    // First pass: add all direct children to maintain adjacency
+    let mut child_menus = Vec::new();
+    for resolved_item in resolved_items {
+        let item = match resolved_item {
+            ResolvedItem::Menu(child_menu) => {
+                child_menus.push((items.len(), child_menu));
+                // Replaced by the flattened menu in the second pass
+                ComputedItem::Back
+            }
+            ResolvedItem::Program(program) => ComputedItem::Program(program),
+            ResolvedItem::Back => ComputedItem::Back,
+            ResolvedItem::DynamicMenu(dynamic_menu) => ComputedItem::DynamicMenu(dynamic_menu),
+            ResolvedItem::Pick(pick) => ComputedItem::Pick(pick),
+            ResolvedItem::MimeOpen(mime_open) => ComputedItem::MimeOpen(mime_open),
+            ResolvedItem::Input(input) => ComputedItem::Input(input),
+            ResolvedItem::FilesMenu(files_menu) => ComputedItem::FilesMenu(files_menu),
+            ResolvedItem::RecentFiles(recent_files) => ComputedItem::RecentFiles(recent_files),
+            ResolvedItem::Alias(_) => {
+                unreachable!("check_aliases rejects the aliases that aren't copied")
+            }
+        };
+        items.push(item);
+    }
+
+    // Second pass: recursively flatten submenus, which sets their offsets
+    for (index, child_menu) in child_menus {
+        items[index] = ComputedItem::Menu(flatten_menu(child_menu, items));
+    }
+
+    ComputedMenu {
+        args,
+        input,
+        items_offset,
+        back_on_cancel,
+        stay_open,
+        actions,
+        labels,
+        hooks,
+        names,
+        ids,
+        multi_select,
+    }
+}
+
+/// The item an alias points at, which it's replaced by a copy of, or the item
+/// itself if it isn't an alias.
+fn alias_target<'a>(item: &'a ResolvedItem, root: &'a ResolvedMenu) -> &'a ResolvedItem {
+    let ResolvedItem::Alias(path) = item else {
+        return item;
+    };
+    let (last, menus) = path.split_last().unwrap();
+    let mut menu = root;
+    for &index in menus {
+        let ResolvedItem::Menu(submenu) = &menu.items[index] else {
+            unreachable!("alias paths only go through menus");
+        };
+        menu = submenu;
+    }
+    &menu.items[*last]
+}
+
+/// `$HOME`, or the home directory from the user database if it's unset.
+pub fn home() -> Result<String> {
+    if let Some(home) = env_var("HOME").filter(|home| !home.is_empty()) {
+        return Ok(home);
+    }
+    std::env::home_dir()
+        .context("HOME is unset and the home directory couldn't be found")?
+        .into_os_string()
+        .into_string()
+        .ok()
+        .context("the home directory isn't valid UTF-8")
+}
+
+/// Expands a leading `~` or `~user` like a shell would, other tildes are left alone.
+pub fn expand_tilde(path: &str) -> String {
+    let Some(rest) = path.strip_prefix('~') else {
+        return path.to_string();
+    };
+    let (user, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let home = if user.is_empty() {
+        match home() {
+            Ok(home) => home,
+            Err(error) => {
+                warn!("can't expand {path}: {error}");
+                return path.to_string();
+            }
+        }
+    } else if let Some(home) = user_home(user) {
+        home
+    } else {
+        warn!("unknown user in path: {path}");
+        return path.to_string();
+    };
+    format!("{home}{rest}")
+}
+
+fn user_home(user: &str) -> Option<String> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        (fields.len() >= 6 && fields[0] == user).then(|| fields[5].to_string())
+    })
+}
+
+fn expand_command(command: &[String]) -> Vec<String> {
+    command.iter().map(|arg| expand_tilde(arg)).collect()
+}
+
+/// Expands `~` in local paths, urls are left alone.
+/// Paths that don't exist are only warned about, they might be created later.
+fn resolve_open_target(target: &str) -> String {
+    let is_local_path = ["/", "~", "./", "../"]
+        .iter()
+        .any(|prefix| target.starts_with(prefix));
+    if !is_local_path {
+        return target.to_string();
+    }
+
+    let path = expand_tilde(target);
+    if !Path::new(&path).exists() {
+        warn!("open target does not exist: {path}");
+    }
+    path
+}
+
+/// Replaces `$NAME` and `${NAME}` with the variable's value and `$$` with `$`.
+/// Returns the name of the first variable that isn't set as the error.
+pub fn expand_env_vars(text: &str) -> Result<String, String> {
+    substitute_env_vars(text, |name| env_var(name).ok_or_else(|| name.to_string()))
+}
+
+/// Every variable name referenced in the text, in order.
+fn env_var_names(text: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let _ = substitute_env_vars(text, |name| {
+        names.push(name.to_string());
+        Ok::<_, ()>(String::new())
+    });
+    names
+}
+
+fn substitute_env_vars<E>(
+    text: &str,
+    mut lookup: impl FnMut(&str) -> Result<String, E>,
+) -> Result<String, E> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+
+        let (name, len) = if let Some(braced) = after.strip_prefix('{')
+            && let Some(end) = braced.find('}')
+        {
+            (&braced[..end], end + 2)
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], end)
+        };
+
+        if name.is_empty() {
+            // A lone `$` isn't a variable
+            expanded.push('$');
+        } else {
+            expanded.push_str(&lookup(name)?);
+        }
+        rest = &after[len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Program;
+    use tempfile::TempDir;
+
+    /// Dirs of the test's own, away from the user's files and other tests'.
+    /// They're removed when the `TempDir` is dropped.
+    fn test_dirs() -> (TempDir, Dirs) {
+        let temp = TempDir::new().unwrap();
+        let dirs = Dirs {
+            cache: temp.path().join("cache"),
+            state: temp.path().join("state"),
+            config: temp.path().join("config"),
+            fuzzel_config: temp.path().join("config/fuzzel/fuzzel.ini"),
+            cache_env: Vec::new(),
+            config_env: Vec::new(),
+        };
+        (temp, dirs)
+    }
+
+    #[test]
+    fn test_build_phase_comprehensive() {
+        let (_temp, dirs) = test_dirs();
+        // Test simple menu building
+        let simple_menu = Menu {
+            fuzzel_args: vec!["--arg1".to_string()],
+            fuzzel_config: vec![],
+            icon_dirs: vec![],
+            items: vec![Item {
+                name: "Item1".to_string(),
+                id: None,
+                icon: None,
+                icon_dirs: Vec::new(),
+                description: None,
+                profiles: Vec::new(),
+                if_host: Vec::new(),
+                unless_host: Vec::new(),
+                actions: Vec::new(),
+                weight: 0,
+                label_command: None,
+                contents: ItemContents::Program(Box::new(Program {
+                    command: vec!["cmd1".to_string()],
+                    ..Default::default()
+                })),
+            }],
+            ..Default::default()
+        };
+        let inheritance_stack = vec![InheritanceFrame::default()];
+        let mut id_gen = IdGenerator::new();
+        let simple_result = build_resolved_menu(
+            &simple_menu,
+            &inheritance_stack,
+            &mut id_gen,
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testsimple",
+        );
+        assert_eq!(
+            simple_result.args,
+            vec!["--arg1", "--cache", "testsimple0.fuzzel.cache"]
+        );
+        assert_eq!(simple_result.input, b"Item1\n");
+        assert_eq!(simple_result.items.len(), 1);
+
+        // Test menu with config file generation
+        let menu_with_config = Menu {
+            fuzzel_args: vec![],
+            fuzzel_config: vec![("width".to_string(), "12".to_string())],
+            icon_dirs: vec![],
+            items: vec![Item {
+                name: "Item1".to_string(),
+                id: None,
+                icon: None,
+                icon_dirs: Vec::new(),
+                description: None,
+                profiles: Vec::new(),
+                if_host: Vec::new(),
+                unless_host: Vec::new(),
+                actions: Vec::new(),
+                weight: 0,
+                label_command: None,
+                contents: ItemContents::Program(Box::new(Program {
+                    command: vec!["cmd1".to_string()],
+                    ..Default::default()
+                })),
+            }],
+            ..Default::default()
+        };
+        let mut fuzzel_inis = Vec::new();
+        let config_result = build_resolved_menu(
+            &menu_with_config,
+            &inheritance_stack,
+            &mut id_gen,
+            &mut fuzzel_inis,
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testconfig",
+        );
+        for ini in &fuzzel_inis {
+            create_fuzzel_config(ini, &dirs, "testconfig").unwrap();
+        }
+        assert_eq!(
+            config_result.args,
+            vec![
+                "--config",
+                "testconfig1.fuzzel.ini",
+                "--cache",
+                "testconfig1.fuzzel.cache"
+            ]
+        );
+
+        // Verify config file was created with correct content
+        let config_content =
+            std::fs::read_to_string(dirs.cache.join("testconfig1.fuzzel.ini")).unwrap();
+        assert_eq!(
+            config_content,
+            format!("include={}\nwidth=12\n", dirs.fuzzel_config.display())
+        );
+
+        // Test nested menu with inheritance
+        let nested_menu = Menu {
+            fuzzel_args: vec!["--base-arg".to_string()],
+            fuzzel_config: vec![("base_key".to_string(), "base_value".to_string())],
+            icon_dirs: vec![],
+            items: vec![
+                Item {
+                    name: "Item1".to_string(),
+                    id: None,
+                    icon: None,
+                    icon_dirs: Vec::new(),
+                    description: None,
+                    profiles: Vec::new(),
+                    if_host: Vec::new(),
+                    unless_host: Vec::new(),
+                    actions: Vec::new(),
+                    weight: 0,
+                    label_command: None,
+                    contents: ItemContents::Program(Box::new(Program {
+                        command: vec!["cmd1".to_string()],
+                        ..Default::default()
+                    })),
+                },
+                Item {
+                    name: "Submenu1".to_string(),
+                    id: None,
+                    icon: None,
+                    icon_dirs: Vec::new(),
+                    description: None,
+                    profiles: Vec::new(),
+                    if_host: Vec::new(),
+                    unless_host: Vec::new(),
+                    actions: Vec::new(),
+                    weight: 0,
+                    label_command: None,
+                    contents: ItemContents::Menu(Box::new(Menu {
+                        fuzzel_args: vec![],
+                        fuzzel_config: vec![("sub_key".to_string(), "sub_value".to_string())],
+                        icon_dirs: vec![],
+                        items: vec![Item {
+                            name: "Item2".to_string(),
+                            id: None,
+                            icon: None,
+                            icon_dirs: Vec::new(),
+                            description: None,
+                            profiles: Vec::new(),
+                            if_host: Vec::new(),
+                            unless_host: Vec::new(),
+                            actions: Vec::new(),
+                            weight: 0,
+                            label_command: None,
+                            contents: ItemContents::Program(Box::new(Program {
+                                command: vec!["cmd2".to_string()],
+                                ..Default::default()
+                            })),
+                        }],
+                        ..Default::default()
+                    })),
+                },
+            ],
+            ..Default::default()
+        };
+        let mut fuzzel_inis = Vec::new();
+        let nested_result = build_resolved_menu(
+            &nested_menu,
+            &inheritance_stack,
+            &mut id_gen,
+            &mut fuzzel_inis,
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testnested",
+        );
+        for ini in &fuzzel_inis {
+            create_fuzzel_config(ini, &dirs, "testnested").unwrap();
+        }
+
+        // Check top-level menu
+        assert_eq!(
+            nested_result.args,
+            vec![
+                "--base-arg",
+                "--config",
+                "testnested2.fuzzel.ini",
+                "--cache",
+                "testnested2.fuzzel.cache"
+            ]
+        );
+        assert_eq!(nested_result.input, b"Item1\nSubmenu1\n");
+        assert_eq!(nested_result.items.len(), 2);
+
+        // Check nested submenu
+        if let ResolvedItem::Menu(ref submenu) = nested_result.items[1] {
+            assert_eq!(
+                submenu.args,
+                vec![
+                    "--base-arg",
+                    "--config",
+                    "testnested3.fuzzel.ini",
+                    "--cache",
+                    "testnested3.fuzzel.cache"
+                ]
+            );
+            assert_eq!(submenu.input, b"Item2\n");
+        } else {
+            panic!("Expected nested menu");
+        }
+
+        // Verify inheritance in config files
+        let base_config =
+            std::fs::read_to_string(dirs.cache.join("testnested2.fuzzel.ini")).unwrap();
+        assert_eq!(
+            base_config,
+            format!(
+                "include={}\nbase_key=base_value\n",
+                dirs.fuzzel_config.display()
+            )
+        );
+
+        let sub_config =
+            std::fs::read_to_string(dirs.cache.join("testnested3.fuzzel.ini")).unwrap();
+        assert_eq!(
+            sub_config,
+            format!(
+                "include={}\nsub_key=sub_value\n",
+                dirs.cache.join("testnested2.fuzzel.ini").display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_flatten_phase_comprehensive() {
+        // Test simple menu flattening
+        let simple_resolved = ResolvedMenu {
+            args: vec!["--arg1".to_string()],
+            input: b"Item1\n".to_vec(),
+            items: vec![ResolvedItem::Program(ComputedProgram {
+                command: vec!["cmd1".to_string()],
+                ..Default::default()
+            })],
+            ..Default::default()
+        };
+        let mut simple_items = Vec::new();
+        let simple_flattened = flatten_resolved_menu(simple_resolved, &mut simple_items);
+
+        assert_eq!(simple_flattened.args, vec!["--arg1"]);
+        assert_eq!(simple_flattened.input, b"Item1\n");
+        assert_eq!(simple_flattened.items_offset, 0);
+        assert_eq!(simple_items.len(), 1);
+        if let ComputedItem::Program(ref prog) = simple_items[0] {
+            assert_eq!(prog.command, vec!["cmd1"]);
+        } else {
+            panic!("Expected program item");
+        }
+
+        // Test nested menu flattening with proper adjacency preservation
+        let nested_submenu = ResolvedMenu {
+            args: vec!["--sub-arg".to_string()],
+            input: b"Item2\n".to_vec(),
+            items: vec![ResolvedItem::Program(ComputedProgram {
+                command: vec!["cmd2".to_string()],
+                ..Default::default()
+            })],
+            ..Default::default()
+        };
+        let nested_resolved = ResolvedMenu {
+            args: vec!["--base-arg".to_string()],
+            input: b"Item1\nSubmenu1\n".to_vec(),
+            items: vec![
+                ResolvedItem::Program(ComputedProgram {
+                    command: vec!["cmd1".to_string()],
+                    ..Default::default()
+                }),
+                ResolvedItem::Menu(nested_submenu),
+            ],
+            ..Default::default()
+        };
+
+        let mut nested_items = Vec::new();
+        let nested_flattened = flatten_resolved_menu(nested_resolved, &mut nested_items);
+
+        // Check flattened menu structure
+        assert_eq!(nested_flattened.args, vec!["--base-arg"]);
+        assert_eq!(nested_flattened.input, b"Item1\nSubmenu1\n");
+        assert_eq!(nested_flattened.items_offset, 0);
+
+        // Check flattened items preserve adjacency
+        assert_eq!(nested_items.len(), 3);
+        if let ComputedItem::Program(ref prog) = nested_items[0] {
+            assert_eq!(prog.command, vec!["cmd1"]);
+        } else {
+            panic!("Expected first program item");
+        }
+        if let ComputedItem::Menu(ref menu) = nested_items[1] {
+            assert_eq!(menu.args, vec!["--sub-arg"]);
+            assert_eq!(menu.input, b"Item2\n");
+            assert_eq!(menu.items_offset, 2); // Points to next item
+        } else {
+            panic!("Expected menu item");
+        }
+        if let ComputedItem::Program(ref prog) = nested_items[2] {
+            assert_eq!(prog.command, vec!["cmd2"]);
+        } else {
+            panic!("Expected second program item");
+        }
+
+        // Test escaped input handling
+        let escaped_resolved = ResolvedMenu {
+            args: vec![],
+            input: b"Item1\0icon\x1f/path/icon.png\nItem2\n".to_vec(),
+            items: vec![
+                ResolvedItem::Program(ComputedProgram {
+                    command: vec!["cmd1".to_string()],
+                    ..Default::default()
+                }),
+                ResolvedItem::Program(ComputedProgram {
+                    command: vec!["cmd2".to_string()],
+                    ..Default::default()
+                }),
+            ],
+            ..Default::default()
+        };
+
+        let expected_input_escaped = escaped_resolved.input.escape_ascii().to_string();
+        let mut escaped_items = Vec::new();
+        let escaped_flattened = flatten_resolved_menu(escaped_resolved, &mut escaped_items);
+
+        // Test the escaped input format
+        let actual_input_escaped = escaped_flattened.input.escape_ascii().to_string();
+        assert_eq!(actual_input_escaped, expected_input_escaped);
+        assert_eq!(
+            actual_input_escaped,
+            "Item1\\x00icon\\x1f/path/icon.png\\nItem2\\n"
+        );
+    }
+
+    #[test]
+    fn test_icon_dir_glob_expansion() {
+        let root = PathBuf::from("./target/test-glob");
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in ["Papirus-Dark/48x48", "Papirus/48x48", "Adwaita/48x48"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+
+        let shallow = IconWalk {
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        let icon_dir = |path, walk| IconDir { path, walk };
+        let mut menu = Menu {
+            fuzzel_args: vec![],
+            fuzzel_config: vec![],
+            icon_dirs: vec![
+                icon_dir(root.join("Papirus*/48x48"), shallow),
+                icon_dir(root.join("Adwaita"), IconWalk::default()),
+                icon_dir(root.join("Missing*"), IconWalk::default()),
+            ],
+            items: vec![],
+            ..Default::default()
+        };
+        let mut expansions = Vec::new();
+        expand_icon_dir_globs(&mut menu, &mut expansions);
+
+        // Matches keep the pattern's walk options
+        assert_eq!(
+            menu.icon_dirs,
+            vec![
+                icon_dir(root.join("Papirus/48x48"), shallow),
+                icon_dir(root.join("Papirus-Dark/48x48"), shallow),
+                icon_dir(root.join("Adwaita"), IconWalk::default()),
+            ]
+        );
+        assert_eq!(expansions.len(), 2);
+        assert!(expansions[1].1.is_empty());
+        assert!(icon_dir_globs_unchanged(&expansions));
+
+        // A newly matching directory must invalidate the cached expansion
+        std::fs::create_dir_all(root.join("Papirus-Light/48x48")).unwrap();
+        assert!(!icon_dir_globs_unchanged(&expansions));
+    }
+
+    #[test]
+    fn test_sanitize_entry() {
+        assert_eq!(sanitize_entry("plain"), "plain");
+        assert_eq!(sanitize_entry("a\0b"), "a\u{FFFD}b");
+        assert_eq!(sanitize_entry("a\x1fb"), "a\u{FFFD}b");
+        assert_eq!(sanitize_entry("a\nb\n"), "a\u{2424}b\u{2424}");
+    }
+
+    #[test]
+    fn test_command_choices() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-try");
+        let install = |dir: &str, name: &str| {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            let path = root.join(dir).join(name);
+            std::fs::write(&path, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        install("usr", "alacritty");
+        install("usr", "xclip");
+        std::fs::create_dir_all(root.join("local")).unwrap();
+        let search_path =
+            || Some(std::env::join_paths([root.join("local"), root.join("usr")]).unwrap());
+
+        let config = r#"
+            program "Terminal" {
+                try {
+                    command "foot"
+                    command "alacritty" "-e" "fish"
+                    command "xterm"
+                }
+                on "copy" {
+                    try {
+                        command "wl-copy"
+                    }
+                }
+            }
+            program "Copy" {
+                try {
+                    command "wl-copy"
+                    command "xclip" "-selection" "clipboard"
+                }
+            }
+            menu "Editors" {
+                program "Editor" {
+                    try {
+                        command "helix"
+                    }
+                }
+            }
+        "#;
+        let mut menu = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let mut choices = Vec::new();
+        choose_commands(&mut menu, search_path(), &mut choices).unwrap();
+        let command = |item: &Item| match &item.contents {
+            ItemContents::Program(program) => program.command.clone(),
+            _ => panic!("Expected program item"),
+        };
+        assert_eq!(command(&menu.items[0]), ["alacritty", "-e", "fish"]);
+        assert_eq!(
+            command(&menu.items[1]),
+            ["xclip", "-selection", "clipboard"]
+        );
+        // Nothing was found for the action or the editor, so they're dropped
+        assert!(menu.items[0].actions.is_empty());
+        let ItemContents::Menu(editors) = &menu.items[2].contents else {
+            panic!("Expected menu item");
+        };
+        assert!(editors.items.is_empty());
+        assert_eq!(choices.len(), 4);
+        assert_eq!(
+            choices[0],
+            (
+                vec![
+                    "foot".to_string(),
+                    "alacritty".to_string(),
+                    "xterm".to_string()
+                ],
+                Some(1)
+            )
+        );
+        assert!(command_choices_unchanged(&choices, search_path()));
+
+        // Installing a program that comes first invalidates the cached choice
+        install("local", "foot");
+        assert!(!command_choices_unchanged(&choices, search_path()));
+        let mut choices = Vec::new();
+        let mut menu = parser::parse_config(config, ParseOptions::default()).unwrap();
+        choose_commands(&mut menu, search_path(), &mut choices).unwrap();
+        assert_eq!(command(&menu.items[0]), ["foot"]);
+        assert!(command_choices_unchanged(&choices, search_path()));
+        // So does a different PATH
+        let usr_only = Some(root.join("usr").into_os_string());
+        assert!(!command_choices_unchanged(&choices, usr_only.clone()));
+
+        let required = r#"
+            program "Editor" {
+                try required=#true {
+                    command "helix"
+                    command "kak"
+                }
+            }
+        "#;
+        let mut menu = parser::parse_config(required, ParseOptions::default()).unwrap();
+        assert_eq!(
+            choose_commands(&mut menu, usr_only, &mut Vec::new())
+                .unwrap_err()
+                .to_string(),
+            "none of the commands for Editor were found in PATH: helix, kak"
+        );
+    }
+
+    #[test]
+    fn test_desktop_entries() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-desktop");
+        let dirs = [root.join("home"), root.join("usr")];
+        for dir in &dirs {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::write(
+            dirs[1].join("firefox.desktop"),
+            "[Desktop Entry]\nName=Firefox\nExec=firefox %u\nIcon=firefox\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dirs[1].join("htop.desktop"),
+            "[Desktop Entry]\nExec=htop\nTerminal=true\nIcon=htop\n",
+        )
+        .unwrap();
+
+        let config = r#"
+            program "Firefox" {
+                desktop "firefox.desktop"
+                on "private" {
+                    desktop "firefox.desktop"
+                    command "firefox" "--private-window"
+                }
+            }
+            program "Htop" icon="utilities-system-monitor" {
+                desktop "htop.desktop"
+            }
+            menu "Browsers" {
+                program "Private" "firefox" "--private-window" {
+                    desktop "firefox.desktop"
+                }
+            }
+        "#;
+        let terminal = ["foot".to_string(), "-e".to_string()];
+        let mut menu = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let mut entries = Vec::new();
+        apply_desktop_entries(&mut menu, &dirs, &terminal, &mut entries).unwrap();
+        let command = |item: &Item| match &item.contents {
+            ItemContents::Program(program) => program.command.clone(),
+            _ => panic!("Expected program item"),
+        };
+        assert_eq!(command(&menu.items[0]), ["firefox"]);
+        assert_eq!(menu.items[0].icon.as_deref(), Some("firefox"));
+        assert_eq!(
+            menu.items[0].actions[0].1.command,
+            ["firefox", "--private-window"]
+        );
+        // Explicit icons and commands win over the entry's
+        assert_eq!(command(&menu.items[1]), ["foot", "-e", "htop"]);
+        assert_eq!(
+            menu.items[1].icon.as_deref(),
+            Some("utilities-system-monitor")
+        );
+        let ItemContents::Menu(browsers) = &menu.items[2].contents else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(command(&browsers.items[0]), ["firefox", "--private-window"]);
+        assert_eq!(browsers.items[0].icon.as_deref(), Some("firefox"));
+        assert_eq!(entries.len(), 4);
+        assert_eq!(
+            entries[0].1,
+            dirs[1].join("firefox.desktop").display().to_string()
+        );
+        assert!(desktop_entries_unchanged(&entries, &dirs));
+
+        // Editing the file or overriding it in an earlier dir invalidates the cache
+        let file = File::options()
+            .write(true)
+            .open(dirs[1].join("firefox.desktop"))
+            .unwrap();
+        file.set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        assert!(!desktop_entries_unchanged(&entries, &dirs));
+        let mut entries = Vec::new();
+        let mut menu = parser::parse_config(config, ParseOptions::default()).unwrap();
+        apply_desktop_entries(&mut menu, &dirs, &terminal, &mut entries).unwrap();
+        assert!(desktop_entries_unchanged(&entries, &dirs));
+        std::fs::copy(dirs[1].join("htop.desktop"), dirs[0].join("htop.desktop")).unwrap();
+        assert!(!desktop_entries_unchanged(&entries, &dirs));
+
+        let missing = r#"program "Mpv" { desktop "mpv.desktop"; }"#;
+        let mut menu = parser::parse_config(missing, ParseOptions::default()).unwrap();
+        let error =
+            apply_desktop_entries(&mut menu, &dirs, &terminal, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the desktop entry mpv.desktop for Mpv was not found"
+        );
+        assert_eq!(
+            error.help().unwrap().to_string(),
+            format!("searched {}, {}", dirs[0].display(), dirs[1].display())
+        );
+
+        std::fs::write(dirs[0].join("broken.desktop"), "[Desktop Entry]\nName=x\n").unwrap();
+        let broken = r#"program "Broken" { desktop "broken.desktop"; }"#;
+        let mut menu = parser::parse_config(broken, ParseOptions::default()).unwrap();
+        assert!(apply_desktop_entries(&mut menu, &dirs, &terminal, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_fuzzel_args_inheritance() {
+        let (_temp, dirs) = test_dirs();
+        let strings = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        // Later options replace earlier ones, flags without values included
+        assert_eq!(
+            merge_fuzzel_args(
+                &strings(&["--lines", "10", "--minimal-lines", "--width=30"]),
+                &strings(&["--lines", "20", "--width", "40", "--minimal-lines"]),
+            ),
+            strings(&["--lines", "20", "--width", "40", "--minimal-lines"])
+        );
+        // Values that start with a dash stay with their option
+        assert_eq!(
+            merge_fuzzel_args(
+                &strings(&["--prompt", "-> ", "--x-margin", "-5", "-l3"]),
+                &strings(&["--x-margin", "-10", "--cache", "--"]),
+            ),
+            strings(&[
+                "--prompt",
+                "-> ",
+                "-l3",
+                "--x-margin",
+                "-10",
+                "--cache",
+                "--"
+            ])
+        );
+        // Short and long spellings are the same option
+        assert_eq!(
+            merge_fuzzel_args(
+                &strings(&["-l3", "-w", "30", "--prompt", "-> "]),
+                &strings(&["--lines=5", "--width", "40"]),
+            ),
+            strings(&["--prompt", "-> ", "--lines=5", "--width", "40"])
+        );
+        assert_eq!(
+            merge_fuzzel_args(
+                &strings(&["--lines", "5", "-p>"]),
+                &strings(&["-l3", "-p", "$"])
+            ),
+            strings(&["-l3", "-p", "$"])
+        );
+        assert_eq!(
+            without_managed_fuzzel_args(strings(&["--cache", "-", "--prompt", "-> "])),
+            strings(&["--prompt", "-> "])
+        );
+
+        let menu = Menu {
+            fuzzel_args: strings(&["--lines", "10", "--hide-prompt"]),
+            items: vec![
+                Item {
+                    name: "Replaces".to_string(),
+                    id: None,
+                    icon: None,
+                    icon_dirs: Vec::new(),
+                    description: None,
+                    profiles: Vec::new(),
+                    if_host: Vec::new(),
+                    unless_host: Vec::new(),
+                    actions: Vec::new(),
+                    weight: 0,
+                    label_command: None,
+                    contents: ItemContents::Menu(Box::new(Menu {
+                        fuzzel_args: strings(&["--lines", "5"]),
+                        ..Default::default()
+                    })),
+                },
+                Item {
+                    name: "Extends parent".to_string(),
+                    id: None,
+                    icon: None,
+                    icon_dirs: Vec::new(),
+                    description: None,
+                    profiles: Vec::new(),
+                    if_host: Vec::new(),
+                    unless_host: Vec::new(),
+                    actions: Vec::new(),
+                    weight: 0,
+                    label_command: None,
+                    contents: ItemContents::Menu(Box::new(Menu {
+                        extra_fuzzel_args: strings(&["-l20"]),
+                        ..Default::default()
+                    })),
+                },
+                Item {
+                    name: "Extends own".to_string(),
+                    id: None,
+                    icon: None,
+                    icon_dirs: Vec::new(),
+                    description: None,
+                    profiles: Vec::new(),
+                    if_host: Vec::new(),
+                    unless_host: Vec::new(),
+                    actions: Vec::new(),
+                    weight: 0,
+                    label_command: None,
+                    contents: ItemContents::Menu(Box::new(Menu {
+                        fuzzel_args: strings(&["--lines", "5", "-w", "10"]),
+                        extra_fuzzel_args: strings(&["--width=50"]),
+                        ..Default::default()
+                    })),
+                },
+                Item {
+                    name: "Opts out".to_string(),
+                    id: None,
+                    icon: None,
+                    icon_dirs: Vec::new(),
+                    description: None,
+                    profiles: Vec::new(),
+                    if_host: Vec::new(),
+                    unless_host: Vec::new(),
+                    actions: Vec::new(),
+                    weight: 0,
+                    label_command: None,
+                    contents: ItemContents::Menu(Box::new(Menu {
+                        fuzzel_args: strings(&["--lines", "5"]),
+                        inherit_fuzzel_args: false,
+                        ..Default::default()
+                    })),
+                },
+                Item {
+                    name: "Managed args".to_string(),
+                    id: None,
+                    icon: None,
+                    icon_dirs: Vec::new(),
+                    description: None,
+                    profiles: Vec::new(),
+                    if_host: Vec::new(),
+                    unless_host: Vec::new(),
+                    actions: Vec::new(),
+                    weight: 0,
+                    label_command: None,
+                    contents: ItemContents::Menu(Box::new(Menu {
+                        fuzzel_args: strings(&["--cache", "/tmp/foo", "--config=/tmp/bar"]),
+                        ..Default::default()
+                    })),
+                },
+            ],
+            ..Default::default()
+        };
+        let inheritance_stack = vec![InheritanceFrame::default()];
+        let resolved = build_resolved_menu(
+            &menu,
+            &inheritance_stack,
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "",
+        );
+
+        let child_args = |index: usize| {
+            let ResolvedItem::Menu(ref child) = resolved.items[index] else {
+                panic!("Expected menu item");
+            };
+            let cache_index = child.args.iter().position(|arg| arg == "--cache").unwrap();
+            child.args[..cache_index].to_vec()
+        };
+        assert_eq!(child_args(0), strings(&["--hide-prompt", "--lines", "5"]));
+        assert_eq!(child_args(1), strings(&["--hide-prompt", "-l20"]));
+        assert_eq!(
+            child_args(2),
+            strings(&["--hide-prompt", "--lines", "5", "--width=50"])
+        );
+        assert_eq!(child_args(3), strings(&["--lines", "5"]));
+        assert_eq!(child_args(4), strings(&["--lines", "10", "--hide-prompt"]));
+    }
+
+    #[test]
+    fn test_fuzzel_config_opt_out() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            fuzzel-config { font "serif"; }
+            menu "Opts out" {
+                inherit-fuzzel-config #false
+                menu "Nested" {
+                    fuzzel-config { width "30"; }
+                    program "Foo" "foo"
+                }
+            }
+            menu "Own config" {
+                inherit-fuzzel-config #false
+                fuzzel-config { width "40"; }
+                program "Bar" "bar"
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let mut fuzzel_inis = Vec::new();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut fuzzel_inis,
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testconfigoptout",
+        );
+        for ini in &fuzzel_inis {
+            create_fuzzel_config(ini, &dirs, "testconfigoptout").unwrap();
+        }
+        let ini = |id| make_fuzzel_config_path(&dirs, id, "testconfigoptout");
+        let child = |menu: &ResolvedMenu, index: usize| match &menu.items[index] {
+            ResolvedItem::Menu(child) => child.args.clone(),
+            _ => panic!("Expected menu item"),
+        };
+        assert!(
+            resolved
+                .args
+                .contains(&fuzzel_config_name(0, "testconfigoptout"))
+        );
+
+        // Neither the menu nor its children get the parent's ini
+        assert!(!child(&resolved, 0).contains(&"--config".to_string()));
+        let ResolvedItem::Menu(ref opts_out) = resolved.items[0] else {
+            panic!("Expected menu item");
+        };
+        assert!(child(opts_out, 0).contains(&fuzzel_config_name(2, "testconfigoptout")));
+        assert_eq!(
+            std::fs::read_to_string(ini(2)).unwrap(),
+            format!("include={}\nwidth=30\n", dirs.fuzzel_config.display())
+        );
+        assert_eq!(
+            std::fs::read_to_string(ini(3)).unwrap(),
+            format!("include={}\nwidth=40\n", dirs.fuzzel_config.display())
+        );
+    }
+
+    #[test]
+    fn test_icon_dir_opt_out() {
+        let frame = |dir: &str, inherit_icon_dirs| InheritanceFrame {
+            icon_dirs: vec![IconDir {
+                path: PathBuf::from(dir),
+                walk: IconWalk::default(),
+            }],
+            inherit_icon_dirs,
+            ..InheritanceFrame::default()
+        };
+        let stack = [
+            frame("/xdg", IconDirInheritance::All),
+            frame("/root", IconDirInheritance::All),
+            frame("/parent", IconDirInheritance::All),
+        ];
+        let dirs = |inheritance, stack: &[InheritanceFrame]| {
+            inherited_icon_dirs(inheritance, stack)
+                .into_iter()
+                .flatten()
+                .map(|dir| dir.path.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            dirs(IconDirInheritance::All, &stack),
+            ["/parent", "/root", "/xdg"]
+        );
+        assert_eq!(dirs(IconDirInheritance::Xdg, &stack), ["/xdg"]);
+        assert!(dirs(IconDirInheritance::None, &stack).is_empty());
+
+        // Children of a menu that opted out don't see past it either
+        let stack = [
+            frame("/xdg", IconDirInheritance::All),
+            frame("/root", IconDirInheritance::All),
+            frame("/parent", IconDirInheritance::Xdg),
+        ];
+        assert_eq!(dirs(IconDirInheritance::All, &stack), ["/parent", "/xdg"]);
+        let stack = [
+            frame("/xdg", IconDirInheritance::All),
+            frame("/parent", IconDirInheritance::None),
+        ];
+        assert_eq!(dirs(IconDirInheritance::All, &stack), ["/parent"]);
+
+        let parsed = parser::parse_config(
+            r#"
+                menu "A" { inherit-icon-dirs "xdg"; program "Foo" "foo"; }
+                menu "B" { inherit-icon-dirs #false; program "Bar" "bar"; }
+            "#,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let inheritance = |index: usize| match &parsed.items[index].contents {
+            ItemContents::Menu(menu) => menu.inherit_icon_dirs,
+            _ => panic!("Expected menu item"),
+        };
+        assert_eq!(inheritance(0), IconDirInheritance::Xdg);
+        assert_eq!(inheritance(1), IconDirInheritance::None);
+        assert!(
+            parser::parse_config(
+                r#"menu "A" { inherit-icon-dirs "some"; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_icon_size() {
+        let (temp, dirs) = test_dirs();
+        let root = temp.path().join("test-icon-size");
+        let icons = root.join("icons");
+        for size in ["16x16", "32x32", "48x48", "256x256"] {
+            let dir = icons.join("hicolor").join(size).join("apps");
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("app.png"), "").unwrap();
+        }
+        let search = |size| {
+            IconFinder::new(IconCache::default())
+                .find(
+                    "App",
+                    "app",
+                    &[&[IconDir {
+                        path: icons.clone(),
+                        walk: IconWalk::default(),
+                    }]],
+                    &icons::Lookup {
+                        size,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+                .strip_prefix(&icons)
+                .unwrap()
+                .display()
+                .to_string()
+        };
+        assert_eq!(search(Some(48)), "hicolor/48x48/apps/app.png");
+        assert_eq!(search(Some(20)), "hicolor/16x16/apps/app.png");
+        // Ties go to the larger one
+        assert_eq!(search(Some(40)), "hicolor/48x48/apps/app.png");
+        assert_eq!(search(None), "hicolor/256x256/apps/app.png");
+
+        // Icons of unknown size only win when there's nothing else
+        std::fs::write(icons.join("app.png"), "").unwrap();
+        assert_eq!(search(Some(48)), "hicolor/48x48/apps/app.png");
+        let scalable = icons.join("hicolor/scalable/apps");
+        std::fs::create_dir_all(&scalable).unwrap();
+        std::fs::write(scalable.join("app.svg"), "").unwrap();
+        assert_eq!(search(Some(48)), "hicolor/scalable/apps/app.svg");
+
+        // Set on a menu, it's inherited by submenus, like prefer-icon-format
+        let config = format!(
+            r#"
+                icon-dir "{}"
+                program "Top" "top" icon="app"
+                menu "Small" {{
+                    icon-size 16
+                    prefer-icon-format "png"
+                    menu "Nested" {{ program "A" "a" icon="app"; }}
+                }}
+            "#,
+            icons.display()
+        );
+        let parsed = parser::parse_config(&config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testiconsize",
+        );
+        let icon_of = |menu: &ResolvedMenu| {
+            let input = String::from_utf8(menu.input.clone()).unwrap();
+            let first = input.lines().next().unwrap();
+            first.split('\x1f').nth(1).unwrap().to_owned()
+        };
+        assert!(icon_of(&resolved).ends_with("scalable/apps/app.svg"));
+        let ResolvedItem::Menu(small) = &resolved.items[1] else {
+            panic!("Expected menu item");
+        };
+        let ResolvedItem::Menu(nested) = &small.items[0] else {
+            panic!("Expected menu item");
+        };
+        assert!(icon_of(nested).ends_with("16x16/apps/app.png"));
+
+        // icon-match is inherited the same way
+        std::fs::write(icons.join("Upper.png"), "").unwrap();
+        let config = format!(
+            r#"
+                icon-dir "{}"
+                defaults {{ icon-match "exact"; }}
+                program "Exact" "a" icon="upper"
+                menu "Loose" {{
+                    icon-match "ignore-case"
+                    menu "Nested" {{ program "A" "a" icon="upper"; }}
+                }}
+            "#,
+            root.display()
+        );
+        let parsed = parser::parse_config(&config, ParseOptions::default()).unwrap();
+        let mut id_gen = IdGenerator::new();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new())],
+            &mut id_gen,
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testiconsize",
+        );
+        assert_eq!(icon_of(&resolved), "upper");
+        let ResolvedItem::Menu(nested) = &resolved.items[1] else {
+            panic!("Expected menu item");
+        };
+        let ResolvedItem::Menu(nested) = &nested.items[0] else {
+            panic!("Expected menu item");
+        };
+        assert!(icon_of(nested).ends_with("icons/Upper.png"));
+        assert!(parser::parse_config(r#"icon-match "fuzzy""#, ParseOptions::default()).is_err());
+
+        // And so is symbolic
+        std::fs::write(icons.join("dark.png"), "").unwrap();
+        std::fs::write(icons.join("dark-symbolic.svg"), "").unwrap();
+        let config = format!(
+            r#"
+                icon-dir "{}"
+                defaults {{ symbolic "prefer"; }}
+                program "Prefer" "a" icon="dark"
+                menu "Avoid" {{
+                    symbolic "avoid"
+                    menu "Nested" {{ program "A" "a" icon="dark-symbolic"; }}
+                }}
+            "#,
+            root.display()
+        );
+        let parsed = parser::parse_config(&config, ParseOptions::default()).unwrap();
+        let mut id_gen = IdGenerator::new();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new())],
+            &mut id_gen,
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testiconsize",
+        );
+        assert!(icon_of(&resolved).ends_with("icons/dark-symbolic.svg"));
+        let ResolvedItem::Menu(nested) = &resolved.items[1] else {
+            panic!("Expected menu item");
+        };
+        let ResolvedItem::Menu(nested) = &nested.items[0] else {
+            panic!("Expected menu item");
+        };
+        assert!(icon_of(nested).ends_with("icons/dark.png"));
+        assert!(parser::parse_config(r#"symbolic "never""#, ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_icon_dir_precedence() {
+        let (temp, dirs) = test_dirs();
+        let root = temp.path().join("test-icon-dir-precedence");
+        // Each icon is in several tiers, the later ones with a better file
+        for path in [
+            "item/sub.png",
+            "item2/sub.svg",
+            "own/sub.svg",
+            "own/own.png",
+            "own2/own.svg",
+            "parent/own.svg",
+            "xdg/own.svg",
+            "parent/parent.png",
+            "xdg/parent.svg",
+            "xdg/xdg.png",
+            "game/own.png",
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let config = format!(
+            r#"
+                icon-dir "{root}/parent"
+                menu "Menu" {{
+                    icon-dir "{root}/own"
+                    icon-dir "{root}/own2"
+                    program "Own" "a" icon="own"
+                    program "Parent" "a" icon="parent"
+                    program "Xdg" "a" icon="xdg"
+                    program "Game" "a" id="game" {{
+                        icon "own"
+                        icon-dir "{root}/game"
+                    }}
+                    alias "Alias" of="game"
+                    menu "Sub" {{
+                        icon "sub"
+                        icon-dir "{root}/item"
+                        icon-dir "{root}/item2"
+                        program "A" "a"
+                    }}
+                }}
+            "#,
+            root = root.display()
+        );
+        let mut parsed = parser::parse_config(&config, ParseOptions::default()).unwrap();
+        resolve_aliases(&mut parsed);
+        let xdg = InheritanceFrame {
+            icon_dirs: vec![IconDir {
+                path: root.join("xdg"),
+                walk: IconWalk::default(),
+            }],
+            ..InheritanceFrame::default()
+        };
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[xdg],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testicondirprecedence",
+        );
+        let ResolvedItem::Menu(menu) = &resolved.items[0] else {
+            panic!("Expected menu item");
+        };
+        let input = String::from_utf8(menu.input.clone()).unwrap();
+        let icons: Vec<&str> = input
+            .lines()
+            .map(|line| {
+                let path = line.split('\x1f').nth(1).unwrap();
+                Path::new(path)
+                    .strip_prefix(&root)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            icons,
+            [
+                "own/own.png",
+                "parent/parent.png",
+                "xdg/xdg.png",
+                "game/own.png",
+                "game/own.png",
+                "item/sub.png"
+            ]
+        );
+        // Only programs have their own icon-dirs
+        let error = |config: &str| {
+            parser::parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"open "A" "/a" { icon-dir "/icons"; }"#),
+            "unexpected node in open: icon-dir"
+        );
+        assert_eq!(
+            error(r#"program "A" "a" { on "alt" { command "b"; icon-dir "/icons"; }; }"#),
+            "actions don't have icons to find"
+        );
+    }
+
+    #[test]
+    fn test_missing_icons() {
+        let (temp, dirs) = test_dirs();
+        let root = temp.path().join("test-missing-icons");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("uff-found.svg"), "").unwrap();
+        std::fs::write(root.join("uff-missin.svg"), "").unwrap();
+        let config = format!(
+            r#"
+                icon-dir "{root}"
+                program "Found" "a" icon="uff-found"
+                menu "Tools" id="tools" {{
+                    program "Missing" "a" icon="uff-missing"
+                }}
+            "#,
+            root = root.display()
+        );
+        let computed = compute_config(
+            &config,
+            &[0; 32],
+            &dirs,
+            "testmissingicons",
+            None,
+            ParseOptions::default(),
+            &GlobalConfig::default(),
+            Path::new("testmissingicons.kdl"),
+            IconCache::default(),
+        )
+        .unwrap();
+        // Menus are named by id like in menu_path
+        let [missing] = computed.missing_icons.as_slice() else {
+            panic!("Expected one missing icon: {:?}", computed.missing_icons);
+        };
+        assert_eq!(missing.item, "tools/Missing");
+        assert_eq!(missing.name, "uff-missing");
+        assert_eq!(missing.dirs[0], root.display().to_string());
+        assert_eq!(missing.similar[0], "uff-missin");
+    }
+
+    #[test]
+    fn test_check_icons() {
+        let (temp, dirs) = test_dirs();
+        let preset = temp.path().join("testcheckicons.kdl");
+        std::fs::write(
+            &preset,
+            r#"
+                fuzzel-config { font "serif"; }
+                program "Missing" "a" icon="/uff-no-such-icon.png"
+                script "Hello" { body "echo hello"; }
+            "#,
+        )
+        .unwrap();
+        let missing = check_icons(&ConfigBuild {
+            dirs: &dirs,
+            path: &preset,
+            profile: None,
+            options: ParseOptions::default(),
+            global: &GlobalConfig::default(),
+            global_source: "",
+        })
+        .unwrap();
+        assert_eq!(missing.len(), 1);
+        // Only looks, so neither the inis nor the scripts are written
+        assert!(!dirs.cache.exists());
+        assert!(!dirs.state.exists());
+    }
+
+    #[test]
+    fn test_icon_paths() {
+        let (temp, dirs) = test_dirs();
+        let root = temp.path().join("test-icon-paths");
+        std::fs::create_dir_all(root.join("icons")).unwrap();
+        std::fs::write(root.join("icons/app.png"), "").unwrap();
+        let config = format!(
+            r#"
+                program "Absolute" "a" icon="{root}/icons/app.png"
+                program "Relative" "a" icon="icons/app.png"
+                program "Dot" "a" icon="./icons/app.png"
+                program "Env" "a" icon="$HOME/uff-no-such-icon.png"
+                menu "Sub" id="sub" {{
+                    program "Tilde" "a" icon="~/uff-no-such-icon.png"
+                    program "Gone" "a" icon="icons/gone.png"
+                }}
+            "#,
+            root = root.display()
+        );
+        let compute = |strict| {
+            compute_config(
+                &config,
+                &[0; 32],
+                &dirs,
+                "testiconpaths",
+                None,
+                ParseOptions {
+                    strict,
+                    ..Default::default()
+                },
+                &GlobalConfig::default(),
+                &root.join("testiconpaths.kdl"),
+                IconCache::default(),
+            )
+        };
+        let computed = compute(false).unwrap();
+        let input = String::from_utf8(computed.initial_menu.input.clone()).unwrap();
+        let icons: Vec<&str> = input
+            .lines()
+            .filter_map(|line| line.split('\x1f').nth(1))
+            .collect();
+        let app = root.join("icons/app.png").display().to_string();
+        let missing = format!("{}/uff-no-such-icon.png", home().unwrap());
+        assert_eq!(icons, [&app, &app, &app, &missing]);
+        let missing_icons: Vec<(&str, &str)> = computed
+            .missing_icons
+            .iter()
+            .map(|icon| (icon.item.as_str(), icon.name.as_str()))
+            .collect();
+        let gone = root.join("icons/gone.png").display().to_string();
+        assert_eq!(
+            missing_icons,
+            [
+                ("Env", missing.as_str()),
+                ("sub/Tilde", missing.as_str()),
+                ("sub/Gone", gone.as_str())
+            ]
+        );
+
+        let error = compute(true).unwrap_err().to_string();
+        assert_eq!(error, format!("the icon of Env doesn't exist: {missing}"));
+    }
+
+    #[test]
+    fn test_icon_dir_walk() {
+        let (temp, dirs) = test_dirs();
+        let root = temp.path().join("test-icon-dir-walk");
+        for dir in ["shallow", "deep"] {
+            let nested = root.join(dir).join("a/b");
+            std::fs::create_dir_all(&nested).unwrap();
+            std::fs::write(nested.join(format!("{dir}.svg")), "").unwrap();
+        }
+        let config = format!(
+            r#"
+                defaults {{ icon-dir-walk max-depth=2; }}
+                icon-dir "{root}/shallow"
+                icon-dir "{root}/deep" max-depth=3 include-hidden=#true
+                program "Shallow" "a" icon="shallow"
+                program "Deep" "b" icon="deep"
+            "#,
+            root = root.display()
+        );
+        let mut parsed = parser::parse_config(&config, ParseOptions::default()).unwrap();
+        assert_eq!(
+            parsed.icon_dirs[1].walk,
+            IconWalk {
+                max_depth: Some(3),
+                follow_symlinks: None,
+                include_hidden: Some(true),
+            }
+        );
+
+        // The preset's defaults win over the global config's, one option at a time
+        let global = GlobalConfig {
+            defaults: Defaults {
+                icon_dir_walk: IconWalk {
+                    max_depth: Some(5),
+                    follow_symlinks: Some(true),
+                    include_hidden: None,
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        apply_global_config(&mut parsed, &global);
+        assert_eq!(
+            parsed.defaults.icon_dir_walk,
+            IconWalk {
+                max_depth: Some(2),
+                follow_symlinks: Some(true),
+                include_hidden: None,
+            }
+        );
+
+        let mut id_gen = IdGenerator::new();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new())],
+            &mut id_gen,
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testicondirwalk",
+        );
+        let input = String::from_utf8(resolved.input).unwrap();
+        let lines: Vec<&str> = input.lines().collect();
+        assert_eq!(lines[0], "Shallow\0icon\x1fshallow");
+        assert!(lines[1].ends_with("deep/a/b/deep.svg"));
+
+        assert!(parser::parse_config(r#"icon-dir "/a" depth=2"#, ParseOptions::default()).is_err());
+        assert!(
+            parser::parse_config(
+                r#"defaults { icon-dir-walk max-depth="2"; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_confirmation_menu() {
+        let (_temp, dirs) = test_dirs();
+        let menu = Menu {
+            fuzzel_args: vec!["--prompt".to_string(), "> ".to_string()],
+            items: vec![Item {
+                name: "Poweroff".to_string(),
+                id: None,
+                icon: None,
+                icon_dirs: Vec::new(),
+                description: None,
+                profiles: Vec::new(),
+                if_host: Vec::new(),
+                unless_host: Vec::new(),
+                actions: Vec::new(),
+                weight: 0,
+                label_command: None,
+                contents: ItemContents::Program(Box::new(Program {
+                    command: vec!["systemctl".to_string(), "poweroff".to_string()],
+                    confirm: Some("Really power off?".to_string()),
+                    ..Default::default()
+                })),
+            }],
+            ..Default::default()
+        };
+        let inheritance_stack = vec![InheritanceFrame::default()];
+        let resolved = build_resolved_menu(
+            &menu,
+            &inheritance_stack,
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testconfirm",
+        );
+        assert_eq!(resolved.input, b"Poweroff\n");
+
+        let mut items = Vec::new();
+        let initial_menu = flatten_resolved_menu(resolved, &mut items);
+        assert!(!initial_menu.back_on_cancel);
+
+        let ComputedItem::Menu(ref confirmation) = items[0] else {
+            panic!("Expected confirmation menu");
+        };
+        assert!(confirmation.back_on_cancel);
+        assert_eq!(confirmation.input, b"Yes\nNo\n");
+        assert_eq!(
+            confirmation.args,
+            vec!["--prompt", "Really power off? ", "--cache", "/dev/null"]
+        );
+        assert!(matches!(
+            items[confirmation.items_offset],
+            ComputedItem::Program(ref program) if program.command == ["systemctl", "poweroff"]
+        ));
+        assert!(matches!(
+            items[confirmation.items_offset + 1],
+            ComputedItem::Back
+        ));
+    }
+
+    #[test]
+    fn test_fuzzel_config_repeated_keys() {
+        let (_temp, dirs) = test_dirs();
+        let pairs = [
+            ("font", "monospace"),
+            ("colors.background", "000000ff"),
+            ("font", "sans"),
+            ("key-bindings.cancel", "Escape"),
+            ("colors.background", "111111ff"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .to_vec();
+
+        let ini = FuzzelIni {
+            id: 0,
+            inherit_id: None,
+            pairs,
+        };
+        let path = create_fuzzel_config(&ini, &dirs, "testrepeated").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            format!(
+                "include={}\n\
+                 font=monospace\n\
+                 font=sans\n\
+                 [colors]\n\
+                 background=000000ff\n\
+                 background=111111ff\n\
+                 [key-bindings]\n\
+                 cancel=Escape\n",
+                dirs.fuzzel_config.display()
+            )
+        );
+
+        // A cache dir that can't be written to is an error, not a panic
+        let unwritable = Dirs {
+            cache: PathBuf::from("/dev/null/uff"),
+            ..dirs
+        };
+        assert_eq!(
+            create_fuzzel_config(&ini, &unwritable, "testrepeated")
+                .unwrap_err()
+                .to_string(),
+            "failed to write fuzzel config /dev/null/uff/testrepeated0.fuzzel.ini"
+        );
+    }
+
+    #[test]
+    fn test_sorted_items() {
+        let (_temp, dirs) = test_dirs();
+        let program = |name: &str| Item {
+            name: name.to_string(),
+            id: None,
+            icon: None,
+            icon_dirs: Vec::new(),
+            description: None,
+            profiles: Vec::new(),
+            if_host: Vec::new(),
+            unless_host: Vec::new(),
+            actions: Vec::new(),
+            weight: 0,
+            label_command: None,
+            contents: ItemContents::Program(Box::new(Program {
+                command: vec![name.to_lowercase()],
+                ..Default::default()
+            })),
+        };
+        let mut menu = Menu {
+            items: vec![
+                program("firefox"),
+                program("Alacritty"),
+                program("Zed"),
+                program("FIREFOX"),
+            ],
+            ..Default::default()
+        };
+        let names = |menu: &Menu| -> Vec<String> {
+            sorted_items(menu)
+                .into_iter()
+                .map(|item| item.name.clone())
+                .collect()
+        };
+
+        assert_eq!(names(&menu), ["firefox", "Alacritty", "Zed", "FIREFOX"]);
+
+        // Equal names keep their config order in both directions
+        menu.sort = SortOrder::Alphabetical;
+        assert_eq!(names(&menu), ["Alacritty", "firefox", "FIREFOX", "Zed"]);
+        menu.sort = SortOrder::Reverse;
+        assert_eq!(names(&menu), ["Zed", "firefox", "FIREFOX", "Alacritty"]);
+
+        // The input and the flattened items follow the same order
+        menu.sort = SortOrder::Alphabetical;
+        let resolved = build_resolved_menu(
+            &menu,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testsort",
+        );
+        assert_eq!(resolved.input, b"Alacritty\nfirefox\nFIREFOX\nZed\n");
+        let mut items = Vec::new();
+        flatten_resolved_menu(resolved, &mut items);
+        assert!(matches!(
+            items[0],
+            ComputedItem::Program(ref program) if program.command == ["alacritty"]
+        ));
+
+        // Groups come first, and are sorted on their own
+        let submenu = |name: &str| Item {
+            contents: ItemContents::Menu(Box::new(Menu {
+                items: vec![program(&format!("{name} app"))],
+                ..Default::default()
+            })),
+            ..program(name)
+        };
+        menu.items.insert(1, submenu("Games"));
+        menu.items.push(submenu("Code"));
+        menu.order = ItemOrder::MenusFirst;
+        assert_eq!(
+            names(&menu),
+            ["Code", "Games", "Alacritty", "firefox", "FIREFOX", "Zed"]
+        );
+        menu.order = ItemOrder::ProgramsFirst;
+        assert_eq!(
+            names(&menu),
+            ["Alacritty", "firefox", "FIREFOX", "Zed", "Code", "Games"]
+        );
+        menu.sort = SortOrder::None;
+        assert_eq!(
+            names(&menu),
+            ["firefox", "Alacritty", "Zed", "FIREFOX", "Games", "Code"]
+        );
+
+        // Submenus are flattened after the items, at offsets that still match
+        menu.order = ItemOrder::MenusFirst;
+        let resolved = build_resolved_menu(
+            &menu,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testsort",
+        );
+        assert_eq!(
+            resolved.input,
+            b"Games\nCode\nfirefox\nAlacritty\nZed\nFIREFOX\n"
+        );
+        let mut items = Vec::new();
+        flatten_resolved_menu(resolved, &mut items);
+        let submenu_program = |index: usize| {
+            let ComputedItem::Menu(submenu) = &items[index] else {
+                panic!("Expected menu item");
+            };
+            match &items[submenu.items_offset] {
+                ComputedItem::Program(program) => program.command[0].clone(),
+                _ => panic!("Expected program item"),
+            }
+        };
+        assert_eq!(submenu_program(0), "games app");
+        assert_eq!(submenu_program(1), "code app");
+        assert!(matches!(
+            items[2],
+            ComputedItem::Program(ref program) if program.command == ["firefox"]
+        ));
+    }
+
+    #[test]
+    fn test_use_fuzzel_cache() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            menu "Counted" {
+                program "A" "a"
+            }
+            menu "Monitors" {
+                use-fuzzel-cache #false
+                menu "Inherited" {
+                    program "B" "b"
+                }
+                menu "Re-enabled" {
+                    use-fuzzel-cache #true
+                    program "C" "c"
+                }
+            }
+        "#;
+        let has_cache = |menu: &ResolvedMenu| menu.args.iter().any(|arg| arg == "--cache");
+        fn submenu(menu: &ResolvedMenu, index: usize) -> &ResolvedMenu {
+            let ResolvedItem::Menu(submenu) = &menu.items[index] else {
+                panic!("Expected menu item");
+            };
+            submenu
+        }
+
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let mut id_gen = IdGenerator::new();
+        let root = root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new());
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[root],
+            &mut id_gen,
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testusecache",
+        );
+        assert!(has_cache(&resolved));
+        assert!(has_cache(submenu(&resolved, 0)));
+        let monitors = submenu(&resolved, 1);
+        assert!(!has_cache(monitors));
+        assert!(!has_cache(submenu(monitors, 0)));
+        assert!(has_cache(submenu(monitors, 1)));
+
+        // Off everywhere from the defaults, unless a menu turns it back on
+        let config = format!("defaults {{ use-fuzzel-cache #false; }}\n{config}");
+        let parsed = parser::parse_config(&config, ParseOptions::default()).unwrap();
+        let mut id_gen = IdGenerator::new();
+        let root = root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new());
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[root],
+            &mut id_gen,
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testusecache",
+        );
+        assert!(!has_cache(&resolved));
+        assert!(!has_cache(submenu(&resolved, 0)));
+        assert!(has_cache(submenu(submenu(&resolved, 1), 1)));
+    }
+
+    #[test]
+    fn test_systemd_scope() {
+        let (_temp, dirs) = test_dirs();
+        assert_eq!(unit_slug("Firefox"), "firefox");
+        assert_eq!(unit_slug("  Lock & Suspend!"), "lock-suspend");
+        assert_eq!(unit_slug("Café ☕ Time"), "caf-time");
+        assert_eq!(unit_slug("日本語"), "program");
+        assert_eq!(
+            unit_slug("A very long name that goes on and on"),
+            "a-very-long-name-that-goes-on-an"
+        );
+
+        let config = r#"
+            defaults {
+                systemd-scope #true
+            }
+            menu "Apps" {
+                program "Web Browser" "firefox"
+                program "Terminal" {
+                    command "foot"
+                    systemd-scope #false
+                }
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let mut id_gen = IdGenerator::new();
+        let root = root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new());
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[root],
+            &mut id_gen,
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testsystemdscope",
+        );
+        let ResolvedItem::Menu(apps) = &resolved.items[0] else {
+            panic!("Expected menu item");
+        };
+        let scopes: Vec<_> = apps
+            .items
+            .iter()
+            .map(|item| {
+                let ResolvedItem::Program(program) = item else {
+                    panic!("Expected program item");
+                };
+                program.systemd_scope.as_deref()
+            })
+            .collect();
+        assert_eq!(scopes, [Some("web-browser"), None]);
+    }
+
+    #[test]
+    fn test_launch_method() {
+        let (_temp, dirs) = test_dirs();
+        let global = parser::parse_global_config(
+            r#"defaults { launch-method "auto"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let methods = |config: &str| -> Vec<ComputedLaunchMethod> {
+            let mut parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+            apply_global_config(&mut parsed, &global);
+            let mut id_gen = IdGenerator::new();
+            let root = root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new());
+            let resolved = build_resolved_menu(
+                &parsed,
+                &[root],
+                &mut id_gen,
+                &mut Vec::new(),
+                &mut IconFinder::new(IconCache::default()),
+                &dirs,
+                "testlaunchmethod",
+            );
+            resolved
+                .items
+                .iter()
+                .map(|item| match item {
+                    ResolvedItem::Program(program) => program.launch_method,
+                    _ => panic!("Expected program item"),
+                })
+                .collect()
+        };
+        let programs = r#"
+            program "Firefox" "firefox"
+            program "Foot" { command "foot"; launch-method "direct"; }
+            program "Editor" { command "zed"; launch-method "swaymsg"; }
+            program "Chat" { command "discord"; launch-method "hyprctl"; }
+            open "Home" "~"
+            suspend
+        "#;
+        // Items without options of their own follow the defaults too
+        assert_eq!(
+            methods(programs),
+            [
+                ComputedLaunchMethod::Auto,
+                ComputedLaunchMethod::Direct,
+                ComputedLaunchMethod::Swaymsg,
+                ComputedLaunchMethod::Hyprctl,
+                ComputedLaunchMethod::Auto,
+                ComputedLaunchMethod::Auto,
+            ]
+        );
+        // The preset's defaults win over config.kdl's
+        assert_eq!(
+            methods(&format!(
+                r#"defaults {{ launch-method "swaymsg"; }}; {programs}"#
+            ))[0],
+            ComputedLaunchMethod::Swaymsg
+        );
+
+        let error = parser::parse_config(
+            r#"program "A" { command "a"; launch-method "hyprland"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "unknown value for launch-method");
+
+        let parsed = parser::parse_config(
+            r#"program "A" { command "a"; hypr-rules "workspace 3 silent" "float"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let ItemContents::Program(program) = &parsed.items[0].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(program.hypr_rules, ["workspace 3 silent", "float"]);
+        let error = parser::parse_config(
+            r#"program "A" { command "a"; hypr-rules "[workspace 3]"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid hypr-rules rule: \"[workspace 3]\""
+        );
+    }
+
+    #[test]
+    fn test_check_commands() {
+        let item = |command: &[&str]| Item {
+            name: "Broken".to_string(),
+            id: None,
+            icon: None,
+            icon_dirs: Vec::new(),
+            description: None,
+            profiles: Vec::new(),
+            if_host: Vec::new(),
+            unless_host: Vec::new(),
+            actions: Vec::new(),
+            weight: 0,
+            label_command: None,
+            contents: ItemContents::Program(Box::new(Program {
+                command: command.iter().map(|arg| arg.to_string()).collect(),
+                ..Default::default()
+            })),
+        };
+        let menu = |items| Menu {
+            items,
+            ..Default::default()
+        };
+        assert!(check_commands(&menu(vec![item(&["foo"])])).is_ok());
+
+        let nested = Item {
+            name: "Nested".to_string(),
+            id: None,
+            contents: ItemContents::Menu(Box::new(menu(vec![item(&[])]))),
+            ..item(&["foo"])
+        };
+        assert_eq!(
+            check_commands(&menu(vec![nested])).unwrap_err().to_string(),
+            "Broken has no command to run"
+        );
+        assert!(check_commands(&menu(vec![item(&["", "bar"])])).is_err());
+    }
+
+    #[test]
+    fn test_item_weights() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            sort "alphabetical"
+            program "Misc" "misc" weight=-1
+            program "Browser" "browser"
+            program "Terminal" "terminal" weight=10
+            program "Editor" { command "editor"; weight 10; }
+            program "Apps" "apps"
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let names: Vec<&str> = sorted_items(&parsed)
+            .into_iter()
+            .map(|item| item.name.as_str())
+            .collect();
+        assert_eq!(names, ["Editor", "Terminal", "Apps", "Browser", "Misc"]);
+
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testweight",
+        );
+        assert_eq!(resolved.input, b"Editor\nTerminal\nApps\nBrowser\nMisc\n");
+        let mut items = Vec::new();
+        flatten_resolved_menu(resolved, &mut items);
+        assert!(matches!(
+            items[1],
+            ComputedItem::Program(ref program) if program.command == ["terminal"]
+        ));
+
+        // Without a sort, equal weights keep their config order
+        let config = r#"
+            program "B" "b"
+            program "A" "a" weight=1
+            program "C" "c"
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let names: Vec<&str> = sorted_items(&parsed)
+            .into_iter()
+            .map(|item| item.name.as_str())
+            .collect();
+        assert_eq!(names, ["A", "B", "C"]);
+
+        // Weights only move items within their group, and back items lead
+        let config = r#"
+            order "programs-first"
+            sort "alphabetical"
+            menu "Heavy menu" { weight 5; program "X" "x"; }
+            program "Misc" "misc" weight=-1
+            menu "Apps" { program "Y" "y"; }
+            program "Terminal" "terminal" weight=10
+            back "Back"
+            program "Browser" "browser"
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testweight",
+        );
+        assert_eq!(
+            resolved.input,
+            b"Back\nTerminal\nBrowser\nMisc\nHeavy menu\nApps\n"
+        );
+        let mut items = Vec::new();
+        flatten_resolved_menu(resolved, &mut items);
+        assert!(matches!(items[0], ComputedItem::Back));
+        assert!(matches!(
+            items[3],
+            ComputedItem::Program(ref program) if program.command == ["misc"]
+        ));
+        assert!(matches!(items[4], ComputedItem::Menu(_)));
+    }
+
+    #[test]
+    fn test_show_indices() {
+        let (_temp, dirs) = test_dirs();
+        let mut config = String::from(
+            r#"
+            show-indices #true
+            program "Lock" "lock" icon="/icons/lock.png"
+            "#,
+        );
+        for n in 2..=10 {
+            config.push_str(&format!("program \"P{n}\" \"p{n}\"\n"));
+        }
+        config.push_str(
+            r#"menu "Sub" { show-indices #true separator=" <> "; markup #true; back "Back"; }"#,
+        );
+        let parsed = parser::parse_config(&config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testindices",
+        );
+        assert_eq!(
+            resolved.input,
+            "1. Lock\0icon\x1f/icons/lock.png\n2. P2\n3. P3\n4. P4\n5. P5\n\
+             6. P6\n7. P7\n8. P8\n9. P9\n10. P10\n11. Sub\n"
+                .as_bytes()
+        );
+        let ResolvedItem::Menu(ref submenu) = resolved.items[10] else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(submenu.input, b"1 &lt;&gt; Back\n");
+
+        // fuzzel's index still points at the same items
+        let mut items = Vec::new();
+        let root = flatten_resolved_menu(resolved, &mut items);
+        assert!(matches!(
+            items[root.items_offset],
+            ComputedItem::Program(ref program) if program.command == ["lock"]
+        ));
+        assert!(matches!(
+            items[root.items_offset + 9],
+            ComputedItem::Program(ref program) if program.command == ["p10"]
+        ));
+    }
+
+    #[test]
+    fn test_hooks() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            hooks {
+                pre-launch "pre"
+                post-launch "post"
+            }
+            program "Top" "top"
+            menu "System" {
+                hooks { pre-launch "~/system-pre"; }
+                menu "Power" {
+                    program "Lock" "lock" { confirm; }
+                }
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testhooks",
+        );
+        let mut items = Vec::new();
+        let root = flatten_resolved_menu(resolved, &mut items);
+        assert_eq!(
+            root.hooks,
+            ComputedHooks {
+                pre_launch: vec!["pre".to_string()],
+                post_launch: vec!["post".to_string()],
+                menu_path: String::new(),
+            }
+        );
+        assert_eq!(root.item_name(1), "System");
+
+        let ComputedItem::Menu(system) = &items[root.items_offset + 1] else {
+            panic!("Expected menu item");
+        };
+        let ComputedItem::Menu(power) = &items[system.items_offset] else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(
+            power.hooks,
+            ComputedHooks {
+                pre_launch: vec![expand_tilde("~/system-pre")],
+                post_launch: vec!["post".to_string()],
+                menu_path: "System/Power".to_string(),
+            }
+        );
+        assert_eq!(power.item_name(0), "Lock");
+        assert_eq!(power.item_name(1), "");
+
+        // Hooks and names survive the cache
+        let computed = compute_config(
+            config,
+            &[0; 32],
+            &dirs,
+            "testhooks",
+            None,
+            ParseOptions::default(),
+            &GlobalConfig::default(),
+            Path::new("testhooks.kdl"),
+            IconCache::default(),
+        )
+        .unwrap();
+        let decoded: ComputedConfig = bitcode::decode(&bitcode::encode(&computed)).unwrap();
+        let ComputedItem::Menu(system) = &decoded.items[decoded.initial_menu.items_offset + 1]
+        else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(system.hooks.menu_path, "System");
+        assert_eq!(system.names, ["Power"]);
+    }
+
+    #[test]
+    fn test_menu_env() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            env {
+                MOZ_ENABLE_WAYLAND "1"
+                GDK_BACKEND "wayland"
+            }
+            program "Firefox" "firefox"
+            menu "Games" {
+                env {
+                    GDK_BACKEND "x11"
+                    DXVK_HUD "fps"
+                }
+                binds { "alt+o" "offline"; }
+                program "Steam" "steam" {
+                    env { DXVK_HUD "0"; }
+                    on "offline" {
+                        command "steam" "-offline"
+                    }
+                }
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testmenuenv",
+        );
+        let mut items = Vec::new();
+        let root = flatten_resolved_menu(resolved, &mut items);
+        let env = |item: &ComputedItem| match item {
+            ComputedItem::Program(program) => program.env.clone(),
+            _ => panic!("Expected program item"),
+        };
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            env(&items[root.items_offset]),
+            pairs(&[("MOZ_ENABLE_WAYLAND", "1"), ("GDK_BACKEND", "wayland")])
+        );
+        let ComputedItem::Menu(games) = &items[root.items_offset + 1] else {
+            panic!("Expected menu item");
+        };
+        // Deeper menus override shallower ones, and the program overrides both
+        assert_eq!(
+            env(&items[games.items_offset]),
+            pairs(&[
+                ("MOZ_ENABLE_WAYLAND", "1"),
+                ("GDK_BACKEND", "x11"),
+                ("DXVK_HUD", "0")
+            ])
+        );
+        assert_eq!(
+            games.actions[0].program.env,
+            pairs(&[
+                ("MOZ_ENABLE_WAYLAND", "1"),
+                ("GDK_BACKEND", "x11"),
+                ("DXVK_HUD", "fps")
+            ])
+        );
+
+        let error = parser::parse_config(
+            r#"env { A "1"; A "2"; }; program "A" "a""#,
+            ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "A is defined more than once");
+    }
+
+    #[test]
+    fn test_menu_env_without_programs() {
+        use crate::{dynamic, files, input};
+
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            env { GDK_BACKEND "wayland"; }
+            suspend
+            open "GitHub" "https://github.com"
+            script "VPN" { body "nmcli connection up vpn"; }
+            dynamic-menu "Sessions" on-empty="run" {
+                source-command tmux list-sessions
+                run-command tmux attach -t
+                empty-command foot tmux
+            }
+            pick "Attach" {
+                list-command tmux list-sessions
+                run-command tmux attach -t "{}"
+            }
+            files-menu "Screenshots" "/tmp"
+            recent-files "Recent"
+            mime-open "Report" "/tmp/report.pdf"
+            input "Search" { command xdg-open; }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testmenuenvwithoutprograms",
+        );
+        let mut items = Vec::new();
+        let root = flatten_resolved_menu(resolved, &mut items);
+        let expected = vec![("GDK_BACKEND".to_string(), "wayland".to_string())];
+        let items = &items[root.items_offset..];
+        for item in &items[..3] {
+            let ComputedItem::Program(program) = item else {
+                panic!("Expected program item");
+            };
+            assert_eq!(program.env, expected);
+        }
+
+        // Programs only made once the item is picked get it too
+        let ComputedItem::DynamicMenu(dynamic_menu) = &items[3] else {
+            panic!("Expected dynamic menu item");
+        };
+        assert_eq!(
+            dynamic::program_for_entry(dynamic_menu, "main").env,
+            expected
+        );
+        let dynamic::Outcome::Run(program) = dynamic::outcome(dynamic_menu, &[]) else {
+            panic!("Expected a program");
+        };
+        assert_eq!(program.env, expected);
+        let ComputedItem::Pick(pick) = &items[4] else {
+            panic!("Expected pick item");
+        };
+        assert_eq!(dynamic::program_for_line(pick, "main").env, expected);
+        let ComputedItem::FilesMenu(files_menu) = &items[5] else {
+            panic!("Expected files menu item");
+        };
+        assert_eq!(
+            files::program_for_file(files_menu, Path::new("/tmp/a.png")).env,
+            expected
+        );
+        let ComputedItem::RecentFiles(recent_files) = &items[6] else {
+            panic!("Expected recent files item");
+        };
+        assert_eq!(recent_files.env, expected);
+        let ComputedItem::MimeOpen(mime_open) = &items[7] else {
+            panic!("Expected mime-open item");
+        };
+        assert_eq!(mime_open.env, expected);
+        let ComputedItem::Input(input) = &items[8] else {
+            panic!("Expected input item");
+        };
+        assert_eq!(input::program_for_text(input, "rust").env, expected);
+    }
+
+    #[test]
+    fn test_runtime_program_defaults() {
+        use crate::{dynamic, files};
+
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            defaults {
+                systemd-scope #true
+                launch-method "hyprctl"
+                stdout "null"
+            }
+            pick "Attach" {
+                list-command tmux list-sessions
+                run-command tmux attach -t "{}"
+            }
+            menu "Screenshots" {
+                multi-select "args"
+                run-command swappy -f
+                program "Latest" "latest-screenshot"
+            }
+            files-menu "Recent" "/tmp"
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let mut id_gen = IdGenerator::new();
+        let root = root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new());
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[root],
+            &mut id_gen,
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testruntimedefaults",
+        );
+        let mut items = Vec::new();
+        let root = flatten_resolved_menu(resolved, &mut items);
+        let ComputedItem::Pick(pick) = &items[root.items_offset] else {
+            panic!("Expected pick item");
+        };
+        let program = dynamic::program_for_line(pick, "main");
+        assert_eq!(program.command, ["tmux", "attach", "-t", "main"]);
+        assert_eq!(program.systemd_scope.as_deref(), Some("attach"));
+        assert_eq!(program.launch_method, ComputedLaunchMethod::Hyprctl);
+        assert_eq!(program.stdout, Some(ComputedOutput::Null));
+
+        let ComputedItem::Menu(screenshots) = &items[root.items_offset + 1] else {
+            panic!("Expected menu item");
+        };
+        let Some(ComputedMultiSelect::Args(program)) = &screenshots.multi_select else {
+            panic!("Expected multi-select args");
+        };
+        assert_eq!(program.launch_method, ComputedLaunchMethod::Hyprctl);
+        let ComputedItem::FilesMenu(files_menu) = &items[root.items_offset + 2] else {
+            panic!("Expected files menu item");
+        };
+        let program = files::program_for_file(files_menu, Path::new("/tmp/a.png"));
+        assert_eq!(program.systemd_scope.as_deref(), Some("recent"));
+        assert_eq!(program.stdout, Some(ComputedOutput::Null));
+    }
+
+    #[test]
+    fn test_show_counts() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            show-counts #true
+            menu "Games" {
+                show-counts #true recursive=#false
+                program "Chess" "chess"
+                program "Work Game" "solitaire" { profiles "work"; }
+                menu "Emulators" {
+                    back "Back"
+                    program "A" "a"
+                    program "B" "b" { profiles "home"; }
+                    program "C" "c"
+                }
+            }
+            program "Foot" "foot"
+        "#;
+        let resolved = |profile: Option<&str>| {
+            let mut parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+            if let Some(profile) = profile {
+                filter_by_profile(&mut parsed, profile);
+            }
+            build_resolved_menu(
+                &parsed,
+                &[InheritanceFrame::default()],
+                &mut IdGenerator::new(),
+                &mut Vec::new(),
+                &mut IconFinder::new(IconCache::default()),
+                &dirs,
+                "testcounts",
+            )
+        };
+
+        let all = resolved(None);
+        assert_eq!(all.input, b"Games (5)\nFoot\n");
+        let ResolvedItem::Menu(ref games) = all.items[0] else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(games.input, b"Chess\nWork Game\nEmulators (3)\n");
+
+        let work = resolved(Some("work"));
+        assert_eq!(work.input, b"Games (4)\nFoot\n");
+        let ResolvedItem::Menu(ref games) = work.items[0] else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(games.input, b"Chess\nWork Game\nEmulators (2)\n");
+    }
+
+    #[test]
+    fn test_filter_by_profile() {
+        let config = r#"
+            program "Always" { command "true"; }
+            program "Slack" { command "slack"; profiles "work"; }
+            program "Steam" { command "steam"; profiles "home" "travel"; }
+            menu "Work tools" {
+                profiles "work"
+                program "VPN" { command "vpn"; }
+            }
+            menu "Games" {
+                back "Back"
+                program "Steam" { command "steam"; profiles "home"; }
+            }
+        "#;
+        let names = |menu: &Menu| -> Vec<String> {
+            menu.items.iter().map(|item| item.name.clone()).collect()
+        };
+
+        let mut work = parser::parse_config(config, ParseOptions::default()).unwrap();
+        assert!(profile_is_used(&work, "work"));
+        filter_by_profile(&mut work, "work");
+        // Games only has a back item left, so it's dropped
+        assert_eq!(names(&work), ["Always", "Slack", "Work tools"]);
+
+        let mut home = parser::parse_config(config, ParseOptions::default()).unwrap();
+        assert!(profile_is_used(&home, "home"));
+        filter_by_profile(&mut home, "home");
+        assert_eq!(names(&home), ["Always", "Steam", "Games"]);
+
+        let mut unknown = parser::parse_config(config, ParseOptions::default()).unwrap();
+        assert!(!profile_is_used(&unknown, "school"));
+        filter_by_profile(&mut unknown, "school");
+        assert_eq!(names(&unknown), ["Always"]);
+    }
+
+    #[test]
+    fn test_filter_by_host() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            program "Always" "true"
+            program "Brightness" "brightnessctl" { if-host "thinkpad" "*-laptop"; }
+            program "Backup" "borg" { unless-host "thinkpad"; }
+            menu "Laptop" {
+                if-host "thinkpad"
+                unless-host "think*"
+                program "Never" "never"
+            }
+            menu "Servers" {
+                back "Back"
+                program "SSH" "ssh" { if-host "desktop"; }
+            }
+        "#;
+        let names = |hostname: &str| -> Vec<String> {
+            let mut menu = parser::parse_config(config, ParseOptions::default()).unwrap();
+            assert!(uses_host_conditions(&menu));
+            filter_by_host(&mut menu, hostname);
+            menu.items.iter().map(|item| item.name.clone()).collect()
+        };
+        // Servers only has a back item left, and unless-host wins over if-host
+        assert_eq!(names("thinkpad"), ["Always", "Brightness"]);
+        assert_eq!(names("work-laptop"), ["Always", "Brightness", "Backup"]);
+        assert_eq!(names("desktop"), ["Always", "Backup", "Servers"]);
+
+        let computed = |config: &str| {
+            compute_config(
+                config,
+                &[0; 32],
+                &dirs,
+                "testhost",
+                None,
+                ParseOptions::default(),
+                &GlobalConfig::default(),
+                Path::new("test.kdl"),
+                IconCache::default(),
+            )
+            .unwrap()
+        };
+        // Only configs with host conditions depend on the machine
+        assert_eq!(computed(r#"program "A" "a""#).hostname, None);
+        assert_eq!(
+            computed(r#"program "A" "a" { unless-host "nowhere"; }"#).hostname,
+            Some(hostname().unwrap_or_default())
+        );
+    }
+
+    #[test]
+    fn test_detected_icon_theme() {
+        let (_temp, dirs) = test_dirs();
+        let computed = |config: &str| {
+            compute_config(
+                config,
+                &[0; 32],
+                &dirs,
+                "testdetectedicontheme",
+                None,
+                ParseOptions::default(),
+                &GlobalConfig::default(),
+                Path::new("test.kdl"),
+                IconCache::default(),
+            )
+            .unwrap()
+        };
+        // Only configs without an icon-theme depend on the desktop's
+        assert_eq!(
+            computed(r#"program "A" "a""#).detected_icon_theme,
+            Some(icons::detect_theme(&default_config_dir().unwrap()))
+        );
+        let config = r#"
+            defaults { fuzzel-config { icon-theme "Papirus"; }; }
+            program "A" "a"
+        "#;
+        assert_eq!(computed(config).detected_icon_theme, None);
+    }
+
+    #[test]
+    fn test_stay_open() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            menu "Volume" {
+                stay-open #true
+                program "+5%" { command "wpctl" "set-volume" "@DEFAULT_SINK@" "5%+"; }
+                program "Mute" { command "wpctl" "set-mute" "@DEFAULT_SINK@" "1"; confirm; }
+                menu "Devices" {
+                    program "Headphones" { command "switch" "headphones"; }
+                }
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        assert!(!parsed.stay_open);
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "teststayopen",
+        );
+        let mut items = Vec::new();
+        let initial_menu = flatten_resolved_menu(resolved, &mut items);
+        assert!(!initial_menu.stay_open);
+
+        let ComputedItem::Menu(ref volume) = items[0] else {
+            panic!("Expected volume menu");
+        };
+        assert!(volume.stay_open);
+
+        // Neither the confirmation nor the nested submenu stay open themselves
+        let ComputedItem::Menu(ref confirmation) = items[volume.items_offset + 1] else {
+            panic!("Expected confirmation menu");
+        };
+        assert!(!confirmation.stay_open);
+        let ComputedItem::Menu(ref devices) = items[volume.items_offset + 2] else {
+            panic!("Expected devices menu");
+        };
+        assert!(!devices.stay_open);
+
+        assert!(parser::parse_config("stay-open", ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_open_items() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            open "GitHub" "https://github.com"
+            open "Downloads" "~/Downloads" { icon "folder"; }
+            menu "Files" {
+                opener "gio" "open"
+                open "Config" "${HOME}/.config/uff"
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testopen",
+        );
+        let mut items = Vec::new();
+        flatten_resolved_menu(resolved, &mut items);
+        let command = |item: &ComputedItem| match item {
+            ComputedItem::Program(program) => program.command.clone(),
+            _ => panic!("Expected program item"),
+        };
+
+        assert_eq!(command(&items[0]), ["xdg-open", "https://github.com"]);
+        assert_eq!(
+            command(&items[1]),
+            [
+                "xdg-open".to_string(),
+                format!("{}/Downloads", home().unwrap())
+            ]
+        );
+        let ComputedItem::Menu(ref files) = items[2] else {
+            panic!("Expected files menu");
+        };
+        assert_eq!(
+            command(&items[files.items_offset]),
+            [
+                "gio".to_string(),
+                "open".to_string(),
+                format!("{}/.config/uff", home().unwrap())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(expand_env_vars("$HOME/a").unwrap(), format!("{home}/a"));
+        assert_eq!(expand_env_vars("${HOME}a").unwrap(), format!("{home}a"));
+        assert_eq!(expand_env_vars("a$").unwrap(), "a$");
+        assert_eq!(
+            expand_env_vars("$$HOME costs $$5").unwrap(),
+            "$HOME costs $5"
+        );
+        assert_eq!(expand_env_vars("no variables").unwrap(), "no variables");
+        assert_eq!(expand_env_vars("${unterminated").unwrap(), "${unterminated");
+        assert_eq!(
+            expand_env_vars("$UFF_TEST_UNSET/a"),
+            Err("UFF_TEST_UNSET".to_string())
+        );
+        assert_eq!(
+            expand_env_vars("${UFF_TEST_UNSET}a"),
+            Err("UFF_TEST_UNSET".to_string())
+        );
+
+        assert_eq!(
+            env_var_names("$A ${B} $$C $A"),
+            ["A".to_string(), "B".to_string(), "A".to_string()]
+        );
+        assert!(env_vars_unchanged(&[
+            ("HOME".to_string(), Some(home)),
+            ("UFF_TEST_UNSET".to_string(), None),
+        ]));
+        assert!(!env_vars_unchanged(&[(
+            "HOME".to_string(),
+            Some("/elsewhere".to_string())
+        )]));
+    }
+
+    #[test]
+    fn test_preset_name_namespaces() {
+        let (temp, dirs) = test_dirs();
+        let root = temp.path().join("test-shared-stem");
+        let presets = [root.join("work"), root.join("home")].map(|dir| {
+            std::fs::create_dir_all(&dir).unwrap();
+            dir.join("testsharedstem.kdl")
+        });
+        std::fs::write(&presets[0], r#"program "Foo" "foo""#).unwrap();
+        std::fs::write(&presets[1], r#"program "Bar" "bar"; program "Baz" "baz""#).unwrap();
+        let names = presets
+            .clone()
+            .map(|preset| preset_name(&preset, None).unwrap());
+        assert_ne!(names[0], names[1]);
+        assert!(names[0].starts_with("testsharedstem-") && names[0].len() == 21);
+        assert_eq!(
+            preset_name(&presets[0], Some("laptop")).unwrap(),
+            format!("{}@laptop", names[0])
+        );
+        // The same file through another path is the same preset
+        assert_eq!(
+            preset_name(&root.join("work/../work/testsharedstem.kdl"), None).unwrap(),
+            names[0]
+        );
+
+        // Files from before the path was part of the name are cleaned up, but
+        // the usage counts are kept under the new name
+        let cache_dir = &dirs.cache;
+        std::fs::create_dir_all(cache_dir.join("scripts/testsharedstem")).unwrap();
+        let legacy = [
+            cache_dir.join("testsharedstem.cache"),
+            cache_dir.join("testsharedstem0.fuzzel.ini"),
+            cache_dir.join("testsharedstem0.fuzzel.cache"),
+            cache_dir.join("scripts/testsharedstem/0123456789abcdef.sh"),
+        ];
+        for path in &legacy {
+            std::fs::write(path, "").unwrap();
+        }
+        std::fs::write(&legacy[2], "42|foo\n").unwrap();
+
+        let build = |preset: &Path| {
+            get_computed_config(
+                &ConfigBuild {
+                    dirs: &dirs,
+                    path: preset,
+                    profile: None,
+                    options: ParseOptions::default(),
+                    global: &GlobalConfig::default(),
+                    global_source: "",
+                },
+                false,
+                None,
+            )
+            .unwrap()
+            .items
+            .len()
+        };
+        assert_eq!(build(&presets[0]), 1);
+        assert!(legacy.iter().all(|path| !path.exists()));
+        assert_eq!(
+            std::fs::read_to_string(dirs.state.join(format!("{}0.fuzzel.cache", names[0])))
+                .unwrap(),
+            "42|foo\n"
+        );
+        assert_eq!(build(&presets[1]), 2);
+
+        // Building one doesn't replace the other's cache
+        let cached_at = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        let cache_path = make_cache_path(&dirs, &names[0]);
+        File::options()
+            .write(true)
+            .open(&cache_path)
+            .unwrap()
+            .set_modified(cached_at)
+            .unwrap();
+        assert_eq!(build(&presets[1]), 2);
+        assert_eq!(build(&presets[0]), 1);
+        assert_eq!(
+            std::fs::metadata(&cache_path).unwrap().modified().unwrap(),
+            cached_at
+        );
+    }
+
+    #[test]
+    fn test_relocatable_cache() {
+        let (temp, dirs) = test_dirs();
+        let preset = temp.path().join("testrelocate.kdl");
+        std::fs::write(
+            &preset,
+            r#"
+            fuzzel-config { font "serif"; }
+            dynamic-menu "Sessions" {
+                source-command "tmux" "list-sessions"
+                run-command "tmux" "attach" "-t"
+            }
+            menu "Foo" {
+                fuzzel-config { width "30"; }
+                program "Bar" "bar"
+            }
+            "#,
+        )
+        .unwrap();
+        let preset_name = preset_name(&preset, None).unwrap();
+        let cache_path = make_cache_path(&dirs, &preset_name);
+        let build = |dirs: &Dirs| {
+            get_computed_config(
+                &ConfigBuild {
+                    dirs,
+                    path: &preset,
+                    profile: None,
+                    options: ParseOptions::default(),
+                    global: &GlobalConfig::default(),
+                    global_source: "",
+                },
+                false,
+                None,
+            )
+            .unwrap()
+        };
+
+        // Used from the cache dir it's in, whether it was just built or not
+        let ini = make_fuzzel_config_path(&dirs, 0, &preset_name)
+            .display()
+            .to_string();
+        for computed in [build(&dirs), build(&dirs)] {
+            assert_eq!(computed.initial_menu.args[..2], ["--config", ini.as_str()]);
+            let ComputedItem::DynamicMenu(sessions) = &computed.items[0] else {
+                panic!("Expected dynamic menu");
+            };
+            assert!(sessions.args.contains(&ini));
+        }
+
+        // But the cache itself doesn't know where that is
+        let cache = std::fs::read(&cache_path).unwrap();
+        let cache_dir = dirs.cache.display().to_string();
+        assert!(
+            !cache
+                .windows(cache_dir.len())
+                .any(|window| window == cache_dir.as_bytes())
+        );
+
+        // So it can be copied to another cache dir and used from there, along
+        // with the inis, which include files where they were copied from
+        let (_moved_temp, moved_dirs) = test_dirs();
+        std::fs::create_dir_all(&moved_dirs.cache).unwrap();
+        let moved_cache_path = make_cache_path(&moved_dirs, &preset_name);
+        std::fs::copy(&cache_path, &moved_cache_path).unwrap();
+        let inis = build(&dirs).fuzzel_inis;
+        let moved_inis = [inis[0].id, inis[1].id].map(|id| {
+            let moved_ini = make_fuzzel_config_path(&moved_dirs, id, &preset_name);
+            std::fs::copy(make_fuzzel_config_path(&dirs, id, &preset_name), &moved_ini).unwrap();
+            moved_ini
+        });
+        let cached_at = UNIX_EPOCH + Duration::from_secs(1);
+        File::options()
+            .write(true)
+            .open(&moved_cache_path)
+            .unwrap()
+            .set_modified(cached_at)
+            .unwrap();
+        let moved = build(&moved_dirs);
+        assert_eq!(
+            moved.initial_menu.args,
+            [
+                "--config".to_string(),
+                moved_dirs
+                    .cache
+                    .join(format!("{preset_name}0.fuzzel.ini"))
+                    .display()
+                    .to_string(),
+                "--cache".to_string(),
+                moved_dirs
+                    .state
+                    .join(format!("{preset_name}0.fuzzel.cache"))
+                    .display()
+                    .to_string(),
+            ]
+        );
+        let includes = moved_inis.each_ref().map(|ini| {
+            let contents = std::fs::read_to_string(ini).unwrap();
+            contents.lines().next().unwrap().to_string()
+        });
+        assert_eq!(
+            includes,
+            [
+                format!("include={}", moved_dirs.fuzzel_config.display()),
+                format!("include={}", moved_inis[0].display()),
+            ]
+        );
+        assert_eq!(
+            std::fs::metadata(&moved_cache_path)
+                .unwrap()
+                .modified()
+                .unwrap(),
+            cached_at
+        );
+    }
+
+    #[test]
+    fn test_config_stamp() {
+        let (temp, dirs) = test_dirs();
+        let preset = temp.path().join("testconfigstamp.kdl");
+        std::fs::write(&preset, r#"program "A" "a""#).unwrap();
+        let cache_path = make_cache_path(&dirs, &preset_name(&preset, None).unwrap());
+        let build = || {
+            get_computed_config(
+                &ConfigBuild {
+                    dirs: &dirs,
+                    path: &preset,
+                    profile: None,
+                    options: ParseOptions::default(),
+                    global: &GlobalConfig::default(),
+                    global_source: "",
+                },
+                false,
+                None,
+            )
+            .unwrap()
+        };
+        let set_modified = |path: &Path, secs| {
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+        set_modified(&preset, 1000);
+        let built = build();
+        assert_eq!(built.config_len, 15);
+        assert_eq!(built.config_stamp, Some((1_000_000_000_000, 15)));
+        assert_eq!(
+            built.global_hash,
+            config_hash(env!("CARGO_PKG_VERSION"), "", "")
+        );
+
+        // What's checked survives the cache
+        let cached = read_cached_config(&cache_path).unwrap();
+        assert_eq!(cached.hash, built.hash);
+        assert_eq!(cached.config_len, built.config_len);
+        assert_eq!(cached.config_stamp, built.config_stamp);
+        assert_eq!(cached.global_hash, built.global_hash);
+
+        // With the same stamp the file isn't read, so an edit that kept it goes unnoticed
+        std::fs::write(&preset, r#"program "B" "b""#).unwrap();
+        set_modified(&preset, 1000);
+        set_modified(&cache_path, 2000);
+        let ComputedItem::Program(program) = &build().items[0] else {
+            panic!("Expected program");
+        };
+        assert_eq!(program.command, ["a"]);
+        // Unless it was changed when the cache was written, which could be right after
+        set_modified(&cache_path, 1000);
+        let ComputedItem::Program(program) = &build().items[0] else {
+            panic!("Expected program");
+        };
+        assert_eq!(program.command, ["b"]);
+
+        // Another stamp means it's read, and used if it's the same config
+        set_modified(&preset, 3000);
+        set_modified(&cache_path, 4000);
+        let ComputedItem::Program(program) = &build().items[0] else {
+            panic!("Expected program");
+        };
+        assert_eq!(program.command, ["b"]);
+        assert_eq!(
+            std::fs::metadata(&cache_path).unwrap().modified().unwrap(),
+            UNIX_EPOCH + Duration::from_secs(4000)
+        );
+
+        // The digest only counts with the length it was taken of
+        let mut cached = read_cached_config(&cache_path).unwrap();
+        cached.config_len += 1;
+        cached.config_stamp = None;
+        let mut source = ConfigSource::new(&preset, "").unwrap();
+        assert!(!source.built(&cached, None).unwrap());
+        cached.config_len -= 1;
+        assert!(source.built(&cached, None).unwrap());
+    }
+
+    #[test]
+    fn test_stale_while_revalidate() {
+        let (temp, dirs) = test_dirs();
+        let preset = temp.path().join("teststale.kdl");
+        std::fs::write(&preset, "").unwrap();
+        // A fake uff that records how it was started to rebuild the cache
+        let uff = temp.path().join("uff");
+        let rebuilds = temp.path().join("rebuilds");
+        std::fs::write(
+            &uff,
+            format!("#!/bin/sh\necho \"$@\" >> {}\n", rebuilds.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&uff, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let build = |config: &str, lenient: bool, refresh_icons: bool| {
+            std::fs::write(&preset, config).unwrap();
+            let options = ParseOptions {
+                lenient,
+                ..Default::default()
+            };
+            get_computed_config(
+                &ConfigBuild {
+                    dirs: &dirs,
+                    path: &preset,
+                    profile: None,
+                    options,
+                    global: &GlobalConfig::default(),
+                    global_source: "",
+                },
+                refresh_icons,
+                Some(&uff),
+            )
+            .unwrap()
+            .items
+            .len()
+        };
+        let one = r#"program "A" "a""#;
+        let two = r#"program "A" "a"; program "B" "b""#;
+        let three = r#"program "A" "a"; program "B" "b"; program "C" "c""#;
+        // Nothing to serve yet
+        assert_eq!(build(one, false, false), 1);
+        // The cache stays as it was, for the background rebuild to replace
+        assert_eq!(build(two, false, false), 1);
+        assert_eq!(build(two, false, false), 1);
+        // Unless looking for icons again was asked for
+        assert_eq!(build(two, false, true), 2);
+        // Or the cache may have let through what this build wouldn't
+        assert_eq!(build(three, true, false), 2);
+        assert_eq!(build(three, true, true), 3);
+        assert_eq!(build(one, false, false), 1);
+
+        // Each stale cache that was used is rebuilt, by a uff that isn't waited for
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::fs::read_to_string(&rebuilds).map_or(0, |log| log.lines().count()) < 3
+            && Instant::now() < deadline
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let log = std::fs::read_to_string(&rebuilds).unwrap();
+        let mut started: Vec<&str> = log.lines().collect();
+        started.sort_unstable();
+        let preset_arg = preset.display();
+        assert_eq!(
+            started,
+            [
+                format!("--warm --lenient {preset_arg}"),
+                format!("--warm {preset_arg}"),
+                format!("--warm {preset_arg}"),
+            ]
+        );
+
+        assert_eq!(
+            rebuild_args(&preset, Some("laptop"), ParseOptions::default()),
+            [
+                OsString::from("--warm"),
+                "--profile".into(),
+                "laptop".into(),
+                preset.clone().into()
+            ]
+        );
+        let lenient = ParseOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            rebuild_args(Path::new("a.kdl"), None, lenient),
+            ["--warm", "--lenient", "a.kdl"]
+        );
+    }
+
+    #[test]
+    fn test_restore_fuzzel_inis() {
+        let (temp, dirs) = test_dirs();
+        let preset = temp.path().join("testrestoreinis.kdl");
+        std::fs::write(
+            &preset,
+            r#"
+            defaults { fuzzel-config { font "serif"; }; }
+            menu "Foo" {
+                fuzzel-config { width "30"; }
+                program "Bar" "bar"
+            }
+            "#,
+        )
+        .unwrap();
+        let preset_name = preset_name(&preset, None).unwrap();
+        let cache_path = make_cache_path(&dirs, &preset_name);
+        let build = || {
+            get_computed_config(
+                &ConfigBuild {
+                    dirs: &dirs,
+                    path: &preset,
+                    profile: None,
+                    options: ParseOptions::default(),
+                    global: &GlobalConfig::default(),
+                    global_source: "",
+                },
+                false,
+                None,
+            )
+            .unwrap()
+        };
+        let inis = build().fuzzel_inis;
+        assert_eq!(inis.len(), 2);
+        let paths = inis
+            .iter()
+            .map(|ini| make_fuzzel_config_path(&dirs, ini.id, &preset_name));
+        let written: Vec<_> = paths
+            .clone()
+            .map(|path| std::fs::read(path).unwrap())
+            .collect();
+
+        // Deleted inis are written again from the cache, not by rebuilding it
+        let cached_at = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        File::options()
+            .write(true)
+            .open(&cache_path)
+            .unwrap()
+            .set_modified(cached_at)
+            .unwrap();
+        for path in paths.clone() {
+            std::fs::remove_file(path).unwrap();
+        }
+        build();
+        assert_eq!(
+            std::fs::metadata(&cache_path).unwrap().modified().unwrap(),
+            cached_at
+        );
+        let restored: Vec<_> = paths.map(|path| std::fs::read(path).unwrap()).collect();
+        assert_eq!(restored, written);
+    }
+
+    #[test]
+    fn test_cache_lock() {
+        let (temp, dirs) = test_dirs();
+        let dir = &dirs.cache;
+        let timeout = Duration::from_secs(5);
+
+        let first = lock_cache(dir, "work", timeout).unwrap();
+        assert!(!first.waited);
+        // Other presets aren't held up
+        assert!(!lock_cache(dir, "home", timeout).unwrap().waited);
+        let second = std::thread::spawn({
+            let dir = dir.clone();
+            move || lock_cache(&dir, "work", timeout).map(|lock| lock.waited)
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        // Giving up builds without it
+        assert!(lock_cache(dir, "work", Duration::from_millis(20)).is_none());
+        drop(first);
+        assert_eq!(second.join().unwrap(), Some(true));
+
+        // Which it also does when there's nowhere to put it
+        std::fs::write(dir.join("file"), "").unwrap();
+        assert!(lock_cache(&dir.join("file"), "work", timeout).is_none());
+
+        // Both builds of a preset started at once work
+        let preset = temp.path().join("testcachelock.kdl");
+        std::fs::write(&preset, r#"menu "Foo" { program "Bar" "bar"; }"#).unwrap();
+        let configs: Vec<_> = std::thread::scope(|scope| {
+            let builds: Vec<_> = (0..2)
+                .map(|_| {
+                    scope.spawn(|| {
+                        get_computed_config(
+                            &ConfigBuild {
+                                dirs: &dirs,
+                                path: &preset,
+                                profile: None,
+                                options: ParseOptions::default(),
+                                global: &GlobalConfig::default(),
+                                global_source: "",
+                            },
+                            false,
+                            None,
+                        )
+                        .unwrap()
+                    })
+                })
+                .collect();
+            builds
+                .into_iter()
+                .map(|build| build.join().unwrap())
+                .collect()
+        });
+        assert_eq!(configs[0].items.len(), configs[1].items.len());
+    }
+
+    #[test]
+    fn test_remove_stale_fuzzel_files() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-stale-fuzzel");
+        let [cache_dir, state_dir] = ["cache", "state"].map(|dir| root.join(dir));
+        let cache_files = [
+            "work.cache",
+            "work0.fuzzel.ini",
+            "work4.fuzzel.ini",
+            "work05.fuzzel.ini",
+            "work0.txt",
+            "workshop5.fuzzel.ini",
+            "work@laptop9.fuzzel.ini",
+            "work1.cache",
+            "work15.fuzzel.ini",
+            // Only inis are kept here now
+            "work7.fuzzel.cache",
+        ];
+        let state_files = [
+            "work3.fuzzel.cache",
+            "work7.fuzzel.cache",
+            "work16.fuzzel.cache",
+            "work4.fuzzel.ini",
+        ];
+        for (dir, files) in [(&cache_dir, &cache_files[..]), (&state_dir, &state_files)] {
+            std::fs::create_dir_all(dir).unwrap();
+            for file in files {
+                std::fs::write(dir.join(file), "").unwrap();
+            }
+        }
+        remove_stale_fuzzel_files(&cache_dir, &state_dir, "work", 4);
+        let left = |dir: &Path| {
+            let mut left: Vec<_> = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            left.sort();
+            left
+        };
+        let mut expected: Vec<_> = cache_files
+            .into_iter()
+            .filter(|file| *file != "work4.fuzzel.ini")
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(left(&cache_dir), expected);
+        assert_eq!(
+            left(&state_dir),
+            [
+                "work16.fuzzel.cache",
+                "work3.fuzzel.cache",
+                "work4.fuzzel.ini"
+            ]
+        );
+
+        // Nothing to do when the directories don't exist yet
+        remove_stale_fuzzel_files(&root.join("missing"), &root.join("missing"), "work", 0);
+
+        // Caches from before the state dir are moved there
+        std::fs::write(cache_dir.join("work2.fuzzel.cache"), "counts").unwrap();
+        std::fs::write(cache_dir.join("work3.fuzzel.cache"), "old").unwrap();
+        std::fs::write(state_dir.join("work3.fuzzel.cache"), "new").unwrap();
+        std::fs::write(cache_dir.join("work12.fuzzel.cache"), "").unwrap();
+        std::fs::write(cache_dir.join("home0.fuzzel.cache"), "").unwrap();
+        move_fuzzel_caches(&cache_dir, &root.join("new-state"), "work", "work");
+        move_fuzzel_caches(&cache_dir, &state_dir, "work", "work");
+        assert_eq!(
+            std::fs::read_to_string(root.join("new-state/work2.fuzzel.cache")).unwrap(),
+            "counts"
+        );
+        assert_eq!(
+            std::fs::read_to_string(state_dir.join("work3.fuzzel.cache")).unwrap(),
+            "new"
+        );
+        assert!(!cache_dir.join("work3.fuzzel.cache").exists());
+        // Except another preset's
+        assert!(cache_dir.join("work12.fuzzel.cache").exists());
+        assert!(cache_dir.join("home0.fuzzel.cache").exists());
+    }
+
+    #[test]
+    fn test_state_dir() {
+        assert_eq!(
+            state_dir(Some("/state".to_string()), "/home/me"),
+            Path::new("/state/uff")
+        );
+        for state_home in [None, Some(String::new()), Some("state".to_string())] {
+            assert_eq!(
+                state_dir(state_home, "/home/me"),
+                Path::new("/home/me/.local/state/uff")
+            );
+        }
+    }
+
+    #[test]
+    fn test_env_reads() {
+        let (_temp, dirs) = test_dirs();
+        // Only remembered while a config is being built
+        env_var("UFF_TEST_UNSET");
+        assert_eq!(EnvReads::start().finish(), []);
+        // Or not at all, for what the build doesn't depend on
+        let reads = EnvReads::start();
+        without_env_reads(|| env_var("UFF_TEST_UNSET"));
+        env_var("UFF_TEST_UNSET_TOO");
+        assert_eq!(reads.finish(), [("UFF_TEST_UNSET_TOO".to_string(), None)]);
+
+        let computed = compute_config(
+            r#"program "A" "a" icon="app"; program "B" "$UFF_TEST_UNSET_TOO""#,
+            &[0; 32],
+            &dirs,
+            "testenvreads",
+            None,
+            ParseOptions::default(),
+            &GlobalConfig::default(),
+            Path::new("test.kdl"),
+            IconCache::default(),
+        );
+        assert!(computed.is_err());
+        // Variables read by uff itself count along with the config's, once each
+        let computed = compute_config(
+            r#"program "A" "a" icon="app"; program "B" "$HOME/b""#,
+            &[0; 32],
+            &dirs,
+            "testenvreads",
+            None,
+            ParseOptions::default(),
+            &GlobalConfig::default(),
+            Path::new("test.kdl"),
+            IconCache::default(),
+        )
+        .unwrap();
+        let names: Vec<&str> = computed
+            .env_vars
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        for name in ["HOME", "XDG_DATA_DIRS", "XDG_DATA_HOME"] {
+            assert_eq!(names.iter().filter(|read| **read == name).count(), 1);
+        }
+        assert!(!names.contains(&"UFF_TEST_UNSET_TOO"));
+        assert!(env_vars_unchanged(&computed.env_vars));
+        // Nothing is remembered after a build, even one that failed
+        env_var("UFF_TEST_UNSET");
+        assert_eq!(ENV_READS.take(), None);
+    }
+
+    #[test]
+    fn test_script_items() {
+        let (_temp, mut dirs) = test_dirs();
+        let cache_env = (
+            "UFF_TEST_CACHE_HOME".to_string(),
+            Some("/cache".to_string()),
+        );
+        dirs.cache_env = vec![cache_env.clone()];
+        let config = |body: &str| {
+            format!(
+                r##"
+                script "Toggle VPN" {{
+                    body #"""
+                        {body}
+                        """#
+                }}
+                script "Python" {{
+                    body "print('hi')" shebang="/usr/bin/env python3"
+                }}
+                "##
+            )
+        };
+        let compute = |config: &str| {
+            compute_config(
+                config,
+                &[0; 32],
+                &dirs,
+                "testscript",
+                None,
+                ParseOptions::default(),
+                &GlobalConfig::default(),
+                Path::new("test.kdl"),
+                IconCache::default(),
+            )
+            .unwrap()
+        };
+        let command = |item: &ComputedItem| match item {
+            ComputedItem::Program(program) => program.command.clone(),
+            _ => panic!("Expected program item"),
+        };
+
+        let first = compute(&config("echo one"));
+        let vpn = command(&first.items[0]);
+        assert_eq!(vpn[0], "sh");
+        assert_eq!(std::fs::read_to_string(&vpn[1]).unwrap(), "echo one\n");
+
+        let python = command(&first.items[1]);
+        assert_eq!(python.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&python[0]).unwrap(),
+            "#!/usr/bin/env python3\nprint('hi')\n"
+        );
+        let mode = std::fs::metadata(&python[0]).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        // A new body gets a new file and the old one is cleaned up
+        let second = compute(&config("echo two"));
+        let new_vpn = command(&second.items[0]);
+        assert_ne!(new_vpn[1], vpn[1]);
+        assert!(!Path::new(&vpn[1]).exists());
+        assert!(Path::new(&python[0]).exists());
+
+        // Programs run them from the cache dir, so only then does the config
+        // depend on what it was found from
+        assert!(second.env_vars.contains(&cache_env));
+        assert!(!compute(r#"program "A" "a""#).env_vars.contains(&cache_env));
+        let from_env = Dirs::from_env().unwrap();
+        assert!(
+            from_env
+                .cache_env
+                .iter()
+                .any(|(name, _)| name == "XDG_CACHE_HOME")
+        );
+    }
+
+    #[test]
+    fn test_config_env() {
+        let (temp, mut dirs) = test_dirs();
+        // Not what it is now, like a config dir that moved since the build
+        let config_env = (
+            "UFF_TEST_CONFIG_HOME".to_string(),
+            Some("/config".to_string()),
+        );
+        dirs.config_env = vec![config_env.clone()];
+        let preset = temp.path().join("testconfigenv.kdl");
+        std::fs::write(&preset, r#"program "A" "a""#).unwrap();
+        let cache_path = make_cache_path(&dirs, &preset_name(&preset, None).unwrap());
+        let build = || {
+            get_computed_config(
+                &ConfigBuild {
+                    dirs: &dirs,
+                    path: &preset,
+                    profile: None,
+                    options: ParseOptions::default(),
+                    global: &GlobalConfig::default(),
+                    global_source: "",
+                },
+                false,
+                None,
+            )
+            .unwrap()
+        };
+
+        // Every ini includes fuzzel's config, so every config depends on it
+        assert!(build().env_vars.contains(&config_env));
+        let cached_at = UNIX_EPOCH + Duration::from_secs(1);
+        File::options()
+            .write(true)
+            .open(&cache_path)
+            .unwrap()
+            .set_modified(cached_at)
+            .unwrap();
+        build();
+        assert_ne!(
+            std::fs::metadata(&cache_path).unwrap().modified().unwrap(),
+            cached_at
+        );
+
+        let from_env = Dirs::from_env().unwrap();
+        assert!(
+            from_env
+                .config_env
+                .iter()
+                .any(|(name, _)| name == "XDG_CONFIG_HOME")
+        );
+    }
+
+    #[test]
+    fn test_dynamic_menu_args() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            fuzzel-args "--lines" "5"
+            dynamic-menu "Sessions" {
+                source-command "tmux" "list-sessions"
+                run-command "tmux" "attach" "-t"
+            }
+        "#;
+        let computed = compute_config(
+            config,
+            &[0; 32],
+            &dirs,
+            "testdynamic",
+            None,
+            ParseOptions::default(),
+            &GlobalConfig::default(),
+            Path::new("test.kdl"),
+            IconCache::default(),
+        )
+        .unwrap();
+        let ComputedItem::DynamicMenu(ref sessions) = computed.items[0] else {
+            panic!("Expected dynamic menu item");
+        };
+        assert_eq!(sessions.source_command, ["tmux", "list-sessions"]);
+        assert_eq!(sessions.run_command, ["tmux", "attach", "-t"]);
+        // Inherits the parent's args but keeps separate usage counts
+        let cache = fuzzel_cache_name(1, "testdynamic");
+        assert_eq!(sessions.args, ["--lines", "5", "--cache", cache.as_str()]);
+        assert_ne!(computed.initial_menu.args, sessions.args);
+
+        let config = r#"
+            fuzzel-args "--lines" "5"
+            pick "Open" {
+                list-command "ls" "~"
+                run-command "xdg-open" "~/{}"
+            }
+        "#;
+        let computed = compute_config(
+            config,
+            &[0; 32],
+            &dirs,
+            "testpick",
+            None,
+            ParseOptions::default(),
+            &GlobalConfig::default(),
+            Path::new("test.kdl"),
+            IconCache::default(),
+        )
+        .unwrap();
+        let ComputedItem::Pick(ref pick) = computed.items[0] else {
+            panic!("Expected pick item");
+        };
+        let home = home().unwrap();
+        assert_eq!(pick.list_command, ["ls", home.as_str()]);
+        assert_eq!(pick.run_command, ["xdg-open", &format!("{home}/{{}}")]);
+        let cache = fuzzel_cache_name(1, "testpick");
+        assert_eq!(pick.args, ["--lines", "5", "--cache", cache.as_str()]);
+    }
+
+    #[test]
+    fn test_expand_tilde() {
+        let home = home().unwrap();
+        assert_eq!(expand_tilde("~"), home);
+        assert_eq!(expand_tilde("~/x"), format!("{home}/x"));
+        assert_eq!(
+            expand_tilde("/data/foo~backup/icons"),
+            "/data/foo~backup/icons"
+        );
+        assert_eq!(expand_tilde("x~y.png"), "x~y.png");
+        assert_eq!(expand_tilde("/usr/share/icons"), "/usr/share/icons");
+        assert_eq!(expand_tilde(""), "");
+        assert_eq!(expand_tilde("~root/icons"), "/root/icons");
+        assert_eq!(
+            expand_tilde("~uff-no-such-user/icons"),
+            "~uff-no-such-user/icons"
+        );
+
+        // Commands only have their leading tildes expanded
+        assert_eq!(
+            expand_command(&["ls".to_string(), "~/a~b".to_string(), "a~".to_string()]),
+            ["ls".to_string(), format!("{home}/a~b"), "a~".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_placeholder_args() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            placeholder "type to filter apps…"
+            fuzzel-args "--lines" "5"
+            menu "Inherits" {
+                program "Foo" "foo"
+            }
+            menu "Overrides" {
+                placeholder "pick one"
+                fuzzel-args "--placeholder" "ignored" "--width" "30"
+                program "Foo" "foo"
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testplaceholder",
+        );
+        let cache = |id| fuzzel_cache_name(id, "testplaceholder");
+
+        assert_eq!(
+            resolved.args,
+            [
+                "--lines",
+                "5",
+                "--placeholder",
+                "type to filter apps…",
+                "--cache",
+                &cache(0)
+            ]
+        );
+        let ResolvedItem::Menu(ref inherits) = resolved.items[0] else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(
+            inherits.args,
+            [
+                "--lines",
+                "5",
+                "--placeholder",
+                "type to filter apps…",
+                "--cache",
+                &cache(1)
+            ]
+        );
+        let ResolvedItem::Menu(ref overrides) = resolved.items[1] else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(
+            overrides.args,
+            [
+                "--lines",
+                "5",
+                "--width",
+                "30",
+                "--placeholder",
+                "pick one",
+                "--cache",
+                &cache(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_power_items() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            power-commands {
+                reboot "loginctl" "reboot"
+            }
+            power-off
+            reboot "Restart now"
+            lock
+            suspend {
+                confirm "Sleep now?"
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let mut id_gen = IdGenerator::new();
+        let root = root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new());
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[root],
+            &mut id_gen,
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testpower",
+        );
+
+        let confirmed = |item: &ResolvedItem| {
+            let ResolvedItem::Menu(confirmation) = item else {
+                panic!("Expected confirmation menu");
+            };
+            let ResolvedItem::Program(program) = &confirmation.items[0] else {
+                panic!("Expected program item");
+            };
+            program.command.clone()
+        };
+        assert_eq!(confirmed(&resolved.items[0]), ["systemctl", "poweroff"]);
+        assert_eq!(confirmed(&resolved.items[1]), ["loginctl", "reboot"]);
+        let ResolvedItem::Program(lock) = &resolved.items[2] else {
+            panic!("Expected program item");
+        };
+        assert_eq!(lock.command, ["loginctl", "lock-session"]);
+        assert_eq!(confirmed(&resolved.items[3]), ["systemctl", "suspend"]);
+    }
+
+    #[test]
+    fn test_global_config() {
+        let (temp, dirs) = test_dirs();
+        let global = parser::parse_global_config(
+            r#"
+            settings { opener "handlr" "open"; }
+            defaults {
+                fuzzel-args "--lines" "5" "--width" "20"
+                icon-theme "Papirus"
+                use-fuzzel-cache #false
+            }
+            "#,
+            ParseOptions::default(),
+        )
+        .unwrap();
 
-        // Test nested menu flattening with proper adjacency preservation
-        let nested_submenu = ResolvedMenu {
-            args: vec!["--sub-arg".to_string()],
-            input: b"Item2\n".to_vec(),
-            items: vec![ResolvedItem::Program(ComputedProgram {
-                command: vec!["cmd2".to_string()],
-            })],
+        // The preset's own defaults come last, so they win
+        let mut parsed = parser::parse_config(
+            r#"
+            defaults {
+                fuzzel-args "--lines" "10"
+                icon-theme "Adwaita"
+            }
+            open "Home" "~"
+            "#,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        apply_global_config(&mut parsed, &global);
+        assert_eq!(
+            parsed.defaults.fuzzel_args,
+            ["--width", "20", "--lines", "10"]
+        );
+        assert_eq!(
+            parsed.defaults.fuzzel_config,
+            [
+                ("icon-theme".to_string(), "Papirus".to_string()),
+                ("icon-theme".to_string(), "Adwaita".to_string())
+            ]
+        );
+        assert_eq!(parsed.defaults.use_fuzzel_cache, Some(false));
+        assert_eq!(
+            parsed.opener,
+            Some(vec!["handlr".to_string(), "open".to_string()])
+        );
+
+        let mut parsed = parser::parse_config(
+            r#"opener "xdg-open"; open "Home" "~""#,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        apply_global_config(&mut parsed, &global);
+        assert_eq!(parsed.opener, Some(vec!["xdg-open".to_string()]));
+
+        // Changing config.kdl changes every preset's hash, so their caches are rebuilt
+        let preset = temp.path().join("testglobal.kdl");
+        std::fs::write(&preset, r#"program "Foo" "foo""#).unwrap();
+        let hash = |source: &str| {
+            get_computed_config(
+                &ConfigBuild {
+                    dirs: &dirs,
+                    path: &preset,
+                    profile: None,
+                    options: ParseOptions::default(),
+                    global: &global,
+                    global_source: source,
+                },
+                false,
+                None,
+            )
+            .unwrap()
+            .hash
         };
-        let nested_resolved = ResolvedMenu {
-            args: vec!["--base-arg".to_string()],
-            input: b"Item1\nSubmenu1\n".to_vec(),
-            items: vec![
-                ResolvedItem::Program(ComputedProgram {
-                    command: vec!["cmd1".to_string()],
-                }),
-                ResolvedItem::Menu(nested_submenu),
-            ],
+        assert_eq!(hash(""), hash(""));
+        assert_ne!(hash(""), hash("settings { }"));
+    }
+
+    #[test]
+    fn test_cache_schema_version() {
+        let (temp, dirs) = test_dirs();
+        let preset = temp.path().join("testcacheschema.kdl");
+        std::fs::write(&preset, r#"program "Foo" "foo""#).unwrap();
+        let cache_path = make_cache_path(&dirs, &preset_name(&preset, None).unwrap());
+        let build = || {
+            get_computed_config(
+                &ConfigBuild {
+                    dirs: &dirs,
+                    path: &preset,
+                    profile: None,
+                    options: ParseOptions::default(),
+                    global: &GlobalConfig::default(),
+                    global_source: "",
+                },
+                false,
+                None,
+            )
+            .unwrap()
         };
+        build();
+        let bytes = std::fs::read(&cache_path).unwrap();
+        assert_eq!(bytes[..4], CACHE_SCHEMA_VERSION.to_le_bytes());
+        assert!(read_cached_config(&cache_path).is_some());
 
-        let mut nested_items = Vec::new();
-        let nested_flattened = flatten_resolved_menu(&nested_resolved, &mut nested_items);
+        // A cache from a uff that encoded configs differently is never decoded
+        let mut old = (CACHE_SCHEMA_VERSION - 1).to_le_bytes().to_vec();
+        old.extend_from_slice(&bytes[4..]);
+        std::fs::write(&cache_path, &old).unwrap();
+        assert!(read_cached_config(&cache_path).is_none());
+        std::fs::write(&cache_path, [1, 0]).unwrap();
+        assert!(read_cached_config(&cache_path).is_none());
+        std::fs::write(&cache_path, CACHE_SCHEMA_VERSION.to_le_bytes()).unwrap();
+        assert!(read_cached_config(&cache_path).is_none());
 
-        // Check flattened menu structure
-        assert_eq!(nested_flattened.args, vec!["--base-arg"]);
-        assert_eq!(nested_flattened.input, b"Item1\nSubmenu1\n");
-        assert_eq!(nested_flattened.items_offset, 0);
+        // And is replaced by the rebuilt one
+        assert_eq!(build().items.len(), 1);
+        assert_eq!(std::fs::read(&cache_path).unwrap(), bytes);
 
-        // Check flattened items preserve adjacency
-        assert_eq!(nested_items.len(), 3);
-        if let ComputedItem::Program(ref prog) = nested_items[0] {
-            assert_eq!(prog.command, vec!["cmd1"]);
-        } else {
-            panic!("Expected first program item");
+        // A cache from another version of uff is rebuilt, even if it decodes
+        let config = std::fs::read_to_string(&preset).unwrap();
+        let old_hash = config_hash("0.0.0", "", &config);
+        assert_ne!(
+            old_hash,
+            config_hash(env!("CARGO_PKG_VERSION"), "", &config)
+        );
+        let old = compute_config(
+            &config,
+            &old_hash,
+            &dirs,
+            "testcacheschema",
+            None,
+            ParseOptions::default(),
+            &GlobalConfig::default(),
+            &preset,
+            IconCache::default(),
+        )
+        .unwrap();
+        cache_config(&cache_path, &old, true);
+        assert_eq!(read_cached_config(&cache_path).unwrap().hash, old.hash);
+        assert_ne!(build().hash, old.hash);
+        assert_eq!(std::fs::read(&cache_path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_cache_compression() {
+        let (temp, dirs) = test_dirs();
+        let preset = temp.path().join("testcachecompression.kdl");
+        let config: String = (0..50)
+            .map(|index| format!("program \"Program {index}\" \"/usr/bin/program-{index}\"\n"))
+            .collect();
+        std::fs::write(&preset, config).unwrap();
+        let cache_path = make_cache_path(&dirs, &preset_name(&preset, None).unwrap());
+        let build = |cache_compression| {
+            let mut global = GlobalConfig::default();
+            global.settings.cache_compression = cache_compression;
+            get_computed_config(
+                &ConfigBuild {
+                    dirs: &dirs,
+                    path: &preset,
+                    profile: None,
+                    options: ParseOptions::default(),
+                    global: &global,
+                    global_source: "",
+                },
+                false,
+                None,
+            )
+            .unwrap()
+        };
+
+        // Either way, it reads back as what was built
+        let mut sizes = Vec::new();
+        for (cache_compression, format) in [(None, CACHE_ZLIB), (Some(false), CACHE_UNCOMPRESSED)] {
+            let _ = std::fs::remove_file(&cache_path);
+            let built = build(cache_compression);
+            let bytes = std::fs::read(&cache_path).unwrap();
+            assert_eq!(bytes[4], format);
+            let cached = read_cached_config(&cache_path).unwrap();
+            assert_eq!(cached.hash, built.hash);
+            assert_eq!(cached.items.len(), 50);
+            assert_eq!(cached.initial_menu.input, built.initial_menu.input);
+            sizes.push(bytes.len());
         }
-        if let ComputedItem::Menu(ref menu) = nested_items[1] {
-            assert_eq!(menu.args, vec!["--sub-arg"]);
-            assert_eq!(menu.input, b"Item2\n");
-            assert_eq!(menu.items_offset, 2); // Points to next item
-        } else {
-            panic!("Expected menu item");
+        assert!(sizes[0] < sizes[1]);
+
+        // A damaged cache is rebuilt instead of decoded
+        let _ = std::fs::remove_file(&cache_path);
+        build(None);
+        let bytes = std::fs::read(&cache_path).unwrap();
+        let mut flipped = bytes.clone();
+        flipped[bytes.len() / 2] ^= 0xff;
+        let mut unknown = bytes.clone();
+        unknown[4] = 2;
+        for damaged in [flipped, bytes[..bytes.len() - 1].to_vec(), unknown] {
+            std::fs::write(&cache_path, damaged).unwrap();
+            assert!(read_cached_config(&cache_path).is_none());
         }
-        if let ComputedItem::Program(ref prog) = nested_items[2] {
-            assert_eq!(prog.command, vec!["cmd2"]);
-        } else {
-            panic!("Expected second program item");
+        assert_eq!(build(None).items.len(), 50);
+        assert!(read_cached_config(&cache_path).is_some());
+    }
+
+    #[test]
+    fn test_defaults_block() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            defaults {
+                fuzzel-args "--lines" "5"
+                icon-theme "Papirus"
+                fuzzel-config {
+                    font "monospace:size=12"
+                }
+            }
+            fuzzel-args "--width" "30"
+            menu "Themed" {
+                fuzzel-config {
+                    font "serif"
+                }
+                menu "Nested" {
+                    inherit-fuzzel-args #false
+                    program "Foo" "foo"
+                }
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let mut id_gen = IdGenerator::new();
+        let mut fuzzel_inis = Vec::new();
+        let root = root_frame(&parsed.defaults, &mut id_gen, &mut fuzzel_inis);
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[root],
+            &mut id_gen,
+            &mut fuzzel_inis,
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testdefaults",
+        );
+        for ini in &fuzzel_inis {
+            create_fuzzel_config(ini, &dirs, "testdefaults").unwrap();
         }
+        let ini = |id| make_fuzzel_config_path(&dirs, id, "testdefaults");
+        let cache = |id| fuzzel_cache_name(id, "testdefaults");
 
-        // Test escaped input handling
-        let escaped_resolved = ResolvedMenu {
-            args: vec![],
-            input: b"Item1\0icon\x1f/path/icon.png\nItem2\n".to_vec(),
-            items: vec![
-                ResolvedItem::Program(ComputedProgram {
-                    command: vec!["cmd1".to_string()],
-                }),
-                ResolvedItem::Program(ComputedProgram {
-                    command: vec!["cmd2".to_string()],
-                }),
-            ],
+        assert_eq!(
+            std::fs::read_to_string(ini(0)).unwrap(),
+            format!(
+                "include={}\nicon-theme=Papirus\nfont=monospace:size=12\n",
+                dirs.fuzzel_config.display()
+            )
+        );
+        assert_eq!(
+            resolved.args,
+            [
+                "--lines",
+                "5",
+                "--width",
+                "30",
+                "--config",
+                &fuzzel_config_name(0, "testdefaults"),
+                "--cache",
+                &cache(1)
+            ]
+        );
+
+        let ResolvedItem::Menu(ref themed) = resolved.items[0] else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(
+            std::fs::read_to_string(ini(2)).unwrap(),
+            format!("include={}\nfont=serif\n", ini(0).display())
+        );
+        let ResolvedItem::Menu(ref nested) = themed.items[0] else {
+            panic!("Expected menu item");
         };
+        assert_eq!(
+            nested.args,
+            [
+                "--lines",
+                "5",
+                "--config",
+                &fuzzel_config_name(2, "testdefaults"),
+                "--cache",
+                &cache(3)
+            ]
+        );
 
-        let mut escaped_items = Vec::new();
-        let escaped_flattened = flatten_resolved_menu(&escaped_resolved, &mut escaped_items);
+        let error = parser::parse_config(
+            "menu \"Sub\" { defaults { icon-theme \"x\"; }; }",
+            ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "unexpected node in menu: defaults");
+    }
 
-        // Test the escaped input format
-        let expected_input_escaped = escaped_resolved.input.escape_ascii().to_string();
-        let actual_input_escaped = escaped_flattened.input.escape_ascii().to_string();
-        assert_eq!(actual_input_escaped, expected_input_escaped);
+    #[test]
+    fn test_input_args() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            fuzzel-args "--lines" "5"
+            input "Search" {
+                command "xdg-open" "https://duckduckgo.com/?q={}"
+                prompt "search: "
+            }
+            input "Unlock vault" {
+                command "vault-unlock"
+                password #true
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testinputargs",
+        );
         assert_eq!(
-            actual_input_escaped,
-            "Item1\\x00icon\\x1f/path/icon.png\\nItem2\\n"
+            resolved.args[2..],
+            ["--cache", &fuzzel_cache_name(0, "testinputargs")]
+        );
+        // The menu's usage counts never see what's typed
+        let ResolvedItem::Input(ref search) = resolved.items[0] else {
+            panic!("Expected input item");
+        };
+        assert_eq!(
+            search.args,
+            [
+                "--lines",
+                "5",
+                "--prompt-only=search: ",
+                "--cache",
+                "/dev/null"
+            ]
+        );
+        // Nor is a password written to disk
+        let ResolvedItem::Input(ref vault) = resolved.items[1] else {
+            panic!("Expected input item");
+        };
+        assert_eq!(
+            vault.args,
+            [
+                "--lines",
+                "5",
+                "--prompt-only=Unlock vault: ",
+                "--cache",
+                "/dev/null",
+                "--password"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_auto_size() {
+        let (_temp, dirs) = test_dirs();
+        let resolve = |config: &str| {
+            let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+            let mut id_gen = IdGenerator::new();
+            let root = root_frame(&parsed.defaults, &mut id_gen, &mut Vec::new());
+            build_resolved_menu(
+                &parsed,
+                &[root],
+                &mut id_gen,
+                &mut Vec::new(),
+                &mut IconFinder::new(IconCache::default()),
+                &dirs,
+                "testautosize",
+            )
+        };
+        let option = |menu: &ResolvedMenu, name: &str| -> Option<String> {
+            let prefix = format!("{name}=");
+            menu.args
+                .iter()
+                .find_map(|arg| arg.strip_prefix(&prefix).map(str::to_owned))
+        };
+
+        // A short menu gets a line per item, and room for the widest name
+        let short = resolve(
+            r#"
+            auto-size #true
+            program "Foo" "foo"
+            program "Firefox" "firefox"
+            program "日本語" "jp"
+            "#,
+        );
+        assert_eq!(option(&short, "--lines").as_deref(), Some("3"));
+        assert_eq!(option(&short, "--width").as_deref(), Some("11"));
+
+        // A long menu stops at the limits, and submenus inherit auto-size
+        let names: String = (0..30)
+            .map(|index| format!("program \"{index} {}\" \"a\"\n", "x".repeat(index * 5)))
+            .collect();
+        let long = resolve(&format!(
+            "auto-size #true max-lines=10 padding=0\nmenu \"Sub\" {{ {names} }}\n{names}"
+        ));
+        assert_eq!(option(&long, "--lines").as_deref(), Some("10"));
+        assert_eq!(option(&long, "--width").as_deref(), Some("80"));
+        let ResolvedItem::Menu(ref sub) = long.items[0] else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(option(sub, "--lines").as_deref(), Some("10"));
+        let narrow = resolve(
+            r#"
+            auto-size #true padding=0 max-width=200
+            program "Firefox" "firefox" { description "web"; }
+            "#,
+        );
+        assert_eq!(option(&narrow, "--lines").as_deref(), Some("1"));
+        assert_eq!(option(&narrow, "--width").as_deref(), Some("13"));
+
+        // Values set by the user win, in args or the ini
+        let explicit = resolve(
+            r#"
+            auto-size #true
+            fuzzel-args "--lines" "5"
+            menu "Ini" {
+                fuzzel-config { width "40"; }
+                program "Foo" "foo"
+            }
+            menu "Short" {
+                fuzzel-args "-l3" "-w" "20"
+                program "Foo" "foo"
+            }
+            menu "Off" {
+                auto-size #false
+                program "Foo" "foo"
+            }
+            "#,
+        );
+        assert_eq!(option(&explicit, "--lines"), None);
+        assert_eq!(option(&explicit, "--width").as_deref(), Some("9"));
+        let submenu = |index: usize| {
+            let ResolvedItem::Menu(ref menu) = explicit.items[index] else {
+                panic!("Expected menu item");
+            };
+            (option(menu, "--lines"), option(menu, "--width"))
+        };
+        assert_eq!(submenu(0), (None, None));
+        assert_eq!(submenu(1), (None, None));
+        assert_eq!(submenu(2), (None, None));
+
+        assert_eq!(display_width("abc"), 3);
+        assert_eq!(display_width("日本"), 4);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("🚀"), 2);
+    }
+
+    #[test]
+    fn test_named_entries() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            defaults {
+                fuzzel-args "--lines" "5"
+            }
+            menu "power" {
+                program "Shutdown" "poweroff"
+            }
+            menu "apps" {
+                fuzzel-args "--width" "40"
+                program "Firefox" "firefox"
+            }
+        "#;
+        let computed = compute_config(
+            config,
+            &[0; 32],
+            &dirs,
+            "testentries",
+            None,
+            ParseOptions::default(),
+            &GlobalConfig::default(),
+            Path::new("test.kdl"),
+            IconCache::default(),
+        )
+        .unwrap();
+
+        let error = computed.entry_menu(None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "this config has multiple entries, pick one with --entry or config.kdl:entry"
+        );
+        assert_eq!(
+            error.help().unwrap().to_string(),
+            r#"available entries: "power", "apps""#
+        );
+        assert!(computed.entry_menu(Some("games")).is_err());
+
+        let apps = computed.entry_menu(Some("apps")).unwrap();
+        assert_eq!(apps.input, b"Firefox\n");
+        assert_eq!(apps.args[..4], ["--lines", "5", "--width", "40"]);
+        let ComputedItem::Program(program) = &computed.items[apps.items_offset] else {
+            panic!("Expected program item");
+        };
+        assert_eq!(program.command, ["firefox"]);
+
+        // Loose items make it a single menu again, which entries can still be picked from
+        let config = format!("{config}\nprogram \"Terminal\" \"foot\"");
+        let computed = compute_config(
+            &config,
+            &[0; 32],
+            &dirs,
+            "testentries",
+            None,
+            ParseOptions::default(),
+            &GlobalConfig::default(),
+            Path::new("test.kdl"),
+            IconCache::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            computed.entry_menu(None).unwrap().input,
+            computed.initial_menu.input
+        );
+        assert_eq!(
+            computed.entry_menu(Some("power")).unwrap().input,
+            b"Shutdown\n"
+        );
+    }
+
+    #[test]
+    fn test_item_ids() {
+        let (_temp, dirs) = test_dirs();
+        let compute = |config: &str| {
+            compute_config(
+                config,
+                &[0; 32],
+                &dirs,
+                "testitemids",
+                None,
+                ParseOptions::default(),
+                &GlobalConfig::default(),
+                Path::new("test.kdl"),
+                IconCache::default(),
+            )
+            .unwrap()
+        };
+        let config = r#"
+            menu "Term" id="terminal" {
+                program "Foot" "foot" id="foot" { systemd-scope #true; }
+            }
+            menu "Web" {
+                program "Firefox" "firefox"
+            }
+        "#;
+        let computed = compute(config);
+        let terminal = computed.entry_menu(Some("terminal")).unwrap();
+        assert_eq!(terminal.hooks.menu_path, "terminal");
+        assert_eq!(terminal.item_id(0), "foot");
+        assert_eq!(
+            computed.entry_menu(Some("Web")).unwrap().item_id(0),
+            "Firefox"
+        );
+
+        // Renaming the menu doesn't change anything that refers to it by id
+        let renamed = compute(&config.replace("\"Term\"", "\"Terminal\""));
+        let terminal = renamed.entry_menu(Some("terminal")).unwrap();
+        assert_eq!(terminal.hooks.menu_path, "terminal");
+        assert_eq!(terminal.input, b"Foot\n");
+        let ComputedItem::Program(foot) = &renamed.items[terminal.items_offset] else {
+            panic!("Expected program item");
+        };
+        assert_eq!(foot.systemd_scope.as_deref(), Some("foot"));
+
+        let decoded: ComputedConfig = bitcode::decode(&bitcode::encode(&renamed)).unwrap();
+        assert_eq!(
+            decoded.entry_menu(Some("terminal")).unwrap().ids,
+            [Some("foot".to_string())]
+        );
+
+        let error = parser::parse_config(
+            r#"program "A" "a" id="same"; menu "B" { program "C" "c" id="same"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            r#"id "same" is used by more than one item"#
+        );
+        let error = parser::parse_config(r#"program "A" "a" id="a b""#, ParseOptions::default())
+            .unwrap_err();
+        assert_eq!(error.to_string(), r#"invalid item id: "a b""#);
+    }
+
+    #[test]
+    fn test_item_descriptions() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            program "Firefox" "firefox" { description "web browser"; }
+            program "Foot" "foot"
+            menu "Markup" {
+                markup #true
+                description-separator ": "
+                program "A & B" "ab" { description "<dim>"; }
+                menu "Nested" {
+                    description "inherits"
+                    back "Back"
+                }
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut Vec::new(),
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testdescriptions",
+        );
+        assert_eq!(
+            resolved.input,
+            "Firefox — web browser\nFoot\nMarkup\n".as_bytes()
+        );
+        let ResolvedItem::Menu(ref markup) = resolved.items[2] else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(
+            markup.input,
+            "A &amp; B: <span alpha=\"60%\">&lt;dim&gt;</span>\n\
+             Nested: <span alpha=\"60%\">inherits</span>\n"
+                .as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_bind_actions() {
+        let (_temp, dirs) = test_dirs();
+        let config = r#"
+            binds {
+                "ctrl+d" "delete"
+                "alt+shift+e" "edit"
+            }
+            program "Notes" "notes" {
+                on "edit" { command "foot" "nvim" "notes.md"; }
+                on "delete" { command "rm" "notes.md"; }
+            }
+            program "Todo" "todo" {
+                on "archive" { command "mv" "todo.md" "archive"; }
+            }
+            menu "Inherits" {
+                program "Log" "log" { on "delete" { command "rm" "log"; }; }
+            }
+        "#;
+        let parsed = parser::parse_config(config, ParseOptions::default()).unwrap();
+        let mut fuzzel_inis = Vec::new();
+        let resolved = build_resolved_menu(
+            &parsed,
+            &[InheritanceFrame::default()],
+            &mut IdGenerator::new(),
+            &mut fuzzel_inis,
+            &mut IconFinder::new(IconCache::default()),
+            &dirs,
+            "testbinds",
+        );
+        for ini in &fuzzel_inis {
+            create_fuzzel_config(ini, &dirs, "testbinds").unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(make_fuzzel_config_path(&dirs, 0, "testbinds")).unwrap(),
+            format!(
+                "include={}\n[key-bindings]\ncustom-1=Control+d\ncustom-2=Mod1+Shift+e\n",
+                dirs.fuzzel_config.display()
+            )
+        );
+
+        let actions: Vec<(usize, usize, Vec<String>)> = resolved
+            .actions
+            .iter()
+            .map(|action| (action.item, action.bind, action.program.command.clone()))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (0, 1, vec!["foot".into(), "nvim".into(), "notes.md".into()]),
+                (0, 0, vec!["rm".into(), "notes.md".into()]),
+            ]
+        );
+
+        // The submenu uses the parent's ini, which already has the binds
+        let ResolvedItem::Menu(ref inherits) = resolved.items[2] else {
+            panic!("Expected menu item");
+        };
+        assert!(inherits.args.contains(&fuzzel_config_name(0, "testbinds")));
+        assert_eq!(inherits.actions[0].bind, 0);
+        assert_eq!(inherits.actions[0].program.command, ["rm", "log"]);
+
+        assert_eq!(fuzzel_key_name("Control+x"), "Control+x");
+        assert_eq!(fuzzel_key_name("super+Return"), "Mod4+Return");
+        assert_eq!(fuzzel_key_name("F5"), "F5");
+    }
+
+    #[test]
+    fn test_aliases() {
+        let (_temp, dirs) = test_dirs();
+        let compute = |config: &str, profile: Option<&str>| {
+            compute_config(
+                config,
+                &[0; 32],
+                &dirs,
+                "testaliases",
+                profile,
+                ParseOptions::default(),
+                &GlobalConfig::default(),
+                Path::new("test.kdl"),
+                IconCache::default(),
+            )
+        };
+        let config = r#"
+            alias "Browser" of="firefox"
+            alias "Mail" of="Web/Thunderbird" { icon "mail"; }
+            alias "Internet" of="Web"
+            menu "Web" {
+                program "Firefox" "firefox" id="firefox" { icon "firefox"; }
+                program "Thunderbird" "thunderbird" { profiles "home"; }
+            }
+        "#;
+        let computed = compute(config, None).unwrap();
+        assert_eq!(
+            computed.initial_menu.input,
+            b"Browser\0icon\x1ffirefox\nMail\0icon\x1fmail\nInternet\nWeb\n"
+        );
+        let program = |index: usize| match &computed.items[index] {
+            ComputedItem::Program(program) => program.command.clone(),
+            _ => panic!("Expected program item"),
+        };
+        assert_eq!(program(0), ["firefox"]);
+        assert_eq!(program(1), ["thunderbird"]);
+        // The alias gets its own copy of the menu, which opens the same programs
+        let (ComputedItem::Menu(alias), ComputedItem::Menu(web)) =
+            (&computed.items[2], &computed.items[3])
+        else {
+            panic!("Expected menu items");
+        };
+        assert_eq!(alias.input, web.input);
+        assert_ne!(alias.items_offset, web.items_offset);
+        assert_eq!(program(alias.items_offset + 1), ["thunderbird"]);
+        assert_eq!(computed.initial_menu.names[0], "Browser");
+
+        // Hiding the target hides its aliases too
+        let work = compute(config, Some("work")).unwrap();
+        assert_eq!(
+            work.initial_menu.input,
+            b"Browser\0icon\x1ffirefox\nInternet\nWeb\n"
+        );
+
+        let error = |config: &str| compute(config, None).unwrap_err().to_string();
+        assert_eq!(
+            error(r#"alias "A" of="nope"; program "B" "b""#),
+            "alias A points at nothing: nope"
+        );
+        assert_eq!(
+            error(r#"alias "A" of="b"; alias "B" of="c" id="b"; program "C" "c" id="c""#),
+            "alias A points at another alias"
+        );
+        assert_eq!(
+            error(
+                r#"alias "A" of="B"; program "B" "b"; menu "M" { program "B" "b"; }; program "B" "c""#
+            ),
+            "alias A could point at several items"
+        );
+        assert_eq!(
+            error(r#"menu "Apps" id="apps" { alias "Again" of="apps"; }"#),
+            "alias Again points at a menu it's in"
+        );
+        assert_eq!(
+            error(r#"menu "A" { menu "B" { alias "C" of="A"; }; program "D" "d"; }"#),
+            "alias C points at a menu it's in"
+        );
+        assert_eq!(
+            error(
+                r#"menu "A" { alias "C" of="B"; program "a" "a"; }; menu "B" { alias "D" of="A"; }"#
+            ),
+            "alias C leads back to a menu it's in"
+        );
+        assert_eq!(
+            error(
+                r#"menu "A" { multi-select #true; alias "B" of="M"; }; menu "M" { program "C" "c"; }"#
+            ),
+            "alias B in a multi-select menu can't point at M"
+        );
+        assert_eq!(
+            error(r#"menu "A" { multi-select #true; alias "B" of="Restart"; }; reboot"#),
+            "alias B in a multi-select menu can't point at Restart"
+        );
+        // Aliases into each other's targets are fine as long as they don't loop
+        compute(
+            r#"menu "A" { alias "C" of="B"; program "a" "a"; }; menu "B" { alias "D" of="A/a"; }"#,
+            None,
+        )
+        .unwrap();
+        let error = |config: &str| {
+            parser::parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"program "A" "a" id="a"; alias "A" of="a""#),
+            "there is already an item named A in this menu"
+        );
+        assert_eq!(
+            error(r#"program "A" "a" id="a"; alias "B" of="a"; alias "B" of="a""#),
+            "there is already an item named B in this menu"
+        );
+        assert_eq!(
+            error(r#"program "A" "a"; alias "B""#),
+            "alias B needs an of=\"target\""
         );
     }
 }
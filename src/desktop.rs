@@ -0,0 +1,323 @@
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::config;
+
+/// The parts of a desktop entry uff uses.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DesktopEntry {
+    pub name: Option<String>,
+    /// `Exec` with the string escapes undone, already checked to split.
+    pub exec: Option<String>,
+    pub icon: Option<String>,
+    /// From `Terminal`, the command has to be run in a terminal.
+    pub terminal: bool,
+    /// From `Hidden`, the entry counts as deleted.
+    pub hidden: bool,
+    /// From `MimeType`, the types of file it can open.
+    pub mime_types: Vec<String>,
+}
+
+impl DesktopEntry {
+    /// `Exec` split into arguments. The file replaces `%f`, `%F`, `%u` and `%U`,
+    /// or is appended if there are none, and other field codes are removed.
+    pub fn command(&self, file: Option<&str>) -> Option<Vec<String>> {
+        split_exec(self.exec.as_deref()?, file).ok()
+    }
+}
+
+/// `$XDG_DATA_HOME` and then each of `$XDG_DATA_DIRS`, in order of precedence.
+pub fn data_dirs() -> Vec<PathBuf> {
+    let data_home = config::env_var("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(Path::new(&config::home().ok()?).join(".local/share")));
+    let data_dirs = config::env_var("XDG_DATA_DIRS")
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".into());
+    data_home
+        .into_iter()
+        .chain(std::env::split_paths(&data_dirs))
+        .collect()
+}
+
+/// `applications` in each of the data dirs, the order entries are looked up in.
+pub fn applications_dirs() -> Vec<PathBuf> {
+    data_dirs()
+        .into_iter()
+        .map(|dir| dir.join("applications"))
+        .collect()
+}
+
+/// The entry in the first of `dirs` that has it, or the file itself if the
+/// name is a path.
+pub fn locate(name: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    if name.contains('/') {
+        let path = PathBuf::from(config::expand_tilde(name));
+        return path.is_file().then_some(path);
+    }
+    dirs.iter()
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// When the file was last changed, in nanoseconds since the epoch.
+pub fn modified(path: &Path) -> Option<u64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+/// Reads the keys uff uses from the `[Desktop Entry]` group, localized keys
+/// like `Icon[de]` are skipped.
+pub fn parse(contents: &str) -> Result<DesktopEntry, String> {
+    let mut in_entry = false;
+    let mut found_entry = false;
+    let mut entry = DesktopEntry::default();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(group) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            in_entry = group == "Desktop Entry";
+            found_entry |= in_entry;
+            continue;
+        }
+        if !in_entry {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("expected key=value, found {line:?}"));
+        };
+        let value = unescape(value.trim());
+        match key.trim() {
+            "Name" => entry.name = Some(value),
+            "Exec" => {
+                split_exec(&value, None)?;
+                entry.exec = Some(value);
+            }
+            "Icon" if !value.is_empty() => entry.icon = Some(value),
+            "Terminal" => entry.terminal = value == "true",
+            "Hidden" => entry.hidden = value == "true",
+            "MimeType" => {
+                entry.mime_types = value
+                    .split(';')
+                    .filter(|mime_type| !mime_type.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    if !found_entry {
+        return Err("there is no [Desktop Entry] group".to_string());
+    }
+    Ok(entry)
+}
+
+/// The escapes allowed in any string value, which come before the Exec
+/// quoting rules.
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(character) = chars.next() {
+        if character != '\\' {
+            unescaped.push(character);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => unescaped.push(' '),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Splits `Exec` into arguments. Quoted arguments can contain spaces and the
+/// escapes `\"`, ``\` ``, `\$` and `\\`. The file codes take `file`, other field
+/// codes like `%i` are dropped and `%%` is a literal `%`.
+fn split_exec(exec: &str, file: Option<&str>) -> Result<Vec<String>, String> {
+    let mut arguments = Vec::new();
+    let mut took_file = false;
+    let mut chars = exec.chars().peekable();
+    loop {
+        while chars.next_if(|character| *character == ' ').is_some() {}
+        let Some(first) = chars.next() else {
+            break;
+        };
+        let mut argument = String::new();
+        if first == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(escaped @ ('"' | '`' | '$' | '\\')) => argument.push(escaped),
+                        Some(other) => {
+                            argument.push('\\');
+                            argument.push(other);
+                        }
+                        None => return Err("unterminated quote in Exec".to_string()),
+                    },
+                    Some(character) => argument.push(character),
+                    None => return Err("unterminated quote in Exec".to_string()),
+                }
+            }
+            arguments.push(argument);
+            continue;
+        }
+
+        let mut had_field_code = false;
+        let mut character = Some(first);
+        while let Some(current) = character.filter(|character| *character != ' ') {
+            if current == '%' {
+                match chars.next() {
+                    Some('%') => argument.push('%'),
+                    Some('f' | 'F' | 'u' | 'U') if let Some(file) = file => {
+                        argument.push_str(file);
+                        took_file = true;
+                    }
+                    Some(_) => had_field_code = true,
+                    None => return Err("Exec ends with a lone %".to_string()),
+                }
+            } else {
+                argument.push(current);
+            }
+            character = chars.next();
+        }
+        // An argument that was only a field code goes away with it
+        if !(argument.is_empty() && had_field_code) {
+            arguments.push(argument);
+        }
+    }
+    if arguments.is_empty() {
+        return Err("Exec is empty".to_string());
+    }
+    if let Some(file) = file
+        && !took_file
+    {
+        arguments.push(file.to_owned());
+    }
+    Ok(arguments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse() {
+        let entry = parse(
+            "# comment\n\
+             [Desktop Entry]\n\
+             Type=Application\n\
+             Name=Firefox\n\
+             Exec=firefox --new-window %u\n\
+             Icon=firefox\n\
+             Icon[de]=firefox-de\n\
+             \n\
+             [Desktop Action private]\n\
+             Exec=firefox --private-window %u\n\
+             Terminal=true\n",
+        )
+        .unwrap();
+        assert_eq!(entry.name.as_deref(), Some("Firefox"));
+        assert_eq!(entry.command(None).unwrap(), ["firefox", "--new-window"]);
+        assert_eq!(
+            entry.command(Some("/tmp/a b.html")).unwrap(),
+            ["firefox", "--new-window", "/tmp/a b.html"]
+        );
+        assert_eq!(entry.icon.as_deref(), Some("firefox"));
+        assert!(!entry.terminal);
+
+        let entry = parse(
+            "[Desktop Entry]\nExec = htop\nTerminal = true\nIcon=\nHidden=true\n\
+             MimeType=text/plain;text/x-log;\n",
+        )
+        .unwrap();
+        assert_eq!(entry.command(None).unwrap(), ["htop"]);
+        // Without a file code the file is appended
+        assert_eq!(entry.command(Some("a.log")).unwrap(), ["htop", "a.log"]);
+        assert!(entry.terminal);
+        assert!(entry.hidden);
+        assert_eq!(entry.icon, None);
+        assert_eq!(entry.mime_types, ["text/plain", "text/x-log"]);
+
+        assert!(parse("[Desktop Action new]\nExec=foo\n").is_err());
+        assert!(parse("[Desktop Entry]\nnot a key\n").is_err());
+        assert!(parse("[Desktop Entry]\nExec=\"foo\n").is_err());
+        assert_eq!(
+            parse("[Desktop Entry]\nName=x\n").unwrap().command(None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_split_exec() {
+        let split = |exec: &str| split_exec(&unescape(exec), None);
+        assert_eq!(split("foo  bar").unwrap(), ["foo", "bar"]);
+        assert_eq!(
+            split(r#""/opt/My App/app" --name "a b" %F"#).unwrap(),
+            ["/opt/My App/app", "--name", "a b"]
+        );
+        // In the file the backslashes are escaped again for the string itself
+        assert_eq!(
+            split(r#"sh -c "echo \\"\\$HOME\\" \\\\ \\`x\\`""#).unwrap(),
+            ["sh", "-c", r#"echo "$HOME" \ `x`"#]
+        );
+        assert_eq!(split("foo 100%% %i %c %k").unwrap(), ["foo", "100%"]);
+        assert_eq!(split("foo --url=%u").unwrap(), ["foo", "--url="]);
+        assert_eq!(split(r#"foo "a\sb""#).unwrap(), ["foo", "a b"]);
+        assert!(split(r#"foo "bar"#).is_err());
+        assert!(split("foo %").is_err());
+        assert!(split("%U").is_err());
+
+        let open = |exec: &str| split_exec(exec, Some("/a b.pdf"));
+        assert_eq!(open("zathura %f").unwrap(), ["zathura", "/a b.pdf"]);
+        assert_eq!(
+            open("app --file=%U %i").unwrap(),
+            ["app", "--file=/a b.pdf"]
+        );
+        assert_eq!(open(r#"app "%f""#).unwrap(), ["app", "%f", "/a b.pdf"]);
+    }
+
+    #[test]
+    fn test_locate() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-desktop-locate");
+        let dirs = [root.join("home"), root.join("usr")];
+        for dir in &dirs {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::write(dirs[1].join("foot.desktop"), "").unwrap();
+        assert_eq!(
+            locate("foot.desktop", &dirs),
+            Some(dirs[1].join("foot.desktop"))
+        );
+        // Earlier dirs override later ones
+        std::fs::write(dirs[0].join("foot.desktop"), "").unwrap();
+        assert_eq!(
+            locate("foot.desktop", &dirs),
+            Some(dirs[0].join("foot.desktop"))
+        );
+        assert_eq!(locate("missing.desktop", &dirs), None);
+
+        let path = dirs[1].join("foot.desktop").display().to_string();
+        assert_eq!(locate(&path, &[]), Some(dirs[1].join("foot.desktop")));
+        assert!(modified(Path::new(&path)).is_some());
+    }
+}
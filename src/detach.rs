@@ -0,0 +1,114 @@
+use std::{
+    ffi::c_int,
+    io,
+    os::unix::process::CommandExt,
+    process::{Child, Command, Stdio},
+};
+
+// Both are in the libc std already links against
+unsafe extern "C" {
+    fn setsid() -> c_int;
+    fn signal(signum: c_int, handler: usize) -> usize;
+}
+
+/// SIGHUP, SIGINT, SIGQUIT, SIGPIPE and SIGTERM, which have these numbers on
+/// every unix. A terminal or compositor may have left them ignored.
+const RESET_SIGNALS: [c_int; 5] = [1, 2, 3, 13, 15];
+const SIG_DFL: usize = 0;
+const SIG_ERR: usize = usize::MAX;
+
+/// Makes the command start in a new session with the default signal handlers,
+/// so it isn't hung up with uff's terminal. Its stdio is `/dev/null`, set any
+/// of it again afterwards to override that.
+pub fn detach(command: &mut Command) {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // Only async-signal-safe calls are made between fork and exec
+    unsafe {
+        command.pre_exec(|| {
+            if setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            for signum in RESET_SIGNALS {
+                if signal(signum, SIG_DFL) == SIG_ERR {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Waits for the child in the background, so it doesn't stay a zombie while
+/// uff keeps running. If uff exits first the child is reaped by init instead.
+pub fn reap(mut child: Child) {
+    std::thread::spawn(move || {
+        let _ = child.wait();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        path::Path,
+        time::{Duration, Instant},
+    };
+    use tempfile::TempDir;
+
+    /// The session id from `/proc/<pid>/stat`, which is the sixth field.
+    fn session_id(stat: &str) -> String {
+        let after_name = &stat[stat.rfind(')').unwrap() + 2..];
+        after_name.split(' ').nth(3).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_detach() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("test-detach");
+        std::fs::create_dir_all(&dir).unwrap();
+        let stat = dir.join("stat");
+        let stderr = dir.join("stderr");
+        let script = format!(
+            "cat /proc/$$/stat > {}; readlink /proc/$$/fd/2 > {}",
+            stat.display(),
+            stderr.display()
+        );
+
+        let mut command = Command::new("sh");
+        command.args(["-c", &script]);
+        detach(&mut command);
+        let mut child = command.spawn().unwrap();
+        assert!(child.wait().unwrap().success());
+        let session = session_id(&std::fs::read_to_string(&stat).unwrap());
+        assert_eq!(session, child.id().to_string());
+        assert_eq!(std::fs::read_to_string(&stderr).unwrap(), "/dev/null\n");
+
+        // Without detaching it stays in uff's session
+        let own_session = session_id(&std::fs::read_to_string("/proc/self/stat").unwrap());
+        let mut child = Command::new("sh")
+            .args(["-c", &script])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let session = session_id(&std::fs::read_to_string(&stat).unwrap());
+        assert_eq!(session, own_session);
+        assert_ne!(std::fs::read_to_string(&stderr).unwrap(), "/dev/null\n");
+    }
+
+    #[test]
+    fn test_reap() {
+        let child = Command::new("true").spawn().unwrap();
+        let proc = Path::new("/proc").join(child.id().to_string());
+        reap(child);
+        // A zombie keeps its /proc entry until it's waited for
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while proc.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!proc.exists());
+    }
+}
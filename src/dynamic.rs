@@ -0,0 +1,217 @@
+use miette::{Context, IntoDiagnostic, Result, ensure};
+use std::{io::Write, process::Command};
+
+use crate::{
+    config::{
+        self, ComputedDynamicMenu, ComputedHooks, ComputedMenu, ComputedOnEmpty, ComputedPick,
+        ComputedProgram,
+    },
+    input,
+};
+
+/// Shown when the source command printed nothing, selecting it goes back.
+const NOTHING_HERE: &str = "nothing here";
+
+/// What opening a dynamic menu leads to, once its entries are read.
+#[derive(Debug)]
+pub enum Outcome {
+    /// Shows the entries, or a line saying there are none.
+    Menu(ComputedMenu),
+    /// Goes straight back to the menu it was picked from.
+    Back,
+    /// Runs this instead of showing anything.
+    Run(ComputedProgram),
+}
+
+/// Runs the source command and returns its non-empty output lines.
+pub fn read_entries(source_command: &[String]) -> Result<Vec<String>> {
+    let output = Command::new(&source_command[0])
+        .args(&source_command[1..])
+        .output()
+        .into_diagnostic()
+        .with_context(|| format!("failed to run source command: {}", source_command.join(" ")))?;
+    ensure!(
+        output.status.success(),
+        "source command {} exited with {}: {}",
+        source_command.join(" "),
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim(),
+    );
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Shows the entries, or does what the dynamic menu's `on-empty` says when there
+/// are none.
+pub fn outcome(dynamic_menu: &ComputedDynamicMenu, entries: &[String]) -> Outcome {
+    if !entries.is_empty() {
+        return Outcome::Menu(build_menu(&dynamic_menu.args, entries));
+    }
+    match &dynamic_menu.on_empty {
+        ComputedOnEmpty::Message => {
+            Outcome::Menu(build_menu(&dynamic_menu.args, &[NOTHING_HERE.to_string()]))
+        }
+        ComputedOnEmpty::Skip => Outcome::Back,
+        ComputedOnEmpty::Run(command) => Outcome::Run(ComputedProgram {
+            command: command.clone(),
+            env: dynamic_menu.env.clone(),
+            ..dynamic_menu.defaults.program()
+        }),
+    }
+}
+
+/// Builds a menu showing the entries with the given fuzzel args. They're listed
+/// in the same order so fuzzel's index can be used to look them up.
+pub fn build_menu(args: &[String], entries: &[String]) -> ComputedMenu {
+    let mut input = Vec::new();
+    for entry in entries {
+        writeln!(&mut input, "{}", config::sanitize_entry(entry)).unwrap();
+    }
+
+    ComputedMenu {
+        args: args.to_vec(),
+        input,
+        items_offset: 0,
+        back_on_cancel: false,
+        stay_open: false,
+        actions: Vec::new(),
+        labels: Vec::new(),
+        hooks: ComputedHooks::default(),
+        names: Vec::new(),
+        ids: Vec::new(),
+        multi_select: None,
+    }
+}
+
+/// The run command with the original, unescaped entry appended.
+pub fn program_for_entry(dynamic_menu: &ComputedDynamicMenu, entry: &str) -> ComputedProgram {
+    let mut command = dynamic_menu.run_command.clone();
+    command.push(entry.to_owned());
+    ComputedProgram {
+        command,
+        env: dynamic_menu.env.clone(),
+        ..dynamic_menu.defaults.program()
+    }
+}
+
+/// The pick's run command with the original, unescaped line in place of `{}`,
+/// or appended if there is none.
+pub fn program_for_line(pick: &ComputedPick, line: &str) -> ComputedProgram {
+    ComputedProgram {
+        command: input::fill_placeholder(&pick.run_command, line),
+        env: pick.env.clone(),
+        ..pick.defaults.program()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ComputedDefaults;
+
+    #[test]
+    fn test_dynamic_menu_entries() {
+        let dynamic_menu = ComputedDynamicMenu {
+            args: vec!["--prompt".to_string(), "session> ".to_string()],
+            source_command: vec![
+                "printf".to_string(),
+                "main\\n\\nwork\\000x\\037y\\n".to_string(),
+            ],
+            run_command: vec!["tmux".to_string(), "attach".to_string(), "-t".to_string()],
+            on_empty: ComputedOnEmpty::Message,
+            env: Vec::new(),
+            defaults: ComputedDefaults::default(),
+        };
+
+        let entries = read_entries(&dynamic_menu.source_command).unwrap();
+        assert_eq!(entries, ["main", "work\0x\x1fy"]);
+
+        let menu = build_menu(&dynamic_menu.args, &entries);
+        assert_eq!(menu.args, dynamic_menu.args);
+        assert_eq!(menu.input, "main\nwork\u{FFFD}x\u{FFFD}y\n".as_bytes());
+
+        let program = program_for_entry(&dynamic_menu, &entries[1]);
+        assert_eq!(program.command, ["tmux", "attach", "-t", "work\0x\x1fy"]);
+
+        assert!(read_entries(&["false".to_string()]).is_err());
+        assert!(read_entries(&["/nonexistent/command".to_string()]).is_err());
+        assert!(read_entries(&["true".to_string()]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_on_empty() {
+        let dynamic_menu = |source: &str, on_empty: ComputedOnEmpty| ComputedDynamicMenu {
+            args: vec!["--prompt".to_string(), "session> ".to_string()],
+            source_command: vec!["printf".to_string(), source.to_string()],
+            run_command: vec!["tmux".to_string(), "attach".to_string(), "-t".to_string()],
+            on_empty,
+            env: Vec::new(),
+            defaults: ComputedDefaults::default(),
+        };
+        let open = |dynamic_menu: &ComputedDynamicMenu| {
+            let entries = read_entries(&dynamic_menu.source_command).unwrap();
+            outcome(dynamic_menu, &entries)
+        };
+        let notify = vec!["notify-send".to_string(), "no sessions".to_string()];
+
+        // Entries are shown whatever on-empty is
+        for on_empty in [
+            ComputedOnEmpty::Message,
+            ComputedOnEmpty::Skip,
+            ComputedOnEmpty::Run(notify.clone()),
+        ] {
+            let Outcome::Menu(menu) = open(&dynamic_menu("main\\n", on_empty)) else {
+                panic!("Expected a menu");
+            };
+            assert_eq!(menu.input, b"main\n");
+        }
+
+        // Blank lines don't count as entries
+        let Outcome::Menu(menu) = open(&dynamic_menu("\\n\\n", ComputedOnEmpty::Message)) else {
+            panic!("Expected a menu");
+        };
+        assert_eq!(menu.input, b"nothing here\n");
+        assert_eq!(menu.args, ["--prompt", "session> "]);
+        assert!(matches!(
+            open(&dynamic_menu("", ComputedOnEmpty::Skip)),
+            Outcome::Back
+        ));
+        let Outcome::Run(program) = open(&dynamic_menu("", ComputedOnEmpty::Run(notify.clone())))
+        else {
+            panic!("Expected a program");
+        };
+        assert_eq!(program.command, notify);
+    }
+
+    #[test]
+    fn test_pick_lines() {
+        let pick = |run_command: &[&str]| ComputedPick {
+            args: Vec::new(),
+            list_command: vec!["printf".to_string(), "a b\\n$(id)\\n{}\\n".to_string()],
+            run_command: run_command.iter().map(|word| word.to_string()).collect(),
+            env: Vec::new(),
+            defaults: ComputedDefaults::default(),
+        };
+        let attach = pick(&["foot", "tmux", "attach", "-t", "{}"]);
+        let lines = read_entries(&attach.list_command).unwrap();
+        assert_eq!(lines, ["a b", "$(id)", "{}"]);
+
+        // The line stays one argument, without being split or expanded
+        let program = program_for_line(&attach, &lines[0]);
+        assert_eq!(program.command, ["foot", "tmux", "attach", "-t", "a b"]);
+        let program = program_for_line(&attach, &lines[1]);
+        assert_eq!(program.command, ["foot", "tmux", "attach", "-t", "$(id)"]);
+        // A line with braces in it isn't substituted again
+        let program = program_for_line(&pick(&["echo", "{}", "--", "{}"]), &lines[2]);
+        assert_eq!(program.command, ["echo", "{}", "--", "{}"]);
+        let program = program_for_line(&pick(&["open", "--file={}.txt"]), "notes");
+        assert_eq!(program.command, ["open", "--file=notes.txt"]);
+        // Without a placeholder it's appended
+        let program = program_for_line(&pick(&["tmux", "attach", "-t"]), "main");
+        assert_eq!(program.command, ["tmux", "attach", "-t", "main"]);
+    }
+}
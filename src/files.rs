@@ -0,0 +1,180 @@
+use log::warn;
+use miette::{Context, IntoDiagnostic, Result};
+use std::{
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    config::{self, ComputedFilesMenu, ComputedHooks, ComputedMenu, ComputedProgram},
+    glob,
+};
+
+/// Shown instead of the files when there are none, selecting it goes back.
+const NOTHING_HERE: &str = "nothing here";
+
+/// Lists the visible files in the menu's directory that match its pattern,
+/// sorted and cut down to the limit. A missing directory has no files.
+pub fn list_files(files_menu: &ComputedFilesMenu) -> Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(&files_menu.dir) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            warn!("{} does not exist", files_menu.dir);
+            return Ok(Vec::new());
+        }
+        Err(error) => {
+            return Err(error)
+                .into_diagnostic()
+                .with_context(|| format!("failed to list {}", files_menu.dir));
+        }
+    };
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry
+            .into_diagnostic()
+            .with_context(|| format!("failed to list {}", files_menu.dir))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.')
+            || files_menu
+                .pattern
+                .as_ref()
+                .is_some_and(|pattern| !glob::matches(pattern, &name))
+        {
+            continue;
+        }
+        // Follows symlinks, so linked files are listed and broken links aren't
+        let Ok(metadata) = std::fs::metadata(entry.path()) else {
+            continue;
+        };
+        if metadata.is_file() {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, name, entry.path()));
+        }
+    }
+
+    if files_menu.newest_first {
+        files.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    } else {
+        files.sort_by(|a, b| a.1.cmp(&b.1));
+    }
+    if let Some(limit) = files_menu.limit {
+        files.truncate(limit);
+    }
+    Ok(files.into_iter().map(|(_, _, path)| path).collect())
+}
+
+/// Builds a menu showing the file names, in the same order as `files` so
+/// fuzzel's index can be used to look them up.
+pub fn build_menu(files_menu: &ComputedFilesMenu, files: &[PathBuf]) -> ComputedMenu {
+    let mut input = Vec::new();
+    if files.is_empty() {
+        writeln!(&mut input, "{NOTHING_HERE}").unwrap();
+    }
+    for file in files {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        writeln!(&mut input, "{}", config::sanitize_entry(&name)).unwrap();
+    }
+
+    ComputedMenu {
+        args: files_menu.args.clone(),
+        input,
+        items_offset: 0,
+        back_on_cancel: false,
+        stay_open: false,
+        actions: Vec::new(),
+        labels: Vec::new(),
+        hooks: ComputedHooks::default(),
+        names: Vec::new(),
+        ids: Vec::new(),
+        multi_select: None,
+    }
+}
+
+/// The opener with the file's path appended.
+pub fn program_for_file(files_menu: &ComputedFilesMenu, file: &Path) -> ComputedProgram {
+    let mut command = files_menu.open_with.clone();
+    command.push(file.display().to_string());
+    ComputedProgram {
+        command,
+        env: files_menu.env.clone(),
+        ..files_menu.defaults.program()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ComputedDefaults;
+    use std::{fs::File, time::Duration};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_files_menu() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("test-files-menu");
+        std::fs::create_dir_all(dir.join("folder.png")).unwrap();
+        let now = SystemTime::now();
+        for (name, age) in [
+            ("b.png", 30),
+            ("a.png", 20),
+            ("c.png", 10),
+            ("notes.txt", 0),
+            (".hidden.png", 0),
+        ] {
+            let file = File::create(dir.join(name)).unwrap();
+            file.set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+
+        let mut files_menu = ComputedFilesMenu {
+            args: Vec::new(),
+            dir: dir.display().to_string(),
+            pattern: Some("*.png".to_string()),
+            newest_first: false,
+            limit: None,
+            open_with: vec!["imv".to_string()],
+            env: Vec::new(),
+            defaults: ComputedDefaults::default(),
+        };
+        let names = |files_menu: &ComputedFilesMenu| -> Vec<String> {
+            list_files(files_menu)
+                .unwrap()
+                .iter()
+                .map(|file| file.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(names(&files_menu), ["a.png", "b.png", "c.png"]);
+
+        files_menu.newest_first = true;
+        files_menu.limit = Some(2);
+        assert_eq!(names(&files_menu), ["c.png", "a.png"]);
+
+        let files = list_files(&files_menu).unwrap();
+        let menu = build_menu(&files_menu, &files);
+        assert_eq!(menu.input, b"c.png\na.png\n");
+        assert_eq!(
+            program_for_file(&files_menu, &files[0]).command,
+            ["imv".to_string(), dir.join("c.png").display().to_string()]
+        );
+
+        files_menu.pattern = Some("*.jpg".to_string());
+        assert!(list_files(&files_menu).unwrap().is_empty());
+        assert_eq!(build_menu(&files_menu, &[]).input, b"nothing here\n");
+
+        files_menu.dir = dir.join("missing").display().to_string();
+        assert!(list_files(&files_menu).unwrap().is_empty());
+
+        // A name with a newline stays one entry, and the original path is opened
+        files_menu.dir = dir.display().to_string();
+        files_menu.pattern = Some("*.jpg".to_string());
+        File::create(dir.join("two\nlines\x1f.jpg")).unwrap();
+        let files = list_files(&files_menu).unwrap();
+        let menu = build_menu(&files_menu, &files);
+        assert_eq!(menu.input, "two\u{2424}lines\u{FFFD}.jpg\n".as_bytes());
+        assert_eq!(
+            program_for_file(&files_menu, &files[0]).command[1],
+            dir.join("two\nlines\x1f.jpg").display().to_string()
+        );
+    }
+}
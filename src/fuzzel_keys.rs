@@ -0,0 +1,250 @@
+/// What a fuzzel.ini option accepts, only used to catch obvious mistakes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    Text,
+    Bool,
+    Integer,
+    /// Hex `RRGGBBAA`.
+    Color,
+}
+
+impl ValueKind {
+    pub fn accepts(self, value: &str) -> bool {
+        match self {
+            Self::Text => true,
+            Self::Bool => matches!(
+                value.to_lowercase().as_str(),
+                "yes" | "no" | "true" | "false" | "on" | "off" | "1" | "0"
+            ),
+            Self::Integer => value.parse::<i64>().is_ok(),
+            Self::Color => value.len() == 8 && value.chars().all(|c| c.is_ascii_hexdigit()),
+        }
+    }
+
+    pub const fn describe(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Bool => "yes or no",
+            Self::Integer => "a whole number",
+            Self::Color => "a color like ff0000ff",
+        }
+    }
+}
+
+use ValueKind::{Bool, Color, Integer, Text};
+
+const MAIN: &[(&str, ValueKind)] = &[
+    ("include", Text),
+    ("output", Text),
+    ("font", Text),
+    ("dpi-aware", Text),
+    ("use-bold", Bool),
+    ("prompt", Text),
+    ("placeholder", Text),
+    ("icon-theme", Text),
+    ("icons-enabled", Bool),
+    ("hide-before-typing", Bool),
+    ("fields", Text),
+    ("password-character", Text),
+    ("filter-desktop", Bool),
+    ("match-mode", Text),
+    ("sort-result", Bool),
+    ("match-counter", Bool),
+    ("delayed-filter-ms", Integer),
+    ("delayed-filter-limit", Integer),
+    ("show-actions", Bool),
+    ("terminal", Text),
+    ("launch-prefix", Text),
+    ("list-executables-in-path", Bool),
+    ("anchor", Text),
+    ("x-margin", Integer),
+    ("y-margin", Integer),
+    ("lines", Integer),
+    ("minimal-lines", Bool),
+    ("width", Integer),
+    ("tabs", Integer),
+    ("horizontal-pad", Integer),
+    ("vertical-pad", Integer),
+    ("inner-pad", Integer),
+    ("image-size-ratio", Text),
+    ("line-height", Text),
+    ("letter-spacing", Text),
+    ("layer", Text),
+    ("keyboard-focus", Text),
+    ("exit-on-keyboard-focus-loss", Bool),
+    ("cache", Text),
+    ("render-workers", Integer),
+    ("match-workers", Integer),
+    ("namespace", Text),
+    ("gamma-correct-blending", Bool),
+    ("hide-prompt", Bool),
+    ("auto-select", Bool),
+    ("enable-mouse", Bool),
+];
+
+const COLORS: &[(&str, ValueKind)] = &[
+    ("background", Color),
+    ("text", Color),
+    ("prompt", Color),
+    ("placeholder", Color),
+    ("input", Color),
+    ("match", Color),
+    ("selection", Color),
+    ("selection-text", Color),
+    ("selection-match", Color),
+    ("counter", Color),
+    ("border", Color),
+];
+
+const BORDER: &[(&str, ValueKind)] = &[
+    ("width", Integer),
+    ("radius", Integer),
+    ("selection-radius", Integer),
+];
+
+const DMENU: &[(&str, ValueKind)] = &[("mode", Text), ("exit-immediately-if-empty", Bool)];
+
+// custom-1 to custom-19 are handled separately
+const KEY_BINDINGS: &[(&str, ValueKind)] = &[
+    ("cancel", Text),
+    ("execute", Text),
+    ("execute-or-next", Text),
+    ("execute-input", Text),
+    ("cursor-left", Text),
+    ("cursor-left-word", Text),
+    ("cursor-right", Text),
+    ("cursor-right-word", Text),
+    ("cursor-home", Text),
+    ("cursor-end", Text),
+    ("delete-prev", Text),
+    ("delete-prev-word", Text),
+    ("delete-line-backward", Text),
+    ("delete-next", Text),
+    ("delete-next-word", Text),
+    ("delete-line-forward", Text),
+    ("delete-line", Text),
+    ("prev", Text),
+    ("prev-with-wrap", Text),
+    ("prev-page", Text),
+    ("next", Text),
+    ("next-with-wrap", Text),
+    ("next-page", Text),
+    ("first", Text),
+    ("last", Text),
+    ("insert-selected", Text),
+    ("expunge", Text),
+    ("clipboard-paste", Text),
+    ("primary-paste", Text),
+];
+
+/// Options in each ini section, keys outside of a section belong to `main`.
+pub const SECTIONS: &[(&str, &[(&str, ValueKind)])] = &[
+    ("main", MAIN),
+    ("colors", COLORS),
+    ("border", BORDER),
+    ("dmenu", DMENU),
+    ("key-bindings", KEY_BINDINGS),
+];
+
+fn section_keys(section: &str) -> Option<&'static [(&'static str, ValueKind)]> {
+    SECTIONS
+        .iter()
+        .find(|(name, _)| *name == section)
+        .map(|(_, keys)| *keys)
+}
+
+pub fn is_known_section(section: &str) -> bool {
+    section_keys(section).is_some()
+}
+
+/// The kind of value a key takes, or `None` if fuzzel doesn't know it.
+pub fn lookup(section: &str, key: &str) -> Option<ValueKind> {
+    if section == "key-bindings"
+        && let Some(number) = key.strip_prefix("custom-")
+        && number
+            .parse::<u8>()
+            .is_ok_and(|number| (1..=19).contains(&number))
+    {
+        return Some(Text);
+    }
+    section_keys(section)?
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, kind)| *kind)
+}
+
+/// The known name that is closest to a misspelled one.
+pub fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= (name.len() / 3).max(2))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+pub fn closest_key(section: &str, key: &str) -> Option<&'static str> {
+    closest(key, section_keys(section)?.iter().map(|(name, _)| *name))
+}
+
+pub fn closest_section(section: &str) -> Option<&'static str> {
+    closest(section, SECTIONS.iter().map(|(name, _)| *name))
+}
+
+/// Levenshtein distance between two strings.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzzel_key_schema() {
+        // No duplicate keys within a section
+        for (section, keys) in SECTIONS {
+            for (i, (key, _)) in keys.iter().enumerate() {
+                assert!(
+                    !keys[i + 1..].iter().any(|(other, _)| other == key),
+                    "{section}.{key} is listed twice"
+                );
+            }
+        }
+
+        assert_eq!(lookup("main", "font"), Some(Text));
+        assert_eq!(lookup("colors", "background"), Some(Color));
+        assert_eq!(lookup("key-bindings", "custom-19"), Some(Text));
+        assert_eq!(lookup("key-bindings", "custom-20"), None);
+        assert_eq!(lookup("main", "fontt"), None);
+        assert_eq!(lookup("colours", "background"), None);
+        assert!(is_known_section("dmenu"));
+        assert!(!is_known_section("colours"));
+
+        assert_eq!(closest_key("main", "fontt"), Some("font"));
+        assert_eq!(
+            closest_key("colors", "selection-txt"),
+            Some("selection-text")
+        );
+        assert_eq!(closest_key("main", "something-else-entirely"), None);
+        assert_eq!(closest_section("colours"), Some("colors"));
+
+        assert!(Bool.accepts("yes") && Bool.accepts("False") && !Bool.accepts("maybe"));
+        assert!(Integer.accepts("-3") && !Integer.accepts("3px"));
+        assert!(Color.accepts("ff00ffAA") && !Color.accepts("#ff00ff"));
+
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("same", "same"), 0);
+    }
+}
@@ -0,0 +1,121 @@
+use std::path::{Component, Path, PathBuf};
+
+pub fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+/// Matches `text` against a shell-style pattern supporting `*`, `?` and `[...]`.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_from(&pattern, &text)
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len()).any(|skip| matches_from(&pattern[1..], &text[skip..])),
+        Some('?') => !text.is_empty() && matches_from(&pattern[1..], &text[1..]),
+        Some('[') => {
+            let Some((set_len, matched)) = text.first().and_then(|c| match_set(&pattern[1..], *c))
+            else {
+                // unterminated sets are matched literally
+                return text.first() == Some(&'[') && matches_from(&pattern[1..], &text[1..]);
+            };
+            matched && matches_from(&pattern[set_len + 2..], &text[1..])
+        }
+        Some(c) => text.first() == Some(c) && matches_from(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Returns the length of the set body (excluding brackets) and whether `c` is in it.
+fn match_set(set: &[char], c: char) -> Option<(usize, bool)> {
+    let (negated, start) = match set.first() {
+        Some('!' | '^') => (true, 1),
+        _ => (false, 0),
+    };
+    // a ']' directly after the opening bracket is part of the set
+    let end = start + 1 + set.get(start + 1..)?.iter().position(|&x| x == ']')?;
+    let body = &set[start..end];
+
+    let mut found = false;
+    let mut i = 0;
+    while i < body.len() {
+        if i + 2 < body.len() && body[i + 1] == '-' {
+            found |= (body[i]..=body[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= body[i] == c;
+            i += 1;
+        }
+    }
+    Some((end, found != negated))
+}
+
+/// Expands a path pattern against the filesystem, one component at a time.
+/// Results are sorted so expansion is deterministic.
+pub fn expand(pattern: &Path) -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::new()];
+
+    for component in pattern.components() {
+        let Component::Normal(part) = component else {
+            for candidate in &mut candidates {
+                candidate.push(component);
+            }
+            continue;
+        };
+
+        let part = part.to_string_lossy();
+        if !is_pattern(&part) {
+            for candidate in &mut candidates {
+                candidate.push(part.as_ref());
+            }
+            continue;
+        }
+
+        let mut next = Vec::new();
+        for candidate in &candidates {
+            let dir = if candidate.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                candidate.as_path()
+            };
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.filter_map(Result::ok) {
+                let name = entry.file_name().to_string_lossy().to_string();
+                // like shells, only match hidden entries when asked to explicitly
+                if name.starts_with('.') && !part.starts_with('.') {
+                    continue;
+                }
+                if matches(&part, &name) {
+                    next.push(candidate.join(name));
+                }
+            }
+        }
+        next.sort();
+        candidates = next;
+    }
+
+    candidates.retain(|path| path.exists());
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matching() {
+        assert!(matches("Papirus*", "Papirus-Dark"));
+        assert!(matches("Papirus*", "Papirus"));
+        assert!(!matches("Papirus*", "Adwaita"));
+        assert!(matches("??x??", "48x48"));
+        assert!(!matches("??x??", "128x128"));
+        assert!(matches("[0-9]*", "16x16"));
+        assert!(!matches("[!0-9]*", "16x16"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("a[b", "a[b"));
+    }
+}
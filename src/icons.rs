@@ -0,0 +1,1801 @@
+//! Finding an icon's file from its name. Data dirs (ones with an `icons` dir
+//! in them, like the XDG ones) are searched the way the freedesktop icon theme
+//! spec says: the theme, the themes it inherits, hicolor and then icons outside
+//! of any theme. Other icon dirs are searched through for a file with the
+//! icon's name.
+
+use bitcode::{Decode, Encode};
+use log::{debug, error, info, warn};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    ffi::OsStr,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    desktop, fuzzel_keys,
+    parser::{IconDir, IconFormat, IconMatch, IconWalk, SymbolicIcons},
+};
+
+/// The theme every theme falls back to.
+const FALLBACK_THEME: &str = "hicolor";
+
+/// What decides between the files an icon could be.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lookup<'a> {
+    /// The menu's icon theme, only hicolor is searched without one.
+    pub theme: Option<&'a str>,
+    /// In pixels, without one the biggest icons win.
+    pub size: Option<u32>,
+    /// Wins over the other format wherever both are found together.
+    pub prefer: IconFormat,
+    /// Whether `Firefox.png` counts for `firefox` outside of themes, whose
+    /// names are always exact.
+    pub matching: IconMatch,
+    /// Whether `name-symbolic` can stand in for `name`, and the other way around.
+    pub symbolic: SymbolicIcons,
+    /// For icon dirs that don't set their own.
+    pub walk: IconWalk,
+}
+
+impl Lookup<'_> {
+    /// The file types looked for, the preferred one first. Fuzzel can show
+    /// xpm too, which only wins when there's nothing else.
+    fn extensions(&self) -> [&'static str; 3] {
+        match self.prefer {
+            IconFormat::Svg => ["svg", "png", "xpm"],
+            IconFormat::Png => ["png", "svg", "xpm"],
+        }
+    }
+
+    /// The names to look for, in order. Each is looked for in every tier before
+    /// the next one, so a plain icon anywhere wins over a symbolic one unless
+    /// they're preferred.
+    fn names(&self, icon: &str) -> Vec<String> {
+        let plain = icon.strip_suffix("-symbolic").unwrap_or(icon);
+        if plain.is_empty() {
+            return vec![icon.to_string()];
+        }
+        let symbolic = format!("{plain}-symbolic");
+        match self.symbolic {
+            SymbolicIcons::Allow => vec![icon.to_string()],
+            SymbolicIcons::Prefer => vec![symbolic, plain.to_string()],
+            SymbolicIcons::Avoid => vec![plain.to_string(), symbolic],
+        }
+    }
+
+    /// Whether the file is the icon, and if so whether its name's case differs
+    /// (which loses to one that doesn't) and where its type is in `extensions`.
+    fn name_rank(&self, icon: &str, file_name: &OsStr) -> Option<(bool, u8)> {
+        let (stem, extension) = file_name.to_str()?.rsplit_once('.')?;
+        let ignore_case = self.matching == IconMatch::IgnoreCase;
+        let same = |found: &str, wanted: &str| {
+            found == wanted || (ignore_case && found.eq_ignore_ascii_case(wanted))
+        };
+        if !same(stem, icon) {
+            return None;
+        }
+        let format = self
+            .extensions()
+            .iter()
+            .position(|wanted| same(extension, wanted))?;
+        let case_differs = stem != icon || extension != self.extensions()[format];
+        Some((case_differs, format as u8))
+    }
+}
+
+/// The desktop's icon theme, for configs without an `icon-theme`: GTK's
+/// `settings.ini` in `config_dir`, then GNOME's setting. `None` if neither
+/// has one, then only hicolor is searched.
+pub fn detect_theme(config_dir: &Path) -> Option<String> {
+    let settings = config_dir.join("gtk-3.0/settings.ini");
+    if let Some(theme) = gtk_settings_theme(&settings) {
+        debug!("icon theme {theme} is from {}", settings.display());
+        return Some(theme);
+    }
+    let theme = gsettings_theme()?;
+    debug!("icon theme {theme} is from gsettings");
+    Some(theme)
+}
+
+/// `gtk-icon-theme-name` from the `[Settings]` of a GTK `settings.ini`.
+fn gtk_settings_theme(path: &Path) -> Option<String> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let mut in_settings = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_settings = line == "[Settings]";
+        } else if in_settings
+            && let Some((key, value)) = line.split_once('=')
+            && key.trim() == "gtk-icon-theme-name"
+        {
+            return theme_name(value);
+        }
+    }
+    None
+}
+
+fn gsettings_theme() -> Option<String> {
+    let output = Command::new("gsettings")
+        .args(["get", "org.gnome.desktop.interface", "icon-theme"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    theme_name(std::str::from_utf8(&output.stdout).ok()?)
+}
+
+/// The name without the quotes gsettings (and some tools writing
+/// `settings.ini`) put around it.
+fn theme_name(value: &str) -> Option<String> {
+    let value = value.trim();
+    let name = ['\'', '"']
+        .iter()
+        .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+        .unwrap_or(value);
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Icons found while building a config, kept in its cache so the next build
+/// only searches again for icons in dirs that changed.
+#[derive(Encode, Decode, Debug, Default, Clone)]
+pub struct IconCache {
+    /// Every dir an icon was searched in, with how it looked then.
+    dirs: Vec<(String, Option<Fingerprint>)>,
+    icons: Vec<CachedIcon>,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+struct CachedIcon {
+    name: String,
+    /// Each tier of icon dirs it was looked for in, with how they're walked.
+    search_dirs: Vec<String>,
+    /// The rest of the `Lookup`, formatted.
+    lookup: String,
+    /// `None` if it wasn't found.
+    path: Option<String>,
+    /// Names close to it when it wasn't found, for `MissingIcon`.
+    similar: Vec<String>,
+    /// Indices into `IconCache::dirs`, for the dirs whose files were looked at.
+    scanned: Vec<u32>,
+}
+
+/// How a dir looked, to notice icons being added to or removed from it.
+/// `None` for one that doesn't exist.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+struct Fingerprint {
+    /// In nanoseconds since the epoch.
+    modified: u64,
+    entries: u32,
+}
+
+fn fingerprint(dir: &Path) -> Option<Fingerprint> {
+    let modified = desktop::modified(dir)?;
+    let entries = std::fs::read_dir(dir).ok()?.count();
+    Some(Fingerprint {
+        modified,
+        entries: u32::try_from(entries).unwrap_or(u32::MAX),
+    })
+}
+
+impl IconCache {
+    /// Whether every dir searched still looks the same, so the icons found
+    /// would be found again.
+    pub fn unchanged(&self) -> bool {
+        self.dirs
+            .iter()
+            .all(|(dir, before)| fingerprint(Path::new(dir)) == *before)
+    }
+
+    /// Whether an icon wasn't found.
+    pub fn has_missing(&self) -> bool {
+        self.icons.iter().any(|icon| icon.path.is_none())
+    }
+
+    /// Drops the icons that weren't found, so they're searched for again.
+    pub fn forget_missing(&mut self) {
+        self.icons.retain(|icon| icon.path.is_some());
+    }
+}
+
+/// An icon name that wasn't found anywhere it was looked for.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct MissingIcon {
+    /// The path to the item from the root menu, like `menu_path`.
+    pub item: String,
+    pub name: String,
+    /// The icon dirs searched, in the order they were searched, or none for a
+    /// path that doesn't exist.
+    pub dirs: Vec<String>,
+    /// Icon names close to it, closest first.
+    pub similar: Vec<String>,
+}
+
+/// Finds the icons for one build of a config, see `IconCache`.
+pub struct IconFinder {
+    /// Icons from the last build whose dirs haven't changed since.
+    previous: IconCache,
+    found: IconCache,
+    /// Where each dir is in `found.dirs`.
+    dir_indices: HashMap<String, u32>,
+    /// Shared by every icon looked for in this build.
+    index: DirIndex,
+    missing: Vec<MissingIcon>,
+}
+
+impl IconFinder {
+    pub fn new(mut previous: IconCache) -> Self {
+        let unchanged: Vec<bool> = previous
+            .dirs
+            .iter()
+            .map(|(dir, before)| fingerprint(Path::new(dir)) == *before)
+            .collect();
+        previous
+            .icons
+            .retain(|icon| icon.scanned.iter().all(|&index| unchanged[index as usize]));
+        Self {
+            previous,
+            found: IconCache::default(),
+            dir_indices: HashMap::new(),
+            index: DirIndex::default(),
+            missing: Vec::new(),
+        }
+    }
+
+    /// The icon's file, reused from the last build when its dirs are unchanged.
+    /// Names that aren't found are kept for `missing`, with the item they're for.
+    pub fn find(
+        &mut self,
+        item: &str,
+        name: &str,
+        tiers: &[&[IconDir]],
+        lookup: &Lookup,
+    ) -> Option<PathBuf> {
+        // Paths aren't searched for, whether they exist is checked with the config
+        if name.contains('/') {
+            info!("icon name contains a '/', treating as full path: {name}");
+            return None;
+        }
+        let search_dirs: Vec<String> = tiers.iter().map(|tier| format!("{tier:?}")).collect();
+        let lookup_key = format!("{lookup:?}");
+        let same = |icon: &&CachedIcon| {
+            icon.name == name && icon.search_dirs == search_dirs && icon.lookup == lookup_key
+        };
+        if let Some(icon) = self.found.icons.iter().find(same) {
+            let (path, similar) = (icon.path.clone(), icon.similar.clone());
+            if path.is_none() {
+                self.not_found(item, name, tiers, similar);
+            }
+            return path.map(PathBuf::from);
+        }
+
+        let (path, similar, mut scanned) = if let Some(icon) = self.previous.icons.iter().find(same)
+        {
+            match &icon.path {
+                Some(path) => debug!("icon '{name}' is where it was last time: {path}"),
+                None => debug!("icon '{name}' wasn't found last time, its dirs are unchanged"),
+            }
+            let scanned: Vec<(String, Option<Fingerprint>)> = icon
+                .scanned
+                .iter()
+                .map(|&index| self.previous.dirs[index as usize].clone())
+                .collect();
+            let (path, similar) = (icon.path.clone(), icon.similar.clone());
+            let scanned = scanned
+                .into_iter()
+                .map(|(dir, before)| self.dir_index(dir, || before))
+                .collect();
+            (path, similar, scanned)
+        } else {
+            let mut scanned = Vec::new();
+            let mut near_misses = NearMisses::new(name);
+            let path = find_icon(
+                name,
+                tiers,
+                lookup,
+                &mut self.index,
+                &mut scanned,
+                &mut near_misses,
+            );
+            let scanned: Vec<u32> = scanned
+                .into_iter()
+                .map(|dir| self.dir_index(dir.display().to_string(), || fingerprint(&dir)))
+                .collect();
+            let similar = match path {
+                Some(_) => Vec::new(),
+                None => near_misses.names().into_iter().map(String::from).collect(),
+            };
+            (
+                path.map(|path| path.display().to_string()),
+                similar,
+                scanned,
+            )
+        };
+        scanned.sort_unstable();
+        scanned.dedup();
+        if path.is_none() {
+            self.not_found(item, name, tiers, similar.clone());
+        }
+        // Misses are kept too, so they're only searched for again once their dirs change
+        self.found.icons.push(CachedIcon {
+            name: name.to_string(),
+            search_dirs,
+            lookup: lookup_key,
+            path: path.clone(),
+            similar,
+            scanned,
+        });
+        path.map(PathBuf::from)
+    }
+
+    fn not_found(&mut self, item: &str, name: &str, tiers: &[&[IconDir]], similar: Vec<String>) {
+        if similar.is_empty() {
+            error!("icon '{name}' not found in specified directories");
+        } else {
+            error!(
+                "icon '{name}' not found in specified directories, similar: {}",
+                similar.join(", ")
+            );
+        }
+        self.missing.push(MissingIcon {
+            item: item.to_string(),
+            name: name.to_string(),
+            dirs: tiers
+                .iter()
+                .flat_map(|tier| tier.iter())
+                .map(|dir| dir.path.display().to_string())
+                .collect(),
+            similar,
+        });
+    }
+
+    /// Where the dir is in `found.dirs`, adding it the first time. Each dir is
+    /// only fingerprinted once per build.
+    fn dir_index(&mut self, dir: String, fingerprint: impl FnOnce() -> Option<Fingerprint>) -> u32 {
+        *self.dir_indices.entry(dir).or_insert_with_key(|dir| {
+            self.found.dirs.push((dir.clone(), fingerprint()));
+            u32::try_from(self.found.dirs.len() - 1).unwrap()
+        })
+    }
+
+    /// The icons found in this build, to be cached with it, and the ones that
+    /// weren't.
+    pub fn finish(self) -> (IconCache, Vec<MissingIcon>) {
+        (self.found, self.missing)
+    }
+}
+
+/// The icon's file, or `None` if it can't be found. The tiers are
+/// searched in order and the first with the icon anywhere in it wins, so a file
+/// in an earlier tier is used even if a later one has a better one. Each dir
+/// whose files were looked at is added to `scanned`, and the names of the
+/// files in them that come close to the icon's to `near_misses`.
+fn find_icon(
+    name: &str,
+    tiers: &[&[IconDir]],
+    lookup: &Lookup,
+    index: &mut DirIndex,
+    scanned: &mut Vec<PathBuf>,
+    near_misses: &mut NearMisses,
+) -> Option<PathBuf> {
+    lookup.names(name).iter().find_map(|variant| {
+        let found = tiers
+            .iter()
+            .find_map(|dirs| search_tier(variant, dirs, lookup, index, scanned, near_misses))?;
+        if variant != name {
+            debug!("icon '{name}' is using {variant}: {}", found.display());
+        }
+        Some(found)
+    })
+}
+
+/// The file names closest to an icon's, from the dirs read while looking for
+/// it. Only a few are kept, and ones that are too different are dropped without
+/// comparing them, so this stays cheap on huge icon packs.
+struct NearMisses<'a> {
+    name: &'a str,
+    /// Closest first, at most `MAX_SUGGESTIONS`.
+    closest: Vec<(usize, String)>,
+}
+
+/// How many similar names are suggested for an icon that can't be found.
+const MAX_SUGGESTIONS: usize = 5;
+
+impl<'a> NearMisses<'a> {
+    const fn new(name: &'a str) -> Self {
+        Self {
+            name,
+            closest: Vec::new(),
+        }
+    }
+
+    /// Keeps the file's name, without its extension, if it's an icon and one
+    /// of the closest so far.
+    fn offer(&mut self, file_name: &OsStr) {
+        let Some((stem, extension)) = file_name.to_str().and_then(|name| name.rsplit_once('.'))
+        else {
+            return;
+        };
+        let is_icon = ["svg", "png", "xpm"]
+            .iter()
+            .any(|wanted| extension.eq_ignore_ascii_case(wanted));
+        // Like fuzzel_keys::closest, a third of the name can be different
+        let max_distance = (self.name.len() / 3).max(2);
+        if !is_icon || stem == self.name || stem.len().abs_diff(self.name.len()) > max_distance {
+            return;
+        }
+        // A different case is the closest miss, since it's what exact matching missed
+        let distance = fuzzel_keys::edit_distance(&self.name.to_lowercase(), &stem.to_lowercase());
+        if distance > max_distance || self.closest.iter().any(|(_, seen)| seen == stem) {
+            return;
+        }
+        let at = self
+            .closest
+            .partition_point(|(other, seen)| (*other, seen.as_str()) <= (distance, stem));
+        if at < MAX_SUGGESTIONS {
+            self.closest.insert(at, (distance, stem.to_string()));
+            self.closest.truncate(MAX_SUGGESTIONS);
+        }
+    }
+
+    fn names(&self) -> Vec<&str> {
+        self.closest.iter().map(|(_, name)| name.as_str()).collect()
+    }
+}
+
+/// Plain dirs are searched first, in order, then the themes in every data dir
+/// of the tier together.
+fn search_tier(
+    name: &str,
+    dirs: &[IconDir],
+    lookup: &Lookup,
+    index: &mut DirIndex,
+    scanned: &mut Vec<PathBuf>,
+    near_misses: &mut NearMisses,
+) -> Option<PathBuf> {
+    let mut base_dirs = Vec::new();
+    let mut pixmap_dirs = Vec::new();
+    for IconDir { path: dir, walk } in dirs {
+        let icons = dir.join("icons");
+        // Creating icons/ in a plain dir changes how it's searched
+        scanned.push(dir.clone());
+        if icons.is_dir() {
+            base_dirs.push(icons);
+            pixmap_dirs.push(dir.join("pixmaps"));
+        } else if let Some(path) = search_dir(
+            name,
+            dir,
+            walk.or(lookup.walk),
+            lookup,
+            index,
+            scanned,
+            near_misses,
+        ) {
+            return Some(path);
+        }
+    }
+    if base_dirs.is_empty() {
+        return None;
+    }
+
+    let themes = Themes {
+        base_dirs: &base_dirs,
+        lookup,
+    };
+    let mut visited = Vec::new();
+    lookup
+        .theme
+        .and_then(|theme| themes.find(name, theme, &mut visited, scanned))
+        .or_else(|| themes.find(name, FALLBACK_THEME, &mut visited, scanned))
+        .or_else(|| {
+            let loose_dirs = base_dirs.iter().chain(&pixmap_dirs);
+            scanned.extend(loose_dirs.clone().cloned());
+            loose_icon(name, loose_dirs, lookup, index, near_misses)
+        })
+}
+
+/// The best of the files in `dir` (or under it, as far as `walk` allows) named
+/// after the icon, see `icon_rank`. Ties go to the first path in alphabetical order.
+fn search_dir(
+    name: &str,
+    dir: &Path,
+    walk: IconWalk,
+    lookup: &Lookup,
+    index: &mut DirIndex,
+    scanned: &mut Vec<PathBuf>,
+    near_misses: &mut NearMisses,
+) -> Option<PathBuf> {
+    let indexed = index.get(dir, walk);
+    scanned.extend(indexed.dirs.iter().cloned());
+    let found = indexed
+        .matches(name)
+        .filter_map(|path| {
+            let name_rank = lookup.name_rank(name, path.file_name()?)?;
+            Some((icon_rank(dir, path, name_rank, lookup), path))
+        })
+        .min();
+    let Some(((case_differs, ..), path)) = found else {
+        indexed.offer_all(near_misses);
+        return None;
+    };
+    if case_differs {
+        debug!("icon '{name}' matched {} ignoring case", path.display());
+    }
+    Some(path.clone())
+}
+
+/// The icon files in each plain dir searched in a build, so a dir is only
+/// walked once however many icons are looked for in it. Dirs are indexed the
+/// first time they're searched.
+#[derive(Default)]
+pub struct DirIndex {
+    dirs: HashMap<(PathBuf, IconWalk), IndexedDir>,
+}
+
+struct IndexedDir {
+    /// The svg, png and xpm files by their stem in lowercase.
+    icons: HashMap<String, Vec<PathBuf>>,
+    /// Every dir whose files were looked at, changes to them change the icons.
+    dirs: Vec<PathBuf>,
+}
+
+impl DirIndex {
+    fn get(&mut self, dir: &Path, walk: IconWalk) -> &IndexedDir {
+        self.dirs
+            .entry((dir.to_path_buf(), walk))
+            .or_insert_with(|| IndexedDir::walk(dir, walk))
+    }
+}
+
+impl IndexedDir {
+    fn walk(dir: &Path, walk: IconWalk) -> Self {
+        let include_hidden = walk.include_hidden.unwrap_or(false);
+        let mut walker = WalkDir::new(dir).follow_links(walk.follow_symlinks.unwrap_or(false));
+        if let Some(max_depth) = walk.max_depth {
+            walker = walker.max_depth(max_depth as usize);
+        }
+        let mut indexed = Self {
+            icons: HashMap::new(),
+            dirs: Vec::new(),
+        };
+        let entries = walker.into_iter().filter_entry(|entry| {
+            include_hidden
+                || entry.depth() == 0
+                || !entry.file_type().is_dir()
+                || !entry.file_name().as_encoded_bytes().starts_with(b".")
+        });
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    match error.loop_ancestor() {
+                        // walkdir stops at a link back to a dir it's already in
+                        Some(ancestor) => warn!(
+                            "skipping symlink loop in icon dir at {}, it leads back to {}",
+                            error.path().unwrap_or(dir).display(),
+                            ancestor.display()
+                        ),
+                        None => debug!("skipping in icon dir: {error}"),
+                    }
+                    continue;
+                }
+            };
+            if entry.file_type().is_dir() {
+                // The files in one at the max depth aren't looked at
+                if walk
+                    .max_depth
+                    .is_none_or(|max_depth| entry.depth() < max_depth as usize)
+                {
+                    indexed.dirs.push(entry.into_path());
+                }
+                continue;
+            }
+            let Some((stem, extension)) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.rsplit_once('.'))
+            else {
+                continue;
+            };
+            if ["svg", "png", "xpm"]
+                .iter()
+                .any(|wanted| extension.eq_ignore_ascii_case(wanted))
+            {
+                indexed
+                    .icons
+                    .entry(stem.to_ascii_lowercase())
+                    .or_default()
+                    .push(entry.into_path());
+            }
+        }
+        indexed
+    }
+
+    /// The files that could be the icon, those whose name is the same ignoring case.
+    fn matches(&self, name: &str) -> impl Iterator<Item = &PathBuf> {
+        self.icons
+            .get(&name.to_ascii_lowercase())
+            .into_iter()
+            .flatten()
+    }
+
+    /// Offers each name once, however many sizes it comes in.
+    fn offer_all(&self, near_misses: &mut NearMisses) {
+        for paths in self.icons.values() {
+            if let Some(file_name) = paths.first().and_then(|path| path.file_name()) {
+                near_misses.offer(file_name);
+            }
+        }
+    }
+}
+
+/// Lower is better. Files named exactly like the icon come first, then the
+/// preferred format, then sized icons by how close they are to the size (or
+/// the largest without one), then ones of unknown size. Sizes come from a
+/// `NNxNN` dir under `dir`, or a PNG's header.
+fn icon_rank(
+    dir: &Path,
+    path: &Path,
+    (case_differs, format): (bool, u8),
+    lookup: &Lookup,
+) -> (bool, u8, u8, u32, Reverse<u32>) {
+    if path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("svg"))
+    {
+        return (case_differs, format, 0, 0, Reverse(0));
+    }
+    let pixels = path
+        .strip_prefix(dir)
+        .unwrap()
+        .components()
+        .filter_map(|component| icon_dir_size(component.as_os_str().to_str()?))
+        .next_back()
+        .or_else(|| png_width(path));
+    match pixels {
+        Some(pixels) => (
+            case_differs,
+            format,
+            0,
+            lookup.size.map_or(0, |size| size.abs_diff(pixels)),
+            Reverse(pixels),
+        ),
+        None => (case_differs, format, 1, 0, Reverse(0)),
+    }
+}
+
+/// The width in a PNG's `IHDR` header, which always comes first.
+fn png_width(path: &Path) -> Option<u32> {
+    let mut header = [0; 24];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    let is_png = header.starts_with(b"\x89PNG\r\n\x1a\n") && &header[12..16] == b"IHDR";
+    is_png.then(|| u32::from_be_bytes(header[16..20].try_into().unwrap()))
+}
+
+/// The size of a theme dir like `48x48`, or `48x48@2` which holds 96 pixel icons.
+fn icon_dir_size(component: &str) -> Option<u32> {
+    let (dimensions, scale) = match component.split_once('@') {
+        Some((dimensions, scale)) => (dimensions, scale.parse::<u32>().ok()?),
+        None => (component, 1),
+    };
+    let (width, height) = dimensions.split_once('x')?;
+    let width = width.parse::<u32>().ok()?;
+    (height.parse::<u32>().ok()? == width).then(|| width.saturating_mul(scale))
+}
+
+/// `name.svg`, `name.png` or `name.xpm` directly in one of the dirs, for icons
+/// that aren't in any theme. Without one, a file whose name only differs in
+/// case, like the `Firefox.png` some apps put in `pixmaps`.
+fn loose_icon<'a>(
+    name: &str,
+    dirs: impl IntoIterator<Item = &'a PathBuf>,
+    lookup: &Lookup,
+    index: &mut DirIndex,
+    near_misses: &mut NearMisses,
+) -> Option<PathBuf> {
+    let dirs: Vec<&PathBuf> = dirs.into_iter().collect();
+    let extensions = lookup.extensions();
+    let exact = dirs
+        .iter()
+        .flat_map(|dir| {
+            extensions
+                .iter()
+                .map(move |extension| dir.join(format!("{name}.{extension}")))
+        })
+        .find(|path| path.is_file());
+    if exact.is_some() {
+        return exact;
+    }
+    // Only the files right in the dir, like read_dir
+    let walk = IconWalk {
+        max_depth: Some(1),
+        follow_symlinks: Some(true),
+        include_hidden: Some(false),
+    };
+    // Read even with exact matching, for the near misses
+    let path = dirs.iter().find_map(|dir| {
+        let indexed = index.get(dir, walk);
+        let found = indexed
+            .matches(name)
+            .filter_map(|path| Some((lookup.name_rank(name, path.file_name()?)?, path)))
+            .min()
+            .map(|(_, path)| path.clone());
+        if found.is_none() {
+            indexed.offer_all(near_misses);
+        }
+        found
+    })?;
+    debug!("icon '{name}' matched {} ignoring case", path.display());
+    Some(path)
+}
+
+/// The `icons` dirs a theme can have files in, the first one with its
+/// `index.theme` describes it.
+struct Themes<'a> {
+    base_dirs: &'a [PathBuf],
+    lookup: &'a Lookup<'a>,
+}
+
+impl Themes<'_> {
+    /// Looks in the theme and then the ones it inherits, skipping the ones in
+    /// `visited` so each is only searched once even if they inherit in a loop.
+    fn find(
+        &self,
+        icon: &str,
+        theme: &str,
+        visited: &mut Vec<String>,
+        scanned: &mut Vec<PathBuf>,
+    ) -> Option<PathBuf> {
+        if visited.iter().any(|seen| seen == theme) {
+            return None;
+        }
+        visited.push(theme.to_string());
+        // Installing or removing the theme changes these
+        scanned.extend(self.base_dirs.iter().cloned());
+        let roots: Vec<PathBuf> = self
+            .base_dirs
+            .iter()
+            .map(|base| base.join(theme))
+            .filter(|root| root.is_dir())
+            .collect();
+        scanned.extend(roots.iter().cloned());
+        let Some(index) = roots
+            .iter()
+            .find_map(|root| std::fs::read_to_string(root.join("index.theme")).ok())
+        else {
+            debug!("icon theme {theme} is not installed");
+            return None;
+        };
+        let index = parse_index(&index);
+        lookup(icon, &roots, &index.directories, self.lookup, scanned).or_else(|| {
+            index
+                .inherits
+                .iter()
+                .find_map(|parent| self.find(icon, parent, visited, scanned))
+        })
+    }
+}
+
+/// The first file in a dir made for the size, or the one in the closest dir if
+/// none are. A dir's files in the preferred format come first.
+fn lookup(
+    icon: &str,
+    roots: &[PathBuf],
+    directories: &[ThemeDir],
+    lookup: &Lookup,
+    scanned: &mut Vec<PathBuf>,
+) -> Option<PathBuf> {
+    let size = lookup.size;
+    let mut closest: Option<((u32, Reverse<u32>), PathBuf)> = None;
+    for dir in directories {
+        for root in roots {
+            let dir_path = root.join(&dir.path);
+            scanned.push(dir_path.clone());
+            for extension in lookup.extensions() {
+                let path = dir_path.join(format!("{icon}.{extension}"));
+                if !path.is_file() {
+                    continue;
+                }
+                if dir.matches(size) {
+                    return Some(path);
+                }
+                let distance = dir.distance(size);
+                if closest.as_ref().is_none_or(|(best, _)| distance < *best) {
+                    closest = Some((distance, path));
+                }
+            }
+        }
+    }
+    closest.map(|(_, path)| path)
+}
+
+/// The parts of an `index.theme` used to find icons.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ThemeIndex {
+    inherits: Vec<String>,
+    directories: Vec<ThemeDir>,
+}
+
+/// One of a theme's `Directories`, with the sizes of the icons in it.
+#[derive(Debug, PartialEq, Eq)]
+struct ThemeDir {
+    path: String,
+    size: u32,
+    scale: u32,
+    kind: DirKind,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum DirKind {
+    /// Only for icons of exactly its size.
+    Fixed,
+    /// For icons from `min` to `max` pixels.
+    Scalable { min: u32, max: u32 },
+    /// For icons up to this many pixels bigger or smaller than its size.
+    Threshold(u32),
+}
+
+impl ThemeDir {
+    /// Whether the dir is made for icons of `size`, never true without one.
+    fn matches(&self, size: Option<u32>) -> bool {
+        let Some(size) = size else {
+            return false;
+        };
+        self.scale == 1
+            && match self.kind {
+                DirKind::Fixed => size == self.size,
+                DirKind::Scalable { min, max } => (min..=max).contains(&size),
+                DirKind::Threshold(threshold) => {
+                    self.size.saturating_sub(threshold) <= size && size <= self.size + threshold
+                }
+            }
+    }
+
+    /// Lower is closer, ties go to the bigger dir. Without a size scalable
+    /// dirs come first and then the biggest.
+    fn distance(&self, size: Option<u32>) -> (u32, Reverse<u32>) {
+        let pixels = self.size.saturating_mul(self.scale);
+        let Some(size) = size else {
+            let scalable = matches!(self.kind, DirKind::Scalable { .. });
+            return (u32::from(!scalable), Reverse(pixels));
+        };
+        let (min, max) = match self.kind {
+            DirKind::Fixed => (self.size, self.size),
+            DirKind::Scalable { min, max } => (min, max),
+            DirKind::Threshold(threshold) => {
+                (self.size.saturating_sub(threshold), self.size + threshold)
+            }
+        };
+        let (min, max) = (min * self.scale, max * self.scale);
+        let distance = if size < min {
+            min - size
+        } else {
+            size.saturating_sub(max)
+        };
+        (distance, Reverse(pixels))
+    }
+}
+
+/// Reads `Inherits` and the `Directories` (and `ScaledDirectories`) with their
+/// own groups. Dirs without a `Size` are skipped, the spec requires one.
+fn parse_index(contents: &str) -> ThemeIndex {
+    let mut groups: Vec<(&str, Vec<(&str, &str)>)> = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(group) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            groups.push((group, Vec::new()));
+        } else if let Some((key, value)) = line.split_once('=')
+            && let Some((_, keys)) = groups.last_mut()
+        {
+            keys.push((key.trim(), value.trim()));
+        }
+    }
+    let get = |group: &str, key: &str| {
+        let (_, keys) = groups.iter().find(|(name, _)| *name == group)?;
+        keys.iter()
+            .rev()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| *value)
+    };
+    let list = |key: &str| -> Vec<String> {
+        get("Icon Theme", key)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_owned)
+            .collect()
+    };
+
+    let mut directories = list("Directories");
+    directories.extend(list("ScaledDirectories"));
+    let directories = directories
+        .into_iter()
+        .filter_map(|path| {
+            let number = |key| get(&path, key)?.parse::<u32>().ok();
+            let size = number("Size")?;
+            let kind = match get(&path, "Type") {
+                Some("Fixed") => DirKind::Fixed,
+                Some("Scalable") => DirKind::Scalable {
+                    min: number("MinSize").unwrap_or(size),
+                    max: number("MaxSize").unwrap_or(size),
+                },
+                _ => DirKind::Threshold(number("Threshold").unwrap_or(2)),
+            };
+            let scale = number("Scale").unwrap_or(1).max(1);
+            Some(ThemeDir {
+                path,
+                size,
+                scale,
+                kind,
+            })
+        })
+        .collect();
+    ThemeIndex {
+        inherits: list("Inherits"),
+        directories,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn icon_dir(path: &Path) -> IconDir {
+        IconDir {
+            path: path.to_path_buf(),
+            walk: IconWalk::default(),
+        }
+    }
+
+    /// Writes a theme with the given `index.theme` dirs (as `path:size:type`)
+    /// and an empty file for each icon, given as a path inside the theme.
+    fn write_theme(base: &Path, name: &str, inherits: &str, dirs: &[&str], icons: &[&str]) {
+        let root = base.join(name);
+        let mut index = format!("[Icon Theme]\nName={name}\n");
+        if !inherits.is_empty() {
+            index.push_str(&format!("Inherits={inherits}\n"));
+        }
+        let paths: Vec<&str> = dirs
+            .iter()
+            .map(|dir| dir.split(':').next().unwrap())
+            .collect();
+        index.push_str(&format!("Directories={}\n", paths.join(",")));
+        for dir in dirs {
+            let [path, size, kind] = dir.split(':').collect::<Vec<_>>()[..] else {
+                panic!("Expected path:size:type");
+            };
+            index.push_str(&format!("\n[{path}]\nSize={size}\nType={kind}\n"));
+            if kind == "Scalable" {
+                index.push_str("MinSize=8\nMaxSize=512\n");
+            }
+        }
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.theme"), index).unwrap();
+        for icon in icons {
+            let path = root.join(icon);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_index() {
+        let index = parse_index(
+            "# comment\n[Icon Theme]\nName=Test\nInherits=Parent, hicolor\n\
+             Directories=16x16/apps,scalable/apps,bad\nScaledDirectories=16x16@2/apps\n\n\
+             [16x16/apps]\nSize=16\nType=Fixed\n\n\
+             [16x16@2/apps]\nSize=16\nScale=2\n\n\
+             [scalable/apps]\nSize=64\nType=Scalable\nMinSize=8\nMaxSize=256\n\n\
+             [bad]\nType=Fixed\n",
+        );
+        assert_eq!(index.inherits, ["Parent", "hicolor"]);
+        assert_eq!(
+            index.directories,
+            [
+                ThemeDir {
+                    path: "16x16/apps".to_string(),
+                    size: 16,
+                    scale: 1,
+                    kind: DirKind::Fixed,
+                },
+                ThemeDir {
+                    path: "scalable/apps".to_string(),
+                    size: 64,
+                    scale: 1,
+                    kind: DirKind::Scalable { min: 8, max: 256 },
+                },
+                ThemeDir {
+                    path: "16x16@2/apps".to_string(),
+                    size: 16,
+                    scale: 2,
+                    kind: DirKind::Threshold(2),
+                },
+            ]
+        );
+
+        let threshold = &ThemeDir {
+            path: String::new(),
+            size: 32,
+            scale: 1,
+            kind: DirKind::Threshold(2),
+        };
+        assert!(threshold.matches(Some(30)) && threshold.matches(Some(34)));
+        assert!(!threshold.matches(Some(35)) && !threshold.matches(None));
+        assert_eq!(threshold.distance(Some(40)), (6, Reverse(32)));
+        assert_eq!(threshold.distance(Some(20)), (10, Reverse(32)));
+        let scaled = &index.directories[2];
+        assert!(!scaled.matches(Some(16)));
+        assert_eq!(scaled.distance(Some(32)), (0, Reverse(32)));
+
+        assert_eq!(icon_dir_size("48x48"), Some(48));
+        assert_eq!(icon_dir_size("48x48@2"), Some(96));
+        assert_eq!(icon_dir_size("48x32"), None);
+        assert_eq!(icon_dir_size("apps"), None);
+    }
+
+    #[test]
+    fn test_find_icon() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-icon-themes");
+        let system = root.join("usr/share");
+        let local = root.join("home/.local/share");
+        let icons = system.join("icons");
+        write_theme(
+            &icons,
+            "Papirus",
+            "Breeze",
+            &["16x16/apps:16:Fixed", "48x48/apps:48:Fixed"],
+            &["16x16/apps/only-small.svg", "48x48/apps/firefox.svg"],
+        );
+        // Papirus inherits Breeze, which inherits it back
+        write_theme(
+            &icons,
+            "Breeze",
+            "Papirus",
+            &["apps/32:32:Threshold"],
+            &["apps/32/kate.png", "apps/32/firefox.png"],
+        );
+        write_theme(
+            &icons,
+            "hicolor",
+            "",
+            &["48x48/apps:48:Threshold", "scalable/apps:64:Scalable"],
+            &[
+                "48x48/apps/firefox.png",
+                "48x48/apps/vlc.png",
+                "scalable/apps/vlc.svg",
+            ],
+        );
+        std::fs::create_dir_all(system.join("pixmaps")).unwrap();
+        std::fs::write(system.join("pixmaps/xterm.png"), "").unwrap();
+        std::fs::write(icons.join("loose.svg"), "").unwrap();
+        // A theme's files can be spread over several data dirs
+        let breeze = local.join("icons/Breeze/apps/32");
+        std::fs::create_dir_all(&breeze).unwrap();
+        std::fs::write(breeze.join("dolphin.png"), "").unwrap();
+
+        let find = |name: &str, theme: Option<&str>, size: Option<u32>| {
+            let lookup = Lookup {
+                theme,
+                size,
+                ..Default::default()
+            };
+            find_icon(
+                name,
+                &[&[icon_dir(&local), icon_dir(&system)]],
+                &lookup,
+                &mut DirIndex::default(),
+                &mut Vec::new(),
+                &mut NearMisses::new(name),
+            )
+            .map(|path| {
+                path.strip_prefix(&root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        };
+        let papirus = Some("Papirus");
+        assert_eq!(
+            find("firefox", papirus, Some(48)).as_deref(),
+            Some("usr/share/icons/Papirus/48x48/apps/firefox.svg")
+        );
+        // Inherited themes come before hicolor
+        assert_eq!(
+            find("kate", papirus, Some(48)).as_deref(),
+            Some("usr/share/icons/Breeze/apps/32/kate.png")
+        );
+        assert_eq!(
+            find("dolphin", papirus, Some(32)).as_deref(),
+            Some("home/.local/share/icons/Breeze/apps/32/dolphin.png")
+        );
+        // A missing size falls back to the closest one in the same theme
+        assert_eq!(
+            find("only-small", papirus, Some(64)).as_deref(),
+            Some("usr/share/icons/Papirus/16x16/apps/only-small.svg")
+        );
+        assert_eq!(
+            find("vlc", papirus, Some(48)).as_deref(),
+            Some("usr/share/icons/hicolor/48x48/apps/vlc.png")
+        );
+        assert_eq!(
+            find("vlc", papirus, Some(128)).as_deref(),
+            Some("usr/share/icons/hicolor/scalable/apps/vlc.svg")
+        );
+        // Without a size, scalable icons win
+        assert_eq!(
+            find("vlc", None, None).as_deref(),
+            Some("usr/share/icons/hicolor/scalable/apps/vlc.svg")
+        );
+        // Without a theme (or an unknown one), only hicolor is used
+        assert_eq!(
+            find("firefox", None, Some(48)).as_deref(),
+            Some("usr/share/icons/hicolor/48x48/apps/firefox.png")
+        );
+        assert_eq!(
+            find("firefox", Some("Missing"), Some(48)).as_deref(),
+            Some("usr/share/icons/hicolor/48x48/apps/firefox.png")
+        );
+        // Then icons outside of themes, and pixmaps last
+        assert_eq!(
+            find("loose", papirus, None).as_deref(),
+            Some("usr/share/icons/loose.svg")
+        );
+        assert_eq!(
+            find("xterm", papirus, None).as_deref(),
+            Some("usr/share/pixmaps/xterm.png")
+        );
+        assert_eq!(find("nothing", papirus, None), None);
+
+        // Plain dirs are searched through before any theme
+        let plain = root.join("plain/nested");
+        std::fs::create_dir_all(&plain).unwrap();
+        std::fs::write(plain.join("firefox.png"), "").unwrap();
+        assert_eq!(
+            find_icon(
+                "firefox",
+                &[&[icon_dir(&root.join("plain")), icon_dir(&system)]],
+                &Lookup {
+                    theme: papirus,
+                    size: Some(48),
+                    ..Default::default()
+                },
+                &mut DirIndex::default(),
+                &mut Vec::new(),
+                &mut NearMisses::new("firefox"),
+            ),
+            Some(plain.join("firefox.png"))
+        );
+    }
+
+    #[test]
+    fn test_icon_formats() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-icon-formats");
+        let png = |width: u32| {
+            let mut header = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+            header.extend(width.to_be_bytes());
+            header.extend(width.to_be_bytes());
+            header
+        };
+        for (path, contents) in [
+            ("plain/b/app.svg", Vec::new()),
+            ("plain/a/app.svg", Vec::new()),
+            ("plain/32x32/app.png", Vec::new()),
+            ("plain/128x128/app.png", Vec::new()),
+            ("plain/loose/app.png", png(64)),
+            ("plain/unknown/app.png", b"not a png".to_vec()),
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        let plain = root.join("plain");
+        let find = |prefer, size| {
+            let lookup = Lookup {
+                size,
+                prefer,
+                ..Default::default()
+            };
+            search_dir(
+                "app",
+                &plain,
+                IconWalk::default(),
+                &lookup,
+                &mut DirIndex::default(),
+                &mut Vec::new(),
+                &mut NearMisses::new("app"),
+            )
+            .map(|path| {
+                path.strip_prefix(&plain)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        };
+        // Ties go to the first path
+        assert_eq!(
+            find(IconFormat::Svg, Some(32)).as_deref(),
+            Some("a/app.svg")
+        );
+        assert_eq!(
+            find(IconFormat::Png, None).as_deref(),
+            Some("128x128/app.png")
+        );
+        assert_eq!(
+            find(IconFormat::Png, Some(40)).as_deref(),
+            Some("32x32/app.png")
+        );
+        // Without a NNxNN dir the size comes from the PNG's header
+        assert_eq!(
+            find(IconFormat::Png, Some(60)).as_deref(),
+            Some("loose/app.png")
+        );
+        assert_eq!(png_width(&plain.join("loose/app.png")), Some(64));
+        assert_eq!(png_width(&plain.join("unknown/app.png")), None);
+
+        // Themes and loose icons go by the same preference
+        let icons = root.join("share/icons");
+        write_theme(
+            &icons,
+            "hicolor",
+            "",
+            &["48x48/apps:48:Fixed"],
+            &["48x48/apps/app.png", "48x48/apps/app.svg"],
+        );
+        std::fs::write(icons.join("loose.png"), "").unwrap();
+        std::fs::write(icons.join("loose.svg"), "").unwrap();
+        let share = root.join("share");
+        let find = |name: &str, prefer| {
+            let lookup = Lookup {
+                size: Some(48),
+                prefer,
+                ..Default::default()
+            };
+            let path = find_icon(
+                name,
+                &[&[icon_dir(&share)]],
+                &lookup,
+                &mut DirIndex::default(),
+                &mut Vec::new(),
+                &mut NearMisses::new(name),
+            )
+            .unwrap();
+            path.strip_prefix(&icons)
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        };
+        assert_eq!(find("app", IconFormat::Svg), "hicolor/48x48/apps/app.svg");
+        assert_eq!(find("app", IconFormat::Png), "hicolor/48x48/apps/app.png");
+        assert_eq!(find("loose", IconFormat::Svg), "loose.svg");
+        assert_eq!(find("loose", IconFormat::Png), "loose.png");
+    }
+
+    #[test]
+    fn test_detect_theme() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-detect-theme");
+        std::fs::create_dir_all(root.join("gtk-3.0")).unwrap();
+        let settings = root.join("gtk-3.0/settings.ini");
+        let theme = |contents: &str| {
+            std::fs::write(&settings, contents).unwrap();
+            gtk_settings_theme(&settings)
+        };
+        assert_eq!(
+            theme("[Settings]\ngtk-theme-name=Adwaita-dark\ngtk-icon-theme-name=Papirus-Dark\n")
+                .as_deref(),
+            Some("Papirus-Dark")
+        );
+        assert_eq!(
+            theme("# written by a tool\n[Settings]\ngtk-icon-theme-name = \"Papirus\"\n")
+                .as_deref(),
+            Some("Papirus")
+        );
+        // Only from the Settings group, and never empty
+        assert_eq!(theme("[Other]\ngtk-icon-theme-name=Papirus\n"), None);
+        assert_eq!(theme("[Settings]\ngtk-icon-theme-name=\n"), None);
+        assert_eq!(theme("[Settings]\ngtk-font-name=Sans 10\n"), None);
+
+        std::fs::write(&settings, "[Settings]\ngtk-icon-theme-name=Papirus\n").unwrap();
+        assert_eq!(detect_theme(&root).as_deref(), Some("Papirus"));
+        std::fs::remove_file(&settings).unwrap();
+        assert_eq!(gtk_settings_theme(&settings), None);
+
+        // As gsettings prints it
+        assert_eq!(
+            theme_name("'Papirus-Dark'\n").as_deref(),
+            Some("Papirus-Dark")
+        );
+        assert_eq!(theme_name("''\n"), None);
+    }
+
+    #[test]
+    fn test_symbolic_icons() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-symbolic-icons");
+        for path in [
+            "first/plain.svg",
+            "first/both.svg",
+            "first/both-symbolic.svg",
+            "first/later-symbolic.svg",
+            "second/later.png",
+            "second/symbolic-symbolic.svg",
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let find = |name: &str, symbolic| {
+            find_icon(
+                name,
+                &[
+                    &[icon_dir(&root.join("first"))],
+                    &[icon_dir(&root.join("second"))],
+                ],
+                &Lookup {
+                    symbolic,
+                    ..Default::default()
+                },
+                &mut DirIndex::default(),
+                &mut Vec::new(),
+                &mut NearMisses::new(name),
+            )
+            .map(|path| {
+                path.strip_prefix(&root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        };
+        let avoid = SymbolicIcons::Avoid;
+        assert_eq!(find("plain", avoid).as_deref(), Some("first/plain.svg"));
+        assert_eq!(find("both", avoid).as_deref(), Some("first/both.svg"));
+        assert_eq!(
+            find("both-symbolic", avoid).as_deref(),
+            Some("first/both.svg")
+        );
+        // A plain icon anywhere wins over a symbolic one in an earlier tier
+        assert_eq!(find("later", avoid).as_deref(), Some("second/later.png"));
+        assert_eq!(
+            find("symbolic", avoid).as_deref(),
+            Some("second/symbolic-symbolic.svg")
+        );
+
+        let prefer = SymbolicIcons::Prefer;
+        assert_eq!(find("plain", prefer).as_deref(), Some("first/plain.svg"));
+        assert_eq!(
+            find("both", prefer).as_deref(),
+            Some("first/both-symbolic.svg")
+        );
+        assert_eq!(
+            find("later", prefer).as_deref(),
+            Some("first/later-symbolic.svg")
+        );
+
+        // Only the name as written
+        let allow = SymbolicIcons::Allow;
+        assert_eq!(find("both", allow).as_deref(), Some("first/both.svg"));
+        assert_eq!(
+            find("both-symbolic", allow).as_deref(),
+            Some("first/both-symbolic.svg")
+        );
+        assert_eq!(find("later", allow).as_deref(), Some("second/later.png"));
+        assert_eq!(find("symbolic", allow), None);
+        assert_eq!(find("-symbolic", avoid), None);
+    }
+
+    #[test]
+    fn test_dir_index() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-dir-index");
+        std::fs::create_dir_all(root.join("a/b/c")).unwrap();
+        std::fs::write(root.join("a/App.PNG"), "").unwrap();
+        std::fs::write(root.join("a/app.svg"), "").unwrap();
+        std::fs::write(root.join("a/notes.txt"), "").unwrap();
+        let walk = IconWalk {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let mut index = DirIndex::default();
+        let indexed = index.get(&root, walk);
+        // Only icon files are kept, under their stem in lowercase
+        let mut matches: Vec<&PathBuf> = indexed.matches("APP").collect();
+        matches.sort();
+        assert_eq!(matches, [&root.join("a/App.PNG"), &root.join("a/app.svg")]);
+        assert_eq!(indexed.icons.len(), 1);
+        // a/b is at the max depth, so its files aren't looked at
+        assert_eq!(indexed.dirs, [root.clone(), root.join("a")]);
+
+        // Each dir is walked once, later searches use what was found then
+        std::fs::write(root.join("a/other.svg"), "").unwrap();
+        let search = |index: &mut DirIndex| {
+            search_dir(
+                "other",
+                &root,
+                walk,
+                &Lookup::default(),
+                index,
+                &mut Vec::new(),
+                &mut NearMisses::new("other"),
+            )
+        };
+        assert_eq!(search(&mut index), None);
+        assert_eq!(
+            search(&mut DirIndex::default()),
+            Some(root.join("a/other.svg"))
+        );
+    }
+
+    #[test]
+    fn test_missing_icon_cache() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-missing-icon-cache");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("other.svg"), "").unwrap();
+        let dirs = [icon_dir(&root)];
+        let find = |cache| {
+            let mut finder = IconFinder::new(cache);
+            let found = finder.find("Ghost", "ghost", &[&dirs], &Lookup::default());
+            let (cache, missing) = finder.finish();
+            (found, cache, missing.len())
+        };
+
+        let (found, cache, missing) = find(IconCache::default());
+        assert_eq!((found, missing), (None, 1));
+        assert!(cache.has_missing());
+        assert!(cache.unchanged());
+
+        // Swapping a file for the icon without changing how the dir looks
+        // means the miss is reused, it's still reported as missing
+        let modified = std::fs::metadata(&root).unwrap().modified().unwrap();
+        std::fs::rename(root.join("other.svg"), root.join("ghost.svg")).unwrap();
+        File::open(&root).unwrap().set_modified(modified).unwrap();
+        assert!(cache.unchanged());
+        let (found, cache, missing) = find(cache);
+        assert_eq!((found, missing), (None, 1));
+
+        // Unless misses are forgotten, like with --refresh-icons
+        let mut forgotten = cache.clone();
+        forgotten.forget_missing();
+        assert!(!forgotten.has_missing());
+        let (found, _, missing) = find(forgotten);
+        assert_eq!((found, missing), (Some(root.join("ghost.svg")), 0));
+
+        // Adding the icon changes the dir, so the miss isn't reused
+        std::fs::write(root.join("another.svg"), "").unwrap();
+        assert!(!cache.unchanged());
+        let (found, cache, missing) = find(cache);
+        assert_eq!((found, missing), (Some(root.join("ghost.svg")), 0));
+        assert!(!cache.has_missing());
+    }
+
+    #[test]
+    fn test_icon_cache() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-icon-cache");
+        let plain = root.join("plain");
+        std::fs::create_dir_all(plain.join("a")).unwrap();
+        std::fs::create_dir_all(plain.join("b")).unwrap();
+        std::fs::write(plain.join("b/app.png"), "").unwrap();
+        let lookup = Lookup::default();
+
+        let mut finder = IconFinder::new(IconCache::default());
+        let found = finder.find("App", "app", &[&[icon_dir(&plain)]], &lookup);
+        assert_eq!(found, Some(plain.join("b/app.png")));
+        // Found again without searching
+        assert_eq!(
+            finder.find("App", "app", &[&[icon_dir(&plain)]], &lookup),
+            found
+        );
+        assert_eq!(
+            finder.find("App", "nope", &[&[icon_dir(&plain)]], &lookup),
+            None
+        );
+        // Paths aren't looked for, so they're never missing
+        assert_eq!(
+            finder.find("App", "/no/such.png", &[&[icon_dir(&plain)]], &lookup),
+            None
+        );
+        let (cache, missing) = finder.finish();
+        // The miss is kept too
+        assert_eq!(cache.icons.len(), 2);
+        assert_eq!(
+            missing,
+            [MissingIcon {
+                item: "App".to_string(),
+                name: "nope".to_string(),
+                dirs: vec![plain.display().to_string()],
+                similar: Vec::new(),
+            }]
+        );
+        assert!(cache.unchanged());
+
+        // Reused while the dirs are unchanged, without looking at the files again
+        std::fs::write(plain.join("b/app.png"), "changed").unwrap();
+        let mut finder = IconFinder::new(cache);
+        assert_eq!(
+            finder.find("App", "app", &[&[icon_dir(&plain)]], &lookup),
+            found
+        );
+        let cache = finder.finish().0;
+        assert!(cache.unchanged());
+
+        // A better icon added to a searched dir is found by the next build
+        std::fs::write(plain.join("a/app.svg"), "").unwrap();
+        assert!(!cache.unchanged());
+        let mut finder = IconFinder::new(cache);
+        assert_eq!(
+            finder.find("App", "app", &[&[icon_dir(&plain)]], &lookup),
+            Some(plain.join("a/app.svg"))
+        );
+        let cache = finder.finish().0;
+        assert!(cache.unchanged());
+
+        // And removing it goes back to the other one
+        std::fs::remove_file(plain.join("a/app.svg")).unwrap();
+        assert!(!cache.unchanged());
+        let mut finder = IconFinder::new(cache);
+        assert_eq!(
+            finder.find("App", "app", &[&[icon_dir(&plain)]], &lookup),
+            found
+        );
+
+        // A different lookup is searched for separately
+        let png = Lookup {
+            prefer: IconFormat::Png,
+            ..Default::default()
+        };
+        assert_eq!(
+            finder.find("App", "app", &[&[icon_dir(&plain)]], &png),
+            found
+        );
+        assert_eq!(finder.finish().0.icons.len(), 2);
+    }
+
+    #[test]
+    fn test_icon_walk() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-icon-walk");
+        let plain = root.join("plain");
+        let deep = plain.join("a/b/c/d");
+        std::fs::create_dir_all(&deep).unwrap();
+        std::fs::create_dir_all(plain.join(".git/objects")).unwrap();
+        std::fs::create_dir_all(root.join("elsewhere")).unwrap();
+        std::fs::write(deep.join("deep.svg"), "").unwrap();
+        std::fs::write(plain.join(".git/objects/hidden.svg"), "").unwrap();
+        std::fs::write(root.join("elsewhere/linked.svg"), "").unwrap();
+        std::os::unix::fs::symlink(root.join("elsewhere"), plain.join("link")).unwrap();
+        // Leads back up to plain, which is only walked once
+        std::os::unix::fs::symlink(&plain, plain.join("a/loop")).unwrap();
+
+        let find = |name: &str, walk: IconWalk, default: IconWalk| {
+            let dir = IconDir {
+                path: plain.clone(),
+                walk,
+            };
+            let lookup = Lookup {
+                walk: default,
+                ..Default::default()
+            };
+            let mut scanned = Vec::new();
+            find_icon(
+                name,
+                &[std::slice::from_ref(&dir)],
+                &lookup,
+                &mut DirIndex::default(),
+                &mut scanned,
+                &mut NearMisses::new(name),
+            )
+            .map(|path| {
+                path.strip_prefix(&plain)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        };
+        let unset = IconWalk::default();
+        assert_eq!(
+            find("deep", unset, unset).as_deref(),
+            Some("a/b/c/d/deep.svg")
+        );
+        let shallow = IconWalk {
+            max_depth: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(find("deep", shallow, unset), None);
+        assert_eq!(find("deep", unset, shallow), None);
+        // The dir's own setting wins over the default
+        let deeper = IconWalk {
+            max_depth: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            find("deep", deeper, shallow).as_deref(),
+            Some("a/b/c/d/deep.svg")
+        );
+
+        // Hidden dirs and symlinks are skipped unless asked for
+        assert_eq!(find("hidden", unset, unset), None);
+        let hidden = IconWalk {
+            include_hidden: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            find("hidden", unset, hidden).as_deref(),
+            Some(".git/objects/hidden.svg")
+        );
+        assert_eq!(find("linked", unset, unset), None);
+        let follow = IconWalk {
+            follow_symlinks: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            find("linked", follow, unset).as_deref(),
+            Some("link/linked.svg")
+        );
+        assert_eq!(
+            find("deep", follow, unset).as_deref(),
+            Some("a/b/c/d/deep.svg")
+        );
+        let follow_off = IconWalk {
+            follow_symlinks: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(find("linked", follow_off, follow), None);
+    }
+
+    #[test]
+    fn test_icon_case_and_xpm() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-icon-case");
+        for path in [
+            "plain/a/Firefox.png",
+            "plain/b/gimp.xpm",
+            "plain/c/Editor.svg",
+            "plain/c/editor.xpm",
+            "share/pixmaps/Terminal.PNG",
+            "share/pixmaps/vim.xpm",
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let share = root.join("share");
+        write_theme(
+            &share.join("icons"),
+            "hicolor",
+            "",
+            &["48x48/apps:48:Fixed"],
+            &["48x48/apps/Files.png", "48x48/apps/mail.xpm"],
+        );
+        let find = |name: &str, matching| {
+            let lookup = Lookup {
+                matching,
+                ..Default::default()
+            };
+            let dirs = [icon_dir(&root.join("plain")), icon_dir(&share)];
+            find_icon(
+                name,
+                &[&dirs],
+                &lookup,
+                &mut DirIndex::default(),
+                &mut Vec::new(),
+                &mut NearMisses::new(name),
+            )
+            .map(|path| {
+                path.strip_prefix(&root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+        };
+        let ignore_case = IconMatch::IgnoreCase;
+        assert_eq!(
+            find("firefox", ignore_case).as_deref(),
+            Some("plain/a/Firefox.png")
+        );
+        assert_eq!(
+            find("gimp", ignore_case).as_deref(),
+            Some("plain/b/gimp.xpm")
+        );
+        // The exact name wins, even as an xpm
+        assert_eq!(
+            find("editor", ignore_case).as_deref(),
+            Some("plain/c/editor.xpm")
+        );
+        assert_eq!(
+            find("Editor", ignore_case).as_deref(),
+            Some("plain/c/Editor.svg")
+        );
+        assert_eq!(
+            find("terminal", ignore_case).as_deref(),
+            Some("share/pixmaps/Terminal.PNG")
+        );
+        assert_eq!(
+            find("vim", ignore_case).as_deref(),
+            Some("share/pixmaps/vim.xpm")
+        );
+        // Themes use xpms but their names are always exact
+        assert_eq!(
+            find("mail", ignore_case).as_deref(),
+            Some("share/icons/hicolor/48x48/apps/mail.xpm")
+        );
+        assert_eq!(find("files", ignore_case), None);
+
+        let exact = IconMatch::Exact;
+        assert_eq!(find("firefox", exact), None);
+        assert_eq!(find("terminal", exact), None);
+        assert_eq!(find("gimp", exact).as_deref(), Some("plain/b/gimp.xpm"));
+        assert_eq!(find("Editor", exact).as_deref(), Some("plain/c/Editor.svg"));
+    }
+
+    #[test]
+    fn test_near_misses() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-near-misses");
+        for path in [
+            "plain/a/firefox_nightly.png",
+            "plain/b/firefox-night.svg",
+            "plain/b/firefox-nightly.txt",
+            "plain/c/thunderbird.png",
+            "share/icons/.keep",
+            "share/pixmaps/Firefox-Nightly.xpm",
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let lookup = Lookup {
+            matching: IconMatch::Exact,
+            ..Default::default()
+        };
+        let dirs = [icon_dir(&root.join("plain")), icon_dir(&root.join("share"))];
+        let mut near_misses = NearMisses::new("firefox-nightly");
+        let found = find_icon(
+            "firefox-nightly",
+            &[&dirs],
+            &lookup,
+            &mut DirIndex::default(),
+            &mut Vec::new(),
+            &mut near_misses,
+        );
+        assert_eq!(found, None);
+        // Closest first, then alphabetically, and only icon files
+        assert_eq!(
+            near_misses.names(),
+            ["Firefox-Nightly", "firefox_nightly", "firefox-night"]
+        );
+
+        // Only the closest few are kept
+        let mut near_misses = NearMisses::new("icon");
+        for name in [
+            "icons", "icon1", "icon2", "icon3", "icon4", "ico", "ic", "con", "x",
+        ] {
+            near_misses.offer(OsStr::new(&format!("{name}.png")));
+        }
+        near_misses.offer(OsStr::new("icon.png"));
+        near_misses.offer(OsStr::new("icon1.svg"));
+        assert_eq!(
+            near_misses.names(),
+            ["con", "ico", "icon1", "icon2", "icon3"]
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use miette::{Context, IntoDiagnostic, Result};
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crate::config::{ComputedInput, ComputedPassVia, ComputedProgram};
+
+fn fuzzel_command(fuzzel: &Path, input: &ComputedInput) -> Command {
+    let mut command = Command::new(fuzzel);
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .arg("--dmenu")
+        .args(&input.args);
+    command
+}
+
+/// Opens fuzzel with nothing to pick from and returns the typed line, or `None`
+/// if it was cancelled or left empty.
+pub fn read_text(fuzzel: &Path, input: &ComputedInput) -> Result<Option<String>> {
+    read_text_from(fuzzel_command(fuzzel, input))
+}
+
+// The text may be a password, so it's never logged or put in an error
+fn read_text_from(mut fuzzel: Command) -> Result<Option<String>> {
+    let output = fuzzel
+        .output()
+        .into_diagnostic()
+        .context("failed to spawn fuzzel")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let text = stdout.strip_suffix('\n').unwrap_or(&stdout);
+    Ok((!text.is_empty()).then(|| text.to_owned()))
+}
+
+/// The command with the text passed the way the input asks. As an argument it
+/// replaces `{}`, or is appended as the last argument if there is no `{}`.
+pub fn program_for_text(input: &ComputedInput, text: &str) -> ComputedProgram {
+    let text = if input.url_encode {
+        percent_encode(text)
+    } else {
+        text.to_owned()
+    };
+    let mut program = ComputedProgram {
+        command: input.command.clone(),
+        redacted: input.password,
+        env: input.env.clone(),
+        ..input.defaults.program()
+    };
+    match &input.pass_via {
+        ComputedPassVia::Arg => program.command = fill_placeholder(&input.command, &text),
+        ComputedPassVia::Stdin => program.stdin = Some(text),
+        ComputedPassVia::Env(name) => program.env.push((name.clone(), text)),
+    }
+    program
+}
+
+/// The command with the text in place of every `{}`, or appended as the last
+/// argument if there is no `{}`. The text is never split or interpreted.
+pub fn fill_placeholder(command: &[String], text: &str) -> Vec<String> {
+    if !command.iter().any(|argument| argument.contains("{}")) {
+        let mut command = command.to_vec();
+        command.push(text.to_owned());
+        return command;
+    }
+    command
+        .iter()
+        .map(|argument| argument.replace("{}", text))
+        .collect()
+}
+
+/// Encodes everything except the characters that are never special in a url.
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ComputedDefaults;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_input_substitution() {
+        let mut input = ComputedInput {
+            args: Vec::new(),
+            command: vec![
+                "xdg-open".to_string(),
+                "https://ddg.gg/?q={}&t={}".to_string(),
+            ],
+            url_encode: false,
+            password: false,
+            pass_via: ComputedPassVia::Arg,
+            env: Vec::new(),
+            defaults: ComputedDefaults::default(),
+        };
+        assert_eq!(
+            program_for_text(&input, "rust kdl").command,
+            ["xdg-open", "https://ddg.gg/?q=rust kdl&t=rust kdl"]
+        );
+
+        input.url_encode = true;
+        assert_eq!(
+            program_for_text(&input, "a&b c/é~").command,
+            [
+                "xdg-open",
+                "https://ddg.gg/?q=a%26b%20c%2F%C3%A9~&t=a%26b%20c%2F%C3%A9~"
+            ]
+        );
+
+        input.command = vec!["notify-send".to_string()];
+        input.url_encode = false;
+        assert_eq!(
+            program_for_text(&input, "hello {}").command,
+            ["notify-send", "hello {}"]
+        );
+    }
+
+    #[test]
+    fn test_password_input() {
+        use crate::launch;
+        use std::{
+            os::unix::fs::PermissionsExt,
+            time::{Duration, Instant},
+        };
+
+        // A fake fuzzel that records its arguments and "types" a password
+        let temp = TempDir::new().unwrap();
+        let fake_bin = temp.path().join("test-fake-fuzzel");
+        std::fs::create_dir_all(&fake_bin).unwrap();
+        let fuzzel_args = fake_bin.join("fuzzel-args");
+        std::fs::write(
+            fake_bin.join("fuzzel"),
+            format!(
+                "#!/bin/sh\nprintf '%s\\n' \"$@\" > {}\necho hunter2\n",
+                fuzzel_args.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(
+            fake_bin.join("fuzzel"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+
+        let argv = fake_bin.join("argv");
+        let received = fake_bin.join("received");
+        let mut input = ComputedInput {
+            args: vec![
+                "--prompt-only=vault: ".to_string(),
+                "--password".to_string(),
+            ],
+            command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!(
+                    "echo \"$0 $*\" > {}; {{ [ -n \"$SECRET\" ] && echo \"$SECRET\" || cat; }} > {}",
+                    argv.display(),
+                    received.display()
+                ),
+                "{}".to_string(),
+            ],
+            url_encode: false,
+            password: true,
+            pass_via: ComputedPassVia::Stdin,
+            env: Vec::new(),
+            defaults: ComputedDefaults::default(),
+        };
+
+        let fuzzel = fuzzel_command(&fake_bin.join("fuzzel"), &input);
+        let text = read_text_from(fuzzel).unwrap().unwrap();
+        assert_eq!(text, "hunter2");
+        assert_eq!(
+            std::fs::read_to_string(&fuzzel_args).unwrap(),
+            "--dmenu\n--prompt-only=vault: \n--password\n"
+        );
+
+        for (pass_via, expected) in [
+            (ComputedPassVia::Stdin, "hunter2\n"),
+            (ComputedPassVia::Env("SECRET".to_string()), "hunter2\n"),
+        ] {
+            input.pass_via = pass_via;
+            let program = program_for_text(&input, &text);
+            assert_eq!(program.describe(), "sh (arguments hidden)");
+            let _ = std::fs::remove_file(&received);
+            launch::spawn_program(&program, None).unwrap();
+            // It isn't waited for, and writes argv first
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !std::fs::read_to_string(&received).is_ok_and(|text| text.ends_with('\n'))
+                && Instant::now() < deadline
+            {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert!(!std::fs::read_to_string(&argv).unwrap().contains("hunter2"));
+            assert_eq!(std::fs::read_to_string(&received).unwrap(), expected);
+        }
+
+        // A missing fuzzel is an error, not an empty line
+        let fuzzel = fuzzel_command(Path::new("/nonexistent/fuzzel"), &input);
+        assert!(read_text_from(fuzzel).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use log::warn;
+use miette::{Context, IntoDiagnostic, Result, ensure, miette};
+use std::{
+    borrow::Cow,
+    io::Read,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use crate::{
+    config::{self, ComputedLabel, ComputedMenu},
+    launch,
+};
+
+/// Label commands are stopped after this long, so a slow one can't hold up the menu.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The menu's input with each label's `{}` replaced by its command's output.
+/// A command that fails only leaves its `{}` empty.
+pub fn fill_labels(menu: &ComputedMenu) -> Cow<'_, [u8]> {
+    if menu.labels.is_empty() {
+        return Cow::Borrowed(&menu.input);
+    }
+
+    let mut input = Vec::with_capacity(menu.input.len());
+    for (index, line) in menu
+        .input
+        .split_inclusive(|&byte| byte == b'\n')
+        .enumerate()
+    {
+        let Some(label) = menu.labels.iter().find(|label| label.item == index) else {
+            input.extend_from_slice(line);
+            continue;
+        };
+        let text = match run_label_command(label) {
+            Ok(text) => text,
+            Err(error) => {
+                warn!("{error:?}");
+                String::new()
+            }
+        };
+        let line = String::from_utf8_lossy(line);
+        input.extend_from_slice(line.replacen("{}", &text, 1).as_bytes());
+    }
+    Cow::Owned(input)
+}
+
+/// The first line the command prints, made safe to put in fuzzel's input.
+fn run_label_command(label: &ComputedLabel) -> Result<String> {
+    let command_line = label.command.join(" ");
+    let mut child = Command::new(&label.command[0])
+        .args(&label.command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .into_diagnostic()
+        .with_context(|| format!("failed to run label command: {command_line}"))?;
+
+    let Some(status) = launch::wait_or_kill(&mut child, TIMEOUT)? else {
+        return Err(miette!(
+            "label command {command_line} took longer than {}s",
+            TIMEOUT.as_secs()
+        ));
+    };
+    ensure!(
+        status.success(),
+        "label command {command_line} exited with {status}"
+    );
+
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .into_diagnostic()
+        .with_context(|| format!("failed to read output of label command: {command_line}"))?;
+    let text = stdout.lines().next().unwrap_or_default();
+    let text = config::sanitize_entry(text);
+    Ok(if label.markup {
+        config::escape_markup(&text)
+    } else {
+        text
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_fill_labels() {
+        let label = |item: usize, script: &str| ComputedLabel {
+            item,
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            markup: false,
+        };
+        let mut menu = ComputedMenu {
+            args: Vec::new(),
+            input: b"Battery ({})\nVPN: {}\0icon\x1fnetwork\nPlain {}\n".to_vec(),
+            items_offset: 0,
+            back_on_cancel: false,
+            stay_open: false,
+            actions: Vec::new(),
+            labels: vec![label(0, "echo 87%; echo ignored"), label(1, "exit 1")],
+            hooks: Default::default(),
+            names: Vec::new(),
+            ids: Vec::new(),
+            multi_select: None,
+        };
+        assert_eq!(
+            fill_labels(&menu).as_ref(),
+            b"Battery (87%)\nVPN: \0icon\x1fnetwork\nPlain {}\n"
+        );
+
+        menu.labels = vec![label(2, "printf 'a<b>\\037'")];
+        menu.labels[0].markup = true;
+        assert_eq!(
+            fill_labels(&menu).as_ref(),
+            "Battery ({})\nVPN: {}\0icon\x1fnetwork\nPlain a&lt;b&gt;\u{FFFD}\n".as_bytes()
+        );
+
+        // Slow commands are given up on
+        menu.labels = vec![label(0, "sleep 5; echo late")];
+        let start = Instant::now();
+        assert!(fill_labels(&menu).starts_with(b"Battery ()\n"));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,1406 @@
+use log::{debug, error, info, warn};
+use miette::{Context, IntoDiagnostic, Result, miette};
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    hash::{BuildHasher, RandomState},
+    io::Write,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    activation,
+    config::{ComputedHooks, ComputedLaunchMethod, ComputedOutput, ComputedProgram},
+    detach, settings,
+};
+
+/// Pre-launch hooks are stopped after this long, so a stuck one can't hold up the launch.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What every launch in one run of uff shares.
+pub struct Launcher {
+    /// From `--exec`, replace uff with whichever program is picked.
+    pub exec: bool,
+    /// From `--dry-run`, print the commands instead of running anything.
+    pub dry_run: bool,
+    /// Give programs the `UFF_*` variables saying where they were launched from.
+    pub export_env: bool,
+    /// Give programs an xdg-activation token, so their window is focused.
+    pub activation: bool,
+    pub preset: String,
+    pub config_path: PathBuf,
+    /// uff itself, started as the watcher of programs with `watch-exit`, see
+    /// `run_watcher`. Without it uff watches them itself.
+    pub watcher: Option<PathBuf>,
+}
+
+impl Launcher {
+    /// Spawns the program between the menu's hooks. A failing pre-launch hook
+    /// stops the launch, the post-launch hook is started without waiting for it.
+    /// With `exec` (or the program's own `exec`) uff is replaced by the program
+    /// instead, so this only returns if that fails.
+    pub fn launch(
+        &self,
+        program: &ComputedProgram,
+        hooks: &ComputedHooks,
+        item_name: &str,
+        item_id: &str,
+    ) -> Result<()> {
+        let origin = self.origin_env(hooks, item_name, item_id);
+        if self.dry_run {
+            // No hooks or activation token, nothing should happen
+            let mut program = program.clone();
+            if self.export_env {
+                program.env.splice(0..0, origin);
+            }
+            for command in planned_commands(&program)? {
+                println!("{command}");
+            }
+            return Ok(());
+        }
+        if !hooks.pre_launch.is_empty() {
+            let description = format!("pre-launch hook {}", hooks.pre_launch.join(" "));
+            info!("running {description}");
+            let mut child = hook_command(&hooks.pre_launch, &origin)
+                .spawn()
+                .into_diagnostic()
+                .with_context(|| format!("failed to run {description}"))?;
+            match wait_or_kill(&mut child, HOOK_TIMEOUT)? {
+                Some(status) if status.success() => {}
+                Some(status) => return Err(miette!("{description} exited with {status}")),
+                None => {
+                    return Err(miette!(
+                        "{description} took longer than {}s",
+                        HOOK_TIMEOUT.as_secs()
+                    ));
+                }
+            }
+        }
+
+        let mut env = Vec::new();
+        if self.export_env {
+            env.extend(origin.iter().cloned());
+        }
+        // Tokens can only be used once, so each launch asks for its own
+        if self.activation
+            && let Some(token) = activation::request_token()
+        {
+            env.push(("XDG_ACTIVATION_TOKEN".to_string(), token));
+        }
+        // The program's own variables come last, so they win
+        let program = &if env.is_empty() {
+            Cow::Borrowed(program)
+        } else {
+            let mut program = program.clone();
+            program.env.splice(0..0, env);
+            Cow::Owned(program)
+        };
+
+        if self.exec || program.exec {
+            match exec_unsupported(program) {
+                Some(reason) => warn!("{reason}, so it's spawned instead of replacing uff"),
+                None => {
+                    // Nothing runs after a successful exec, so it goes first
+                    spawn_post_launch(hooks, &origin);
+                    return Err(
+                        exec_program(program).wrap_err(format!("failed to launch {item_name}"))
+                    );
+                }
+            }
+        }
+
+        spawn_program(program, self.watcher.as_deref())
+            .with_context(|| format!("failed to launch {item_name}"))?;
+        spawn_post_launch(hooks, &origin);
+        Ok(())
+    }
+
+    /// The item's display name and id, the menus leading to it joined with `/`,
+    /// the preset's name and the path of its config.
+    fn origin_env(
+        &self,
+        hooks: &ComputedHooks,
+        item_name: &str,
+        item_id: &str,
+    ) -> Vec<(String, String)> {
+        vec![
+            ("UFF_ITEM_NAME".to_string(), item_name.to_string()),
+            ("UFF_ITEM_ID".to_string(), item_id.to_string()),
+            ("UFF_MENU_PATH".to_string(), hooks.menu_path.clone()),
+            ("UFF_PRESET".to_string(), self.preset.clone()),
+            (
+                "UFF_CONFIG_PATH".to_string(),
+                self.config_path.display().to_string(),
+            ),
+        ]
+    }
+}
+
+fn spawn_post_launch(hooks: &ComputedHooks, origin: &[(String, String)]) {
+    if !hooks.post_launch.is_empty()
+        && let Err(error) = hook_command(&hooks.post_launch, origin).spawn()
+    {
+        warn!(
+            "failed to run post-launch hook {}: {error}",
+            hooks.post_launch.join(" ")
+        );
+    }
+}
+
+/// Hooks always get the `UFF_*` variables, even with `export-env #false`.
+fn hook_command(command: &[String], origin: &[(String, String)]) -> Command {
+    let mut hook = Command::new(&command[0]);
+    hook.args(&command[1..])
+        .stdin(Stdio::null())
+        .envs(origin.iter().map(|(name, value)| (name, value)));
+    hook
+}
+
+pub fn spawn_program(program: &ComputedProgram, watcher: Option<&Path>) -> Result<()> {
+    spawn_wrapped(program, Wrappers::find(program, installed), watcher)
+}
+
+/// Spawns the program through the wrappers that were found for it, started by
+/// `watcher` if it has `watch-exit`.
+fn spawn_wrapped(
+    program: &ComputedProgram,
+    wrappers: Wrappers,
+    watcher: Option<&Path>,
+) -> Result<()> {
+    let stdio = ProgramStdio::open(program)?;
+    if !program.chain.is_empty() {
+        if let Some(notification) = &program.notify {
+            notify(
+                &notification.summary,
+                notification.body.as_deref(),
+                notification.icon.as_deref(),
+            );
+        }
+        let chain: Vec<Vec<String>> = program
+            .chain
+            .iter()
+            .map(|command| wrap_command(program, command, wrappers))
+            .collect();
+        let setup = |command: &mut Command| {
+            command.envs(program.env.iter().map(|(name, value)| (name, value)));
+            stdio.apply(command, program.attached)
+        };
+        if let Err(error) = run_chain(&chain, program.continue_on_error, setup) {
+            notify("uff: chain failed", Some(&error.to_string()), None);
+            return Err(error);
+        }
+        return Ok(());
+    }
+
+    if program.command.is_empty() {
+        return Err(miette!("the selected item has no command to run"));
+    }
+    info!("running program: {}", program.describe());
+    let wrapper = wrapper_prefix(program, wrappers);
+    if !wrapper.is_empty() {
+        debug!("running it through {}", wrapper.join(" "));
+    }
+    if let Some(compositor) = wrappers.compositor {
+        debug!("handing it to {}", compositor.tool());
+    }
+    let command = wrap_command(program, &program.command, wrappers);
+    let (name, arguments) = command.split_first().unwrap();
+    let watcher = watcher.filter(|_| program.watch_exit.is_some());
+    let mut command = if let Some((exe, timeout)) = watcher.zip(program.watch_exit) {
+        let mut watched = Command::new(exe);
+        watched.args(watcher_args(timeout, &program.describe(), &command));
+        watched
+    } else {
+        let mut unwatched = Command::new(name);
+        unwatched.args(arguments);
+        unwatched
+    };
+    command.envs(program.env.iter().map(|(name, value)| (name, value)));
+    stdio.apply(&mut command, program.attached)?;
+    if program.stdin.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    let mut child = command
+        .spawn()
+        .into_diagnostic()
+        .context("failed to spawn selected command")?;
+    if let Some(text) = &program.stdin {
+        // Closed once written, so the command sees the end of its input
+        let mut stdin = child.stdin.take().unwrap();
+        if let Err(error) = writeln!(stdin, "{text}") {
+            warn!("failed to write to {name}'s stdin: {error}");
+        }
+    }
+
+    if let Some(notification) = &program.notify {
+        notify(
+            &notification.summary,
+            notification.body.as_deref(),
+            notification.icon.as_deref(),
+        );
+    }
+
+    // Without a watcher process, uff watches it itself
+    if watcher.is_none()
+        && let Some(timeout) = program.watch_exit
+        && let Some(status) = watch_exit(&mut child, Duration::from_secs(timeout))
+    {
+        let message = format!("{} exited with {status}", program.describe());
+        error!("{message}");
+        notify("uff: command failed", Some(&message), None);
+    }
+    detach::reap(child);
+
+    Ok(())
+}
+
+/// What `spawn_program` would run, each command quoted for `sh` with the
+/// wrappers that are installed.
+fn planned_commands(program: &ComputedProgram) -> Result<Vec<String>> {
+    let commands = if program.chain.is_empty() {
+        if program.command.is_empty() {
+            return Err(miette!("the selected item has no command to run"));
+        }
+        std::slice::from_ref(&program.command)
+    } else {
+        &program.chain
+    };
+    let wrappers = Wrappers::find(program, installed);
+    Ok(commands
+        .iter()
+        .map(|command| {
+            let wrapped = wrap_command(program, command, wrappers);
+            if program.redacted {
+                format!("{} (arguments hidden)", wrapped[0])
+            } else {
+                shell_join(&wrapped)
+            }
+        })
+        .collect())
+}
+
+/// Which of the tools a program asks to be run through are installed, checked
+/// once per launch so a missing one is only warned about once.
+#[derive(Debug, Clone, Copy, Default)]
+struct Wrappers {
+    systemd_run: bool,
+    nice: bool,
+    ionice: bool,
+    /// Hands the wrapped command to the compositor, see `handoff_command`.
+    compositor: Option<Compositor>,
+}
+
+/// A compositor that can start programs itself, so they're its children
+/// instead of uff's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compositor {
+    Sway,
+    Hyprland,
+}
+
+impl Compositor {
+    /// The tool that asks it to start a program.
+    fn tool(self) -> &'static str {
+        match self {
+            Compositor::Sway => "swaymsg",
+            Compositor::Hyprland => "hyprctl",
+        }
+    }
+}
+
+impl Wrappers {
+    fn find(program: &ComputedProgram, installed: impl Fn(&str) -> bool) -> Self {
+        let find = |wanted: bool, name: &str, fallback: &str| {
+            let found = wanted && installed(name);
+            if wanted && !found {
+                warn!("{name} is not installed, {fallback}");
+            }
+            found
+        };
+        let mut compositor = compositor(program.launch_method, |name| std::env::var_os(name));
+        if let Some(chosen) = compositor
+            && let Some(reason) = handoff_unsupported(program)
+        {
+            let tool = chosen.tool();
+            // Only worth a warning when the tool was asked for by name
+            if program.launch_method == ComputedLaunchMethod::Auto {
+                debug!("{reason}, so it's launched directly instead of through {tool}");
+            } else {
+                warn!("{reason}, so it's launched directly instead of through {tool}");
+            }
+            compositor = None;
+        }
+        Self {
+            compositor: compositor
+                .filter(|compositor| find(true, compositor.tool(), "launching directly")),
+            systemd_run: find(
+                program.systemd_scope.is_some(),
+                "systemd-run",
+                "launching without a systemd scope",
+            ),
+            nice: find(
+                program.nice.is_some(),
+                "nice",
+                "launching at normal priority",
+            ),
+            ionice: find(
+                program.ionice.is_some(),
+                "ionice",
+                "launching at normal I/O priority",
+            ),
+        }
+    }
+}
+
+/// The command behind `systemd-run`, `nice` and `ionice` as the program asks,
+/// leaving out the ones that aren't installed.
+fn wrap_command(program: &ComputedProgram, command: &[String], wrappers: Wrappers) -> Vec<String> {
+    let mut wrapped = wrapper_prefix(program, wrappers);
+    wrapped.extend(command.iter().cloned());
+    match wrappers.compositor {
+        Some(compositor) => handoff_command(compositor, program, &wrapped),
+        None => wrapped,
+    }
+}
+
+/// The `systemd-run`, `nice` and `ionice` words that `wrap_command` puts in
+/// front of the command, before it's handed to the compositor.
+fn wrapper_prefix(program: &ComputedProgram, wrappers: Wrappers) -> Vec<String> {
+    let mut wrapped = Vec::new();
+    if let Some(slug) = &program.systemd_scope
+        && wrappers.systemd_run
+    {
+        wrapped.extend([
+            "systemd-run".to_string(),
+            "--user".to_string(),
+            "--scope".to_string(),
+            "--quiet".to_string(),
+            format!("--unit={}", unit_name(slug)),
+            "--".to_string(),
+        ]);
+    }
+    if let Some(nice) = program.nice
+        && wrappers.nice
+    {
+        wrapped.extend(["nice".to_string(), "-n".to_string(), nice.to_string()]);
+    }
+    if let Some(ionice) = program.ionice
+        && wrappers.ionice
+    {
+        wrapped.extend([
+            "ionice".to_string(),
+            "-c".to_string(),
+            ionice.class.to_string(),
+        ]);
+        if let Some(level) = ionice.level {
+            wrapped.extend(["-n".to_string(), level.to_string()]);
+        }
+    }
+    wrapped
+}
+
+/// The compositor the launch method hands programs to, `auto` picks the one
+/// uff was started under going by the variables each sets for its session.
+fn compositor(
+    method: ComputedLaunchMethod,
+    var: impl Fn(&str) -> Option<OsString>,
+) -> Option<Compositor> {
+    let set = |name: &str| var(name).is_some_and(|value| !value.is_empty());
+    match method {
+        ComputedLaunchMethod::Direct => None,
+        ComputedLaunchMethod::Swaymsg => Some(Compositor::Sway),
+        ComputedLaunchMethod::Hyprctl => Some(Compositor::Hyprland),
+        ComputedLaunchMethod::Auto if set("SWAYSOCK") => Some(Compositor::Sway),
+        ComputedLaunchMethod::Auto if set("HYPRLAND_INSTANCE_SIGNATURE") => {
+            Some(Compositor::Hyprland)
+        }
+        ComputedLaunchMethod::Auto => None,
+    }
+}
+
+/// Why the program can't be handed to the compositor, if it can't. It's
+/// started with the compositor's environment and stdio, and uff can't wait for it.
+fn handoff_unsupported(program: &ComputedProgram) -> Option<&'static str> {
+    if !program.chain.is_empty() {
+        Some("a chain waits for each command")
+    } else if program.stdin.is_some() {
+        Some("the program's input is written to its stdin")
+    } else if program.attached {
+        Some("it stays attached to uff with detach #false")
+    } else if program.stdout.is_some() || program.stderr.is_some() {
+        Some("its output is redirected")
+    } else {
+        None
+    }
+}
+
+/// `swaymsg exec -- <command>` or `hyprctl dispatch exec -- [rules] <command>`,
+/// with the compositor's shell running `env` to set the program's variables
+/// since it doesn't get uff's.
+fn handoff_command(
+    compositor: Compositor,
+    program: &ComputedProgram,
+    command: &[String],
+) -> Vec<String> {
+    let mut words = Vec::new();
+    if !program.env.is_empty() {
+        words.push("env".to_string());
+        words.extend(
+            program
+                .env
+                .iter()
+                .map(|(name, value)| format!("{name}={value}")),
+        );
+    }
+    words.extend(command.iter().cloned());
+    let mut shell_command = shell_join(&words);
+    let mut handoff = vec![compositor.tool().to_string()];
+    match compositor {
+        Compositor::Sway => handoff.push("exec".to_string()),
+        Compositor::Hyprland => {
+            handoff.extend(["dispatch".to_string(), "exec".to_string()]);
+            // Hyprland reads the rules itself before handing the rest to its shell
+            if !program.hypr_rules.is_empty() {
+                shell_command = format!("[{}] {shell_command}", program.hypr_rules.join("; "));
+            }
+        }
+    }
+    handoff.extend(["--".to_string(), shell_command]);
+    handoff
+}
+
+/// Quotes each word for `sh` and joins them with spaces, so they come back out
+/// as the same words.
+pub fn shell_join(words: &[String]) -> String {
+    words
+        .iter()
+        .map(|word| shell_quote(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The word as is if the shell leaves it alone, or in single quotes otherwise.
+/// A single quote can't be escaped inside them, so it's closed, escaped and
+/// reopened. `=` is quoted so a first word isn't read as an assignment, and `,`
+/// so sway doesn't split the command on it.
+fn shell_quote(word: &str) -> Cow<'_, str> {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || "-_./:+@%".contains(character));
+    if plain {
+        Cow::Borrowed(word)
+    } else {
+        Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+    }
+}
+
+/// A unit name that won't clash with an earlier launch of the same item.
+fn unit_name(slug: &str) -> String {
+    let random = RandomState::new().hash_one(std::process::id()) as u32;
+    format!("uff-{slug}-{random:08x}")
+}
+
+fn installed(name: &str) -> bool {
+    settings::find_executable(name, std::env::var_os("PATH")).is_ok()
+}
+
+/// Why uff can't be replaced by the program, if it can't.
+fn exec_unsupported(program: &ComputedProgram) -> Option<&'static str> {
+    if !program.chain.is_empty() {
+        Some("a chain runs more than one command")
+    } else if program.stdin.is_some() {
+        Some("the program's input is written to its stdin")
+    } else {
+        None
+    }
+}
+
+/// Replaces uff with the program, keeping its stdio and session. Only returns
+/// if that failed.
+fn exec_program(program: &ComputedProgram) -> miette::Report {
+    if program.command.is_empty() {
+        return miette!("the selected item has no command to run");
+    }
+    let command = wrap_command(
+        program,
+        &program.command,
+        Wrappers::find(program, installed),
+    );
+    info!("replacing uff with: {}", program.describe());
+    if let Some(notification) = &program.notify {
+        notify(
+            &notification.summary,
+            notification.body.as_deref(),
+            notification.icon.as_deref(),
+        );
+    }
+    let mut exec = Command::new(&command[0]);
+    exec.args(&command[1..])
+        .envs(program.env.iter().map(|(name, value)| (name, value)));
+    // Never detached, uff's own session and stdio are what's being kept
+    if let Err(error) = ProgramStdio::open(program).and_then(|stdio| stdio.apply(&mut exec, true)) {
+        return error;
+    }
+    let error = exec.exec();
+    miette!(
+        help = "uff has nothing else to fall back to, check that the command exists",
+        "failed to exec {}: {error}",
+        program.describe()
+    )
+}
+
+/// Where a program's stdout and stderr go when the config chose, with log files
+/// opened once so every step of a chain writes to the same one.
+struct ProgramStdio {
+    stdout: Option<Output>,
+    stderr: Option<Output>,
+}
+
+enum Output {
+    Null,
+    Inherit,
+    File(File),
+}
+
+impl ProgramStdio {
+    fn open(program: &ComputedProgram) -> Result<Self> {
+        let stdout = program.stdout.as_ref().map(Output::open).transpose()?;
+        // Opened separately the two would write over each other
+        let stderr = match (&stdout, &program.stderr) {
+            (Some(Output::File(file)), Some(ComputedOutput::File { .. }))
+                if program.stderr == program.stdout =>
+            {
+                Some(Output::File(file.try_clone().into_diagnostic()?))
+            }
+            _ => program.stderr.as_ref().map(Output::open).transpose()?,
+        };
+        Ok(Self { stdout, stderr })
+    }
+
+    /// Detaches the command unless it stays `attached`, then redirects it.
+    fn apply(&self, command: &mut Command, attached: bool) -> Result<()> {
+        if !attached {
+            detach::detach(command);
+        }
+        if let Some(stdout) = &self.stdout {
+            command.stdout(stdout.stdio()?);
+        }
+        if let Some(stderr) = &self.stderr {
+            command.stderr(stderr.stdio()?);
+        }
+        Ok(())
+    }
+}
+
+impl Output {
+    fn open(output: &ComputedOutput) -> Result<Self> {
+        Ok(match output {
+            ComputedOutput::Null => Self::Null,
+            ComputedOutput::Inherit => Self::Inherit,
+            ComputedOutput::File { path, append } => Self::File(open_log_file(path, *append)?),
+        })
+    }
+
+    fn stdio(&self) -> Result<Stdio> {
+        Ok(match self {
+            Self::Null => Stdio::null(),
+            Self::Inherit => Stdio::inherit(),
+            Self::File(file) => file.try_clone().into_diagnostic()?.into(),
+        })
+    }
+}
+
+/// Opens the file for writing, creating it and its parent directories if needed.
+fn open_log_file(path: &str, append: bool) -> Result<File> {
+    let path = Path::new(path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .into_diagnostic()
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .into_diagnostic()
+        .with_context(|| format!("failed to open {}", path.display()))
+}
+
+/// Runs each command after the one before it has exited, stopping at the first
+/// failure unless `continue_on_error` is set. `setup` is applied to each command first.
+fn run_chain(
+    chain: &[Vec<String>],
+    continue_on_error: bool,
+    setup: impl Fn(&mut Command) -> Result<()>,
+) -> Result<()> {
+    for (step, command) in chain.iter().enumerate() {
+        let description = format!("step {} of chain: {}", step + 1, command.join(" "));
+        info!("running {description}");
+        let mut child = Command::new(&command[0]);
+        child.args(&command[1..]);
+        let status = setup(&mut child)
+            .and_then(|()| child.status().into_diagnostic())
+            .with_context(|| format!("failed to run {description}"));
+        let error = match status {
+            Ok(status) if status.success() => continue,
+            Ok(status) => miette!("{description} exited with {status}"),
+            Err(error) => error,
+        };
+        if !continue_on_error {
+            return Err(error);
+        }
+        warn!("{error:?}");
+    }
+    Ok(())
+}
+
+/// uff itself, to run as the watcher for programs with `watch-exit` and to
+/// rebuild stale caches in the background.
+pub fn uff_exe() -> Option<PathBuf> {
+    std::env::current_exe()
+        .inspect_err(|error| warn!("failed to find uff's executable: {error}"))
+        .ok()
+}
+
+/// `uff --watch-exit` runs the command as its own child, so only the watcher
+/// waits to see whether it fails, not uff.
+fn watcher_args(timeout: u64, description: &str, command: &[String]) -> Vec<String> {
+    let mut args = vec![
+        "--watch-exit".to_string(),
+        timeout.to_string(),
+        description.to_string(),
+    ];
+    args.extend_from_slice(command);
+    args
+}
+
+/// The watcher started with `watcher_args`, which notifies if the command
+/// fails to start or exits with an error within its timeout. It inherits the
+/// program's env and stdio, so the command does too.
+pub fn run_watcher(args: &[String]) {
+    if let Some(message) = watched_failure(args) {
+        notify("uff: command failed", Some(&message), None);
+    }
+}
+
+fn watched_failure(args: &[String]) -> Option<String> {
+    let [timeout, description, name, arguments @ ..] = args else {
+        return Some("uff --watch-exit needs a timeout, a description and a command".to_string());
+    };
+    let Ok(timeout) = timeout.parse() else {
+        return Some(format!("invalid --watch-exit timeout: {timeout}"));
+    };
+    let mut child = match Command::new(name).args(arguments).spawn() {
+        Ok(child) => child,
+        Err(error) => return Some(format!("failed to start {description}: {error}")),
+    };
+    let status = watch_exit(&mut child, Duration::from_secs(timeout))?;
+    Some(format!("{description} exited with {status}"))
+}
+
+/// Waits up to `timeout` for the child to exit, returning its status if it failed.
+/// A child that is still running afterwards is left alone.
+fn watch_exit(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        match child.try_wait() {
+            Ok(Some(status)) => return (!status.success()).then_some(status),
+            Ok(None) => std::thread::sleep(Duration::from_millis(50)),
+            Err(error) => {
+                warn!("failed to watch launched command: {error}");
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Waits up to `timeout` for the child to exit, killing it if it hasn't.
+/// Returns `None` if it had to be killed.
+pub fn wait_or_kill(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().into_diagnostic()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn notify_command(summary: &str, body: Option<&str>, icon: Option<&str>) -> Command {
+    let mut command = Command::new("notify-send");
+    if let Some(icon) = icon {
+        command.args(["--icon", icon]);
+    }
+    command.arg(summary).args(body);
+    command
+}
+
+/// Sends a desktop notification, failures are only logged.
+pub fn notify(summary: &str, body: Option<&str>, icon: Option<&str>) {
+    run_notify_command(notify_command(summary, body, icon));
+}
+
+fn run_notify_command(mut command: Command) {
+    match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("notify-send exited with {status}"),
+        Err(error) => warn!("failed to run notify-send: {error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn launcher() -> Launcher {
+        Launcher {
+            exec: false,
+            dry_run: false,
+            export_env: true,
+            activation: false,
+            preset: "default".to_string(),
+            config_path: PathBuf::from("/config/uff/default.kdl"),
+            watcher: None,
+        }
+    }
+
+    /// The file's contents once it exists and `done` says they're complete,
+    /// since programs aren't waited for. Whatever they are after 5 seconds
+    /// otherwise.
+    fn read_when(path: &Path, done: impl Fn(&str) -> bool) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let contents = std::fs::read_to_string(path);
+            if contents.as_deref().is_ok_and(&done) || Instant::now() >= deadline {
+                return contents.unwrap_or_default();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_watch_exit() {
+        let mut failing = Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        let status = watch_exit(&mut failing, Duration::from_secs(5)).unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        let mut succeeding = Command::new("true").spawn().unwrap();
+        assert!(watch_exit(&mut succeeding, Duration::from_secs(5)).is_none());
+
+        // Long running programs are only watched until the timeout
+        let mut sleeping = Command::new("sleep").arg("5").spawn().unwrap();
+        let start = Instant::now();
+        assert!(watch_exit(&mut sleeping, Duration::from_millis(200)).is_none());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        sleeping.kill().unwrap();
+        sleeping.wait().unwrap();
+    }
+
+    #[test]
+    fn test_watcher() {
+        let strings = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let args = watcher_args(5, "sh -c 'exit 3'", &strings(&["sh", "-c", "exit 3"]));
+        assert_eq!(
+            args,
+            ["--watch-exit", "5", "sh -c 'exit 3'", "sh", "-c", "exit 3"]
+        );
+        assert_eq!(
+            watched_failure(&args[1..]).unwrap(),
+            "sh -c 'exit 3' exited with exit status: 3"
+        );
+        assert!(watched_failure(&watcher_args(5, "true", &strings(&["true"]))[1..]).is_none());
+
+        // It stops watching at the timeout, leaving the program running
+        let start = Instant::now();
+        assert!(watched_failure(&strings(&["1", "sleep", "sleep", "3"])).is_none());
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        assert!(
+            watched_failure(&strings(&["5", "missing", "/nonexistent/program"]))
+                .unwrap()
+                .starts_with("failed to start missing: ")
+        );
+        assert!(watched_failure(&strings(&["soon", "true", "true"])).is_some());
+        assert!(watched_failure(&strings(&["5", "true"])).is_some());
+    }
+
+    #[test]
+    fn test_spawn_watched() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A fake uff that records how it was started to watch the program
+        let dir = TempDir::new().unwrap();
+        let watcher = dir.path().join("uff");
+        let recorded = dir.path().join("args");
+        std::fs::write(
+            &watcher,
+            format!(
+                "#!/bin/sh\nprintf '%s\\n' \"$UFF_ITEM_NAME\" \"$@\" > {}\n",
+                recorded.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&watcher, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut launcher = launcher();
+        launcher.watcher = Some(watcher);
+
+        // It gets the program's env, which the program inherits from it
+        let mut program = ComputedProgram {
+            command: vec!["sh".to_string(), "-c".to_string(), "exit 3".to_string()],
+            watch_exit: Some(5),
+            ..Default::default()
+        };
+        launcher
+            .launch(&program, &ComputedHooks::default(), "Fail", "fail")
+            .unwrap();
+        assert_eq!(
+            read_when(&recorded, |args| args.ends_with("exit 3\n")),
+            "Fail\n--watch-exit\n5\nsh -c exit 3\nsh\n-c\nexit 3\n"
+        );
+
+        // Programs without watch-exit are started by themselves
+        std::fs::remove_file(&recorded).unwrap();
+        program.command[2] = format!("echo unwatched > {}", recorded.display());
+        program.watch_exit = None;
+        launcher
+            .launch(&program, &ComputedHooks::default(), "Echo", "echo")
+            .unwrap();
+        assert_eq!(
+            read_when(&recorded, |args| args.ends_with('\n')),
+            "unwatched\n"
+        );
+    }
+
+    #[test]
+    fn test_notify_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A fake notify-send that records its arguments
+        let temp = TempDir::new().unwrap();
+        let fake_bin = temp.path().join("test-fake-notify");
+        std::fs::create_dir_all(&fake_bin).unwrap();
+        let script = fake_bin.join("notify-send");
+        let output = fake_bin.join("args");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\nprintf '%s\\n' \"$@\" > {}\n", output.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut command =
+            notify_command("Screenshot saved", Some("in ~/Pictures"), Some("/icon.png"));
+        command.env("PATH", &fake_bin);
+        run_notify_command(command);
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "--icon\n/icon.png\nScreenshot saved\nin ~/Pictures\n"
+        );
+
+        // A missing notify-send only warns
+        let mut command = notify_command("Screenshot saved", None, None);
+        command.env("PATH", "/nonexistent");
+        run_notify_command(command);
+    }
+
+    #[test]
+    fn test_run_chain() {
+        let temp = TempDir::new().unwrap();
+        let log = temp.path().join("test-chain-log");
+        let step = |name: &str| {
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("echo {name} >> {}", log.display()),
+            ]
+        };
+        let failing = vec!["sh".to_string(), "-c".to_string(), "exit 4".to_string()];
+
+        run_chain(&[step("lock"), step("suspend")], false, |_| Ok(())).unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "lock\nsuspend\n");
+
+        let _ = std::fs::remove_file(&log);
+        let error = run_chain(
+            &[step("lock"), failing.clone(), step("suspend")],
+            false,
+            |_| Ok(()),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "step 2 of chain: sh -c exit 4 exited with exit status: 4"
+        );
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "lock\n");
+
+        let _ = std::fs::remove_file(&log);
+        let missing = vec!["/nonexistent/command".to_string()];
+        run_chain(&[missing, failing, step("suspend")], true, |_| Ok(())).unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "suspend\n");
+    }
+
+    #[test]
+    fn test_wrap_command() {
+        use crate::config::ComputedIoPriority;
+
+        let command = vec!["make".to_string(), "-j8".to_string()];
+        let mut program = ComputedProgram {
+            command: command.clone(),
+            ..Default::default()
+        };
+        let all = Wrappers {
+            systemd_run: true,
+            nice: true,
+            ionice: true,
+            compositor: None,
+        };
+        assert_eq!(wrap_command(&program, &command, all), command);
+
+        program.nice = Some(10);
+        program.ionice = Some(ComputedIoPriority {
+            class: 2,
+            level: Some(7),
+        });
+        assert_eq!(
+            wrap_command(&program, &command, all),
+            [
+                "nice", "-n", "10", "ionice", "-c", "2", "-n", "7", "make", "-j8"
+            ]
+        );
+
+        program.ionice = Some(ComputedIoPriority {
+            class: 3,
+            level: None,
+        });
+        assert_eq!(
+            wrap_command(&program, &command, all),
+            ["nice", "-n", "10", "ionice", "-c", "3", "make", "-j8"]
+        );
+
+        program.nice = None;
+        program.systemd_scope = Some("firefox".to_string());
+        let wrapped = wrap_command(&program, &command, all);
+        assert_eq!(
+            wrapped[..4],
+            ["systemd-run", "--user", "--scope", "--quiet"]
+        );
+        let unit = wrapped[4].strip_prefix("--unit=uff-firefox-").unwrap();
+        assert!(unit.len() == 8 && unit.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_eq!(wrapped[5..], ["--", "ionice", "-c", "3", "make", "-j8"]);
+
+        // Missing wrappers are skipped
+        program.nice = Some(10);
+        let wrappers = Wrappers::find(&program, |name| name == "ionice");
+        assert_eq!(
+            wrap_command(&program, &command, wrappers),
+            ["ionice", "-c", "3", "make", "-j8"]
+        );
+        let wrappers = Wrappers::find(&program, |_| false);
+        assert_eq!(wrap_command(&program, &command, wrappers), command);
+    }
+
+    #[test]
+    fn test_planned_commands() {
+        let mut program = ComputedProgram {
+            command: vec!["foot".to_string(), "my term".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(planned_commands(&program).unwrap(), ["foot 'my term'"]);
+        program.redacted = true;
+        assert_eq!(
+            planned_commands(&program).unwrap(),
+            ["foot (arguments hidden)"]
+        );
+        program.redacted = false;
+        program.chain = vec![
+            vec!["make".to_string()],
+            vec!["make".to_string(), "install".to_string()],
+        ];
+        assert_eq!(
+            planned_commands(&program).unwrap(),
+            ["make", "make install"]
+        );
+        assert!(planned_commands(&ComputedProgram::default()).is_err());
+    }
+
+    #[test]
+    fn test_shell_join() {
+        let words: Vec<String> = [
+            "firefox",
+            "--new-window",
+            "https://example.com/?q=a b&c=d",
+            "it's",
+            "",
+            "$HOME `id` $(id) \\ \"*\"",
+            "a\nb",
+            "FOO=bar",
+            "x,y;z",
+        ]
+        .iter()
+        .map(|word| word.to_string())
+        .collect();
+        let joined = shell_join(&words);
+        assert_eq!(
+            joined.split(' ').take(3).collect::<Vec<_>>(),
+            ["firefox", "--new-window", "'https://example.com/?q=a"]
+        );
+        assert!(joined.contains(r"'it'\''s' '' "));
+
+        // sh splits it back into the same words, without expanding anything
+        let output = Command::new("sh")
+            .args(["-c", &format!("printf '%s\\0' {joined}")])
+            .output()
+            .unwrap();
+        let split: Vec<&str> = std::str::from_utf8(&output.stdout)
+            .unwrap()
+            .strip_suffix('\0')
+            .unwrap()
+            .split('\0')
+            .collect();
+        assert_eq!(split, words);
+    }
+
+    #[test]
+    fn test_swaymsg() {
+        use ComputedLaunchMethod::{Auto, Direct, Hyprctl, Swaymsg};
+
+        let session = |vars: &'static [(&str, &str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| OsString::from(value))
+            }
+        };
+        let sway = session(&[("SWAYSOCK", "/run/user/1000/sway-ipc.sock")]);
+        let hyprland = session(&[("HYPRLAND_INSTANCE_SIGNATURE", "abc_123")]);
+        assert_eq!(compositor(Direct, sway), None);
+        assert_eq!(compositor(Swaymsg, session(&[])), Some(Compositor::Sway));
+        assert_eq!(compositor(Hyprctl, sway), Some(Compositor::Hyprland));
+        assert_eq!(compositor(Auto, sway), Some(Compositor::Sway));
+        assert_eq!(compositor(Auto, hyprland), Some(Compositor::Hyprland));
+        assert_eq!(compositor(Auto, session(&[])), None);
+        assert_eq!(compositor(Auto, session(&[("SWAYSOCK", "")])), None);
+
+        let command = vec![
+            "foot".to_string(),
+            "--title".to_string(),
+            "my term".to_string(),
+        ];
+        let mut program = ComputedProgram {
+            command: command.clone(),
+            launch_method: Swaymsg,
+            nice: Some(5),
+            ..Default::default()
+        };
+        let wrappers = Wrappers::find(&program, |_| true);
+        assert_eq!(wrappers.compositor, Some(Compositor::Sway));
+        assert_eq!(
+            wrap_command(&program, &command, wrappers),
+            ["swaymsg", "exec", "--", "nice -n 5 foot --title 'my term'"]
+        );
+        // Sway doesn't pass on uff's environment, so it's set in the command
+        program.env = vec![("UFF_ITEM_NAME".to_string(), "My Term".to_string())];
+        assert_eq!(
+            wrap_command(&program, &command, wrappers)[3],
+            "env 'UFF_ITEM_NAME=My Term' nice -n 5 foot --title 'my term'"
+        );
+
+        // Without swaymsg, or for programs sway can't run, it's launched directly
+        let found = Wrappers::find(&program, |name| name != "swaymsg");
+        assert_eq!(found.compositor, None);
+        program.stdin = Some("text".to_string());
+        assert!(handoff_unsupported(&program).is_some());
+        assert_eq!(Wrappers::find(&program, |_| true).compositor, None);
+        program.stdin = None;
+        program.attached = true;
+        assert!(handoff_unsupported(&program).is_some());
+        program.attached = false;
+        program.chain = vec![command.clone()];
+        assert!(handoff_unsupported(&program).is_some());
+        program.chain = Vec::new();
+        program.stdout = Some(ComputedOutput::Null);
+        assert!(handoff_unsupported(&program).is_some());
+    }
+
+    #[test]
+    fn test_hyprctl() {
+        let command = vec!["echo".to_string(), "it's \"quoted\"".to_string()];
+        let mut program = ComputedProgram {
+            command: command.clone(),
+            launch_method: ComputedLaunchMethod::Hyprctl,
+            ..Default::default()
+        };
+        let wrappers = Wrappers::find(&program, |_| true);
+        assert_eq!(wrappers.compositor, Some(Compositor::Hyprland));
+        let hyprctl = |program: &ComputedProgram| wrap_command(program, &command, wrappers);
+        assert_eq!(
+            hyprctl(&program),
+            [
+                "hyprctl",
+                "dispatch",
+                "exec",
+                "--",
+                r#"echo 'it'\''s "quoted"'"#
+            ]
+        );
+
+        // Rules go in front of the environment, which is part of the shell's command
+        program.hypr_rules = vec!["workspace 3 silent".to_string()];
+        assert_eq!(
+            hyprctl(&program)[4],
+            r#"[workspace 3 silent] echo 'it'\''s "quoted"'"#
+        );
+        program.hypr_rules.push("float".to_string());
+        program.env = vec![("A".to_string(), "b c".to_string())];
+        assert_eq!(
+            hyprctl(&program)[4],
+            r#"[workspace 3 silent; float] env 'A=b c' echo 'it'\''s "quoted"'"#
+        );
+
+        // Sway has no rules, so they're left out
+        program.launch_method = ComputedLaunchMethod::Swaymsg;
+        let wrappers = Wrappers::find(&program, |_| true);
+        assert_eq!(
+            wrap_command(&program, &command, wrappers)[3],
+            r#"env 'A=b c' echo 'it'\''s "quoted"'"#
+        );
+    }
+
+    #[test]
+    fn test_spawn_through_compositor() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A fake swaymsg that records its arguments
+        let temp = TempDir::new().unwrap();
+        let fake_bin = temp.path().join("test-fake-swaymsg");
+        std::fs::create_dir_all(&fake_bin).unwrap();
+        let script = fake_bin.join("swaymsg");
+        let output = fake_bin.join("args");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\nprintf '%s\\n' \"$@\" > {}\n", output.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // More words than the handoff has, which the wrapper used to be cut from
+        let program = ComputedProgram {
+            command: ["foot", "-a", "scratch", "-e", "htop", "-d", "5"]
+                .map(str::to_string)
+                .to_vec(),
+            env: vec![("PATH".to_string(), fake_bin.display().to_string())],
+            nice: Some(10),
+            launch_method: ComputedLaunchMethod::Swaymsg,
+            ..Default::default()
+        };
+        spawn_wrapped(&program, Wrappers::find(&program, |_| true), None).unwrap();
+        assert_eq!(
+            read_when(&output, |args| args.lines().count() == 3),
+            format!(
+                "exec\n--\nenv 'PATH={}' nice -n 10 foot -a scratch -e htop -d 5\n",
+                fake_bin.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_exec_program() {
+        let mut program = ComputedProgram {
+            command: vec!["/nonexistent/command".to_string(), "--flag".to_string()],
+            exec: true,
+            ..Default::default()
+        };
+        assert_eq!(exec_unsupported(&program), None);
+        // A failed exec comes back as an error instead of replacing the tests
+        assert!(
+            exec_program(&program)
+                .to_string()
+                .starts_with("failed to exec /nonexistent/command --flag: ")
+        );
+
+        program.stdin = Some("text".to_string());
+        assert!(exec_unsupported(&program).is_some());
+        program.stdin = None;
+        program.chain = vec![vec!["true".to_string()]];
+        assert!(exec_unsupported(&program).is_some());
+    }
+
+    #[test]
+    fn test_output_redirection() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("test-output-redirection");
+        let log = dir.join("logs/echo.log");
+        let file = |append| ComputedOutput::File {
+            path: log.display().to_string(),
+            append,
+        };
+        let mut program = ComputedProgram {
+            command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "echo out; echo err >&2".to_string(),
+            ],
+            stdout: Some(file(false)),
+            stderr: Some(ComputedOutput::Null),
+            ..Default::default()
+        };
+
+        // The missing directory is created
+        spawn_program(&program, None).unwrap();
+        assert_eq!(read_when(&log, |log| log.ends_with('\n')), "out\n");
+        // It's emptied before the program starts
+        spawn_program(&program, None).unwrap();
+        assert_eq!(read_when(&log, |log| log.ends_with('\n')), "out\n");
+
+        program.stdout = Some(file(true));
+        program.stderr = Some(file(true));
+        spawn_program(&program, None).unwrap();
+        assert_eq!(
+            read_when(&log, |log| log.ends_with("err\n")),
+            "out\nout\nerr\n"
+        );
+
+        // Each step of a chain adds to the same file
+        program.stdout = Some(file(false));
+        program.stderr = None;
+        program.chain = vec![
+            vec!["echo".to_string(), "one".to_string()],
+            vec!["echo".to_string(), "two".to_string()],
+        ];
+        // Which is waited for
+        spawn_program(&program, None).unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "one\ntwo\n");
+
+        // And gets the program's environment
+        program.env = vec![("UFF_TEST_STEP".to_string(), "three".to_string())];
+        program.chain = vec![vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            "echo $UFF_TEST_STEP".to_string(),
+        ]];
+        spawn_program(&program, None).unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "three\n");
+
+        program.stdout = Some(ComputedOutput::File {
+            path: "/dev/null/echo.log".to_string(),
+            append: false,
+        });
+        let error = launcher()
+            .launch(&program, &ComputedHooks::default(), "Echo", "Echo")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "failed to launch Echo");
+    }
+
+    #[test]
+    fn test_launch_with_hooks() {
+        let temp = TempDir::new().unwrap();
+        let log = temp.path().join("test-hooks-log");
+        let append = |line: &str| {
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("echo {line} >> {}", log.display()),
+            ]
+        };
+        let mut hooks = ComputedHooks {
+            pre_launch: append("pre \"$UFF_ITEM_NAME\" \"$UFF_MENU_PATH\""),
+            post_launch: append("post \"$UFF_ITEM_NAME\""),
+            menu_path: "System/Power".to_string(),
+        };
+        let program = ComputedProgram {
+            command: append("program"),
+            ..Default::default()
+        };
+
+        launcher().launch(&program, &hooks, "Lock", "Lock").unwrap();
+        // Neither the program nor the post-launch hook is waited for, so
+        // either may finish first
+        let log_contents = read_when(&log, |log| log.lines().count() == 3);
+        let mut lines: Vec<&str> = log_contents.lines().collect();
+        assert_eq!(lines.remove(0), "pre Lock System/Power");
+        lines.sort_unstable();
+        assert_eq!(lines, ["post Lock", "program"]);
+
+        // A failing pre-launch hook stops the launch
+        let _ = std::fs::remove_file(&log);
+        hooks.pre_launch = vec!["false".to_string()];
+        let error = launcher()
+            .launch(&program, &hooks, "Lock", "Lock")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "pre-launch hook false exited with exit status: 1"
+        );
+        assert!(!log.exists());
+    }
+
+    #[test]
+    fn test_origin_env() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("test-origin-env");
+        std::fs::create_dir_all(&dir).unwrap();
+        // A helper script that records the variables it was started with,
+        // moving them into place once they're all written
+        let script = dir.join("record-env");
+        let recorded = dir.join("env");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\nenv | grep '^UFF_' | sort > {0}.part\nmv {0}.part {0}\n",
+                recorded.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let hooks = ComputedHooks {
+            menu_path: "Apps/Web".to_string(),
+            ..Default::default()
+        };
+        let mut program = ComputedProgram {
+            command: vec![script.display().to_string()],
+            ..Default::default()
+        };
+        let launch = |launcher: &Launcher, program: &ComputedProgram| {
+            let _ = std::fs::remove_file(&recorded);
+            launcher
+                .launch(program, &hooks, "Firefox", "firefox")
+                .unwrap();
+            read_when(&recorded, |_| true)
+        };
+        let mut launcher = launcher();
+        assert_eq!(
+            launch(&launcher, &program),
+            "UFF_CONFIG_PATH=/config/uff/default.kdl\n\
+             UFF_ITEM_ID=firefox\n\
+             UFF_ITEM_NAME=Firefox\n\
+             UFF_MENU_PATH=Apps/Web\n\
+             UFF_PRESET=default\n"
+        );
+
+        // The program's own variables win
+        program
+            .env
+            .push(("UFF_ITEM_NAME".to_string(), "custom".to_string()));
+        assert!(launch(&launcher, &program).contains("UFF_ITEM_NAME=custom\n"));
+
+        launcher.export_env = false;
+        program.env.clear();
+        assert_eq!(launch(&launcher, &program), "");
+    }
+}
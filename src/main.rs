@@ -1,15 +1,31 @@
 use colog::format::CologStyle;
-use log::{Level, LevelFilter, info};
+use log::{Level, LevelFilter, info, warn};
 use miette::{Context, IntoDiagnostic, Result, ensure, miette};
 use std::{
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
+mod activation;
 mod config;
+mod desktop;
+mod detach;
+mod dynamic;
+mod files;
+mod fuzzel_keys;
+mod glob;
+mod icons;
+mod input;
+mod labels;
+mod launch;
+mod mime;
+mod multi;
 mod parser;
-use config::ComputedItem;
+mod recent;
+mod settings;
+use config::{ComputedItem, ConfigBuild};
+use parser::ParseOptions;
 
 struct LogFormatter;
 impl CologStyle for LogFormatter {
@@ -25,6 +41,12 @@ impl CologStyle for LogFormatter {
 }
 
 pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    // Started by `launch::spawn_program` for programs with watch-exit
+    if args.get(1).is_some_and(|arg| arg == "--watch-exit") {
+        launch::run_watcher(&args[2..]);
+        return;
+    }
     if let Err(e) = run() {
         eprintln!("{e:?}");
         std::process::exit(1);
@@ -32,70 +54,625 @@ pub fn main() {
 }
 
 fn run() -> Result<()> {
+    // Everything is let through until the level is known from the settings
     colog::default_builder()
         .format(colog::formatter(LogFormatter))
-        .filter_level(LevelFilter::Info)
+        .filter_level(LevelFilter::Trace)
         .init();
+    log::set_max_level(LevelFilter::Info);
 
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 2 || args.get(1) == Some(&"--help".into()) || args.get(1) == Some(&"-h".into())
-    {
-        println!("usage: {} [config_path]", args[0]);
+    let mut args = std::env::args();
+    let program_name = args.next().unwrap_or_else(|| "uff".to_string());
+    let mut config_path = None;
+    let mut profile = None;
+    let mut entry = None;
+    let mut strict = false;
+    let mut lenient = false;
+    let mut log_level = None;
+    let mut exec = false;
+    let mut dry_run = false;
+    let mut check_icons = false;
+    let mut refresh_icons = false;
+    let mut warm = false;
+    let mut show_usage = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => show_usage = true,
+            "--strict" => strict = true,
+            "--lenient" => lenient = true,
+            "--exec" => exec = true,
+            "--dry-run" => dry_run = true,
+            "--check-icons" => check_icons = true,
+            "--refresh-icons" => refresh_icons = true,
+            "--warm" => warm = true,
+            "--profile" => {
+                profile = Some(
+                    args.next()
+                        .ok_or_else(|| miette!("--profile needs a name"))?,
+                );
+            }
+            _ if arg.starts_with("--profile=") => {
+                profile = Some(arg["--profile=".len()..].to_string());
+            }
+            "--entry" => {
+                entry = Some(args.next().ok_or_else(|| miette!("--entry needs a name"))?);
+            }
+            _ if arg.starts_with("--entry=") => {
+                entry = Some(arg["--entry=".len()..].to_string());
+            }
+            "--log-level" => {
+                log_level = Some(
+                    args.next()
+                        .ok_or_else(|| miette!("--log-level needs a level"))?,
+                );
+            }
+            _ if arg.starts_with("--log-level=") => {
+                log_level = Some(arg["--log-level=".len()..].to_string());
+            }
+            _ if config_path.is_none() => config_path = Some(PathBuf::from(arg)),
+            _ => show_usage = true,
+        }
+    }
+    let dirs = config::Dirs::from_env()?;
+    if show_usage {
+        println!(
+            "usage: {program_name} [--profile name] [--entry name] [--strict] [--lenient] [--log-level level] [--exec] [--dry-run] [--check-icons] [--refresh-icons] [--warm] [config_path[:entry]]"
+        );
         println!("config_path defaults to $XDG_CONFIG_HOME/uff/default.kdl");
+        println!("--entry opens one of the config's top-level menus");
+        println!("--profile hides items tagged with other profiles");
+        println!("--strict fails on likely mistakes instead of warning");
+        println!(
+            "--lenient lets a repeated node overwrite the first instead of failing, and shows newlines in names as ␤"
+        );
+        println!("--log-level is one of off, error, warn, info (the default), debug or trace");
+        println!("--exec replaces uff with the selected program instead of starting it");
+        println!("--dry-run prints the selected program's command instead of running it");
+        println!("--check-icons lists every icon that can't be found instead of opening fuzzel");
+        println!(
+            "--refresh-icons looks for icons that weren't found again, even if their dirs haven't changed"
+        );
+        println!("--warm builds the config's cache if it's stale instead of opening fuzzel");
+        println!(
+            "settings shared by every config go in {}",
+            settings::global_config_path(&dirs.config).display()
+        );
         return Ok(());
     }
+    if let Some(profile) = &profile {
+        ensure!(
+            !profile.is_empty() && !profile.contains('/'),
+            "invalid profile name: {profile:?}"
+        );
+    }
+
+    let mut config_path = config_path.unwrap_or_else(|| config::default_config_path(&dirs.config));
+    // `config.kdl:entry`, unless a file with that exact name exists
+    if !config_path.exists()
+        && let Some((path, name)) = config_path.to_str().and_then(|path| path.rsplit_once(':'))
+        && !name.is_empty()
+        && !name.contains('/')
+    {
+        ensure!(
+            entry.is_none(),
+            "the entry is given twice, as {name:?} and with --entry"
+        );
+        entry = Some(name.to_string());
+        config_path = PathBuf::from(path);
+    }
+
+    let options = ParseOptions {
+        strict,
+        lenient,
+        ..Default::default()
+    };
+    let (global, global_source) =
+        settings::read_global_config(&settings::global_config_path(&dirs.config), options)?;
+    log::set_max_level(settings::log_level(log_level.as_deref(), &global.settings)?);
+
+    let build = ConfigBuild {
+        dirs: &dirs,
+        path: &config_path,
+        profile: profile.as_deref(),
+        options,
+        global: &global,
+        global_source: &global_source,
+    };
+    if check_icons {
+        let missing = config::check_icons(&build)?;
+        for icon in &missing {
+            println!("{}: icon '{}' not found", icon.item, icon.name);
+            if !icon.dirs.is_empty() {
+                println!("  searched: {}", icon.dirs.join(", "));
+            }
+            if !icon.similar.is_empty() {
+                println!("  similar: {}", icon.similar.join(", "));
+            }
+        }
+        return match missing.len() {
+            0 => {
+                println!("every icon was found");
+                Ok(())
+            }
+            1 => Err(miette!("an icon wasn't found")),
+            count => Err(miette!("{count} icons weren't found")),
+        };
+    }
 
-    let config_path = args
-        .get(1)
-        .map_or_else(config::default_config_path, PathBuf::from);
-
-    let computed_config = config::get_computed_config(&config_path)?;
-
-    let mut current_item = &ComputedItem::Menu(computed_config.initial_menu);
-    while let ComputedItem::Menu(current_menu) = current_item {
-        let mut fuzzel = Command::new("fuzzel")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .args(["--dmenu", "--index"])
-            .args(&current_menu.args)
-            .spawn()
-            .into_diagnostic()
-            .context("failed to spawn fuzzel")?;
-
-        let mut fuzzel_stdin = fuzzel
-            .stdin
-            .take()
-            .ok_or_else(|| miette!("failed to get fuzzel's stdin"))?;
-
-        fuzzel_stdin
-            .write_all(&current_menu.input)
-            .into_diagnostic()
-            .context("failed to pass input to fuzzel")?;
-
-        drop(fuzzel_stdin); // fuzzel waits until stdin is closed
-
-        let output = fuzzel
-            .wait_with_output()
-            .into_diagnostic()
-            .context("failed to wait on fuzzel")?;
-
-        ensure!(output.status.success(), "fuzzel exited without success");
-
-        let stdout = std::str::from_utf8(&output.stdout).into_diagnostic()?;
-        let selected_index: usize = stdout.trim().parse().into_diagnostic()?;
-        current_item = &computed_config.items[selected_index + current_menu.items_offset];
+    let exe = launch::uff_exe();
+    let serve_stale = !warm && global.settings.stale_while_revalidate.unwrap_or(false);
+    let computed_config = match config::get_computed_config(
+        &build,
+        refresh_icons,
+        exe.as_deref().filter(|_| serve_stale),
+    ) {
+        Ok(computed_config) => computed_config,
+        // Nobody sees the stderr of a rebuild started by stale-while-revalidate,
+        // and the next launch opens the stale menu again without saying why
+        Err(error) if warm => {
+            let causes: Vec<String> = error.chain().map(ToString::to_string).collect();
+            launch::notify(
+                "uff: failed to rebuild the cached config",
+                Some(&format!(
+                    "{}\nrun uff --warm {} to see where",
+                    causes.join(": "),
+                    config_path.display()
+                )),
+                None,
+            );
+            return Err(error);
+        }
+        Err(error) => return Err(error),
+    };
+    if warm {
+        return Ok(());
     }
 
-    let ComputedItem::Program(program) = &current_item else {
-        unreachable!();
+    let fuzzel = settings::fuzzel_path(&global.settings)?;
+
+    let launcher = launch::Launcher {
+        exec,
+        dry_run,
+        export_env: computed_config.export_env,
+        activation: computed_config.activation,
+        preset: config_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        config_path: std::path::absolute(&config_path).unwrap_or_else(|_| config_path.clone()),
+        watcher: exe,
     };
 
-    info!("running program: {}", program.command.join(" "));
-    Command::new(&program.command[0])
-        .args(&program.command[1..])
+    show_menus(
+        &fuzzel,
+        &launcher,
+        &computed_config.items,
+        computed_config.entry_menu(entry.as_deref())?,
+    )
+}
+
+/// Shows the menus from `entry_menu` until a program is launched or fuzzel is
+/// cancelled in a menu that can't go back. Cancelling a runtime item's menu
+/// goes back to the one it was picked from.
+fn show_menus(
+    fuzzel: &Path,
+    launcher: &launch::Launcher,
+    items: &[ComputedItem],
+    entry_menu: &config::ComputedMenu,
+) -> Result<()> {
+    // Menus the user has navigated through and the last line selected in each,
+    // the last one is currently open
+    let mut menu_stack = vec![(entry_menu, None)];
+    // The menu the program was picked from and the item's index in it
+    let (launch_menu, launch_index, program) = loop {
+        let (current_menu, last_selected) = *menu_stack.last().unwrap();
+        if let Some(multi_select) = &current_menu.multi_select {
+            let input = labels::fill_labels(current_menu);
+            // Binds have no actions when picking several, so they select like enter
+            let picked = multi::select(&input, |input, select| {
+                Ok(run_fuzzel(fuzzel, &current_menu.args, input, select)?
+                    .map(|selection| selection.index))
+            })?;
+            let Some(picked) = picked else {
+                if current_menu.stay_open {
+                    return Ok(());
+                }
+                ensure!(current_menu.back_on_cancel, "fuzzel exited without success");
+                menu_stack.pop();
+                continue;
+            };
+            multi::launch_picked(launcher, items, current_menu, multi_select, &picked)?;
+            if !current_menu.stay_open {
+                return Ok(());
+            }
+            continue;
+        }
+        let Some(Selection {
+            index: selected_index,
+            bind,
+        }) = run_fuzzel(
+            fuzzel,
+            &current_menu.args,
+            &labels::fill_labels(current_menu),
+            last_selected,
+        )?
+        else {
+            if current_menu.stay_open {
+                return Ok(());
+            }
+            ensure!(current_menu.back_on_cancel, "fuzzel exited without success");
+            menu_stack.pop();
+            continue;
+        };
+        menu_stack.last_mut().unwrap().1 = Some(selected_index);
+        if let Some(bind) = bind {
+            let Some(action) = current_menu
+                .actions
+                .iter()
+                .find(|action| action.item == selected_index && action.bind == bind)
+            else {
+                warn!("custom-{} has no action on this item", bind + 1);
+                continue;
+            };
+            if !current_menu.stay_open {
+                break (current_menu, selected_index, &action.program);
+            }
+            launcher.launch(
+                &action.program,
+                &current_menu.hooks,
+                current_menu.item_name(selected_index),
+                current_menu.item_id(selected_index),
+            )?;
+            continue;
+        }
+        match &items[selected_index + current_menu.items_offset] {
+            ComputedItem::Menu(menu) => menu_stack.push((menu, None)),
+            ComputedItem::Program(program) => {
+                // Confirmations are only asked once, go back to the menu they came from
+                while menu_stack.last().unwrap().0.back_on_cancel {
+                    menu_stack.pop();
+                }
+                let (menu, selected) = *menu_stack.last().unwrap();
+                let index = selected.unwrap_or(selected_index);
+                if !menu.stay_open {
+                    break (menu, index, program);
+                }
+                launcher.launch(
+                    program,
+                    &menu.hooks,
+                    menu.item_name(index),
+                    menu.item_id(index),
+                )?;
+            }
+            ComputedItem::Back => {
+                menu_stack.pop();
+                if menu_stack.is_empty() {
+                    return Ok(());
+                }
+            }
+            ComputedItem::DynamicMenu(dynamic_menu) => {
+                let entries = dynamic::read_entries(&dynamic_menu.source_command)?;
+                if entries.is_empty() {
+                    info!(
+                        "{} produced no entries",
+                        dynamic_menu.source_command.join(" ")
+                    );
+                }
+                let menu = match dynamic::outcome(dynamic_menu, &entries) {
+                    dynamic::Outcome::Menu(menu) => menu,
+                    dynamic::Outcome::Back => continue,
+                    dynamic::Outcome::Run(program) => {
+                        launcher.launch(
+                            &program,
+                            &current_menu.hooks,
+                            current_menu.item_name(selected_index),
+                            current_menu.item_id(selected_index),
+                        )?;
+                        if !current_menu.stay_open {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                };
+                // Binds have no actions in dynamic menus, so they select like enter
+                let Some(Selection {
+                    index: entry_index, ..
+                }) = run_fuzzel(fuzzel, &menu.args, &menu.input, None)?
+                else {
+                    continue;
+                };
+                // Without entries there's only a line that goes back
+                if entries.is_empty() {
+                    continue;
+                }
+                let entry = entries
+                    .get(entry_index)
+                    .ok_or_else(|| miette!("fuzzel returned an unknown entry"))?;
+                launcher.launch(
+                    &dynamic::program_for_entry(dynamic_menu, entry),
+                    &current_menu.hooks,
+                    entry,
+                    current_menu.item_id(selected_index),
+                )?;
+                if !current_menu.stay_open {
+                    return Ok(());
+                }
+            }
+            ComputedItem::Pick(pick) => {
+                // Listed every time, so it's never stale
+                let lines = dynamic::read_entries(&pick.list_command)?;
+                if lines.is_empty() {
+                    warn!("{} listed nothing to pick", pick.list_command.join(" "));
+                    continue;
+                }
+                let menu = dynamic::build_menu(&pick.args, &lines);
+                // Cancelling goes back to the menu, binds select like enter
+                let Some(Selection {
+                    index: line_index, ..
+                }) = run_fuzzel(fuzzel, &menu.args, &menu.input, None)?
+                else {
+                    continue;
+                };
+                let line = lines
+                    .get(line_index)
+                    .ok_or_else(|| miette!("fuzzel returned an unknown entry"))?;
+                launcher.launch(
+                    &dynamic::program_for_line(pick, line),
+                    &current_menu.hooks,
+                    current_menu.item_name(selected_index),
+                    current_menu.item_id(selected_index),
+                )?;
+                if !current_menu.stay_open {
+                    return Ok(());
+                }
+            }
+            ComputedItem::MimeOpen(mime_open) => {
+                let (mime_type, handlers) = mime::find_handlers(mime_open)?;
+                if handlers.is_empty() {
+                    warn!("no application can open {} ({mime_type})", mime_open.file);
+                    continue;
+                }
+                let menu = mime::build_menu(mime_open, &handlers);
+                let Some(Selection {
+                    index: handler_index,
+                    ..
+                }) = run_fuzzel(fuzzel, &menu.args, &menu.input, None)?
+                else {
+                    continue;
+                };
+                let handler = handlers
+                    .get(handler_index)
+                    .ok_or_else(|| miette!("fuzzel returned an unknown entry"))?;
+                launcher.launch(
+                    &mime::program_for_handler(mime_open, handler),
+                    &current_menu.hooks,
+                    current_menu.item_name(selected_index),
+                    current_menu.item_id(selected_index),
+                )?;
+                if !current_menu.stay_open {
+                    return Ok(());
+                }
+            }
+            ComputedItem::FilesMenu(files_menu) => {
+                let files = files::list_files(files_menu)?;
+                let menu = files::build_menu(files_menu, &files);
+                let Some(Selection {
+                    index: file_index, ..
+                }) = run_fuzzel(fuzzel, &menu.args, &menu.input, None)?
+                else {
+                    continue;
+                };
+                // An empty directory only shows an entry that goes back
+                let Some(file) = files.get(file_index) else {
+                    continue;
+                };
+                launcher.launch(
+                    &files::program_for_file(files_menu, file),
+                    &current_menu.hooks,
+                    &file.file_name().unwrap_or_default().to_string_lossy(),
+                    current_menu.item_id(selected_index),
+                )?;
+                if !current_menu.stay_open {
+                    return Ok(());
+                }
+            }
+            ComputedItem::RecentFiles(recent_files) => {
+                let files = recent::list_files(recent_files);
+                let menu = recent::build_menu(recent_files, &files);
+                let Some(Selection {
+                    index: file_index, ..
+                }) = run_fuzzel(fuzzel, &menu.args, &menu.input, None)?
+                else {
+                    continue;
+                };
+                // Without recent files there's only an entry that goes back
+                let Some(file) = files.get(file_index) else {
+                    continue;
+                };
+                launcher.launch(
+                    &recent::program_for_file(recent_files, file),
+                    &current_menu.hooks,
+                    &file.path.file_name().unwrap_or_default().to_string_lossy(),
+                    current_menu.item_id(selected_index),
+                )?;
+                if !current_menu.stay_open {
+                    return Ok(());
+                }
+            }
+            ComputedItem::Input(input) => {
+                // Cancelling or typing nothing goes back to the menu
+                let Some(text) = input::read_text(fuzzel, input)? else {
+                    continue;
+                };
+                launcher.launch(
+                    &input::program_for_text(input, &text),
+                    &current_menu.hooks,
+                    current_menu.item_name(selected_index),
+                    current_menu.item_id(selected_index),
+                )?;
+                if !current_menu.stay_open {
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    launcher.launch(
+        program,
+        &launch_menu.hooks,
+        launch_menu.item_name(launch_index),
+        launch_menu.item_id(launch_index),
+    )
+}
+
+/// A line picked in fuzzel.
+struct Selection {
+    index: usize,
+    /// The custom key binding used instead of enter, starting at 0 for custom-1.
+    bind: Option<usize>,
+}
+
+/// Shows the input in fuzzel and returns the selected line, or `None` if fuzzel
+/// was cancelled. `select` puts the cursor on that line.
+fn run_fuzzel(
+    fuzzel: &Path,
+    args: &[String],
+    input: &[u8],
+    select: Option<usize>,
+) -> Result<Option<Selection>> {
+    let mut fuzzel = Command::new(fuzzel);
+    fuzzel
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .args(["--dmenu", "--index"])
+        .args(args);
+    if let Some(select) = select {
+        fuzzel.arg(format!("--select-index={select}"));
+    }
+    let mut fuzzel = fuzzel
         .spawn()
         .into_diagnostic()
-        .context("failed to spawn selected command")?;
+        .context("failed to spawn fuzzel")?;
+
+    let mut fuzzel_stdin = fuzzel
+        .stdin
+        .take()
+        .ok_or_else(|| miette!("failed to get fuzzel's stdin"))?;
+
+    fuzzel_stdin
+        .write_all(input)
+        .into_diagnostic()
+        .context("failed to pass input to fuzzel")?;
+
+    drop(fuzzel_stdin); // fuzzel waits until stdin is closed
 
-    Ok(())
+    let output = fuzzel
+        .wait_with_output()
+        .into_diagnostic()
+        .context("failed to wait on fuzzel")?;
+
+    // custom-1 to custom-19 exit with 10 to 28 after printing the selection
+    let bind = match output.status.code() {
+        Some(0) => None,
+        Some(code @ 10..=28) => Some((code - 10) as usize),
+        _ => return Ok(None),
+    };
+
+    let stdout = std::str::from_utf8(&output.stdout).into_diagnostic()?;
+    let index = stdout.trim().parse().into_diagnostic()?;
+    Ok(Some(Selection { index, bind }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        ComputedDefaults, ComputedDynamicMenu, ComputedFilesMenu, ComputedInput, ComputedOnEmpty,
+        ComputedPassVia, ComputedPick, ComputedRecentFiles,
+    };
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_cancel_runtime_item() {
+        // A fake fuzzel that picks the first line once, and is cancelled after
+        let temp = TempDir::new().unwrap();
+        let calls = temp.path().join("calls");
+        let fuzzel = temp.path().join("fuzzel");
+        std::fs::write(
+            &fuzzel,
+            format!(
+                "#!/bin/sh\ncat > /dev/null\necho >> {calls}\n[ \"$(wc -l < {calls})\" -eq 1 ] && echo 0 || exit 1\n",
+                calls = calls.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&fuzzel, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let echo = vec!["echo".to_string(), "main".to_string()];
+        let items = [
+            ComputedItem::DynamicMenu(ComputedDynamicMenu {
+                args: Vec::new(),
+                source_command: echo.clone(),
+                run_command: echo.clone(),
+                on_empty: ComputedOnEmpty::Message,
+                env: Vec::new(),
+                defaults: ComputedDefaults::default(),
+            }),
+            ComputedItem::Pick(ComputedPick {
+                args: Vec::new(),
+                list_command: echo.clone(),
+                run_command: echo.clone(),
+                env: Vec::new(),
+                defaults: ComputedDefaults::default(),
+            }),
+            ComputedItem::FilesMenu(ComputedFilesMenu {
+                args: Vec::new(),
+                dir: temp.path().display().to_string(),
+                pattern: None,
+                newest_first: false,
+                limit: None,
+                open_with: echo.clone(),
+                env: Vec::new(),
+                defaults: ComputedDefaults::default(),
+            }),
+            ComputedItem::RecentFiles(ComputedRecentFiles {
+                args: Vec::new(),
+                limit: 10,
+                open_with: echo.clone(),
+                env: Vec::new(),
+                defaults: ComputedDefaults::default(),
+            }),
+            ComputedItem::Input(ComputedInput {
+                args: Vec::new(),
+                command: echo.clone(),
+                url_encode: false,
+                password: false,
+                pass_via: ComputedPassVia::Arg,
+                env: Vec::new(),
+                defaults: ComputedDefaults::default(),
+            }),
+        ];
+        let launcher = launch::Launcher {
+            exec: false,
+            dry_run: true,
+            export_env: false,
+            activation: false,
+            preset: "test".to_string(),
+            config_path: PathBuf::from("test.kdl"),
+            watcher: None,
+        };
+        for offset in 0..items.len() {
+            let _ = std::fs::remove_file(&calls);
+            let menu = config::ComputedMenu {
+                items_offset: offset,
+                ..dynamic::build_menu(&[], &["Item".to_string()])
+            };
+            // Back to the menu it was picked from, which can't go back itself
+            let error = show_menus(&fuzzel, &launcher, &items, &menu).unwrap_err();
+            assert_eq!(error.to_string(), "fuzzel exited without success");
+            assert_eq!(std::fs::read_to_string(&calls).unwrap().lines().count(), 3);
+        }
+    }
 }
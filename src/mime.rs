@@ -0,0 +1,422 @@
+use log::debug;
+use miette::{Result, miette};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use walkdir::WalkDir;
+
+use crate::{
+    config::{self, ComputedMenu, ComputedMimeOpen, ComputedProgram},
+    desktop::{self, DesktopEntry},
+    dynamic, glob,
+};
+
+/// An application that can open the file, from its desktop entry.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Handler {
+    /// The desktop file id, like `org.pwmt.zathura.desktop`.
+    pub id: String,
+    pub name: String,
+    pub icon: Option<String>,
+    /// With the file filled in.
+    pub command: Vec<String>,
+}
+
+/// The file's MIME type and the applications for it, best first.
+pub fn find_handlers(mime_open: &ComputedMimeOpen) -> Result<(String, Vec<Handler>)> {
+    let data_dirs = desktop::data_dirs();
+    let mime_type = mime_type(Path::new(&mime_open.file), &data_dirs)
+        .ok_or_else(|| miette!("couldn't tell what type of file {} is", mime_open.file))?;
+    debug!("{} is {mime_type}", mime_open.file);
+    let applications_dirs: Vec<PathBuf> = data_dirs
+        .iter()
+        .map(|dir| dir.join("applications"))
+        .collect();
+    let handlers = handlers(
+        &mime_type,
+        &applications_dirs,
+        &mimeapps_lists(&applications_dirs),
+        mime_open,
+    );
+    Ok((mime_type, handlers))
+}
+
+/// Shows each application's name with its icon, in the same order as `handlers`.
+pub fn build_menu(mime_open: &ComputedMimeOpen, handlers: &[Handler]) -> ComputedMenu {
+    let mut input = Vec::new();
+    for handler in handlers {
+        write!(&mut input, "{}", config::sanitize_entry(&handler.name)).unwrap();
+        if let Some(icon) = &handler.icon {
+            write!(&mut input, "\0icon\x1f{}", config::sanitize_entry(icon)).unwrap();
+        }
+        input.push(b'\n');
+    }
+    ComputedMenu {
+        input,
+        ..dynamic::build_menu(&mime_open.args, &[])
+    }
+}
+
+pub fn program_for_handler(mime_open: &ComputedMimeOpen, handler: &Handler) -> ComputedProgram {
+    ComputedProgram {
+        command: handler.command.clone(),
+        env: mime_open.env.clone(),
+        ..mime_open.defaults.program()
+    }
+}
+
+/// From the shared-mime-info glob rules, or `xdg-mime` for files they don't
+/// cover.
+fn mime_type(path: &Path, data_dirs: &[PathBuf]) -> Option<String> {
+    if path.is_dir() {
+        return Some("inode/directory".to_string());
+    }
+    let name = path.file_name()?.to_string_lossy();
+    mime_type_from_globs(&name, data_dirs).or_else(|| {
+        let output = Command::new("xdg-mime")
+            .args(["query", "filetype"])
+            .arg(path)
+            .output()
+            .ok()?;
+        let mime_type = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        (output.status.success() && mime_type.contains('/')).then_some(mime_type)
+    })
+}
+
+/// Each `mime/globs2` line is `weight:type:glob`, optionally followed by `:cs`
+/// for a case-sensitive glob. The heaviest match wins, then the longest glob.
+fn mime_type_from_globs(name: &str, data_dirs: &[PathBuf]) -> Option<String> {
+    let mut best: Option<(u32, usize, String)> = None;
+    for dir in data_dirs {
+        let Ok(globs) = std::fs::read_to_string(dir.join("mime/globs2")) else {
+            continue;
+        };
+        for line in globs.lines().filter(|line| !line.starts_with('#')) {
+            let mut fields = line.split(':');
+            let (Some(weight), Some(mime_type), Some(pattern)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let Ok(weight) = weight.parse() else {
+                continue;
+            };
+            let case_sensitive = fields.next().is_some_and(|flags| flags.contains("cs"));
+            let matched = if case_sensitive {
+                glob::matches(pattern, name)
+            } else {
+                glob::matches(&pattern.to_lowercase(), &name.to_lowercase())
+            };
+            if matched
+                && best.as_ref().is_none_or(|(best_weight, best_len, _)| {
+                    (weight, pattern.len()) > (*best_weight, *best_len)
+                })
+            {
+                best = Some((weight, pattern.len(), mime_type.to_owned()));
+            }
+        }
+    }
+    best.map(|(_, _, mime_type)| mime_type)
+}
+
+/// The `mimeapps.list` files in order of precedence.
+fn mimeapps_lists(applications_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let config_dirs = std::env::var_os("XDG_CONFIG_DIRS")
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/etc/xdg".into());
+    config::default_config_dir()
+        .ok()
+        .into_iter()
+        .chain(std::env::split_paths(&config_dirs))
+        .chain(applications_dirs.iter().cloned())
+        .map(|dir| dir.join("mimeapps.list"))
+        .collect()
+}
+
+/// The desktop file ids given for the MIME type in each group of the
+/// `mimeapps.list` files.
+#[derive(Debug, Default)]
+struct Associations {
+    defaults: Vec<String>,
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+fn read_associations(lists: &[PathBuf], mime_type: &str) -> Associations {
+    let mut associations = Associations::default();
+    for list in lists {
+        let Ok(contents) = std::fs::read_to_string(list) else {
+            continue;
+        };
+        let mut group = "";
+        for line in contents.lines().map(str::trim) {
+            if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                group = name;
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            if key.trim() != mime_type {
+                continue;
+            }
+            let ids = value
+                .split(';')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_owned);
+            match group {
+                "Default Applications" => associations.defaults.extend(ids),
+                "Added Associations" => associations.added.extend(ids),
+                "Removed Associations" => associations.removed.extend(ids),
+                _ => {}
+            }
+        }
+    }
+    associations
+}
+
+/// Every desktop entry with its file id, which is its path below the
+/// applications dir with `/` replaced by `-`. Earlier dirs hide the same id in
+/// later ones.
+fn installed_entries(applications_dirs: &[PathBuf]) -> Vec<(String, PathBuf)> {
+    let mut entries: Vec<(String, PathBuf)> = Vec::new();
+    for dir in applications_dirs {
+        for file in WalkDir::new(dir)
+            .follow_links(true)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|file| file.ok())
+            .filter(|file| file.file_type().is_file())
+        {
+            let path = file.into_path();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != "desktop")
+            {
+                continue;
+            }
+            let id = path
+                .strip_prefix(dir)
+                .unwrap()
+                .to_string_lossy()
+                .replace('/', "-");
+            if !entries.iter().any(|(seen, _)| *seen == id) {
+                entries.push((id, path));
+            }
+        }
+    }
+    entries
+}
+
+/// The defaults from `mimeapps.list` come first, then its added associations
+/// and then every other entry that lists the type, by name. Entries removed by
+/// `mimeapps.list`, hidden or without an `Exec` are left out.
+fn handlers(
+    mime_type: &str,
+    applications_dirs: &[PathBuf],
+    lists: &[PathBuf],
+    mime_open: &ComputedMimeOpen,
+) -> Vec<Handler> {
+    let associations = read_associations(lists, mime_type);
+    let installed: Vec<(String, DesktopEntry)> = installed_entries(applications_dirs)
+        .into_iter()
+        .filter_map(|(id, path)| {
+            let contents = std::fs::read_to_string(&path).ok()?;
+            match desktop::parse(&contents) {
+                Ok(entry) => Some((id, entry)),
+                Err(error) => {
+                    debug!("skipping {}: {error}", path.display());
+                    None
+                }
+            }
+        })
+        .filter(|(_, entry)| !entry.hidden)
+        .collect();
+
+    let mut listing: Vec<&(String, DesktopEntry)> = installed
+        .iter()
+        .filter(|(_, entry)| entry.mime_types.iter().any(|listed| listed == mime_type))
+        .collect();
+    listing.sort_by_key(|(id, entry)| entry.name.as_deref().unwrap_or(id).to_lowercase());
+    let associated = associations
+        .added
+        .iter()
+        .chain(listing.iter().map(|(id, _)| id))
+        .filter(|id| !associations.removed.contains(id));
+
+    let mut handlers: Vec<Handler> = Vec::new();
+    for id in associations.defaults.iter().chain(associated) {
+        if handlers.iter().any(|handler| handler.id == *id) {
+            continue;
+        }
+        let Some((_, entry)) = installed.iter().find(|(installed, _)| installed == id) else {
+            continue;
+        };
+        let Some(command) = entry.command(Some(&mime_open.file)) else {
+            continue;
+        };
+        let command = if entry.terminal {
+            mime_open.terminal.iter().cloned().chain(command).collect()
+        } else {
+            command
+        };
+        handlers.push(Handler {
+            id: id.clone(),
+            name: entry.name.clone().unwrap_or_else(|| id.clone()),
+            icon: entry.icon.clone(),
+            command,
+        });
+    }
+    handlers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ComputedDefaults;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_mime_type() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-mime-type");
+        let dirs = [root.join("home"), root.join("usr")];
+        std::fs::create_dir_all(dirs[1].join("mime")).unwrap();
+        std::fs::write(
+            dirs[1].join("mime/globs2"),
+            "# comment\n\
+             50:application/pdf:*.pdf\n\
+             50:application/gzip:*.gz\n\
+             55:application/x-compressed-tar:*.tar.gz\n\
+             50:text/x-makefile:Makefile:cs\n\
+             10:text/plain:*\n",
+        )
+        .unwrap();
+        let mime_type = |name: &str| mime_type_from_globs(name, &dirs);
+        assert_eq!(mime_type("report.PDF").as_deref(), Some("application/pdf"));
+        // Heavier and then longer globs win
+        assert_eq!(
+            mime_type("backup.tar.gz").as_deref(),
+            Some("application/x-compressed-tar")
+        );
+        assert_eq!(mime_type("Makefile").as_deref(), Some("text/x-makefile"));
+        assert_eq!(mime_type("makefile").as_deref(), Some("text/plain"));
+        assert_eq!(mime_type_from_globs("a.pdf", &[]), None);
+        assert_eq!(
+            super::mime_type(&root, &dirs).as_deref(),
+            Some("inode/directory")
+        );
+    }
+
+    #[test]
+    fn test_handlers() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("test-mime-handlers");
+        let dirs = [root.join("home"), root.join("usr")];
+        std::fs::create_dir_all(&dirs[0]).unwrap();
+        std::fs::create_dir_all(dirs[1].join("org")).unwrap();
+        let entry = |dir: &Path, file: &str, contents: &str| {
+            std::fs::write(dir.join(file), format!("[Desktop Entry]\n{contents}")).unwrap();
+        };
+        entry(
+            &dirs[1],
+            "zathura.desktop",
+            "Name=Zathura\nExec=zathura %U\nIcon=zathura\nMimeType=application/pdf;\n",
+        );
+        entry(
+            &dirs[1].join("org"),
+            "gnome.Evince.desktop",
+            "Name=Document Viewer\nExec=evince %f\nMimeType=application/pdf;image/tiff;\n",
+        );
+        entry(
+            &dirs[1],
+            "gimp.desktop",
+            "Name=GIMP\nExec=gimp\nMimeType=image/png;\n",
+        );
+        entry(
+            &dirs[1],
+            "less.desktop",
+            "Name=Less\nExec=less\nTerminal=true\nMimeType=text/plain;\n",
+        );
+        entry(
+            &dirs[1],
+            "firefox.desktop",
+            "Name=Firefox\nExec=firefox %u\nMimeType=application/pdf;\n",
+        );
+        // Overrides the one in usr, and hides it
+        entry(
+            &dirs[0],
+            "firefox.desktop",
+            "Name=Firefox\nExec=firefox\nHidden=true\n",
+        );
+        entry(
+            &dirs[1],
+            "okular.desktop",
+            "Name=Okular\nExec=okular %u\nMimeType=application/pdf;\n",
+        );
+
+        let lists = [root.join("mimeapps.list")];
+        std::fs::write(
+            &lists[0],
+            "[Default Applications]\n\
+             application/pdf=zathura.desktop;missing.desktop;\n\
+             [Added Associations]\n\
+             application/pdf=gimp.desktop;\n\
+             [Removed Associations]\n\
+             application/pdf=okular.desktop;\n",
+        )
+        .unwrap();
+
+        let mime_open = ComputedMimeOpen {
+            args: Vec::new(),
+            file: "/docs/a report.pdf".to_string(),
+            terminal: vec!["foot".to_string(), "-e".to_string()],
+            env: Vec::new(),
+            defaults: ComputedDefaults::default(),
+        };
+        let found = handlers("application/pdf", &dirs, &lists, &mime_open);
+        let summary: Vec<(&str, Vec<String>)> = found
+            .iter()
+            .map(|handler| (handler.id.as_str(), handler.command.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "zathura.desktop",
+                    vec!["zathura".to_string(), "/docs/a report.pdf".to_string()]
+                ),
+                (
+                    "gimp.desktop",
+                    vec!["gimp".to_string(), "/docs/a report.pdf".to_string()]
+                ),
+                (
+                    "org-gnome.Evince.desktop",
+                    vec!["evince".to_string(), "/docs/a report.pdf".to_string()]
+                ),
+            ]
+        );
+        assert_eq!(found[0].icon.as_deref(), Some("zathura"));
+        assert_eq!(found[2].name, "Document Viewer");
+
+        let found = handlers("text/plain", &dirs, &lists, &mime_open);
+        assert_eq!(
+            found[0].command,
+            ["foot", "-e", "less", "/docs/a report.pdf"]
+        );
+        assert!(handlers("video/mp4", &dirs, &lists, &mime_open).is_empty());
+
+        let found = handlers("application/pdf", &dirs, &lists, &mime_open);
+        let menu = build_menu(&mime_open, &found[..2]);
+        assert_eq!(
+            String::from_utf8(menu.input).unwrap(),
+            "Zathura\0icon\x1fzathura\nGIMP\n"
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use miette::{Result, miette};
+
+use crate::{
+    config::{ComputedItem, ComputedMenu, ComputedMultiSelect},
+    launch::Launcher,
+};
+
+const PICKED: &[u8] = b"[x] ";
+const NOT_PICKED: &[u8] = b"[ ] ";
+
+/// Lets several lines of the menu's input be picked. fuzzel only returns one
+/// line, so picking an item toggles its mark and reopens the menu, and the
+/// extra last line finishes. `show` runs fuzzel with the input and the line to
+/// put the cursor on, returning the picked line or `None` if it was cancelled.
+///
+/// Returns the picked lines in menu order, or `None` if fuzzel was cancelled.
+pub fn select(
+    input: &[u8],
+    mut show: impl FnMut(&[u8], Option<usize>) -> Result<Option<usize>>,
+) -> Result<Option<Vec<usize>>> {
+    let lines: Vec<&[u8]> = input.split_inclusive(|&byte| byte == b'\n').collect();
+    let mut picked = vec![false; lines.len()];
+    let mut cursor = None;
+    loop {
+        let Some(index) = show(&marked_input(&lines, &picked), cursor)? else {
+            return Ok(None);
+        };
+        cursor = Some(index);
+        if let Some(mark) = picked.get_mut(index) {
+            *mark = !*mark;
+        } else if index > lines.len() {
+            return Err(miette!("fuzzel returned an unknown entry"));
+        } else if picked.contains(&true) {
+            return Ok(Some(
+                (0..lines.len()).filter(|&index| picked[index]).collect(),
+            ));
+        }
+        // Finishing with nothing picked just shows the menu again
+    }
+}
+
+/// Each line with its mark in front, then the line that finishes.
+fn marked_input(lines: &[&[u8]], picked: &[bool]) -> Vec<u8> {
+    let mut input = Vec::new();
+    for (line, &picked) in lines.iter().zip(picked) {
+        input.extend_from_slice(if picked { PICKED } else { NOT_PICKED });
+        input.extend_from_slice(line);
+        if !line.ends_with(b"\n") {
+            input.push(b'\n');
+        }
+    }
+    let count = picked.iter().filter(|&&picked| picked).count();
+    input.extend_from_slice(format!("Run {count} picked\n").as_bytes());
+    input
+}
+
+/// Launches each picked program in order, or the menu's `run-command` once
+/// with their names appended.
+pub fn launch_picked(
+    launcher: &Launcher,
+    items: &[ComputedItem],
+    menu: &ComputedMenu,
+    multi_select: &ComputedMultiSelect,
+    picked: &[usize],
+) -> Result<()> {
+    match multi_select {
+        ComputedMultiSelect::Each => {
+            for &index in picked {
+                let ComputedItem::Program(program) = &items[menu.items_offset + index] else {
+                    return Err(miette!("{} is not a program", menu.item_name(index)));
+                };
+                launcher.launch(
+                    program,
+                    &menu.hooks,
+                    menu.item_name(index),
+                    menu.item_id(index),
+                )?;
+            }
+            Ok(())
+        }
+        ComputedMultiSelect::Args(program) => {
+            let names: Vec<&str> = picked.iter().map(|&index| menu.item_name(index)).collect();
+            let ids: Vec<&str> = picked.iter().map(|&index| menu.item_id(index)).collect();
+            let mut program = program.clone();
+            program
+                .command
+                .extend(names.iter().map(|name| name.to_string()));
+            launcher.launch(&program, &menu.hooks, &names.join("\n"), &ids.join("\n"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ComputedHooks, ComputedProgram};
+    use std::{
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_select() {
+        let input = b"Firefox\0icon\x1ffirefox\nFoot\nMpv\n";
+        // A fake fuzzel that picks these lines in turn and records what it showed
+        let mut shown = Vec::new();
+        let mut picks = vec![Some(3), Some(2), Some(0), Some(2), Some(3)].into_iter();
+        let picked = select(input, |input, cursor| {
+            shown.push((String::from_utf8_lossy(input).into_owned(), cursor));
+            Ok(picks.next().unwrap())
+        })
+        .unwrap();
+        assert_eq!(picked, Some(vec![0]));
+        assert_eq!(
+            shown[0],
+            (
+                "[ ] Firefox\0icon\x1ffirefox\n[ ] Foot\n[ ] Mpv\nRun 0 picked\n".to_string(),
+                None
+            )
+        );
+        // Finishing with nothing picked shows the menu again
+        assert_eq!(shown[1].1, Some(3));
+        assert_eq!(shown[2].1, Some(2));
+        assert!(shown[2].0.ends_with("[x] Mpv\nRun 1 picked\n"));
+        assert_eq!(
+            shown[4],
+            (
+                "[x] Firefox\0icon\x1ffirefox\n[ ] Foot\n[ ] Mpv\nRun 1 picked\n".to_string(),
+                Some(2)
+            )
+        );
+
+        let mut picks = vec![Some(1), Some(0), None].into_iter();
+        assert_eq!(
+            select(input, |_, _| Ok(picks.next().unwrap())).unwrap(),
+            None
+        );
+        assert!(select(input, |_, _| Ok(Some(4))).is_err());
+    }
+
+    #[test]
+    fn test_launch_picked() {
+        let temp = TempDir::new().unwrap();
+        let log = temp.path().join("test-multi-select-log");
+        let record = |words: &[&str]| {
+            let mut command = vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("echo \"$@\" >> {}", log.display()),
+                "sh".to_string(),
+            ];
+            command.extend(words.iter().map(|word| word.to_string()));
+            command
+        };
+        let program = |name: &str| {
+            ComputedItem::Program(ComputedProgram {
+                command: record(&[name]),
+                ..Default::default()
+            })
+        };
+        let items = vec![
+            ComputedItem::Back,
+            program("firefox"),
+            program("foot"),
+            program("mpv"),
+        ];
+        let menu = ComputedMenu {
+            args: Vec::new(),
+            input: b"Firefox\nFoot\nMpv\n".to_vec(),
+            items_offset: 1,
+            back_on_cancel: false,
+            stay_open: false,
+            actions: Vec::new(),
+            labels: Vec::new(),
+            hooks: ComputedHooks::default(),
+            names: vec!["Firefox".to_string(), "Foot".to_string(), "Mpv".to_string()],
+            ids: Vec::new(),
+            multi_select: Some(ComputedMultiSelect::Each),
+        };
+        let launcher = Launcher {
+            exec: false,
+            dry_run: false,
+            export_env: false,
+            activation: false,
+            preset: "test".to_string(),
+            config_path: PathBuf::from("/test.kdl"),
+            watcher: None,
+        };
+
+        launch_picked(
+            &launcher,
+            &items,
+            &menu,
+            &ComputedMultiSelect::Each,
+            &[0, 2],
+        )
+        .unwrap();
+        // Neither is waited for, so either may finish first
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::fs::read_to_string(&log).map_or(0, |log| log.lines().count()) < 2
+            && Instant::now() < deadline
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let log_contents = std::fs::read_to_string(&log).unwrap();
+        let mut launched: Vec<&str> = log_contents.lines().collect();
+        launched.sort_unstable();
+        assert_eq!(launched, ["firefox", "mpv"]);
+
+        let _ = std::fs::remove_file(&log);
+        // With the menu's environment
+        let multi_select = ComputedMultiSelect::Args(Box::new(ComputedProgram {
+            command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("echo $UFF_TEST_OPENER \"$@\" >> {}", log.display()),
+                "sh".to_string(),
+            ],
+            env: vec![("UFF_TEST_OPENER".to_string(), "open".to_string())],
+            ..Default::default()
+        }));
+        launch_picked(&launcher, &items, &menu, &multi_select, &[0, 1]).unwrap();
+        // Not watched, so it may still be running
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !std::fs::read_to_string(&log).is_ok_and(|log| log.ends_with('\n'))
+            && Instant::now() < deadline
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "open Firefox Foot\n"
+        );
+    }
+}
@@ -1,34 +1,641 @@
-use crate::config::home;
-use kdl::{KdlDocument, KdlNode};
-use log::warn;
+use crate::{
+    config::{expand_env_vars, expand_tilde},
+    fuzzel_keys,
+};
+use kdl::{KdlDocument, KdlEntry, KdlNode, KdlValue};
+use log::{LevelFilter, warn};
 use miette::{Diagnostic, LabeledSpan, Result, SourceSpan, miette};
-use std::{fmt::Debug, path::PathBuf};
+use std::{fmt::Debug, ops::RangeInclusive, path::PathBuf};
 use thiserror::Error;
 
+mod menu_def;
+mod template;
+
 #[derive(Debug)]
 pub struct Menu {
     pub fuzzel_args: Vec<String>,
+    /// Appended after `fuzzel_args`, overriding any repeated options.
+    pub extra_fuzzel_args: Vec<String>,
+    pub inherit_fuzzel_args: bool,
     pub fuzzel_config: Vec<(String, String)>,
-    pub icon_dirs: Vec<PathBuf>,
+    /// Include the parent's generated ini, or only the defaults otherwise.
+    pub inherit_fuzzel_config: bool,
+    pub icon_dirs: Vec<IconDir>,
+    pub inherit_icon_dirs: IconDirInheritance,
+    pub sort: SortOrder,
+    /// Groups submenus and programs before `sort` is applied in each group.
+    pub order: ItemOrder,
+    /// Reopen the menu after launching one of its programs.
+    pub stay_open: bool,
+    /// Lets several of the menu's programs be picked at once.
+    pub multi_select: Option<MultiSelect>,
+    /// Command that `open` items pass their target to, inherited by submenus.
+    pub opener: Option<Vec<String>>,
+    /// Hint shown in the empty input box, inherited by submenus.
+    pub placeholder: Option<String>,
+    /// Goes between an item's name and description, inherited by submenus.
+    pub description_separator: Option<String>,
+    /// Dims descriptions with pango markup, inherited by submenus.
+    pub markup: Option<bool>,
+    /// Let fuzzel reorder entries by usage, inherited by submenus.
+    pub use_fuzzel_cache: Option<bool>,
+    /// Preferred icon size in pixels, inherited by submenus.
+    pub icon_size: Option<u32>,
+    /// Which file wins when an icon has several, inherited by submenus.
+    pub prefer_icon_format: Option<IconFormat>,
+    /// How an icon's name is compared to file names, inherited by submenus.
+    pub icon_match: Option<IconMatch>,
+    /// Whether `-symbolic` icons are used in place of others, inherited by submenus.
+    pub symbolic: Option<SymbolicIcons>,
+    /// Put each item's number and this separator before its name.
+    pub show_indices: Option<String>,
+    /// Put the number of items after submenu names, counting their submenus'
+    /// items too when it's `Some(true)`.
+    pub show_counts: Option<bool>,
+    /// Fit fuzzel's lines and width to the items, inherited by submenus.
+    /// `Some(None)` turns off an inherited one.
+    pub auto_size: Option<Option<AutoSize>>,
+    /// Keys and the item actions they run, replacing the parent's binds.
+    pub binds: Vec<(String, String)>,
+    /// Environment variables for every program below the menu.
+    pub env: Vec<(String, String)>,
+    pub hooks: Hooks,
+    /// Set by the top-level `defaults` block, empty on submenus.
+    pub defaults: Defaults,
+    /// Set on the root when the file only has menus (and defaults), each of
+    /// which is an entry that has to be picked at launch.
+    pub entries_only: bool,
     pub items: Vec<Item>,
 }
 
+impl Default for Menu {
+    fn default() -> Self {
+        Self {
+            fuzzel_args: Vec::new(),
+            extra_fuzzel_args: Vec::new(),
+            inherit_fuzzel_args: true,
+            fuzzel_config: Vec::new(),
+            inherit_fuzzel_config: true,
+            icon_dirs: Vec::new(),
+            inherit_icon_dirs: IconDirInheritance::All,
+            sort: SortOrder::None,
+            order: ItemOrder::Config,
+            stay_open: false,
+            multi_select: None,
+            opener: None,
+            placeholder: None,
+            description_separator: None,
+            markup: None,
+            use_fuzzel_cache: None,
+            icon_size: None,
+            prefer_icon_format: None,
+            icon_match: None,
+            symbolic: None,
+            show_indices: None,
+            show_counts: None,
+            auto_size: None,
+            binds: Vec::new(),
+            env: Vec::new(),
+            hooks: Hooks::default(),
+            defaults: Defaults::default(),
+            entries_only: false,
+            items: Vec::new(),
+        }
+    }
+}
+
+/// Limits for `auto-size`, which sets `--lines` and `--width` from the items.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoSize {
+    /// Longer menus scroll instead.
+    pub max_lines: u32,
+    pub max_width: u32,
+    /// Added to the longest entry, in characters, to leave room for icons.
+    pub padding: u32,
+}
+
+impl Default for AutoSize {
+    fn default() -> Self {
+        Self {
+            max_lines: 20,
+            max_width: 80,
+            padding: 4,
+        }
+    }
+}
+
+/// Commands run around every launch from a menu, each one inherited by submenus
+/// that don't set their own.
+#[derive(Debug, Default)]
+pub struct Hooks {
+    /// Waited for before launching, failing stops the launch.
+    pub pre_launch: Option<Vec<String>>,
+    /// Started after a successful launch without waiting for it.
+    pub post_launch: Option<Vec<String>>,
+}
+
+/// The optional `config.kdl` shared by every preset.
+#[derive(Debug, Default)]
+pub struct GlobalConfig {
+    pub settings: Settings,
+    /// Goes under each preset's own `defaults` block.
+    pub defaults: Defaults,
+}
+
+/// The `settings` block in `config.kdl`.
+#[derive(Debug, Default)]
+pub struct Settings {
+    /// Used by presets that don't set an opener at the top level.
+    pub opener: Option<Vec<String>>,
+    /// Run instead of `fuzzel` from `$PATH`, unless `$UFF_FUZZEL` is set.
+    pub fuzzel_path: Option<String>,
+    pub log_level: Option<LevelFilter>,
+    /// Use a stale cached config while it's rebuilt in the background.
+    pub stale_while_revalidate: Option<bool>,
+    /// Compress cached configs, which is on unless it's `false`.
+    pub cache_compression: Option<bool>,
+}
+
+/// Nodes allowed in the `settings` block, used to suggest a fix for typos.
+const SETTINGS: &[&str] = &[
+    "opener",
+    "fuzzel-path",
+    "log-level",
+    "stale-while-revalidate",
+    "cache-compression",
+];
+
+/// Fuzzel settings below every menu, which menus can override but not opt out of.
+#[derive(Debug, Default)]
+pub struct Defaults {
+    pub fuzzel_args: Vec<String>,
+    pub fuzzel_config: Vec<(String, String)>,
+    /// Replacements for the built-in power commands, from the `power-commands` block.
+    pub power_commands: Vec<(PowerAction, Vec<String>)>,
+    pub use_fuzzel_cache: Option<bool>,
+    /// Preferred icon size for menus that don't set their own.
+    pub icon_size: Option<u32>,
+    pub prefer_icon_format: Option<IconFormat>,
+    pub icon_match: Option<IconMatch>,
+    pub symbolic: Option<SymbolicIcons>,
+    /// How icon dirs are walked when their `icon-dir` doesn't say.
+    pub icon_dir_walk: IconWalk,
+    pub systemd_scope: Option<bool>,
+    pub launch_method: Option<LaunchMethod>,
+    pub stdout: Option<Output>,
+    pub stderr: Option<Output>,
+    /// Give launched programs the `UFF_*` variables, `#true` unless set.
+    pub export_env: Option<bool>,
+    /// Give launched programs an xdg-activation token, `#true` unless set.
+    pub activation: Option<bool>,
+    /// Put in front of the command of a desktop entry that runs in a terminal.
+    pub terminal: Option<Vec<String>>,
+}
+
+/// Where a launched program's stdout or stderr goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Null,
+    Inherit,
+    /// With environment variables expanded, but not `~`.
+    File {
+        path: String,
+        append: bool,
+    },
+}
+
+/// What happens to the items picked in a multi-select menu.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultiSelect {
+    /// Launch each one in order.
+    Each,
+    /// Run the menu's `run-command` once with their names appended.
+    Args(Vec<String>),
+}
+
+/// A dir from `icon-dir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IconDir {
+    pub path: PathBuf,
+    pub walk: IconWalk,
+}
+
+/// How much of a plain icon dir is searched, from the properties of `icon-dir`
+/// or `icon-dir-walk` in the defaults. Dirs with an `icons` dir inside are
+/// searched through the icon theme instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct IconWalk {
+    /// How many dirs down to look, unlimited when unset.
+    pub max_depth: Option<u32>,
+    /// `#false` when unset.
+    pub follow_symlinks: Option<bool>,
+    /// Look inside dirs whose name starts with a dot, `#false` when unset.
+    pub include_hidden: Option<bool>,
+}
+
+impl IconWalk {
+    /// Each option that isn't set here comes from `fallback`.
+    #[must_use]
+    pub const fn or(self, fallback: Self) -> Self {
+        Self {
+            max_depth: if self.max_depth.is_some() {
+                self.max_depth
+            } else {
+                fallback.max_depth
+            },
+            follow_symlinks: if self.follow_symlinks.is_some() {
+                self.follow_symlinks
+            } else {
+                fallback.follow_symlinks
+            },
+            include_hidden: if self.include_hidden.is_some() {
+                self.include_hidden
+            } else {
+                fallback.include_hidden
+            },
+        }
+    }
+}
+
+/// The properties that set an `IconWalk`.
+const ICON_WALK_PARAMETERS: &[&str] = &["max-depth", "follow-symlinks", "include-hidden"];
+
+/// Which of the parent menus' icon dirs a menu also searches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconDirInheritance {
+    All,
+    /// Only the XDG data dirs searched by default.
+    Xdg,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Keep the order from the config.
+    None,
+    Alphabetical,
+    Reverse,
+}
+
+/// The kind of file picked when an icon has several, from `prefer-icon-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IconFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+/// How an icon's name is compared to file names, from `icon-match`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IconMatch {
+    /// `Firefox.png` is found for `firefox`, after any `firefox.png`.
+    #[default]
+    IgnoreCase,
+    Exact,
+}
+
+/// Whether an icon's `-symbolic` variant is used, from `symbolic`. Symbolic
+/// icons are dark outlines that can't be seen on a dark background.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymbolicIcons {
+    /// Only the name as written is looked for.
+    Allow,
+    /// `name-symbolic` is looked for first, then `name`.
+    Prefer,
+    /// `name` is looked for first, then `name-symbolic`.
+    #[default]
+    Avoid,
+}
+
+/// Which kind of item goes first in a menu, from its `order` node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemOrder {
+    /// Keep them mixed, as in the config.
+    Config,
+    /// Items that open another list, like submenus and dynamic menus, go first.
+    MenusFirst,
+    ProgramsFirst,
+}
+
 #[derive(Debug)]
 pub struct Item {
     pub name: String,
+    /// From the `id` property, used instead of the name to refer to the item
+    /// so renaming it doesn't break anything.
+    pub id: Option<String>,
     pub icon: Option<String>,
+    /// Searched for the item's icon before its menu's icon dirs, from a
+    /// program's `icon-dir` nodes.
+    pub icon_dirs: Vec<IconDir>,
+    /// Secondary text shown after the name.
+    pub description: Option<String>,
+    /// Only shown with one of these `--profile`s, untagged items are always shown.
+    pub profiles: Vec<String>,
+    /// Only shown on hosts matching one of these exact names or globs.
+    pub if_host: Vec<String>,
+    /// Hidden on hosts matching one of these.
+    pub unless_host: Vec<String>,
+    /// Programs run instead when the key bound to the action is pressed.
+    pub actions: Vec<(String, Program)>,
+    /// Heavier items go first, before the menu's `sort` is applied.
+    pub weight: i64,
+    /// Its first line of output replaces `{}` in the name each time the menu is shown.
+    pub label_command: Option<Vec<String>>,
     pub contents: ItemContents,
 }
 
 #[derive(Debug)]
 pub enum ItemContents {
-    Menu(Menu),
-    Program(Program),
+    Menu(Box<Menu>),
+    Program(Box<Program>),
+    /// Returns to the parent menu.
+    Back,
+    /// A url or path passed to the menu's opener.
+    Open(String),
+    /// A file opened with one of the applications for its MIME type, picked
+    /// when the item is.
+    MimeOpen(String),
+    Script(Script),
+    DynamicMenu(DynamicMenu),
+    Pick(Pick),
+    Input(Input),
+    FilesMenu(FilesMenu),
+    RecentFiles(RecentFiles),
+    Power(Power),
+    /// Another entry for an item elsewhere in the config.
+    Alias(Alias),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Program {
     pub command: Vec<String>,
+    /// Question to ask before running the command.
+    pub confirm: Option<String>,
+    /// Seconds to watch the command for a failing exit.
+    pub watch_exit: Option<u64>,
+    pub notify: Option<Notification>,
+    /// Commands run one after another instead of `command`.
+    pub chain: Option<Chain>,
+    /// Commands to pick `command` from, from a `try` block.
+    pub fallbacks: Option<Fallbacks>,
+    /// A desktop entry's file name or path, which gives the command and icon
+    /// when they aren't set.
+    pub desktop: Option<String>,
+    /// Environment variables, overriding the ones from its menus.
+    pub env: Vec<(String, String)>,
+    /// Niceness to start the command with, from -20 to 19.
+    pub nice: Option<i8>,
+    pub ionice: Option<IoPriority>,
+    /// Run in its own systemd user scope, overriding the `defaults` block.
+    pub systemd_scope: Option<bool>,
+    /// Overrides the `defaults` block.
+    pub launch_method: Option<LaunchMethod>,
+    /// Window rules for Hyprland's `exec`, like `workspace 3 silent`.
+    pub hypr_rules: Vec<String>,
+    /// From `detach #false`, keep uff's session and stdio.
+    pub attached: bool,
+    /// Replace uff with the command instead of spawning it.
+    pub exec: bool,
+    pub stdout: Option<Output>,
+    pub stderr: Option<Output>,
+}
+
+/// What starts a program, from `launch-method`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaunchMethod {
+    /// uff spawns it itself.
+    Direct,
+    /// Handed to sway with `swaymsg exec`, so it's sway's child instead of uff's.
+    Swaymsg,
+    /// Handed to Hyprland with `hyprctl dispatch exec`.
+    Hyprctl,
+    /// `Swaymsg` under sway, `Hyprctl` under Hyprland and `Direct` otherwise.
+    Auto,
+}
+
+/// The I/O scheduling class passed to `ionice`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoPriority {
+    pub class: IoClass,
+    /// From 0 (highest) to 7, not allowed for `idle`.
+    pub level: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+/// Commands run in order, each waiting for the one before it.
+#[derive(Debug)]
+pub struct Chain {
+    pub commands: Vec<Vec<String>>,
+    /// Keep going after a command fails instead of stopping.
+    pub continue_on_error: bool,
+}
+
+/// Candidates for a program's command, the first one found in `$PATH` when the
+/// config is built is used.
+#[derive(Debug)]
+pub struct Fallbacks {
+    pub commands: Vec<Vec<String>>,
+    /// Fail to build the config if none are found, instead of dropping the item.
+    pub required: bool,
+}
+
+/// A menu filled with the output lines of `source_command` when it is opened.
+#[derive(Debug)]
+pub struct DynamicMenu {
+    pub source_command: Vec<String>,
+    /// Run with the selected line appended as the last argument.
+    pub run_command: Vec<String>,
+    pub on_empty: OnEmpty,
+}
+
+/// What a dynamic menu does when its source command prints nothing.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub enum OnEmpty {
+    /// Shows a "nothing here" line that goes back when picked.
+    #[default]
+    Message,
+    /// Goes back without opening fuzzel.
+    Skip,
+    /// Runs the `empty-command` instead.
+    Run(Vec<String>),
+}
+
+/// Shows the output lines of `list_command` each time it's picked, and runs
+/// `run_command` with the chosen line.
+#[derive(Debug)]
+pub struct Pick {
+    pub list_command: Vec<String>,
+    /// The chosen line replaces `{}` in its arguments, or is appended if there is none.
+    pub run_command: Vec<String>,
+}
+
+/// A built-in item like `reboot`, its command comes from `PowerAction::command`
+/// unless the config's `power-commands` block replaces it.
+#[derive(Debug)]
+pub struct Power {
+    pub action: PowerAction,
+    /// Question to ask before running the command.
+    pub confirm: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    PowerOff,
+    Reboot,
+    Suspend,
+    Hibernate,
+    Lock,
+}
+
+impl PowerAction {
+    pub const ALL: [Self; 5] = [
+        Self::PowerOff,
+        Self::Reboot,
+        Self::Suspend,
+        Self::Hibernate,
+        Self::Lock,
+    ];
+
+    pub const fn node_name(self) -> &'static str {
+        match self {
+            Self::PowerOff => "power-off",
+            Self::Reboot => "reboot",
+            Self::Suspend => "suspend",
+            Self::Hibernate => "hibernate",
+            Self::Lock => "lock",
+        }
+    }
+
+    fn from_node_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|action| action.node_name() == name)
+    }
+
+    const fn default_name(self) -> &'static str {
+        match self {
+            Self::PowerOff => "Shut down",
+            Self::Reboot => "Restart",
+            Self::Suspend => "Sleep",
+            Self::Hibernate => "Hibernate",
+            Self::Lock => "Lock",
+        }
+    }
+
+    const fn default_icon(self) -> &'static str {
+        match self {
+            Self::PowerOff => "system-shutdown",
+            Self::Reboot => "system-reboot",
+            Self::Suspend => "system-suspend",
+            Self::Hibernate => "system-suspend-hibernate",
+            Self::Lock => "system-lock-screen",
+        }
+    }
+
+    /// Asked before the actions that would lose unsaved work.
+    const fn default_question(self) -> Option<&'static str> {
+        match self {
+            Self::PowerOff => Some("Shut down now?"),
+            Self::Reboot => Some("Restart now?"),
+            Self::Suspend | Self::Hibernate | Self::Lock => None,
+        }
+    }
+
+    pub const fn command(self) -> &'static [&'static str] {
+        match self {
+            Self::PowerOff => &["systemctl", "poweroff"],
+            Self::Reboot => &["systemctl", "reboot"],
+            Self::Suspend => &["systemctl", "suspend"],
+            Self::Hibernate => &["systemctl", "hibernate"],
+            Self::Lock => &["loginctl", "lock-session"],
+        }
+    }
+}
+
+/// A menu of the files in `dir`, listed each time it's opened.
+#[derive(Debug)]
+pub struct FilesMenu {
+    pub dir: String,
+    /// Only file names matching it are shown.
+    pub pattern: Option<String>,
+    pub sort: FileSort,
+    /// Most files to show, after sorting.
+    pub limit: Option<usize>,
+    /// Runs with the selected path appended, the menu's opener is used otherwise.
+    pub open_with: Option<Vec<String>>,
+}
+
+/// A menu of the recently used files GTK apps keep track of, read each time it's opened.
+#[derive(Debug)]
+pub struct RecentFiles {
+    /// Most files to show, newest first.
+    pub limit: usize,
+    /// Runs with the selected path appended, the menu's opener is used otherwise.
+    pub open_with: Option<Vec<String>>,
+}
+
+/// `alias "Browser" of="firefox"`, launching the same thing as the item it points at.
+#[derive(Debug)]
+pub struct Alias {
+    /// The target's id, or the ids or names of the menus leading to it from
+    /// the root menu and then its own, joined with `/`.
+    pub target: String,
+    /// Where `of` is, for errors about the target.
+    pub span: SourceSpan,
+    /// The target's index in each menu's sorted items on the way to it, filled
+    /// in by the config once the menus have been filtered.
+    pub path: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileSort {
+    Name,
+    /// Newest first.
+    Mtime,
+}
+
+/// Asks for a line of text when selected and runs `command` with it.
+#[derive(Debug)]
+pub struct Input {
+    /// `{}` in any argument is replaced with the text.
+    pub command: Vec<String>,
+    pub prompt: Option<String>,
+    /// Percent-encode the text first, for putting it in urls.
+    pub url_encode: bool,
+    /// Hide the typed text in fuzzel and in uff's logs.
+    pub password: bool,
+    pub pass_via: Option<PassVia>,
+}
+
+/// How the typed text of an input reaches its command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PassVia {
+    /// In place of `{}` in the arguments.
+    Arg,
+    Stdin,
+    /// In the named environment variable.
+    Env(String),
+}
+
+/// A shell script written inline in the config.
+#[derive(Debug)]
+pub struct Script {
+    pub body: String,
+    /// Interpreter line written at the top, `sh` runs the script otherwise.
+    pub shebang: Option<String>,
+}
+
+/// A desktop notification sent after a program is launched.
+#[derive(Debug)]
+pub struct Notification {
+    pub summary: String,
+    pub body: Option<String>,
 }
 
 // This is used to remove the default unnamed source from a KdlDiagnostic
@@ -45,30 +652,357 @@ impl Diagnostic for KdlDiagnosticWrapper {
     }
 }
 
-pub fn parse_config(src: &str) -> Result<Menu> {
+/// Errors found while parsing, collected so they can all be reported at once.
+#[derive(Default)]
+struct Diagnostics {
+    reports: Vec<miette::Report>,
+    /// Likely mistakes are errors instead of warnings.
+    strict: bool,
+    /// Nodes given twice overwrite the first instead of being errors.
+    lenient: bool,
+    /// Item ids seen so far, which have to be unique in the whole config.
+    ids: Vec<(String, SourceSpan)>,
+}
+
+impl Diagnostics {
+    /// Records the error, if any, so parsing can carry on.
+    fn check<T>(&mut self, result: Result<T>) -> Option<T> {
+        result.map_err(|report| self.reports.push(report)).ok()
+    }
+
+    /// Warns about a likely mistake, or records it as an error under `--strict`.
+    fn lint(&mut self, report: miette::Report) {
+        if self.strict {
+            self.reports.push(report);
+            return;
+        }
+        match report.help() {
+            Some(help) => warn!("{report}, {help}"),
+            None => warn!("{report}"),
+        }
+    }
+
+    /// Remembers `node` as the first of its kind, or reports it as a duplicate.
+    /// Under `--lenient` the later one replaces the first, so it's only a warning.
+    fn define_once<'a>(&self, first: &mut Option<&'a KdlNode>, node: &'a KdlNode) -> Result<()> {
+        let Some(first) = first.replace(node) else {
+            return Ok(());
+        };
+        let name = node.name().value();
+        if self.lenient {
+            warn!("{name} already defined, overwriting");
+            return Ok(());
+        }
+        Err(already_defined_at(
+            name,
+            first.span(),
+            node.span(),
+            "remove one of them, with --lenient the later one is used",
+        ))
+    }
+
+    /// Parses each node in turn, an error only skips the rest of that node.
+    fn each_node<'a>(
+        &mut self,
+        doc: &'a KdlDocument,
+        mut parse: impl FnMut(&'a KdlNode, &mut Self) -> Result<()>,
+    ) {
+        for node in doc.nodes() {
+            let result = parse(node, self);
+            self.check(result);
+        }
+    }
+
+    /// How many errors have been recorded so far.
+    const fn count(&self) -> usize {
+        self.reports.len()
+    }
+
+    fn finish<T>(mut self, value: T) -> Result<T> {
+        match self.reports.len() {
+            0 => Ok(value),
+            1 => Err(self.reports.pop().unwrap()),
+            _ => Err(ConfigErrors(self.reports).into()),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("found {} problems in the config", .0.len())]
+struct ConfigErrors(Vec<miette::Report>);
+impl Diagnostic for ConfigErrors {
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        Some(Box::new(self.0.iter().map(AsRef::as_ref)))
+    }
+}
+
+/// Settings that change how closely the config is checked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    /// Fail on likely mistakes that are otherwise only warned about.
+    pub strict: bool,
+    /// Let a node given twice overwrite the first instead of failing.
+    pub lenient: bool,
+    /// Set by `allow-unknown-fuzzel-keys`, inherited by submenus.
+    pub allow_unknown_fuzzel_keys: bool,
+}
+
+/// Config format versions this uff can read, configs without `uff-version` are
+/// version 1.
+pub const CONFIG_VERSIONS: RangeInclusive<u32> = 1..=1;
+
+pub fn parse_config(src: &str, options: ParseOptions) -> Result<Menu> {
+    let mut doc = src.parse::<KdlDocument>().map_err(|e| {
+        let original = e.diagnostics[0].clone();
+        KdlDiagnosticWrapper(original)
+    })?;
+
+    let mut diagnostics = Diagnostics {
+        strict: options.strict,
+        lenient: options.lenient,
+        ..Diagnostics::default()
+    };
+    // Checked before anything else, so a config for a newer uff fails with this
+    // instead of errors about nodes this one doesn't know
+    check_version(&doc, &mut diagnostics)?;
+
+    // defaults and power-commands are only allowed at the top level, so they're
+    // taken out before the root menu is parsed
+    let mut defaults = Defaults::default();
+    let mut defaults_node = None;
+    let mut power_commands_node = None;
+    let mut power_commands = Vec::new();
+    diagnostics.each_node(&doc, |node, diagnostics| {
+        match node.name().value() {
+            "defaults" => {
+                diagnostics.define_once(&mut defaults_node, node)?;
+                defaults = parse_defaults_from_nodes(children(node)?, options, diagnostics);
+            }
+            "power-commands" => {
+                diagnostics.define_once(&mut power_commands_node, node)?;
+                power_commands = parse_power_commands(children(node)?, diagnostics);
+            }
+            _ => return Ok(()),
+        }
+        no_arguments(node)?;
+        no_parameters(node)
+    });
+    defaults.power_commands = power_commands;
+    doc.nodes_mut().retain(|node| {
+        !matches!(
+            node.name().value(),
+            "uff-version" | "defaults" | "power-commands"
+        )
+    });
+    template::expand_templates(&mut doc, &mut diagnostics);
+    menu_def::expand_menu_refs(&mut doc, &mut diagnostics);
+
+    let mut menu = parse_menu_from_nodes(&doc, options, &mut diagnostics);
+    menu.defaults = defaults;
+    // Top-level hooks apply to every entry, so they don't make the root a menu of its own
+    menu.entries_only = doc
+        .nodes()
+        .iter()
+        .all(|node| matches!(node.name().value(), "menu" | "hooks"));
+
+    for item in &menu.items {
+        if matches!(item.contents, ItemContents::Back) {
+            warn!(
+                "back item '{}' is in the root menu, selecting it will exit",
+                item.name
+            );
+        }
+    }
+
+    diagnostics.finish(menu)
+}
+
+pub fn parse_global_config(src: &str, options: ParseOptions) -> Result<GlobalConfig> {
     let doc = src.parse::<KdlDocument>().map_err(|e| {
         let original = e.diagnostics[0].clone();
         KdlDiagnosticWrapper(original)
     })?;
-    parse_menu_from_nodes(&doc)
+
+    let mut diagnostics = Diagnostics {
+        strict: options.strict,
+        lenient: options.lenient,
+        ..Diagnostics::default()
+    };
+    check_version(&doc, &mut diagnostics)?;
+    let mut global = GlobalConfig::default();
+    let mut settings_node = None;
+    let mut defaults_node = None;
+    diagnostics.each_node(&doc, |node, diagnostics| {
+        match node.name().value() {
+            "settings" => {
+                diagnostics.define_once(&mut settings_node, node)?;
+                global.settings = parse_settings(children(node)?, diagnostics);
+            }
+            "defaults" => {
+                diagnostics.define_once(&mut defaults_node, node)?;
+                global.defaults = parse_defaults_from_nodes(children(node)?, options, diagnostics);
+            }
+            "uff-version" => return Ok(()), // already checked
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    help = "config.kdl can contain settings and defaults, menus go in presets",
+                    "unexpected node in config.kdl: {other}",
+                ));
+            }
+        }
+        no_arguments(node)?;
+        no_parameters(node)
+    });
+    diagnostics.finish(global)
 }
 
-fn no_parameters(node: &KdlNode) -> Result<()> {
-    for entry in node.entries() {
-        if let Some(name) = entry.name() {
+/// Fails if the top-level `uff-version` is newer than this uff can read, and
+/// warns if it's older than the current one.
+fn check_version(doc: &KdlDocument, diagnostics: &mut Diagnostics) -> Result<()> {
+    let mut version_node = None;
+    for node in doc.nodes() {
+        if node.name().value() != "uff-version" {
+            continue;
+        }
+        if let Some(first) = version_node.replace(node) {
+            diagnostics.check::<()>(Err(already_defined(first, node)));
+            continue;
+        }
+        let result = config_version(node);
+        let Some(version) = diagnostics.check(result) else {
+            continue;
+        };
+        let (current, repository) = (CONFIG_VERSIONS.end(), env!("CARGO_PKG_REPOSITORY"));
+        if version > *current {
             return Err(miette!(
                 labels = vec![LabeledSpan::new_primary_with_span(
-                    Some("remove this name".to_string()),
-                    name.span(),
+                    Some(format!("written for config version {version}")),
+                    node.span()
                 )],
-                "{} should not have any named parameters",
-                node.name().value().to_owned(),
+                help = format!(
+                    "uff {} reads config versions up to {current}, get a newer one from {repository}",
+                    env!("CARGO_PKG_VERSION"),
+                ),
+                "this config requires a newer uff",
             ));
         }
+        if version < *current {
+            warn!(
+                "this config is written for config version {version}, some of it may behave differently in version {current}, see {repository}"
+            );
+        }
     }
     Ok(())
 }
 
+/// The version as a number, or a string of digits like `"2"`.
+fn config_version(node: &KdlNode) -> Result<u32> {
+    no_parameters(node)?;
+    no_children(node)?;
+    let entry = one_value(node)?;
+    let version = match entry.value() {
+        KdlValue::Integer(version) => u32::try_from(*version).ok(),
+        KdlValue::String(version) => version.parse().ok(),
+        _ => None,
+    };
+    version.filter(|version| *version >= 1).ok_or_else(|| {
+        miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            help = format!("this uff writes uff-version \"{}\"", CONFIG_VERSIONS.end()),
+            "uff-version should be a version number",
+        )
+    })
+}
+
+fn parse_settings(doc: &KdlDocument, diagnostics: &mut Diagnostics) -> Settings {
+    let mut settings = Settings::default();
+    let mut nodes: Vec<&KdlNode> = Vec::new();
+    diagnostics.each_node(doc, |node, diagnostics| {
+        if let Some(first) = nodes
+            .iter()
+            .find(|first| first.name().value() == node.name().value())
+        {
+            return Err(already_defined(first, node));
+        }
+        nodes.push(node);
+        match node.name().value() {
+            "opener" => settings.opener = Some(command_arguments(node, diagnostics)?),
+            // Expanded along with $UFF_FUZZEL when it's looked up
+            "fuzzel-path" => settings.fuzzel_path = Some(one_argument(node)?),
+            "log-level" => {
+                let level = one_choice(node, &["off", "error", "warn", "info", "debug", "trace"])?;
+                settings.log_level = Some(level.parse().unwrap());
+            }
+            "stale-while-revalidate" => {
+                settings.stale_while_revalidate = Some(one_bool(node)?);
+            }
+            "cache-compression" => {
+                settings.cache_compression = Some(one_bool(node)?);
+            }
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.name().span()
+                    )],
+                    help = fuzzel_keys::closest(other, SETTINGS.iter().copied()).map_or_else(
+                        || format!("settings can contain {}", SETTINGS.join(", ")),
+                        |suggestion| format!("did you mean {suggestion}?")
+                    ),
+                    "unknown setting: {other}",
+                ));
+            }
+        }
+        no_parameters(node)?;
+        no_children(node)
+    });
+    settings
+}
+
+fn no_parameters(node: &KdlNode) -> Result<()> {
+    only_parameters(node, &[])
+}
+
+fn already_defined(first: &KdlNode, second: &KdlNode) -> miette::Report {
+    already_defined_at(
+        second.name().value(),
+        first.span(),
+        second.span(),
+        "merge them into a single node",
+    )
+}
+
+fn already_defined_at(
+    name: &str,
+    first: SourceSpan,
+    second: SourceSpan,
+    help: &str,
+) -> miette::Report {
+    miette!(
+        labels = vec![
+            LabeledSpan::new_with_span(Some("first defined here".to_string()), first),
+            LabeledSpan::new_primary_with_span(Some("redefined here".to_string()), second),
+        ],
+        help = help.to_owned(),
+        "{name} is defined more than once",
+    )
+}
+
+fn named_entry<'a>(node: &'a KdlNode, name: &str) -> Option<&'a KdlEntry> {
+    node.entries().iter().rfind(|entry| {
+        entry
+            .name()
+            .is_some_and(|entry_name| entry_name.value() == name)
+    })
+}
+
 fn no_arguments(node: &KdlNode) -> Result<()> {
     if let Some(first) = node.entries().first() {
         let last = node.entries().last().unwrap().span();
@@ -107,51 +1041,548 @@ fn no_children(node: &KdlNode) -> Result<()> {
     Ok(())
 }
 
-fn one_argument(node: &KdlNode) -> Result<String> {
-    if node.entries().len() != 1 {
-        let labeled_span = if node.entries().is_empty() {
-            let after_node = node.name().span().offset() + node.name().span().len();
-            LabeledSpan::new_primary_with_span(
-                Some("here".to_string()),
-                SourceSpan::new(after_node.into(), 0),
-            )
-        } else {
-            let first = node.entries()[1].span();
-            let last = node.entries().last().unwrap().span();
-            let full_span = SourceSpan::new(
-                first.offset().into(),
-                (last.offset() + last.len()) - first.offset(),
-            );
-            let these = if node.entries().len() < 3 {
-                "remove this".to_string()
-            } else {
-                "remove these".to_string()
-            };
-            LabeledSpan::new_primary_with_span(Some(these), full_span)
-        };
-        return Err(miette!(
-            labels = vec![labeled_span],
-            "{} should have exactly one argument",
-            node.name().value().to_owned(),
-        ));
-    }
+/// Nodes in a menu that become one of its items, named by their first argument.
+fn is_item_node(name: &str) -> bool {
+    matches!(
+        name,
+        "program"
+            | "menu"
+            | "script"
+            | "dynamic-menu"
+            | "pick"
+            | "open"
+            | "mime-open"
+            | "input"
+            | "files-menu"
+            | "recent-files"
+            | "alias"
+            | "back"
+    ) || PowerAction::from_node_name(name).is_some()
+}
 
-    let Some(argument) = node.entries()[0].value().as_string() else {
-        return Err(miette!(
+/// The item's `id` property, checked to be unique in the whole config and to only
+/// use ascii letters, digits, `-`, `_` and `.`.
+fn item_id(node: &KdlNode, diagnostics: &mut Diagnostics) -> Option<String> {
+    let entry = named_entry(node, "id")?;
+    let id = diagnostics.check(string_parameter(node, "id"))??;
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || "-_.".contains(character));
+    if !valid {
+        diagnostics.check::<()>(Err(miette!(
             labels = vec![LabeledSpan::new_primary_with_span(
                 Some("this".to_string()),
-                node.entries()[0].span()
+                entry.span()
             )],
-            help = "try wrapping it in quotes",
-            "argument should be a string",
-        ));
-    };
-
-    Ok(argument.to_owned())
-}
-
-fn many_arguments(node: &KdlNode) -> Result<Vec<String>> {
-    if node.entries().is_empty() {
+            help = "ids can only use ascii letters, digits, -, _ and .",
+            "invalid item id: {id:?}",
+        )));
+        return None;
+    }
+    // A menu-def placed more than once repeats the same node, which is still one item
+    if let Some((_, first)) = diagnostics
+        .ids
+        .iter()
+        .find(|(seen, first)| *seen == id && *first != entry.span())
+    {
+        let first = *first;
+        diagnostics.check::<()>(Err(miette!(
+            labels = vec![
+                LabeledSpan::new_with_span(Some("first used here".to_string()), first),
+                LabeledSpan::new_primary_with_span(
+                    Some("used again here".to_string()),
+                    entry.span()
+                ),
+            ],
+            help = "ids have to be unique in the whole config",
+            "id {id:?} is used by more than one item",
+        )));
+        return None;
+    }
+    if !diagnostics.ids.contains(&(id.clone(), entry.span())) {
+        diagnostics.ids.push((id.clone(), entry.span()));
+    }
+    Some(id)
+}
+
+/// Shown instead of a newline in text that goes in fuzzel's input.
+pub const NEWLINE_SYMBOL: &str = "\u{2424}";
+
+/// fuzzel reads `\0` and `\x1f` as the start of an entry's icon options and a
+/// newline as the end of the entry, so text shown in the menu can't contain
+/// them. Otherwise entries would merge or split and the picked line would map
+/// to the wrong item. Under `--lenient` newlines are shown as `␤` instead.
+fn check_display_text(entry: &KdlEntry, what: &str, diagnostics: &mut Diagnostics) {
+    let Some(text) = entry.value().as_string() else {
+        return;
+    };
+    for (byte, description) in [('\0', "a NUL byte"), ('\x1f', "a 0x1f byte")] {
+        if text.contains(byte) {
+            diagnostics.check::<()>(Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    entry.span()
+                )],
+                help = "remove it, fuzzel reads it as the start of the entry's icon",
+                "{what} can't contain {description}",
+            )));
+        }
+    }
+    if text.contains('\n') {
+        if diagnostics.lenient {
+            warn!("{text:?} contains a newline, showing it as {NEWLINE_SYMBOL}");
+        } else {
+            diagnostics.check::<()>(Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    entry.span()
+                )],
+                help = format!("remove it, with --lenient it's shown as {NEWLINE_SYMBOL}"),
+                "{what} can't contain a newline",
+            )));
+        }
+    }
+}
+
+/// Entries without a name, named parameters are handled separately.
+fn positional(node: &KdlNode) -> Vec<&KdlEntry> {
+    node.entries()
+        .iter()
+        .filter(|entry| entry.name().is_none())
+        .collect()
+}
+
+fn only_parameters(node: &KdlNode, allowed: &[&str]) -> Result<()> {
+    for entry in node.entries() {
+        if let Some(name) = entry.name()
+            && !allowed.contains(&name.value())
+        {
+            if allowed.is_empty() {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("remove this name".to_string()),
+                        name.span(),
+                    )],
+                    "{} should not have any named parameters",
+                    node.name().value().to_owned(),
+                ));
+            }
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    name.span(),
+                )],
+                help = format!("expected one of: {}", allowed.join(", ")),
+                "unexpected named parameter on {}: {}",
+                node.name().value().to_owned(),
+                name.value(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn string_parameter(node: &KdlNode, name: &str) -> Result<Option<String>> {
+    let Some(entry) = named_entry(node, name) else {
+        return Ok(None);
+    };
+    let Some(value) = entry.value().as_string() else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            help = "try wrapping it in quotes",
+            "{name} should be a string",
+        ));
+    };
+    Ok(Some(value.to_owned()))
+}
+
+fn choice_parameter<'a>(
+    node: &KdlNode,
+    name: &str,
+    choices: &[&'a str],
+) -> Result<Option<&'a str>> {
+    let Some(entry) = named_entry(node, name) else {
+        return Ok(None);
+    };
+    let argument = entry.value().as_string();
+    let Some(choice) = choices.iter().find(|choice| Some(**choice) == argument) else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            help = format!("expected one of: {}", choices.join(", ")),
+            "unknown value for {name}",
+        ));
+    };
+    Ok(Some(choice))
+}
+
+fn bool_parameter(node: &KdlNode, name: &str) -> Result<Option<bool>> {
+    let Some(entry) = named_entry(node, name) else {
+        return Ok(None);
+    };
+    let Some(value) = entry.value().as_bool() else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            help = "use #true or #false",
+            "{name} should be a boolean",
+        ));
+    };
+    Ok(Some(value))
+}
+
+fn integer_parameter(node: &KdlNode, name: &str) -> Result<Option<i64>> {
+    let Some(entry) = named_entry(node, name) else {
+        return Ok(None);
+    };
+    let Some(value) = entry
+        .value()
+        .as_integer()
+        .and_then(|value| i64::try_from(value).ok())
+    else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            "{name} should be an integer",
+        ));
+    };
+    Ok(Some(value))
+}
+
+fn bounded_parameter(
+    node: &KdlNode,
+    name: &str,
+    range: RangeInclusive<u32>,
+) -> Result<Option<u32>> {
+    let Some(entry) = named_entry(node, name) else {
+        return Ok(None);
+    };
+    let value = entry
+        .value()
+        .as_integer()
+        .and_then(|value| u32::try_from(value).ok())
+        .filter(|value| range.contains(value));
+    let Some(value) = value else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            "{name} should be an integer between {} and {}",
+            range.start(),
+            range.end(),
+        ));
+    };
+    Ok(Some(value))
+}
+
+fn one_value(node: &KdlNode) -> Result<&KdlEntry> {
+    let arguments = positional(node);
+    if arguments.len() != 1 {
+        let labeled_span = if arguments.is_empty() {
+            let after_node = node.name().span().offset() + node.name().span().len();
+            LabeledSpan::new_primary_with_span(
+                Some("here".to_string()),
+                SourceSpan::new(after_node.into(), 0),
+            )
+        } else {
+            let first = arguments[1].span();
+            let last = arguments.last().unwrap().span();
+            let full_span = SourceSpan::new(
+                first.offset().into(),
+                (last.offset() + last.len()) - first.offset(),
+            );
+            let these = if arguments.len() < 3 {
+                "remove this".to_string()
+            } else {
+                "remove these".to_string()
+            };
+            LabeledSpan::new_primary_with_span(Some(these), full_span)
+        };
+        return Err(miette!(
+            labels = vec![labeled_span],
+            "{} should have exactly one argument",
+            node.name().value().to_owned(),
+        ));
+    }
+
+    Ok(arguments[0])
+}
+
+/// A KDL type annotation like `(path)"~/foo"`, which changes how the string
+/// after it is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Annotation {
+    /// Expands `~` and variables, and checks that the path exists.
+    Path,
+    /// Runs a command given as one string with `sh -c`.
+    Sh,
+    /// Taken as written, without expanding variables.
+    Raw,
+}
+
+impl Annotation {
+    const ALL: [Self; 3] = [Self::Path, Self::Sh, Self::Raw];
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Path => "path",
+            Self::Sh => "sh",
+            Self::Raw => "raw",
+        }
+    }
+}
+
+/// The entry's type annotation, if it has one of the `supported` ones.
+fn annotation(entry: &KdlEntry, supported: &[Annotation]) -> Result<Option<Annotation>> {
+    let Some(ty) = entry.ty() else {
+        return Ok(None);
+    };
+    Annotation::ALL
+        .into_iter()
+        .find(|annotation| annotation.name() == ty.value() && supported.contains(annotation))
+        .map(Some)
+        .ok_or_else(|| {
+            let names: Vec<String> = supported
+                .iter()
+                .map(|annotation| format!("({})", annotation.name()))
+                .collect();
+            miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    ty.span()
+                )],
+                help = if names.is_empty() {
+                    "this value can't have one, remove it".to_string()
+                } else {
+                    format!("supported here: {}", names.join(", "))
+                },
+                "unsupported type annotation: ({})",
+                ty.value(),
+            )
+        })
+}
+
+fn one_argument(node: &KdlNode) -> Result<String> {
+    Ok(one_annotated_argument(node, &[])?.0)
+}
+
+fn one_annotated_argument(
+    node: &KdlNode,
+    supported: &[Annotation],
+) -> Result<(String, Option<Annotation>)> {
+    let entry = one_value(node)?;
+    let Some(argument) = entry.value().as_string() else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            help = "try wrapping it in quotes",
+            "argument should be a string",
+        ));
+    };
+
+    Ok((argument.to_owned(), annotation(entry, supported)?))
+}
+
+/// Expands environment variables in an argument, pointing at it if one isn't set.
+/// `(raw)` leaves it as written, and `(path)` also expands `~` and checks that
+/// the path exists.
+fn expand_argument(
+    entry: &KdlEntry,
+    value: &str,
+    annotation: Option<Annotation>,
+) -> Result<String> {
+    if annotation == Some(Annotation::Raw) {
+        return Ok(value.to_owned());
+    }
+    let expanded = expand_env_vars(value).map_err(|name| {
+        miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("used here".to_string()),
+                entry.span()
+            )],
+            help = "set it, or write $$ for a literal $",
+            "environment variable {name} is not set",
+        )
+    })?;
+    if annotation != Some(Annotation::Path) {
+        return Ok(expanded);
+    }
+    let path = expand_tilde(&expanded);
+    if !std::path::Path::new(&path).exists() {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this path".to_string()),
+                entry.span()
+            )],
+            help = "(path) checks that it exists, remove it to skip the check",
+            "{path} doesn't exist",
+        ));
+    }
+    Ok(path)
+}
+
+/// Takes `(path)` and `(raw)` annotations, see `expand_argument`.
+fn one_expanded_argument(node: &KdlNode) -> Result<String> {
+    let (value, annotation) = one_annotated_argument(node, &[Annotation::Path, Annotation::Raw])?;
+    expand_argument(one_value(node)?, &value, annotation)
+}
+
+/// The arguments of a node holding a command, which can't start with an empty program.
+/// Besides `(path)` and `(raw)`, a command written as one `(sh)` string is run
+/// with `sh -c`, which expands its variables itself.
+fn command_arguments(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Vec<String>> {
+    let arguments = many_annotated_arguments(node, &Annotation::ALL)?;
+    let entries = positional(node);
+    let command = annotated_command(&entries, &arguments)?;
+    let spans: Vec<SourceSpan> = entries.iter().map(|entry| entry.span()).collect();
+    check_command(&command, &spans, diagnostics)?;
+    Ok(command)
+}
+
+/// Expands each argument, or wraps a lone `(sh)` one in `sh -c`.
+fn annotated_command(
+    entries: &[&KdlEntry],
+    arguments: &[(String, Option<Annotation>)],
+) -> Result<Vec<String>> {
+    if let Some(index) = arguments
+        .iter()
+        .position(|(_, annotation)| *annotation == Some(Annotation::Sh))
+    {
+        if arguments.len() != 1 {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    entries[index].span()
+                )],
+                help = "put the whole command in the one string",
+                "a (sh) command should be its only argument",
+            ));
+        }
+        return Ok(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            arguments[0].0.clone(),
+        ]);
+    }
+    entries
+        .iter()
+        .zip(arguments)
+        .map(|(entry, (value, annotation))| expand_argument(entry, value, *annotation))
+        .collect()
+}
+
+/// `spans` points at where each argument came from.
+fn check_command(
+    command: &[String],
+    spans: &[SourceSpan],
+    diagnostics: &mut Diagnostics,
+) -> Result<()> {
+    if command
+        .first()
+        .is_some_and(|program| program.trim().is_empty())
+    {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this is empty".to_string()),
+                spans[0]
+            )],
+            help = "the first argument is the program to run",
+            "command should start with a program",
+        ));
+    }
+    for (argument, span) in command.iter().zip(spans).skip(1) {
+        if argument.trim().is_empty() {
+            diagnostics.lint(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    *span
+                )],
+                help = "remove it if it isn't meant to be passed",
+                "command has an empty argument",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn one_choice<'a>(node: &KdlNode, choices: &[&'a str]) -> Result<&'a str> {
+    let entry = one_value(node)?;
+    let argument = entry.value().as_string();
+    let Some(choice) = choices.iter().find(|choice| Some(**choice) == argument) else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            help = format!("expected one of: {}", choices.join(", ")),
+            "unknown value for {}",
+            node.name().value().to_owned(),
+        ));
+    };
+
+    Ok(choice)
+}
+
+fn one_bool(node: &KdlNode) -> Result<bool> {
+    let entry = one_value(node)?;
+    let Some(argument) = entry.value().as_bool() else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            help = "use #true or #false",
+            "argument should be a boolean",
+        ));
+    };
+
+    Ok(argument)
+}
+
+fn one_integer(node: &KdlNode, range: RangeInclusive<i128>) -> Result<i128> {
+    let entry = one_value(node)?;
+    let argument = entry.value().as_integer();
+    let Some(argument) = argument.filter(|argument| range.contains(argument)) else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entry.span()
+            )],
+            "argument should be an integer between {} and {}",
+            range.start(),
+            range.end(),
+        ));
+    };
+
+    Ok(argument)
+}
+
+fn many_arguments(node: &KdlNode) -> Result<Vec<String>> {
+    Ok(many_annotated_arguments(node, &[])?
+        .into_iter()
+        .map(|(argument, _)| argument)
+        .collect())
+}
+
+fn many_annotated_arguments(
+    node: &KdlNode,
+    supported: &[Annotation],
+) -> Result<Vec<(String, Option<Annotation>)>> {
+    let arguments = positional(node);
+    if arguments.is_empty() {
         let after_node = node.name().span().offset() + node.name().span().len();
         return Err(miette!(
             labels = vec![LabeledSpan::new_primary_with_span(
@@ -164,9 +1595,9 @@ fn many_arguments(node: &KdlNode) -> Result<Vec<String>> {
     }
 
     let mut args = Vec::new();
-    for entry in node.entries() {
+    for entry in arguments {
         if let Some(value) = entry.value().as_string() {
-            args.push(value.to_owned());
+            args.push((value.to_owned(), annotation(entry, supported)?));
         } else {
             return Err(miette!(
                 labels = vec![LabeledSpan::new_primary_with_span(
@@ -200,219 +1631,3968 @@ fn children(node: &KdlNode) -> Result<&KdlDocument> {
     })
 }
 
-fn parse_menu_from_nodes(doc: &KdlDocument) -> Result<Menu> {
+fn parse_menu_from_nodes(
+    doc: &KdlDocument,
+    mut options: ParseOptions,
+    diagnostics: &mut Diagnostics,
+) -> Menu {
+    // Needed before any fuzzel-config or submenu is parsed, a bad value is reported below
+    if let Some(node) = doc
+        .nodes()
+        .iter()
+        .rfind(|node| node.name().value() == "allow-unknown-fuzzel-keys")
+        && let Ok(allow) = one_bool(node)
+    {
+        options.allow_unknown_fuzzel_keys = allow;
+    }
     let mut fuzzel_args = Vec::new();
+    let mut fuzzel_args_node = None;
+    let mut extra_fuzzel_args = Vec::new();
+    let mut extra_fuzzel_args_node = None;
+    let mut inherit_fuzzel_args = true;
+    let mut inherit_fuzzel_config = true;
+    let mut inherit_icon_dirs = IconDirInheritance::All;
     let mut fuzzel_config = Vec::new();
+    let mut fuzzel_config_node = None;
+    let mut sort = SortOrder::None;
+    let mut order = ItemOrder::Config;
+    let mut stay_open = false;
+    let mut multi_select = None;
+    let mut multi_select_node = None;
+    let mut run_command = None;
+    let mut run_command_node = None;
+    let mut opener = None;
+    let mut placeholder = None;
+    let mut description_separator = None;
+    let mut markup = None;
+    let mut use_fuzzel_cache = None;
+    let mut icon_size = None;
+    let mut prefer_icon_format = None;
+    let mut icon_match = None;
+    let mut symbolic = None;
+    let mut show_indices = None;
+    let mut show_counts = None;
+    let mut auto_size = None;
+    let mut binds = Vec::new();
+    let mut binds_node = None;
+    let mut env = Vec::new();
+    let mut env_node = None;
+    let mut hooks = Hooks::default();
+    let mut hooks_node = None;
     let mut icon_dirs = Vec::new();
     let mut items = Vec::new();
 
-    for node in doc.nodes() {
+    diagnostics.each_node(doc, |node, diagnostics| {
+        if is_item_node(node.name().value())
+            && let Some(name) = positional(node).first()
+        {
+            check_display_text(name, "item names", diagnostics);
+        }
+        let id = if is_item_node(node.name().value()) {
+            item_id(node, diagnostics)
+        } else {
+            None
+        };
+        let item_count = items.len();
         match node.name().value() {
             "fuzzel-args" => {
-                if !fuzzel_args.is_empty() {
-                    warn!("fuzzel-args already defined, overwriting");
-                }
+                diagnostics.define_once(&mut fuzzel_args_node, node)?;
                 fuzzel_args = many_arguments(node)?;
                 no_parameters(node)?;
                 no_children(node)?;
             }
-            "fuzzel-config" => {
-                if !fuzzel_config.is_empty() {
-                    warn!("fuzzel-config already defined, overwriting");
-                    fuzzel_config.clear();
-                }
-                let children = children(node)?;
-                for kv in children.nodes() {
-                    let key = kv.name().value().to_owned();
-                    let value = one_argument(kv)?;
-                    fuzzel_config.push((key, value));
-                    no_parameters(kv)?;
-                }
-                no_arguments(node)?;
-            }
-            "icon-dir" => {
-                let path_str = one_argument(node)?;
-                let path = PathBuf::from(path_str.replace('~', &home()));
-                if !path.is_absolute() {
-                    warn!(
-                        "relative icon-dirs can behave unexpectedly, consider using absolute paths"
-                    );
-                }
-                icon_dirs.push(path);
+            "extra-fuzzel-args" => {
+                diagnostics.define_once(&mut extra_fuzzel_args_node, node)?;
+                extra_fuzzel_args = many_arguments(node)?;
                 no_parameters(node)?;
                 no_children(node)?;
             }
-            "menu" | "program" => {
-                let name = one_argument(node)?;
-                let children = children(node)?;
-                items.push(parse_item_from_nodes(node.name().value(), &name, children)?);
+            "inherit-fuzzel-args" => {
+                inherit_fuzzel_args = one_bool(node)?;
                 no_parameters(node)?;
+                no_children(node)?;
             }
-            "icon" => {} // already parsed by parse_item_from_nodes
-            other => {
-                return Err(miette!(
-                    labels = vec![LabeledSpan::new_primary_with_span(
-                        Some("this".to_string()),
-                        node.span()
-                    )],
-                    "unexpected node in menu: {}",
-                    other,
-                ));
-            }
-        }
-    }
-
-    Ok(Menu {
-        fuzzel_args,
-        fuzzel_config,
-        icon_dirs,
-        items,
-    })
-}
-
-fn parse_program_from_nodes(doc: &KdlDocument) -> Result<Program> {
-    let mut command: Vec<String> = Vec::new();
-
-    for node in doc.nodes() {
-        match node.name().value() {
-            "command" => {
-                if !command.is_empty() {
-                    warn!("command already defined, overwriting");
-                }
-                command = many_arguments(node)?;
+            "inherit-fuzzel-config" => {
+                inherit_fuzzel_config = one_bool(node)?;
                 no_parameters(node)?;
                 no_children(node)?;
             }
-            "icon" => {} // already parsed by parse_item_from_nodes
+            "inherit-icon-dirs" => {
+                // #true and #false are accepted like the other inherit- nodes
+                inherit_icon_dirs = match one_value(node)?.value().as_bool() {
+                    Some(true) => IconDirInheritance::All,
+                    Some(false) => IconDirInheritance::None,
+                    None => match one_choice(node, &["all", "xdg", "none"])? {
+                        "xdg" => IconDirInheritance::Xdg,
+                        "none" => IconDirInheritance::None,
+                        _ => IconDirInheritance::All,
+                    },
+                };
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "placeholder" => {
+                placeholder = Some(one_argument(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "description-separator" => {
+                description_separator = Some(one_argument(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "markup" => {
+                markup = Some(one_bool(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "show-indices" => {
+                let separator = string_parameter(node, "separator")?;
+                show_indices =
+                    one_bool(node)?.then(|| separator.unwrap_or_else(|| ". ".to_string()));
+                only_parameters(node, &["separator"])?;
+                no_children(node)?;
+            }
+            "show-counts" => {
+                let recursive = bool_parameter(node, "recursive")?.unwrap_or(true);
+                show_counts = one_bool(node)?.then_some(recursive);
+                only_parameters(node, &["recursive"])?;
+                no_children(node)?;
+            }
+            "auto-size" => {
+                only_parameters(node, &["max-lines", "max-width", "padding"])?;
+                let default = AutoSize::default();
+                let limits = AutoSize {
+                    max_lines: bounded_parameter(node, "max-lines", 1..=100)?
+                        .unwrap_or(default.max_lines),
+                    max_width: bounded_parameter(node, "max-width", 1..=500)?
+                        .unwrap_or(default.max_width),
+                    padding: bounded_parameter(node, "padding", 0..=100)?
+                        .unwrap_or(default.padding),
+                };
+                auto_size = Some(one_bool(node)?.then_some(limits));
+                no_children(node)?;
+            }
+            "use-fuzzel-cache" => {
+                use_fuzzel_cache = Some(one_bool(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "icon-size" => {
+                icon_size = Some(one_integer(node, 1..=4096)? as u32);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "prefer-icon-format" => {
+                prefer_icon_format = Some(parse_icon_format(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "icon-match" => {
+                icon_match = Some(parse_icon_match(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "symbolic" => {
+                symbolic = Some(parse_symbolic(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "binds" => {
+                if let Some(first) = binds_node {
+                    return Err(already_defined(first, node));
+                }
+                binds_node = Some(node);
+                binds = parse_binds(node, diagnostics)?;
+            }
+            "env" => {
+                diagnostics.define_once(&mut env_node, node)?;
+                env = parse_env(node, diagnostics)?;
+            }
+            "hooks" => {
+                if let Some(first) = hooks_node {
+                    return Err(already_defined(first, node));
+                }
+                hooks_node = Some(node);
+                no_arguments(node)?;
+                no_parameters(node)?;
+                hooks = parse_hooks(children(node)?, diagnostics);
+            }
+            "allow-unknown-fuzzel-keys" => {
+                one_bool(node)?; // already used before the loop
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "stay-open" => {
+                stay_open = one_bool(node)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "multi-select" => {
+                diagnostics.define_once(&mut multi_select_node, node)?;
+                multi_select = match one_value(node)?.value().as_bool() {
+                    Some(true) => Some(MultiSelect::Each),
+                    Some(false) => None,
+                    None => {
+                        one_choice(node, &["args"])?;
+                        Some(MultiSelect::Args(Vec::new()))
+                    }
+                };
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "run-command" => {
+                diagnostics.define_once(&mut run_command_node, node)?;
+                run_command = Some(command_arguments(node, diagnostics)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "sort" => {
+                sort = match one_choice(node, &["none", "alphabetical", "reverse"])? {
+                    "alphabetical" => SortOrder::Alphabetical,
+                    "reverse" => SortOrder::Reverse,
+                    _ => SortOrder::None,
+                };
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "order" => {
+                order = match one_choice(node, &["config", "menus-first", "programs-first"])? {
+                    "menus-first" => ItemOrder::MenusFirst,
+                    "programs-first" => ItemOrder::ProgramsFirst,
+                    _ => ItemOrder::Config,
+                };
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "fuzzel-config" => {
+                if let Some(first) = fuzzel_config_node {
+                    return Err(already_defined(first, node));
+                }
+                fuzzel_config_node = Some(node);
+                fuzzel_config = parse_fuzzel_config(node, options, diagnostics)?;
+            }
+            "icon-dir" => icon_dirs.push(parse_icon_dir(node)?),
+            "program" => items.push(parse_program_item(node, diagnostics)?),
+            "menu" | "script" | "dynamic-menu" | "pick" => {
+                let name = one_argument(node)?;
+                let children = children(node)?;
+                let reported = diagnostics.count();
+                let item = parse_item_from_nodes(
+                    node.name().value(),
+                    &name,
+                    children,
+                    options,
+                    diagnostics,
+                )?;
+                // Items that failed to parse have already been reported
+                if let ItemContents::Menu(menu) = &item.contents
+                    && menu.items.is_empty()
+                    && diagnostics.count() == reported
+                {
+                    return Err(miette!(
+                        labels = vec![LabeledSpan::new_primary_with_span(
+                            Some("this menu".to_string()),
+                            node.span()
+                        )],
+                        help = "add a program or another item to it, or remove the menu",
+                        "menu {name} has no entries",
+                    ));
+                }
+                items.push(item);
+                if node.name().value() == "dynamic-menu" {
+                    only_parameters(node, &["id", "on-empty"])?;
+                    let on_empty = parse_on_empty(node, children, diagnostics)?;
+                    if let Some(ItemContents::DynamicMenu(dynamic_menu)) =
+                        items.last_mut().map(|item| &mut item.contents)
+                    {
+                        dynamic_menu.on_empty = on_empty;
+                    }
+                } else {
+                    only_parameters(node, &["id"])?;
+                }
+            }
+            "open" | "mime-open" => items.push(parse_open_item(node, diagnostics)?),
+            "input" => items.push(parse_input_item(node, diagnostics)?),
+            "files-menu" => items.push(parse_files_menu_item(node, diagnostics)?),
+            "recent-files" => items.push(parse_recent_files_item(node, diagnostics)?),
+            "alias" => items.push(parse_alias_item(node, diagnostics)?),
+            name if let Some(action) = PowerAction::from_node_name(name) => {
+                items.push(parse_power_item(node, action, diagnostics)?);
+            }
+            "opener" => {
+                opener = Some(command_arguments(node, diagnostics)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "back" => {
+                let name = one_argument(node)?;
+                let empty = KdlDocument::new();
+                let children = node.children().unwrap_or(&empty);
+                items.push(parse_item_from_nodes(
+                    "back",
+                    &name,
+                    children,
+                    options,
+                    diagnostics,
+                )?);
+                only_parameters(node, &["id"])?;
+            }
+            name if COMMON_ITEM_NODES.contains(&name) => {} // already parsed by parse_item_from_nodes
             other => {
                 return Err(miette!(
                     labels = vec![LabeledSpan::new_primary_with_span(
                         Some("this".to_string()),
                         node.span()
                     )],
-                    "unexpected node in program: {}",
+                    "unexpected node in menu: {}",
                     other,
                 ));
             }
         }
-    }
+        if let Some(item) = items.get_mut(item_count) {
+            item.id = id;
+        }
+        Ok(())
+    });
 
-    if command.is_empty() {
-        return Err(miette!(
-            labels = vec![LabeledSpan::new_primary_with_span(
-                Some("here".to_string()),
-                doc.span(),
-            )],
-            "program should have a command",
-        ));
+    match (&mut multi_select, run_command) {
+        (Some(MultiSelect::Args(command)), Some(run_command)) => *command = run_command,
+        (Some(MultiSelect::Args(_)), None) => {
+            diagnostics.check::<()>(Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    multi_select_node.unwrap().span()
+                )],
+                help = "add a run-command to the menu, the picked names are appended to it",
+                "multi-select \"args\" needs a run-command",
+            )));
+            multi_select = None;
+        }
+        (_, Some(_)) => {
+            diagnostics.check::<()>(Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    run_command_node.unwrap().span()
+                )],
+                help = "add multi-select \"args\" to pass the picked names to it",
+                "run-command is only used by multi-select menus",
+            )));
+        }
+        (_, None) => {}
+    }
+    if multi_select.is_some() {
+        check_multi_select_items(doc, diagnostics);
     }
+    check_alias_names(doc, diagnostics);
 
-    Ok(Program { command })
+    Menu {
+        fuzzel_args,
+        extra_fuzzel_args,
+        inherit_fuzzel_args,
+        fuzzel_config,
+        inherit_fuzzel_config,
+        icon_dirs,
+        inherit_icon_dirs,
+        sort,
+        order,
+        stay_open,
+        multi_select,
+        opener,
+        placeholder,
+        description_separator,
+        markup,
+        use_fuzzel_cache,
+        icon_size,
+        prefer_icon_format,
+        icon_match,
+        symbolic,
+        show_indices,
+        show_counts,
+        auto_size,
+        binds,
+        env,
+        hooks,
+        defaults: Defaults::default(),
+        entries_only: false,
+        items,
+    }
 }
 
-fn parse_item_from_nodes(kind: &str, name: &str, doc: &KdlDocument) -> Result<Item> {
-    let mut icon: Option<String> = None;
-
+/// Picked items are launched together, so each has to be something that runs
+/// straight away.
+fn check_multi_select_items(doc: &KdlDocument, diagnostics: &mut Diagnostics) {
     for node in doc.nodes() {
-        if node.name().value() == "icon" {
-            if icon.is_some() {
-                warn!("icon already defined, overwriting");
+        let name = node.name().value();
+        if matches!(
+            name,
+            "menu"
+                | "dynamic-menu"
+                | "pick"
+                | "mime-open"
+                | "input"
+                | "files-menu"
+                | "recent-files"
+                | "back"
+        ) {
+            diagnostics.check::<()>(Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("not a program".to_string()),
+                    node.span()
+                )],
+                help = "move it to another menu, or remove multi-select",
+                "a multi-select menu can only hold programs, found {name}",
+            )));
+        } else if let Some(confirm) = node.children().and_then(|children| children.get("confirm")) {
+            // Only confirm #false, which power items need to turn theirs off
+            if confirm.entries().len() == 1
+                && confirm.entries()[0].name().is_none()
+                && confirm.entries()[0].value().as_bool() == Some(false)
+            {
+                continue;
             }
-            icon = Some(one_argument(node)?);
-            no_parameters(node)?;
-            no_children(node)?;
+            diagnostics.check::<()>(Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    confirm.span()
+                )],
+                help = "remove it, or move the item out of the multi-select menu",
+                "items in a multi-select menu can't ask for confirmation",
+            )));
+        } else if let Some(action) = PowerAction::from_node_name(name)
+            && action.default_question().is_some()
+        {
+            diagnostics.check::<()>(Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("asks for confirmation by default".to_string()),
+                    node.span()
+                )],
+                help = "add confirm #false to it, or move it out of the multi-select menu",
+                "items in a multi-select menu can't ask for confirmation",
+            )));
         }
     }
-
-    let contents = match kind {
-        "menu" => ItemContents::Menu(parse_menu_from_nodes(doc)?),
-        "program" => ItemContents::Program(parse_program_from_nodes(doc)?),
-        _ => unreachable!(),
-    };
-
-    Ok(Item {
-        name: name.to_owned(),
-        icon,
-        contents,
-    })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// `command` is the shorthand given as a `cmd` property, if any.
+fn parse_program_from_nodes(
+    doc: &KdlDocument,
+    mut command: Vec<String>,
+    diagnostics: &mut Diagnostics,
+) -> Program {
+    let mut command_node = None;
+    let mut confirm = None;
+    let mut watch_exit = false;
+    let mut watch_exit_timeout = None;
+    let mut notify = None;
+    let mut chain: Option<Chain> = None;
+    let mut chain_node = None;
+    let mut fallbacks = None;
+    let mut try_node = None;
+    let mut desktop = None;
+    let mut desktop_node = None;
+    let mut env = Vec::new();
+    let mut env_node = None;
+    let mut nice = None;
+    let mut ionice = None;
+    let mut systemd_scope = None;
+    let mut launch_method = None;
+    let mut hypr_rules = Vec::new();
+    let mut hypr_rules_node = None;
+    let mut attached = false;
+    let mut exec = false;
+    let mut stdout = None;
+    let mut stderr = None;
 
-    #[test]
-    fn test_parse_phase_comprehensive() {
-        // Test simple program parsing
-        let simple_config = r#"
-            program "Item1" {
-                command "cmd1"
+    diagnostics.each_node(doc, |node, diagnostics| {
+        match node.name().value() {
+            "nice" => {
+                nice = Some(one_integer(node, -20..=19)?.try_into().unwrap());
+                no_parameters(node)?;
+                no_children(node)?;
             }
-        "#;
-        let simple = parse_config(simple_config).unwrap();
-        assert_eq!(simple.items.len(), 1);
-        assert_eq!(simple.items[0].name, "Item1");
-        if let ItemContents::Program(ref prog) = simple.items[0].contents {
-            assert_eq!(prog.command, vec!["cmd1"]);
-        } else {
-            panic!("Expected program item");
-        }
-
-        // Test parsing with fuzzel config
-        let config_with_fuzzel = r#"
-            fuzzel-args "--arg1" "--arg2"
-            fuzzel-config {
-                key1 "value1"
-                key2 "value2"
+            "ionice" => {
+                ionice = Some(parse_ionice(node)?);
+                only_parameters(node, &["level"])?;
+                no_children(node)?;
             }
-            program "Item1" {
-                icon "icon1"
-                command "cmd1"
+            "systemd-scope" => {
+                systemd_scope = Some(one_bool(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
             }
-        "#;
-        let with_config = parse_config(config_with_fuzzel).unwrap();
-        assert_eq!(with_config.fuzzel_args, vec!["--arg1", "--arg2"]);
-        assert_eq!(
-            with_config.fuzzel_config,
-            vec![
-                ("key1".to_string(), "value1".to_string()),
-                ("key2".to_string(), "value2".to_string()),
-            ]
-        );
-        assert_eq!(with_config.items[0].icon, Some("icon1".to_string()));
-
-        // Test nested menu parsing
-        let nested_config = r#"
-            program "Item1" {
-                command "cmd1"
+            "launch-method" => {
+                launch_method = Some(parse_launch_method(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
             }
-            menu "Submenu1" {
-                fuzzel-config {
-                    subkey "subvalue"
-                }
-                program "Item2" {
-                    command "cmd2" "arg2"
+            "hypr-rules" => {
+                diagnostics.define_once(&mut hypr_rules_node, node)?;
+                hypr_rules = parse_hypr_rules(node)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "detach" => {
+                attached = !one_bool(node)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "exec" => {
+                exec = one_bool(node)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "stdout" => {
+                stdout = Some(parse_output(node)?);
+                no_children(node)?;
+            }
+            "stderr" => {
+                stderr = Some(parse_output(node)?);
+                no_children(node)?;
+            }
+            "notify" => {
+                let summary = one_argument(node)?;
+                let body = string_parameter(node, "body")?;
+                notify = Some(Notification { summary, body });
+                only_parameters(node, &["body"])?;
+                no_children(node)?;
+            }
+            "watch-exit" => {
+                watch_exit = one_bool(node)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "watch-exit-timeout" => {
+                watch_exit_timeout = Some(one_integer(node, 1..=60)?.try_into().unwrap());
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "command" => {
+                diagnostics.define_once(&mut command_node, node)?;
+                command = command_arguments(node, diagnostics)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "chain" => {
+                if let Some(first) = chain_node {
+                    return Err(already_defined(first, node));
                 }
+                chain_node = Some(node);
+                chain = Some(parse_chain(node, diagnostics)?);
             }
-        "#;
-        let nested = parse_config(nested_config).unwrap();
-        assert_eq!(nested.items.len(), 2);
-        assert_eq!(nested.items[0].name, "Item1");
-        assert_eq!(nested.items[1].name, "Submenu1");
-        if let ItemContents::Menu(ref submenu) = nested.items[1].contents {
-            assert_eq!(
-                submenu.fuzzel_config,
-                vec![("subkey".to_string(), "subvalue".to_string())]
-            );
-            assert_eq!(submenu.items.len(), 1);
-            assert_eq!(submenu.items[0].name, "Item2");
-            if let ItemContents::Program(ref prog) = submenu.items[0].contents {
-                assert_eq!(prog.command, vec!["cmd2", "arg2"]);
-            } else {
-                panic!("Expected program in submenu");
+            "try" => {
+                diagnostics.define_once(&mut try_node, node)?;
+                fallbacks = Some(parse_try(node, diagnostics)?);
             }
-        } else {
-            panic!("Expected menu item");
+            "desktop" => {
+                diagnostics.define_once(&mut desktop_node, node)?;
+                desktop = Some(one_argument(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "env" => {
+                diagnostics.define_once(&mut env_node, node)?;
+                env = parse_env(node, diagnostics)?;
+            }
+            "confirm" => {
+                let question = if node.entries().is_empty() {
+                    "Are you sure?".to_string()
+                } else {
+                    one_argument(node)?
+                };
+                confirm = Some(question);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "icon-dir" => {} // parsed onto the item by parse_program_item
+            name if COMMON_ITEM_NODES.contains(&name) => {} // already parsed by parse_item_from_nodes
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    "unexpected node in program: {}",
+                    other,
+                ));
+            }
+        }
+        Ok(())
+    });
+
+    if watch_exit_timeout.is_some() && !watch_exit {
+        warn!("watch-exit-timeout has no effect without watch-exit #true");
+    }
+    let watch_exit = watch_exit.then(|| watch_exit_timeout.unwrap_or(3));
+    if let Some(chain_node) = chain_node {
+        if !command.is_empty() {
+            diagnostics.check::<()>(Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    chain_node.span()
+                )],
+                help = "move the command into the chain",
+                "program has both a command and a chain",
+            )));
         }
+        if watch_exit.is_some() {
+            warn!("watch-exit has no effect on a chain, which is waited for anyway");
+        }
+        if exec {
+            warn!("exec has no effect on a chain, which runs more than one command");
+        }
+    }
+    if let Some(try_node) = try_node
+        && (!command.is_empty() || chain_node.is_some())
+    {
+        diagnostics.check::<()>(Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                try_node.span()
+            )],
+            help = "move the command into the try block",
+            "program has both a try block and a {}",
+            if chain_node.is_some() {
+                "chain"
+            } else {
+                "command"
+            },
+        )));
+    }
+
+    Program {
+        command,
+        confirm,
+        watch_exit,
+        notify,
+        chain,
+        fallbacks,
+        desktop,
+        env,
+        nice,
+        ionice,
+        systemd_scope,
+        launch_method,
+        hypr_rules,
+        attached,
+        exec,
+        stdout,
+        stderr,
+    }
+}
+
+fn parse_icon_format(node: &KdlNode) -> Result<IconFormat> {
+    Ok(match one_choice(node, &["svg", "png"])? {
+        "png" => IconFormat::Png,
+        _ => IconFormat::Svg,
+    })
+}
+
+fn parse_icon_match(node: &KdlNode) -> Result<IconMatch> {
+    Ok(match one_choice(node, &["ignore-case", "exact"])? {
+        "exact" => IconMatch::Exact,
+        _ => IconMatch::IgnoreCase,
+    })
+}
+
+fn parse_symbolic(node: &KdlNode) -> Result<SymbolicIcons> {
+    Ok(match one_choice(node, &["allow", "prefer", "avoid"])? {
+        "allow" => SymbolicIcons::Allow,
+        "prefer" => SymbolicIcons::Prefer,
+        _ => SymbolicIcons::Avoid,
+    })
+}
+
+fn parse_launch_method(node: &KdlNode) -> Result<LaunchMethod> {
+    Ok(
+        match one_choice(node, &["direct", "swaymsg", "hyprctl", "auto"])? {
+            "swaymsg" => LaunchMethod::Swaymsg,
+            "hyprctl" => LaunchMethod::Hyprctl,
+            "auto" => LaunchMethod::Auto,
+            _ => LaunchMethod::Direct,
+        },
+    )
+}
+
+/// Each argument is one rule. Hyprland ends the rules at the first `]`, so
+/// they can't contain one.
+fn parse_hypr_rules(node: &KdlNode) -> Result<Vec<String>> {
+    let rules = many_arguments(node)?;
+    for (rule, entry) in rules.iter().zip(positional(node)) {
+        if rule.contains(['[', ']']) || rule.trim().is_empty() {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    entry.span()
+                )],
+                help = "give each rule without brackets, like hypr-rules \"workspace 3 silent\" \"float\"",
+                "invalid hypr-rules rule: {rule:?}",
+            ));
+        }
+    }
+    Ok(rules)
+}
+
+fn parse_output(node: &KdlNode) -> Result<Output> {
+    only_parameters(node, &["append"])?;
+    let value = one_argument(node)?;
+    let append = bool_parameter(node, "append")?;
+    let output = match value.as_str() {
+        "null" => Output::Null,
+        "inherit" => Output::Inherit,
+        _ if let Some(path) = value.strip_prefix("file:")
+            && !path.is_empty() =>
+        {
+            Output::File {
+                path: expand_argument(one_value(node)?, path, None)?,
+                append: append.unwrap_or(false),
+            }
+        }
+        _ => {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    one_value(node)?.span()
+                )],
+                help = "expected one of: null, inherit, file:PATH",
+                "unknown value for {}",
+                node.name().value(),
+            ));
+        }
+    };
+    if append.is_some() && !matches!(output, Output::File { .. }) {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                named_entry(node, "append").unwrap().span()
+            )],
+            help = "append only applies to file: targets",
+            "unexpected property append",
+        ));
+    }
+    Ok(output)
+}
+
+fn parse_ionice(node: &KdlNode) -> Result<IoPriority> {
+    let class = match one_argument(node)?.as_str() {
+        "realtime" => IoClass::Realtime,
+        "best-effort" => IoClass::BestEffort,
+        "idle" => IoClass::Idle,
+        _ => {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    one_value(node)?.span()
+                )],
+                help = "expected one of: realtime, best-effort, idle",
+                "unknown ionice class",
+            ));
+        }
+    };
+    let level = match integer_parameter(node, "level")? {
+        None => None,
+        Some(_) if class == IoClass::Idle => {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    named_entry(node, "level").unwrap().span()
+                )],
+                help = "remove the level",
+                "the idle class has no levels",
+            ));
+        }
+        Some(level @ 0..=7) => Some(level as u8),
+        Some(_) => {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    named_entry(node, "level").unwrap().span()
+                )],
+                "level should be an integer between 0 and 7",
+            ));
+        }
+    };
+    Ok(IoPriority { class, level })
+}
+
+fn parse_chain(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Chain> {
+    only_parameters(node, &["continue-on-error"])?;
+    if let Some(argument) = positional(node).first() {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                argument.span()
+            )],
+            help = "put each command in a command node inside the chain",
+            "chain should not have any arguments",
+        ));
+    }
+    let mut commands = Vec::new();
+    diagnostics.each_node(children(node)?, |step, diagnostics| {
+        if step.name().value() != "command" {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    step.span()
+                )],
+                "unexpected node in chain: {}",
+                step.name().value(),
+            ));
+        }
+        commands.push(command_arguments(step, diagnostics)?);
+        no_parameters(step)?;
+        no_children(step)
+    });
+    // Nodes that failed to parse have already been reported
+    if children(node)?.nodes().is_empty() {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("here".to_string()),
+                node.span(),
+            )],
+            "chain should have commands",
+        ));
+    }
+    Ok(Chain {
+        commands,
+        continue_on_error: bool_parameter(node, "continue-on-error")?.unwrap_or(false),
+    })
+}
+
+fn parse_try(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Fallbacks> {
+    only_parameters(node, &["required"])?;
+    if let Some(argument) = positional(node).first() {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                argument.span()
+            )],
+            help = "put each command in a command node inside the try block",
+            "try should not have any arguments",
+        ));
+    }
+    let mut commands = Vec::new();
+    diagnostics.each_node(children(node)?, |candidate, diagnostics| {
+        if candidate.name().value() != "command" {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    candidate.span()
+                )],
+                "unexpected node in try: {}",
+                candidate.name().value(),
+            ));
+        }
+        commands.push(command_arguments(candidate, diagnostics)?);
+        no_parameters(candidate)?;
+        no_children(candidate)
+    });
+    // Nodes that failed to parse have already been reported
+    if children(node)?.nodes().is_empty() {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("here".to_string()),
+                node.span(),
+            )],
+            "try should have commands",
+        ));
+    }
+    Ok(Fallbacks {
+        commands,
+        required: bool_parameter(node, "required")?.unwrap_or(false),
+    })
+}
+
+fn parse_fuzzel_config(
+    node: &KdlNode,
+    options: ParseOptions,
+    diagnostics: &mut Diagnostics,
+) -> Result<Vec<(String, String)>> {
+    let mut fuzzel_config = Vec::new();
+    diagnostics.each_node(children(node)?, |kv, diagnostics| {
+        let key = kv.name().value();
+        if let Some(section) = kv.children() {
+            // a block sets keys in an ini section, e.g. `colors { ... }`
+            check_fuzzel_section(kv, options)?;
+            diagnostics.each_node(section, |section_kv, _| {
+                let value = one_expanded_argument(section_kv)?;
+                let section_key = section_kv.name().value();
+                check_fuzzel_key(key, section_kv, &value, options)?;
+                fuzzel_config.push((format!("{key}.{section_key}"), value));
+                no_parameters(section_kv)?;
+                no_children(section_kv)
+            });
+            no_arguments(kv)?;
+        } else {
+            let value = one_expanded_argument(kv)?;
+            check_fuzzel_key("main", kv, &value, options)?;
+            fuzzel_config.push((key.to_owned(), value));
+        }
+        no_parameters(kv)
+    });
+    no_arguments(node)?;
+    Ok(fuzzel_config)
+}
+
+/// fuzzel has 19 custom key bindings that can be used for actions.
+const MAX_BINDS: usize = 19;
+
+fn parse_binds(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Vec<(String, String)>> {
+    let mut binds = Vec::new();
+    diagnostics.each_node(children(node)?, |bind, _| {
+        let action = one_argument(bind)?;
+        no_parameters(bind)?;
+        no_children(bind)?;
+        if binds.len() == MAX_BINDS {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    bind.span()
+                )],
+                "a menu can have at most {MAX_BINDS} binds",
+            ));
+        }
+        binds.push((bind.name().value().to_owned(), action));
+        Ok(())
+    });
+    no_arguments(node)?;
+    no_parameters(node)?;
+    Ok(binds)
+}
+
+/// Each child is a variable's name with its value as the argument.
+fn parse_env(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Vec<(String, String)>> {
+    let mut env = Vec::new();
+    let mut variables: Vec<&KdlNode> = Vec::new();
+    diagnostics.each_node(children(node)?, |variable, _| {
+        let name = variable.name().value();
+        if name.is_empty() || name.contains(['=', '\0']) {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    variable.name().span()
+                )],
+                "invalid environment variable name: {name:?}",
+            ));
+        }
+        if let Some(first) = variables.iter().find(|first| first.name().value() == name) {
+            return Err(already_defined_at(
+                name,
+                first.span(),
+                variable.span(),
+                "remove one of them",
+            ));
+        }
+        let value = one_expanded_argument(variable)?;
+        no_parameters(variable)?;
+        no_children(variable)?;
+        variables.push(variable);
+        env.push((name.to_owned(), value));
+        Ok(())
+    });
+    no_arguments(node)?;
+    no_parameters(node)?;
+    Ok(env)
+}
+
+fn parse_defaults_from_nodes(
+    doc: &KdlDocument,
+    options: ParseOptions,
+    diagnostics: &mut Diagnostics,
+) -> Defaults {
+    let mut defaults = Defaults::default();
+    let mut fuzzel_config_node = None;
+
+    diagnostics.each_node(doc, |node, diagnostics| {
+        match node.name().value() {
+            "fuzzel-args" => {
+                defaults.fuzzel_args = many_arguments(node)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "fuzzel-config" => {
+                if let Some(first) = fuzzel_config_node {
+                    return Err(already_defined(first, node));
+                }
+                fuzzel_config_node = Some(node);
+                // Keys before it (like icon-theme) are kept, so they can be overridden
+                defaults
+                    .fuzzel_config
+                    .extend(parse_fuzzel_config(node, options, diagnostics)?);
+            }
+            "icon-theme" => {
+                let theme = one_expanded_argument(node)?;
+                defaults
+                    .fuzzel_config
+                    .push(("icon-theme".to_string(), theme));
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "use-fuzzel-cache" => {
+                defaults.use_fuzzel_cache = Some(one_bool(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "icon-size" => {
+                defaults.icon_size = Some(one_integer(node, 1..=4096)? as u32);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "prefer-icon-format" => {
+                defaults.prefer_icon_format = Some(parse_icon_format(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "icon-match" => {
+                defaults.icon_match = Some(parse_icon_match(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "symbolic" => {
+                defaults.symbolic = Some(parse_symbolic(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "icon-dir-walk" => {
+                if let Some(argument) = positional(node).first() {
+                    return Err(miette!(
+                        labels = vec![LabeledSpan::new_primary_with_span(
+                            Some("this".to_string()),
+                            argument.span()
+                        )],
+                        help = "set it with max-depth=N, follow-symlinks=#true or include-hidden=#true",
+                        "icon-dir-walk should not have any arguments",
+                    ));
+                }
+                defaults.icon_dir_walk = parse_icon_walk(node)?;
+                only_parameters(node, ICON_WALK_PARAMETERS)?;
+                no_children(node)?;
+            }
+            "systemd-scope" => {
+                defaults.systemd_scope = Some(one_bool(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "launch-method" => {
+                defaults.launch_method = Some(parse_launch_method(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "stdout" => {
+                defaults.stdout = Some(parse_output(node)?);
+                no_children(node)?;
+            }
+            "stderr" => {
+                defaults.stderr = Some(parse_output(node)?);
+                no_children(node)?;
+            }
+            "export-env" => {
+                defaults.export_env = Some(one_bool(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "activation" => {
+                defaults.activation = Some(one_bool(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "terminal" => {
+                defaults.terminal = Some(command_arguments(node, diagnostics)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    help = "defaults can contain fuzzel-args, fuzzel-config, icon-theme, icon-size, prefer-icon-format, icon-match, symbolic, icon-dir-walk, use-fuzzel-cache, systemd-scope, launch-method, stdout, stderr, export-env, activation and terminal",
+                    "unexpected node in defaults: {}",
+                    other,
+                ));
+            }
+        }
+        Ok(())
+    });
+
+    defaults
+}
+
+/// An `icon-dir` node, on a menu or a program.
+fn parse_icon_dir(node: &KdlNode) -> Result<IconDir> {
+    let path_str = one_expanded_argument(node)?;
+    let path = PathBuf::from(expand_tilde(&path_str));
+    if !path.is_absolute() {
+        warn!("relative icon-dirs can behave unexpectedly, consider using absolute paths");
+    }
+    only_parameters(node, ICON_WALK_PARAMETERS)?;
+    no_children(node)?;
+    Ok(IconDir {
+        path,
+        walk: parse_icon_walk(node)?,
+    })
+}
+
+fn parse_icon_walk(node: &KdlNode) -> Result<IconWalk> {
+    Ok(IconWalk {
+        max_depth: bounded_parameter(node, "max-depth", 0..=100)?,
+        follow_symlinks: bool_parameter(node, "follow-symlinks")?,
+        include_hidden: bool_parameter(node, "include-hidden")?,
+    })
+}
+
+/// Warns about a likely mistake, or fails with it under `--strict`.
+pub fn lint(options: ParseOptions, report: miette::Report) -> Result<()> {
+    if options.strict {
+        return Err(report);
+    }
+    match report.help() {
+        Some(help) => warn!("{report}, {help}"),
+        None => warn!("{report}"),
+    }
+    Ok(())
+}
+
+fn check_fuzzel_section(node: &KdlNode, options: ParseOptions) -> Result<()> {
+    let section = node.name().value();
+    if options.allow_unknown_fuzzel_keys || fuzzel_keys::is_known_section(section) {
+        return Ok(());
+    }
+    let suggestion = fuzzel_keys::closest_section(section);
+    lint(
+        options,
+        miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                node.name().span()
+            )],
+            help = suggestion.map_or_else(
+                || "use allow-unknown-fuzzel-keys #true if your fuzzel supports it".to_string(),
+                |suggestion| format!("did you mean {suggestion}?")
+            ),
+            "unknown fuzzel-config section: {section}",
+        ),
+    )
+}
+
+fn check_fuzzel_key(
+    section: &str,
+    node: &KdlNode,
+    value: &str,
+    options: ParseOptions,
+) -> Result<()> {
+    let key = node.name().value();
+    match fuzzel_keys::lookup(section, key) {
+        Some(kind) if !kind.accepts(value) => lint(
+            options,
+            miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some(format!("expected {}", kind.describe())),
+                    positional(node)[0].span()
+                )],
+                "unexpected value for fuzzel-config key {key}",
+            ),
+        ),
+        Some(_) => Ok(()),
+        // Unknown sections are reported once by check_fuzzel_section
+        None if options.allow_unknown_fuzzel_keys || !fuzzel_keys::is_known_section(section) => {
+            Ok(())
+        }
+        None => {
+            let suggestion = fuzzel_keys::closest_key(section, key);
+            lint(
+                options,
+                miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.name().span()
+                    )],
+                    help = suggestion.map_or_else(
+                        || "use allow-unknown-fuzzel-keys #true if your fuzzel supports it"
+                            .to_string(),
+                        |suggestion| format!("did you mean {suggestion}?")
+                    ),
+                    "unknown fuzzel-config key: {key}",
+                ),
+            )
+        }
+    }
+}
+
+fn parse_script_from_nodes(doc: &KdlDocument, diagnostics: &mut Diagnostics) -> Result<Script> {
+    let mut body_node: Option<&KdlNode> = None;
+    let mut shebang = None;
+
+    diagnostics.each_node(doc, |node, _| {
+        match node.name().value() {
+            "body" => {
+                if let Some(first) = body_node {
+                    return Err(already_defined(first, node));
+                }
+                body_node = Some(node);
+                only_parameters(node, &["shebang"])?;
+                shebang = string_parameter(node, "shebang")?;
+                no_children(node)?;
+            }
+            name if COMMON_ITEM_NODES.contains(&name) => {} // already parsed by parse_item_from_nodes
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    "unexpected node in script: {}",
+                    other,
+                ));
+            }
+        }
+        Ok(())
+    });
+
+    let Some(body_node) = body_node else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("here".to_string()),
+                doc.span(),
+            )],
+            "script should have a body",
+        ));
+    };
+
+    Ok(Script {
+        body: one_argument(body_node)?,
+        shebang,
+    })
+}
+
+/// The command whose output lines are shown, named `list_name`, and the
+/// `run-command` given the chosen line, for a `kind` item.
+/// `parsed_elsewhere` are nodes of the item that its caller reads.
+fn parse_list_and_run(
+    kind: &str,
+    list_name: &str,
+    parsed_elsewhere: &[&str],
+    doc: &KdlDocument,
+    diagnostics: &mut Diagnostics,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut list_command = Vec::new();
+    let mut list_command_node = None;
+    let mut run_command = Vec::new();
+    let mut run_command_node = None;
+
+    diagnostics.each_node(doc, |node, diagnostics| {
+        match node.name().value() {
+            name if name == list_name => {
+                diagnostics.define_once(&mut list_command_node, node)?;
+                list_command = command_arguments(node, diagnostics)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "run-command" => {
+                diagnostics.define_once(&mut run_command_node, node)?;
+                run_command = command_arguments(node, diagnostics)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            name if COMMON_ITEM_NODES.contains(&name) => {} // already parsed by parse_item_from_nodes
+            name if parsed_elsewhere.contains(&name) => {}
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    "unexpected node in {kind}: {}",
+                    other,
+                ));
+            }
+        }
+        Ok(())
+    });
+
+    for (command, name) in [(&list_command, list_name), (&run_command, "run-command")] {
+        // A node that failed to parse has already been reported
+        if command.is_empty() && !has_node(doc, name) {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("here".to_string()),
+                    doc.span(),
+                )],
+                "{kind} should have a {name}",
+            ));
+        }
+    }
+
+    Ok((list_command, run_command))
+}
+
+/// The dynamic menu's `on-empty` property, and the `empty-command` node it
+/// needs when it's "run".
+fn parse_on_empty(
+    node: &KdlNode,
+    doc: &KdlDocument,
+    diagnostics: &mut Diagnostics,
+) -> Result<OnEmpty> {
+    let on_empty = choice_parameter(node, "on-empty", &["message", "skip", "run"])?;
+    let mut command = None;
+    let mut command_node = None;
+    diagnostics.each_node(doc, |node, diagnostics| {
+        if node.name().value() == "empty-command" {
+            diagnostics.define_once(&mut command_node, node)?;
+            command = Some(command_arguments(node, diagnostics)?);
+            no_parameters(node)?;
+            no_children(node)?;
+        }
+        Ok(())
+    });
+
+    match (on_empty, command) {
+        (Some("run"), Some(command)) => Ok(OnEmpty::Run(command)),
+        // A node that failed to parse has already been reported
+        (Some("run"), None) if command_node.is_none() => Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                named_entry(node, "on-empty").unwrap().span()
+            )],
+            help = "add an empty-command to the dynamic-menu, it runs instead of the menu",
+            "on-empty=\"run\" needs an empty-command",
+        )),
+        (_, Some(_)) => Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                command_node.unwrap().span()
+            )],
+            help = "add on-empty=\"run\" to the dynamic-menu to run it when there are no entries",
+            "empty-command is only used with on-empty=\"run\"",
+        )),
+        (Some("skip"), _) => Ok(OnEmpty::Skip),
+        _ => Ok(OnEmpty::Message),
+    }
+}
+
+fn parse_input_item(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Item> {
+    let name = one_argument(node)?;
+    only_parameters(node, &["url-encode", "id"])?;
+    let url_encode = bool_parameter(node, "url-encode")?.unwrap_or(false);
+    let doc = children(node)?;
+    let common = parse_common_item_nodes(doc, diagnostics);
+
+    let mut command = Vec::new();
+    let mut command_node = None;
+    let mut prompt = None;
+    let mut password = false;
+    let mut pass_via = None;
+    diagnostics.each_node(doc, |node, diagnostics| {
+        match node.name().value() {
+            "command" => {
+                diagnostics.define_once(&mut command_node, node)?;
+                command = command_arguments(node, diagnostics)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "prompt" => {
+                prompt = Some(one_argument(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "password" => {
+                password = one_bool(node)?;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "pass-via" => {
+                pass_via = Some(parse_pass_via(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            name if COMMON_ITEM_NODES.contains(&name) => {} // already parsed by parse_common_item_nodes
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    "unexpected node in input: {}",
+                    other,
+                ));
+            }
+        }
+        Ok(())
+    });
+
+    // A command node that failed to parse has already been reported
+    if command.is_empty() && !has_node(doc, "command") {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("here".to_string()),
+                node.span(),
+            )],
+            help = "the typed text replaces {} in its arguments",
+            "input should have a command",
+        ));
+    }
+
+    Ok(common.into_item(
+        name,
+        ItemContents::Input(Input {
+            command,
+            prompt,
+            url_encode,
+            password,
+            pass_via,
+        }),
+    ))
+}
+
+fn parse_pass_via(node: &KdlNode) -> Result<PassVia> {
+    let value = one_argument(node)?;
+    match value.as_str() {
+        "arg" => Ok(PassVia::Arg),
+        "stdin" => Ok(PassVia::Stdin),
+        _ if let Some(name) = value.strip_prefix("env:")
+            && !name.is_empty()
+            && !name.contains('=') =>
+        {
+            Ok(PassVia::Env(name.to_owned()))
+        }
+        _ => Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                one_value(node)?.span()
+            )],
+            help = "expected one of: arg, stdin, env:NAME",
+            "unknown value for pass-via",
+        )),
+    }
+}
+
+fn has_node(doc: &KdlDocument, name: &str) -> bool {
+    doc.nodes().iter().any(|node| node.name().value() == name)
+}
+
+fn only_common_item_nodes(kind: &str, doc: &KdlDocument, diagnostics: &mut Diagnostics) {
+    diagnostics.each_node(doc, |node, _| {
+        if !COMMON_ITEM_NODES.contains(&node.name().value()) {
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    node.span()
+                )],
+                "unexpected node in {kind}: {}",
+                node.name().value(),
+            ));
+        }
+        Ok(())
+    });
+}
+
+const COMMON_ITEM_NODES: &[&str] = &[
+    "icon",
+    "description",
+    "profiles",
+    "if-host",
+    "unless-host",
+    "weight",
+    "label-command",
+    "on",
+];
+
+/// What an item has besides its name and contents, from the nodes every item kind accepts.
+struct CommonItemNodes {
+    icon: Option<String>,
+    description: Option<String>,
+    profiles: Vec<String>,
+    if_host: Vec<String>,
+    unless_host: Vec<String>,
+    actions: Vec<(String, Program)>,
+    weight: i64,
+    label_command: Option<Vec<String>>,
+}
+
+impl CommonItemNodes {
+    /// Newlines left in the name or description by `--lenient` are shown as `␤`,
+    /// see `check_display_text`.
+    fn into_item(self, name: String, contents: ItemContents) -> Item {
+        Item {
+            name: name.replace('\n', NEWLINE_SYMBOL),
+            id: None,
+            icon: self.icon,
+            icon_dirs: Vec::new(),
+            description: self
+                .description
+                .map(|description| description.replace('\n', NEWLINE_SYMBOL)),
+            profiles: self.profiles,
+            if_host: self.if_host,
+            unless_host: self.unless_host,
+            actions: self.actions,
+            weight: self.weight,
+            label_command: self.label_command,
+            contents,
+        }
+    }
+}
+
+fn parse_common_item_nodes(doc: &KdlDocument, diagnostics: &mut Diagnostics) -> CommonItemNodes {
+    let mut icon: Option<String> = None;
+    let mut icon_node = None;
+    let mut description: Option<String> = None;
+    let mut description_node = None;
+    let mut profiles = Vec::new();
+    let mut if_host = Vec::new();
+    let mut unless_host = Vec::new();
+    let mut actions: Vec<(String, Program)> = Vec::new();
+    let mut action_nodes: Vec<(String, &KdlNode)> = Vec::new();
+    let mut weight = 0;
+    let mut weight_node: Option<&KdlNode> = None;
+    let mut label_command = None;
+    let mut label_command_node = None;
+
+    diagnostics.each_node(doc, |node, diagnostics| {
+        match node.name().value() {
+            "icon" => {
+                diagnostics.define_once(&mut icon_node, node)?;
+                icon = Some(one_expanded_argument(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "description" => {
+                diagnostics.define_once(&mut description_node, node)?;
+                description = Some(one_argument(node)?);
+                check_display_text(positional(node)[0], "descriptions", diagnostics);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "profiles" => {
+                profiles.extend(many_arguments(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "if-host" => {
+                if_host.extend(many_arguments(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "unless-host" => {
+                unless_host.extend(many_arguments(node)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "weight" => {
+                if let Some(first) = weight_node {
+                    return Err(already_defined(first, node));
+                }
+                weight_node = Some(node);
+                weight = one_integer(node, i128::from(i64::MIN)..=i128::from(i64::MAX))? as i64;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "label-command" => {
+                diagnostics.define_once(&mut label_command_node, node)?;
+                label_command = Some(command_arguments(node, diagnostics)?);
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            "on" => {
+                let action = one_argument(node)?;
+                no_parameters(node)?;
+                if let Some((_, first)) = action_nodes.iter().find(|(name, _)| *name == action) {
+                    return Err(already_defined(first, node));
+                }
+                action_nodes.push((action.clone(), node));
+                let program = parse_program_from_nodes(children(node)?, Vec::new(), diagnostics);
+                if let Some(confirm) = children(node)?
+                    .nodes()
+                    .iter()
+                    .find(|child| child.name().value() == "confirm")
+                {
+                    return Err(miette!(
+                        labels = vec![LabeledSpan::new_primary_with_span(
+                            Some("this".to_string()),
+                            confirm.span()
+                        )],
+                        "actions can't ask for confirmation",
+                    ));
+                }
+                if let Some(icon_dir) = children(node)?
+                    .nodes()
+                    .iter()
+                    .find(|child| child.name().value() == "icon-dir")
+                {
+                    return Err(miette!(
+                        labels = vec![LabeledSpan::new_primary_with_span(
+                            Some("this".to_string()),
+                            icon_dir.span()
+                        )],
+                        help = "put it on the program instead",
+                        "actions don't have icons to find",
+                    ));
+                }
+                if program.command.is_empty()
+                    && program.chain.is_none()
+                    && program.fallbacks.is_none()
+                    && program.desktop.is_none()
+                    && !has_node(children(node)?, "command")
+                    && !has_node(children(node)?, "chain")
+                    && !has_node(children(node)?, "try")
+                    && !has_node(children(node)?, "desktop")
+                {
+                    return Err(miette!(
+                        labels = vec![LabeledSpan::new_primary_with_span(
+                            Some("here".to_string()),
+                            node.span(),
+                        )],
+                        "action should have a command",
+                    ));
+                }
+                actions.push((action, program));
+            }
+            _ => {}
+        }
+        Ok(())
+    });
+
+    CommonItemNodes {
+        icon,
+        description,
+        profiles,
+        if_host,
+        unless_host,
+        actions,
+        weight,
+        label_command,
+    }
+}
+
+fn parse_item_from_nodes(
+    kind: &str,
+    name: &str,
+    doc: &KdlDocument,
+    options: ParseOptions,
+    diagnostics: &mut Diagnostics,
+) -> Result<Item> {
+    let common = parse_common_item_nodes(doc, diagnostics);
+
+    let contents = match kind {
+        "menu" => ItemContents::Menu(Box::new(parse_menu_from_nodes(doc, options, diagnostics))),
+        "script" => ItemContents::Script(parse_script_from_nodes(doc, diagnostics)?),
+        "dynamic-menu" => {
+            let (source_command, run_command) =
+                parse_list_and_run(kind, "source-command", &["empty-command"], doc, diagnostics)?;
+            // The on-empty property is on the node, see parse_on_empty
+            ItemContents::DynamicMenu(DynamicMenu {
+                source_command,
+                run_command,
+                on_empty: OnEmpty::default(),
+            })
+        }
+        "pick" => {
+            let (list_command, run_command) =
+                parse_list_and_run(kind, "list-command", &[], doc, diagnostics)?;
+            ItemContents::Pick(Pick {
+                list_command,
+                run_command,
+            })
+        }
+        "back" => {
+            only_common_item_nodes(kind, doc, diagnostics);
+            ItemContents::Back
+        }
+        _ => unreachable!(),
+    };
+
+    Ok(common.into_item(name.to_owned(), contents))
+}
+
+/// Programs can be written as a block, or inline with the command after the name
+/// and optional `icon` and `weight` properties. A `cmd` property is split on spaces
+/// and goes before any inline arguments.
+fn parse_program_item(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Item> {
+    only_parameters(node, &["icon", "cmd", "weight", "id"])?;
+    if positional(node).is_empty() {
+        one_argument(node)?; // reports the missing name
+    }
+    let arguments = many_annotated_arguments(node, &Annotation::ALL)?;
+    let entries = positional(node);
+    annotation(entries[0], &[])?;
+    let empty = KdlDocument::new();
+    let children = node.children().unwrap_or(&empty);
+    let child = |name: &str| {
+        children
+            .nodes()
+            .iter()
+            .find(|child| child.name().value() == name)
+    };
+
+    // A field can only come from the node itself or a child node, not both
+    for field in ["icon", "weight"] {
+        if let Some(entry) = named_entry(node, field)
+            && let Some(child) = child(field)
+        {
+            return Err(already_defined_at(
+                field,
+                entry.span(),
+                child.span(),
+                &format!("use either the {field} property or the {field} node"),
+            ));
+        }
+    }
+    let inline_command = named_entry(node, "cmd")
+        .map(KdlEntry::span)
+        .into_iter()
+        .chain(entries.iter().skip(1).map(|entry| entry.span()))
+        .reduce(|first, last| {
+            SourceSpan::new(
+                first.offset().min(last.offset()).into(),
+                (first.offset() + first.len()).max(last.offset() + last.len())
+                    - first.offset().min(last.offset()),
+            )
+        });
+    if let Some(inline_command) = inline_command
+        && let Some(child) = child("command")
+    {
+        return Err(already_defined_at(
+            "command",
+            inline_command,
+            child.span(),
+            "use either an inline command or the command node",
+        ));
+    }
+
+    let mut command = Vec::new();
+    let mut spans = Vec::new();
+    if let Some(cmd) = string_parameter(node, "cmd")? {
+        let entry = named_entry(node, "cmd").unwrap();
+        for word in cmd.split_whitespace() {
+            command.push(expand_argument(entry, word, None)?);
+            spans.push(entry.span());
+        }
+    }
+    if !command.is_empty()
+        && let Some(index) = arguments
+            .iter()
+            .position(|(_, annotation)| *annotation == Some(Annotation::Sh))
+    {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entries[index].span()
+            )],
+            help = "put the whole command in the one string",
+            "a (sh) command can't be used with cmd",
+        ));
+    }
+    command.extend(annotated_command(&entries[1..], &arguments[1..])?);
+    spans.extend(entries.iter().skip(1).map(|entry| entry.span()));
+    check_command(&command, &spans, diagnostics)?;
+
+    let mut common = parse_common_item_nodes(children, diagnostics);
+    if let Some(entry) = named_entry(node, "icon") {
+        let value = string_parameter(node, "icon")?.unwrap();
+        let annotation = annotation(entry, &[Annotation::Path, Annotation::Raw])?;
+        common.icon = Some(expand_argument(entry, &value, annotation)?);
+    }
+    if let Some(weight) = integer_parameter(node, "weight")? {
+        common.weight = weight;
+    }
+    let program = parse_program_from_nodes(children, command, diagnostics);
+
+    // A command or chain node that failed to parse has already been reported
+    if program.command.is_empty()
+        && program.chain.is_none()
+        && program.fallbacks.is_none()
+        && program.desktop.is_none()
+        && child("command").is_none()
+        && child("chain").is_none()
+        && child("try").is_none()
+        && child("desktop").is_none()
+    {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("here".to_string()),
+                node.span(),
+            )],
+            help = "add the command after the name, in a command node or from a desktop entry",
+            "program should have a command",
+        ));
+    }
+
+    let mut icon_dirs = Vec::new();
+    diagnostics.each_node(children, |child, _| {
+        if child.name().value() == "icon-dir" {
+            icon_dirs.push(parse_icon_dir(child)?);
+        }
+        Ok(())
+    });
+
+    let mut item = common.into_item(
+        arguments[0].0.clone(),
+        ItemContents::Program(Box::new(program)),
+    );
+    item.icon_dirs = icon_dirs;
+    Ok(item)
+}
+
+/// `open` or `mime-open`, which both take a name and a target.
+fn parse_open_item(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Item> {
+    let kind = node.name().value();
+    let (arguments, annotations): (Vec<String>, Vec<_>) =
+        many_annotated_arguments(node, &[Annotation::Path, Annotation::Raw])?
+            .into_iter()
+            .unzip();
+    annotation(positional(node)[0], &[])?;
+    let [name, target] = <[String; 2]>::try_from(arguments).map_err(|arguments| {
+        let entries = positional(node);
+        let label = if arguments.len() < 2 {
+            let name = entries[0].span();
+            LabeledSpan::new_primary_with_span(
+                Some("add a target here".to_string()),
+                SourceSpan::new((name.offset() + name.len()).into(), 0),
+            )
+        } else {
+            let first = entries[2].span();
+            let last = entries.last().unwrap().span();
+            LabeledSpan::new_primary_with_span(
+                Some("remove these".to_string()),
+                SourceSpan::new(
+                    first.offset().into(),
+                    (last.offset() + last.len()) - first.offset(),
+                ),
+            )
+        };
+        miette!(
+            labels = vec![label],
+            help = if kind == "open" {
+                "open takes a name and one url or path"
+            } else {
+                "mime-open takes a name and one path"
+            },
+            "{kind} should have exactly one target",
+        )
+    })?;
+
+    let empty = KdlDocument::new();
+    let children = node.children().unwrap_or(&empty);
+    let common = parse_common_item_nodes(children, diagnostics);
+    only_common_item_nodes(kind, children, diagnostics);
+    only_parameters(node, &["id"])?;
+    let target = expand_argument(positional(node)[1], &target, annotations[1])?;
+    let contents = if kind == "open" {
+        ItemContents::Open(target)
+    } else {
+        ItemContents::MimeOpen(target)
+    };
+
+    Ok(common.into_item(name, contents))
+}
+
+/// `power-commands { reboot "loginctl" "reboot" }`, replacing the commands of power items.
+fn parse_power_commands(
+    doc: &KdlDocument,
+    diagnostics: &mut Diagnostics,
+) -> Vec<(PowerAction, Vec<String>)> {
+    let mut commands: Vec<(PowerAction, Vec<String>)> = Vec::new();
+    let mut nodes: Vec<&KdlNode> = Vec::new();
+    diagnostics.each_node(doc, |node, diagnostics| {
+        let Some(action) = PowerAction::from_node_name(node.name().value()) else {
+            let names: Vec<&str> = PowerAction::ALL.iter().map(|a| a.node_name()).collect();
+            return Err(miette!(
+                labels = vec![LabeledSpan::new_primary_with_span(
+                    Some("this".to_string()),
+                    node.span()
+                )],
+                help = format!("expected one of: {}", names.join(", ")),
+                "unexpected node in power-commands: {}",
+                node.name().value(),
+            ));
+        };
+        if let Some(first) = nodes
+            .iter()
+            .find(|first| first.name().value() == node.name().value())
+        {
+            return Err(already_defined(first, node));
+        }
+        nodes.push(node);
+        commands.push((action, command_arguments(node, diagnostics)?));
+        no_parameters(node)?;
+        no_children(node)
+    });
+    commands
+}
+
+fn parse_hooks(doc: &KdlDocument, diagnostics: &mut Diagnostics) -> Hooks {
+    let mut hooks = Hooks::default();
+    let mut pre_launch_node = None;
+    let mut post_launch_node = None;
+    diagnostics.each_node(doc, |node, diagnostics| {
+        let (hook, first) = match node.name().value() {
+            "pre-launch" => (&mut hooks.pre_launch, &mut pre_launch_node),
+            "post-launch" => (&mut hooks.post_launch, &mut post_launch_node),
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    help = "expected pre-launch or post-launch",
+                    "unexpected node in hooks: {other}",
+                ));
+            }
+        };
+        diagnostics.define_once(first, node)?;
+        *hook = Some(command_arguments(node, diagnostics)?);
+        no_parameters(node)?;
+        no_children(node)
+    });
+    hooks
+}
+
+/// Power items can be written without a name or block, `reboot` alone is enough.
+fn parse_power_item(
+    node: &KdlNode,
+    action: PowerAction,
+    diagnostics: &mut Diagnostics,
+) -> Result<Item> {
+    let name = if positional(node).is_empty() {
+        action.default_name().to_string()
+    } else {
+        one_argument(node)?
+    };
+    only_parameters(node, &["id"])?;
+
+    let empty = KdlDocument::new();
+    let doc = node.children().unwrap_or(&empty);
+    let mut common = parse_common_item_nodes(doc, diagnostics);
+    common
+        .icon
+        .get_or_insert_with(|| action.default_icon().to_string());
+    let mut confirm = action.default_question().map(str::to_owned);
+    diagnostics.each_node(doc, |node, _| {
+        match node.name().value() {
+            // confirm #false turns it off, a string replaces the question
+            "confirm" => {
+                let question = match positional(node).first().map(|entry| entry.value()) {
+                    None | Some(KdlValue::Bool(true)) => Some(
+                        confirm
+                            .clone()
+                            .unwrap_or_else(|| "Are you sure?".to_string()),
+                    ),
+                    Some(KdlValue::Bool(false)) => None,
+                    Some(_) => Some(one_argument(node)?),
+                };
+                confirm = question;
+                no_parameters(node)?;
+                no_children(node)?;
+            }
+            name if COMMON_ITEM_NODES.contains(&name) => {} // already parsed by parse_common_item_nodes
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    "unexpected node in {}: {}",
+                    action.node_name(),
+                    other,
+                ));
+            }
+        }
+        Ok(())
+    });
+
+    Ok(common.into_item(name, ItemContents::Power(Power { action, confirm })))
+}
+
+fn parse_files_menu_item(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Item> {
+    let (arguments, annotations): (Vec<String>, Vec<_>) =
+        many_annotated_arguments(node, &[Annotation::Path, Annotation::Raw])?
+            .into_iter()
+            .unzip();
+    annotation(positional(node)[0], &[])?;
+    let [name, dir] = <[String; 2]>::try_from(arguments).map_err(|arguments| {
+        let entries = positional(node);
+        let label = if arguments.len() < 2 {
+            let name = entries[0].span();
+            LabeledSpan::new_primary_with_span(
+                Some("add a directory here".to_string()),
+                SourceSpan::new((name.offset() + name.len()).into(), 0),
+            )
+        } else {
+            let first = entries[2].span();
+            let last = entries.last().unwrap().span();
+            LabeledSpan::new_primary_with_span(
+                Some("remove these".to_string()),
+                SourceSpan::new(
+                    first.offset().into(),
+                    (last.offset() + last.len()) - first.offset(),
+                ),
+            )
+        };
+        miette!(
+            labels = vec![label],
+            help = "files-menu takes a name and the directory to list",
+            "files-menu should have exactly one directory",
+        )
+    })?;
+    only_parameters(node, &["id"])?;
+    let dir = expand_argument(positional(node)[1], &dir, annotations[1])?;
+
+    let empty = KdlDocument::new();
+    let doc = node.children().unwrap_or(&empty);
+    let common = parse_common_item_nodes(doc, diagnostics);
+    let mut files_menu = FilesMenu {
+        dir,
+        pattern: None,
+        sort: FileSort::Name,
+        limit: None,
+        open_with: None,
+    };
+    diagnostics.each_node(doc, |node, diagnostics| {
+        match node.name().value() {
+            "open-with" => files_menu.open_with = Some(command_arguments(node, diagnostics)?),
+            "pattern" => files_menu.pattern = Some(one_argument(node)?),
+            "sort" => {
+                files_menu.sort = match one_choice(node, &["name", "mtime"])? {
+                    "mtime" => FileSort::Mtime,
+                    _ => FileSort::Name,
+                };
+            }
+            "limit" => {
+                files_menu.limit = Some(one_integer(node, 1..=i128::from(u32::MAX))? as usize);
+            }
+            name if COMMON_ITEM_NODES.contains(&name) => return Ok(()), // already parsed by parse_common_item_nodes
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    "unexpected node in files-menu: {}",
+                    other,
+                ));
+            }
+        }
+        no_parameters(node)?;
+        no_children(node)
+    });
+
+    Ok(common.into_item(name, ItemContents::FilesMenu(files_menu)))
+}
+
+fn parse_recent_files_item(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Item> {
+    let name = one_argument(node)?;
+    only_parameters(node, &["id"])?;
+
+    let empty = KdlDocument::new();
+    let doc = node.children().unwrap_or(&empty);
+    let common = parse_common_item_nodes(doc, diagnostics);
+    let mut recent_files = RecentFiles {
+        limit: 15,
+        open_with: None,
+    };
+    diagnostics.each_node(doc, |node, diagnostics| {
+        match node.name().value() {
+            "open-with" => recent_files.open_with = Some(command_arguments(node, diagnostics)?),
+            "limit" => {
+                recent_files.limit = one_integer(node, 1..=i128::from(u32::MAX))? as usize;
+            }
+            name if COMMON_ITEM_NODES.contains(&name) => return Ok(()), // already parsed by parse_common_item_nodes
+            other => {
+                return Err(miette!(
+                    labels = vec![LabeledSpan::new_primary_with_span(
+                        Some("this".to_string()),
+                        node.span()
+                    )],
+                    "unexpected node in recent-files: {}",
+                    other,
+                ));
+            }
+        }
+        no_parameters(node)?;
+        no_children(node)
+    });
+
+    Ok(common.into_item(name, ItemContents::RecentFiles(recent_files)))
+}
+
+fn parse_alias_item(node: &KdlNode, diagnostics: &mut Diagnostics) -> Result<Item> {
+    let name = one_argument(node)?;
+    only_parameters(node, &["id", "of"])?;
+    let Some(target) = string_parameter(node, "of")? else {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                node.span()
+            )],
+            help =
+                "point it at an item's id, or its path from the root menu like of=\"Apps/Firefox\"",
+            "alias {name} needs an of=\"target\"",
+        ));
+    };
+    let span = named_entry(node, "of").unwrap().span();
+
+    let empty = KdlDocument::new();
+    let children = node.children().unwrap_or(&empty);
+    let common = parse_common_item_nodes(children, diagnostics);
+    only_common_item_nodes("alias", children, diagnostics);
+
+    Ok(common.into_item(
+        name,
+        ItemContents::Alias(Alias {
+            target,
+            span,
+            path: Vec::new(),
+        }),
+    ))
+}
+
+/// An alias is another entry for an item, so sharing a name with one of the
+/// items next to it would make two lines that can't be told apart.
+fn check_alias_names(doc: &KdlDocument, diagnostics: &mut Diagnostics) {
+    let items: Vec<(&KdlNode, Option<String>)> = doc
+        .nodes()
+        .iter()
+        .filter(|node| is_item_node(node.name().value()))
+        .map(|node| {
+            let name = positional(node)
+                .first()
+                .and_then(|entry| entry.value().as_string())
+                .map(str::to_owned);
+            (node, name)
+        })
+        .collect();
+    for (index, (alias, name)) in items.iter().enumerate() {
+        let Some(name) = name.as_ref().filter(|_| alias.name().value() == "alias") else {
+            continue;
+        };
+        // Two aliases with one name are only reported at the second one
+        let other = items
+            .iter()
+            .enumerate()
+            .find(|(other_index, (other, other_name))| {
+                *other_index != index
+                    && other_name.as_ref() == Some(name)
+                    && (other.name().value() != "alias" || *other_index < index)
+            });
+        if let Some((_, (other, _))) = other {
+            diagnostics.check::<()>(Err(miette!(
+                labels = vec![
+                    LabeledSpan::new_with_span(Some("this item".to_string()), other.span()),
+                    LabeledSpan::new_primary_with_span(
+                        Some("has the same name as this alias".to_string()),
+                        alias.span()
+                    ),
+                ],
+                help = "rename the alias, the item it points at keeps its own name",
+                "there is already an item named {name} in this menu",
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_phase_comprehensive() {
+        // Test simple program parsing
+        let simple_config = r#"
+            program "Item1" {
+                command "cmd1"
+            }
+        "#;
+        let simple = parse_config(simple_config, ParseOptions::default()).unwrap();
+        assert_eq!(simple.items.len(), 1);
+        assert_eq!(simple.items[0].name, "Item1");
+        if let ItemContents::Program(ref prog) = simple.items[0].contents {
+            assert_eq!(prog.command, vec!["cmd1"]);
+        } else {
+            panic!("Expected program item");
+        }
+
+        // Test parsing with fuzzel config
+        let config_with_fuzzel = r#"
+            fuzzel-args "--arg1" "--arg2"
+            fuzzel-config {
+                key1 "value1"
+                key2 "value2"
+            }
+            program "Item1" {
+                icon "icon1"
+                command "cmd1"
+            }
+        "#;
+        let with_config = parse_config(config_with_fuzzel, ParseOptions::default()).unwrap();
+        assert_eq!(with_config.fuzzel_args, vec!["--arg1", "--arg2"]);
+        assert_eq!(
+            with_config.fuzzel_config,
+            vec![
+                ("key1".to_string(), "value1".to_string()),
+                ("key2".to_string(), "value2".to_string()),
+            ]
+        );
+        assert_eq!(with_config.items[0].icon, Some("icon1".to_string()));
+
+        // Test repeated keys and sections in fuzzel config
+        let config_with_sections = r#"
+            fuzzel-config {
+                key1 "value1"
+                colors {
+                    background "000000ff"
+                }
+                key1 "value2"
+            }
+        "#;
+        let with_sections = parse_config(config_with_sections, ParseOptions::default()).unwrap();
+        assert_eq!(
+            with_sections.fuzzel_config,
+            vec![
+                ("key1".to_string(), "value1".to_string()),
+                ("colors.background".to_string(), "000000ff".to_string()),
+                ("key1".to_string(), "value2".to_string()),
+            ]
+        );
+
+        // Test that a second fuzzel-config block is an error
+        let config_with_two_blocks = r#"
+            fuzzel-config {
+                key1 "value1"
+            }
+            fuzzel-config {
+                key2 "value2"
+            }
+        "#;
+        assert!(parse_config(config_with_two_blocks, ParseOptions::default()).is_err());
+
+        // Test nested menu parsing
+        let nested_config = r#"
+            program "Item1" {
+                command "cmd1"
+            }
+            menu "Submenu1" {
+                fuzzel-config {
+                    subkey "subvalue"
+                }
+                program "Item2" {
+                    command "cmd2" "arg2"
+                }
+            }
+        "#;
+        let nested = parse_config(nested_config, ParseOptions::default()).unwrap();
+        assert_eq!(nested.items.len(), 2);
+        assert_eq!(nested.items[0].name, "Item1");
+        assert_eq!(nested.items[1].name, "Submenu1");
+        if let ItemContents::Menu(ref submenu) = nested.items[1].contents {
+            assert_eq!(
+                submenu.fuzzel_config,
+                vec![("subkey".to_string(), "subvalue".to_string())]
+            );
+            assert_eq!(submenu.items.len(), 1);
+            assert_eq!(submenu.items[0].name, "Item2");
+            if let ItemContents::Program(ref prog) = submenu.items[0].contents {
+                assert_eq!(prog.command, vec!["cmd2", "arg2"]);
+            } else {
+                panic!("Expected program in submenu");
+            }
+        } else {
+            panic!("Expected menu item");
+        }
+    }
+
+    #[test]
+    fn test_back_items() {
+        let config = r#"
+            menu "Submenu" {
+                back "Back"
+                back "Also back" {
+                    icon "go-previous"
+                }
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::Menu(ref submenu) = parsed.items[0].contents else {
+            panic!("Expected menu item");
+        };
+        assert!(matches!(submenu.items[0].contents, ItemContents::Back));
+        assert_eq!(submenu.items[0].icon, None);
+        assert!(matches!(submenu.items[1].contents, ItemContents::Back));
+        assert_eq!(submenu.items[1].icon, Some("go-previous".to_string()));
+
+        let with_command = r#"
+            back "Back" {
+                command "foo"
+            }
+        "#;
+        assert!(parse_config(with_command, ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_program_options() {
+        let config = r#"
+            program "Screenshot" {
+                command "grim"
+                confirm
+                watch-exit #true
+                notify "Screenshot saved" body="in ~/Pictures"
+            }
+            program "Poweroff" {
+                command "systemctl" "poweroff"
+                confirm "Really?"
+                watch-exit #true
+                watch-exit-timeout 10
+                notify "Bye"
+                detach #false
+                exec #true
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::Program(ref screenshot) = parsed.items[0].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(screenshot.confirm.as_deref(), Some("Are you sure?"));
+        assert_eq!(screenshot.watch_exit, Some(3));
+        let notification = screenshot.notify.as_ref().unwrap();
+        assert_eq!(notification.summary, "Screenshot saved");
+        assert_eq!(notification.body.as_deref(), Some("in ~/Pictures"));
+
+        let ItemContents::Program(ref poweroff) = parsed.items[1].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(poweroff.confirm.as_deref(), Some("Really?"));
+        assert_eq!(poweroff.watch_exit, Some(10));
+        assert_eq!(poweroff.notify.as_ref().unwrap().body, None);
+        assert!(!screenshot.attached);
+        assert!(poweroff.attached);
+        assert!(!screenshot.exec);
+        assert!(poweroff.exec);
+
+        let bad_property = r#"
+            program "Foo" {
+                command "foo"
+                notify "Done" title="Foo"
+            }
+        "#;
+        assert!(parse_config(bad_property, ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_priority() {
+        let config = r#"
+            program "Build" {
+                command "make"
+                nice 10
+                ionice "best-effort" level=7
+            }
+            program "Backup" {
+                command "restic" "backup"
+                ionice "idle"
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::Program(ref build) = parsed.items[0].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(build.nice, Some(10));
+        assert_eq!(
+            build.ionice,
+            Some(IoPriority {
+                class: IoClass::BestEffort,
+                level: Some(7)
+            })
+        );
+        let ItemContents::Program(ref backup) = parsed.items[1].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(backup.nice, None);
+        assert_eq!(
+            backup.ionice,
+            Some(IoPriority {
+                class: IoClass::Idle,
+                level: None
+            })
+        );
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"program "A" { command "a"; nice 20; }"#),
+            "argument should be an integer between -20 and 19"
+        );
+        assert_eq!(
+            error(r#"program "A" { command "a"; ionice "lazy"; }"#),
+            "unknown ionice class"
+        );
+        assert_eq!(
+            error(r#"program "A" { command "a"; ionice "realtime" level=8; }"#),
+            "level should be an integer between 0 and 7"
+        );
+        assert_eq!(
+            error(r#"program "A" { command "a"; ionice "idle" level=0; }"#),
+            "the idle class has no levels"
+        );
+    }
+
+    #[test]
+    fn test_output() {
+        let config = r#"
+            defaults {
+                stderr "null"
+            }
+            program "Build" {
+                command "make"
+                stdout "file:$HOME/logs/build.log" append=#true
+                stderr "inherit"
+            }
+        "#;
+        let home = std::env::var("HOME").unwrap();
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(parsed.defaults.stderr, Some(Output::Null));
+        let ItemContents::Program(ref build) = parsed.items[0].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(
+            build.stdout,
+            Some(Output::File {
+                path: format!("{home}/logs/build.log"),
+                append: true
+            })
+        );
+        assert_eq!(build.stderr, Some(Output::Inherit));
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"program "A" { command "a"; stdout "file:"; }"#),
+            "unknown value for stdout"
+        );
+        assert_eq!(
+            error(r#"program "A" { command "a"; stderr "null" append=#true; }"#),
+            "unexpected property append"
+        );
+    }
+
+    #[test]
+    fn test_sort_option() {
+        assert_eq!(
+            parse_config("", ParseOptions::default()).unwrap().sort,
+            SortOrder::None
+        );
+        assert_eq!(
+            parse_config(r#"sort "alphabetical""#, ParseOptions::default())
+                .unwrap()
+                .sort,
+            SortOrder::Alphabetical
+        );
+        let nested = parse_config(
+            r#"menu "Apps" { sort "reverse"; program "Foo" "foo"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let ItemContents::Menu(ref apps) = nested.items[0].contents else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(apps.sort, SortOrder::Reverse);
+
+        assert!(parse_config(r#"sort "random""#, ParseOptions::default()).is_err());
+        assert!(parse_config("sort #true", ParseOptions::default()).is_err());
+
+        assert_eq!(
+            parse_config(r#"order "menus-first""#, ParseOptions::default())
+                .unwrap()
+                .order,
+            ItemOrder::MenusFirst
+        );
+        assert_eq!(
+            parse_config(r#"order "programs-first""#, ParseOptions::default())
+                .unwrap()
+                .order,
+            ItemOrder::ProgramsFirst
+        );
+        assert!(parse_config(r#"order "folders-first""#, ParseOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_open_item() {
+        let parsed = parse_config(
+            r#"open "Docs" "~/Documents" { icon "folder"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(parsed.items[0].name, "Docs");
+        assert_eq!(parsed.items[0].icon.as_deref(), Some("folder"));
+        assert!(
+            matches!(parsed.items[0].contents, ItemContents::Open(ref target) if target == "~/Documents")
+        );
+        assert_eq!(
+            parse_config("", ParseOptions::default()).unwrap().opener,
+            None
+        );
+        assert_eq!(
+            parse_config(r#"opener "gio" "open""#, ParseOptions::default())
+                .unwrap()
+                .opener,
+            Some(vec!["gio".to_string(), "open".to_string()])
+        );
+
+        assert!(parse_config(r#"open "Docs""#, ParseOptions::default()).is_err());
+        assert!(parse_config(r#"open "Docs" "a" "b""#, ParseOptions::default()).is_err());
+        assert!(
+            parse_config(
+                r#"open "Docs" "a" { command "foo"; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+
+        let parsed = parse_config(
+            r#"mime-open "Open report" "~/docs/report.pdf""#,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        assert!(
+            matches!(parsed.items[0].contents, ItemContents::MimeOpen(ref file) if file == "~/docs/report.pdf")
+        );
+        assert_eq!(
+            parse_config(r#"mime-open "Open report""#, ParseOptions::default())
+                .unwrap_err()
+                .to_string(),
+            "mime-open should have exactly one target"
+        );
+    }
+
+    #[test]
+    fn test_script_item() {
+        let config = r##"
+            script "Greet" {
+                icon "face-smile"
+                body #"""
+                    echo "hello"
+                    echo "world"
+                    """#
+            }
+        "##;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::Script(ref script) = parsed.items[0].contents else {
+            panic!("Expected script item");
+        };
+        assert_eq!(script.body, "echo \"hello\"\necho \"world\"");
+        assert_eq!(script.shebang, None);
+
+        assert!(
+            parse_config(r#"script "Empty" { icon "foo"; }"#, ParseOptions::default()).is_err()
+        );
+        assert!(
+            parse_config(
+                r#"script "Twice" { body "a"; body "b"; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+        assert!(
+            parse_config(
+                r#"script "Bad" { body "a" interpreter="sh"; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_dynamic_menu() {
+        let config = r##"
+            dynamic-menu "Sessions" {
+                source-command "tmux" "list-sessions" "-F" "#S"
+                run-command "tmux-attach.sh"
+            }
+        "##;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::DynamicMenu(ref sessions) = parsed.items[0].contents else {
+            panic!("Expected dynamic menu item");
+        };
+        assert_eq!(
+            sessions.source_command,
+            ["tmux", "list-sessions", "-F", "#S"]
+        );
+        assert_eq!(sessions.run_command, ["tmux-attach.sh"]);
+
+        assert!(
+            parse_config(
+                r#"dynamic-menu "A" { source-command "ls"; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+        assert!(
+            parse_config(
+                r#"dynamic-menu "A" { run-command "ls"; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+        assert!(
+            parse_config(
+                r#"dynamic-menu "A" { command "ls"; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_on_empty() {
+        let on_empty = |properties: &str, nodes: &str| {
+            let config = format!(
+                r#"dynamic-menu "Sessions" {properties} {{
+                    source-command "tmux" "ls"
+                    run-command "tmux-attach.sh"
+                    {nodes}
+                }}"#
+            );
+            let parsed = parse_config(&config, ParseOptions::default())?;
+            let ItemContents::DynamicMenu(dynamic_menu) = &parsed.items[0].contents else {
+                panic!("Expected dynamic menu item");
+            };
+            Ok::<_, miette::Report>(match &dynamic_menu.on_empty {
+                OnEmpty::Message => vec!["message".to_string()],
+                OnEmpty::Skip => vec!["skip".to_string()],
+                OnEmpty::Run(command) => command.clone(),
+            })
+        };
+        assert_eq!(on_empty("", "").unwrap(), ["message"]);
+        assert_eq!(on_empty(r#"on-empty="message""#, "").unwrap(), ["message"]);
+        assert_eq!(on_empty(r#"on-empty="skip""#, "").unwrap(), ["skip"]);
+        assert_eq!(
+            on_empty(
+                r#"on-empty="run""#,
+                r#"empty-command "notify-send" "no sessions""#
+            )
+            .unwrap(),
+            ["notify-send", "no sessions"]
+        );
+
+        let error = |properties: &str, nodes: &str| {
+            let error = on_empty(properties, nodes).unwrap_err();
+            let label = error.labels().unwrap().next().unwrap();
+            (error.to_string(), label.offset())
+        };
+        let (message, offset) = error(r#"on-empty="hide""#, "");
+        assert_eq!(message, "unknown value for on-empty");
+        assert_eq!(offset, "dynamic-menu \"Sessions\" ".len());
+        let (message, offset) = error(r#"on-empty="run""#, "");
+        assert_eq!(message, "on-empty=\"run\" needs an empty-command");
+        assert_eq!(offset, "dynamic-menu \"Sessions\" ".len());
+        assert_eq!(
+            error(r#"on-empty="skip""#, r#"empty-command "true""#).0,
+            "empty-command is only used with on-empty=\"run\""
+        );
+        // Only dynamic menus have it
+        assert!(
+            parse_config(
+                r#"pick "A" on-empty="skip" { list-command "ls"; run-command "cat"; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_pick() {
+        let config = r##"
+            pick "Attach tmux" {
+                list-command "tmux" "list-sessions" "-F" "#S"
+                run-command "foot" "tmux" "attach" "-t" "{}"
+            }
+        "##;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::Pick(ref pick) = parsed.items[0].contents else {
+            panic!("Expected pick item");
+        };
+        assert_eq!(pick.list_command, ["tmux", "list-sessions", "-F", "#S"]);
+        assert_eq!(pick.run_command, ["foot", "tmux", "attach", "-t", "{}"]);
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"pick "A" { run-command "ls"; }"#),
+            "pick should have a list-command"
+        );
+        assert_eq!(
+            error(r#"pick "A" { list-command "ls"; }"#),
+            "pick should have a run-command"
+        );
+        assert_eq!(
+            error(r#"pick "A" { list-command "ls"; run-command "ls"; command "ls"; }"#),
+            "unexpected node in pick: command"
+        );
+    }
+
+    #[test]
+    fn test_env_var_expansion() {
+        let home = std::env::var("HOME").unwrap();
+        let config = r#"
+            fuzzel-config {
+                font "$HOME"
+                colors { background "$${HOME}" }
+            }
+            icon-dir "$HOME/.icons"
+            program "Browser" {
+                command "swaymsg" "exec" "${HOME}/browser"
+                icon "$HOME/browser.png"
+            }
+            open "Home" "$HOME"
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(
+            parsed.fuzzel_config,
+            [
+                ("font".to_string(), home.clone()),
+                ("colors.background".to_string(), "${HOME}".to_string())
+            ]
+        );
+        assert_eq!(
+            parsed.icon_dirs[0].path,
+            PathBuf::from(format!("{home}/.icons"))
+        );
+        assert_eq!(parsed.items[0].icon, Some(format!("{home}/browser.png")));
+        let ItemContents::Program(ref browser) = parsed.items[0].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(browser.command[2], format!("{home}/browser"));
+        assert!(
+            matches!(parsed.items[1].contents, ItemContents::Open(ref target) if *target == home)
+        );
+
+        let unset = r#"program "Foo" { command "$UFF_TEST_UNSET"; }"#;
+        let error = parse_config(unset, ParseOptions::default()).unwrap_err();
+        assert!(error.to_string().contains("UFF_TEST_UNSET"));
+        assert_eq!(
+            error.labels().unwrap().next().unwrap().offset(),
+            unset.find("\"$UFF").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fuzzel_key_validation() {
+        let strict = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let valid = r#"
+            fuzzel-config {
+                font "monospace:size=12"
+                lines "10"
+                colors { background "282828ff"; }
+                key-bindings { custom-1 "Mod1+1"; }
+            }
+        "#;
+        assert!(parse_config(valid, strict).is_ok());
+
+        let typo = r#"fuzzel-config { fontt "monospace:size=12"; }"#;
+        assert!(parse_config(typo, ParseOptions::default()).is_ok());
+        let error = parse_config(typo, strict).unwrap_err();
+        assert_eq!(error.to_string(), "unknown fuzzel-config key: fontt");
+        assert_eq!(error.help().unwrap().to_string(), "did you mean font?");
+        assert_eq!(
+            error.labels().unwrap().next().unwrap().offset(),
+            typo.find("fontt").unwrap()
+        );
+
+        let bad_section = r#"fuzzel-config { colours { background "282828ff"; }; }"#;
+        let error = parse_config(bad_section, strict).unwrap_err();
+        assert_eq!(error.help().unwrap().to_string(), "did you mean colors?");
+        assert!(parse_config(r#"fuzzel-config { lines "ten"; }"#, strict).is_err());
+        assert!(parse_config(r#"fuzzel-config { colors { text "red"; }; }"#, strict).is_err());
+
+        // The escape hatch also applies to submenus
+        let patched = r#"
+            allow-unknown-fuzzel-keys #true
+            menu "Patched" {
+                fuzzel-config { extra-option "1"; extra { key "value"; }; }
+                program "Foo" { command "foo"; }
+            }
+        "#;
+        assert!(parse_config(patched, strict).is_ok());
+    }
+
+    #[test]
+    fn test_program_properties() {
+        let program = |src: &str| {
+            let parsed = parse_config(src, ParseOptions::default()).unwrap();
+            let item = parsed.items.into_iter().next().unwrap();
+            let ItemContents::Program(program) = item.contents else {
+                panic!("Expected program item");
+            };
+            (item.name, item.icon, program.command)
+        };
+
+        assert_eq!(
+            program(r#"program "Firefox" icon="firefox" cmd="firefox --new-window""#),
+            (
+                "Firefox".to_string(),
+                Some("firefox".to_string()),
+                vec!["firefox".to_string(), "--new-window".to_string()]
+            )
+        );
+        // Extra arguments keep their spaces
+        assert_eq!(
+            program(r#"program "Notes" "~/My Notes.md" cmd="gnome-text-editor""#).2,
+            ["gnome-text-editor", "~/My Notes.md"]
+        );
+        // Properties combine with the other field's child node
+        assert_eq!(
+            program(r#"program "Foo" icon="foo" { command "foo"; confirm; }"#).2,
+            ["foo"]
+        );
+        assert_eq!(
+            program(r#"program "Foo" cmd="foo" { icon "foo"; }"#).1,
+            Some("foo".to_string())
+        );
+
+        let mixed_icon = r#"program "Foo" icon="a" { icon "b"; command "foo"; }"#;
+        let error = parse_config(mixed_icon, ParseOptions::default()).unwrap_err();
+        let offsets: Vec<usize> = error
+            .labels()
+            .unwrap()
+            .map(|label| label.offset())
+            .collect();
+        assert_eq!(
+            offsets,
+            [
+                mixed_icon.find("icon=").unwrap(),
+                mixed_icon.find("icon \"b\"").unwrap()
+            ]
+        );
+        let mixed_cmd = r#"program "Foo" cmd="foo" { command "foo"; }"#;
+        assert!(parse_config(mixed_cmd, ParseOptions::default()).is_err());
+
+        for invalid in [
+            r#"program "Foo""#,
+            r#"program "Foo" cmd="""#,
+            r#"program "Foo" cmd=1"#,
+            r#"program "Foo" cmd="foo" title="Foo""#,
+        ] {
+            assert!(
+                parse_config(invalid, ParseOptions::default()).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_positional_command() {
+        let program = |src: &str| {
+            let parsed = parse_config(src, ParseOptions::default()).unwrap();
+            let item = parsed.items.into_iter().next().unwrap();
+            let ItemContents::Program(program) = item.contents else {
+                panic!("Expected program item");
+            };
+            (item.icon, program.command)
+        };
+
+        assert_eq!(
+            program(r#"program "Screenshot" "grim" "-g" "$$(slurp)""#),
+            (
+                None,
+                vec!["grim".to_string(), "-g".to_string(), "$(slurp)".to_string()]
+            )
+        );
+        assert_eq!(
+            program(r#"program "Screenshot" "grim" { icon "camera"; }"#),
+            (Some("camera".to_string()), vec!["grim".to_string()])
+        );
+        assert_eq!(
+            program(r#"program "Screenshot" "grim" icon="camera" { confirm; }"#),
+            (Some("camera".to_string()), vec!["grim".to_string()])
+        );
+
+        let conflict = r#"program "Screenshot" "grim" "-g" { command "grim"; }"#;
+        let error = parse_config(conflict, ParseOptions::default()).unwrap_err();
+        let labels: Vec<(usize, usize)> = error
+            .labels()
+            .unwrap()
+            .map(|label| (label.offset(), label.len()))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                (conflict.find("\"grim\"").unwrap(), "\"grim\" \"-g\"".len()),
+                (conflict.find("command").unwrap(), "command \"grim\"".len())
+            ]
+        );
+
+        // Without a name the usual single argument error points after the node name
+        let error = parse_config("program", ParseOptions::default()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "program should have exactly one argument"
+        );
+        assert_eq!(error.labels().unwrap().next().unwrap().offset(), 7);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config = r#"
+            program "Slack" {
+                command "slack"
+                profiles "work"
+                profiles "travel"
+            }
+            back "Back" { profiles "home"; }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(parsed.items[0].profiles, ["work", "travel"]);
+        assert_eq!(parsed.items[1].profiles, ["home"]);
+
+        assert!(
+            parse_config(
+                r#"program "Foo" { command "foo"; profiles; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+        assert!(
+            parse_config(
+                r#"program "Foo" { command "foo"; profiles 1; }"#,
+                ParseOptions::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let config = r#"
+            sort "sideways"
+            menu "Apps" {
+                program "Firefox" {
+                    command "firefox"
+                    watch-exit 3
+                }
+                frobnicate
+            }
+            program "Terminal" "foot"
+        "#;
+        let error = parse_config(config, ParseOptions::default()).unwrap_err();
+        assert_eq!(error.to_string(), "found 3 problems in the config");
+        let related: Vec<(String, usize)> = error
+            .related()
+            .unwrap()
+            .map(|related| {
+                let label = related.labels().unwrap().next().unwrap();
+                (related.to_string(), label.offset())
+            })
+            .collect();
+        assert_eq!(
+            related,
+            [
+                (
+                    "unknown value for sort".to_string(),
+                    config.find("\"sideways\"").unwrap()
+                ),
+                (
+                    "argument should be a boolean".to_string(),
+                    config.find('3').unwrap()
+                ),
+                (
+                    "unexpected node in menu: frobnicate".to_string(),
+                    config.find("frobnicate").unwrap()
+                ),
+            ]
+        );
+
+        // A failed command node doesn't also report a missing command
+        let error =
+            parse_config(r#"program "Foo" { command 1; }"#, ParseOptions::default()).unwrap_err();
+        assert_eq!(error.to_string(), "argument should be a string");
+        assert!(error.related().is_none());
+    }
+
+    #[test]
+    fn test_binds() {
+        let config = r#"
+            binds { "ctrl+d" "delete"; }
+            program "Notes" "notes" {
+                on "delete" {
+                    command "rm" "notes.md"
+                    notify "deleted"
+                }
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(parsed.binds, [("ctrl+d".to_string(), "delete".to_string())]);
+        let (action, program) = &parsed.items[0].actions[0];
+        assert_eq!(action, "delete");
+        assert_eq!(program.command, ["rm", "notes.md"]);
+        assert_eq!(program.notify.as_ref().unwrap().summary, "deleted");
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"program "A" "a" { on "x" { command "b"; }; on "x" { command "c"; }; }"#),
+            "on is defined more than once"
+        );
+        assert_eq!(
+            error(r#"program "A" "a" { on "x" { command "b"; confirm; }; }"#),
+            "actions can't ask for confirmation"
+        );
+        assert_eq!(
+            error(r#"program "A" "a" { on "x" { notify "hi"; }; }"#),
+            "action should have a command"
+        );
+        let too_many: String = (1..=20).map(|n| format!("\"F{n}\" \"a\"; ")).collect();
+        assert_eq!(
+            error(&format!("binds {{ {too_many} }}")),
+            "a menu can have at most 19 binds"
+        );
+    }
+
+    #[test]
+    fn test_chain() {
+        let config = r#"
+            program "Suspend" {
+                chain continue-on-error=#true {
+                    command "swaylock" "-f"
+                    command "systemctl" "suspend"
+                }
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::Program(program) = &parsed.items[0].contents else {
+            panic!("Expected program item");
+        };
+        let chain = program.chain.as_ref().unwrap();
+        assert_eq!(
+            chain.commands,
+            [vec!["swaylock", "-f"], vec!["systemctl", "suspend"]]
+        );
+        assert!(chain.continue_on_error);
+        assert!(program.command.is_empty());
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"program "A" "a" { chain { command "b"; }; }"#),
+            "program has both a command and a chain"
+        );
+        assert_eq!(
+            error(r#"program "A" { chain; }"#),
+            "chain should have children"
+        );
+        assert_eq!(
+            error(r#"program "A" { chain {}; }"#),
+            "chain should have commands"
+        );
+        assert_eq!(
+            error(r#"program "A" { chain { sleep "1"; }; }"#),
+            "unexpected node in chain: sleep"
+        );
+        assert_eq!(
+            error(r#"program "A" { chain continue-on-error="yes" { command "b"; }; }"#),
+            "continue-on-error should be a boolean"
+        );
+    }
+
+    #[test]
+    fn test_empty_command_arguments() {
+        let error = |config: &str, options| parse_config(config, options).unwrap_err();
+        let strict = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+
+        let config = r#"program "A" { command "" "--flag"; }"#;
+        let report = error(config, ParseOptions::default());
+        assert_eq!(report.to_string(), "command should start with a program");
+        let label = report.labels().unwrap().next().unwrap();
+        assert_eq!(label.offset(), config.find(r#""""#).unwrap());
+        for config in [
+            r#"program "A" " ""#,
+            r#"dynamic-menu "A" { source-command " "; run-command "b"; }"#,
+            r#"program "A" { chain { command ""; }; }"#,
+        ] {
+            assert_eq!(
+                error(config, ParseOptions::default()).to_string(),
+                "command should start with a program"
+            );
+        }
+
+        // Empty arguments only warn, unless strict
+        let config = r#"program "A" "firefox" "" { on "edit" { command "a" " "; }; }"#;
+        assert!(parse_config(config, ParseOptions::default()).is_ok());
+        let report = error(config, strict);
+        assert_eq!(report.to_string(), "found 2 problems in the config");
+        let labels: Vec<usize> = report
+            .related()
+            .unwrap()
+            .map(|related| related.labels().unwrap().next().unwrap().offset())
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                config.find(r#""""#).unwrap(),
+                config.find(r#"" "; }"#).unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_menu() {
+        let config = r#"program "A" "a"
+menu "Games" {
+    icon "games"
+}"#;
+        let error = parse_config(config, ParseOptions::default()).unwrap_err();
+        assert_eq!(error.to_string(), "menu Games has no entries");
+        let label = error.labels().unwrap().next().unwrap();
+        assert_eq!(label.offset(), config.find("menu").unwrap());
+        assert_eq!(label.len(), config.len() - label.offset());
+
+        // Only the broken item is reported, not the menu it leaves empty
+        let error =
+            parse_config(r#"menu "Games" { program "A"; }"#, ParseOptions::default()).unwrap_err();
+        assert_eq!(error.to_string(), "program should have a command");
+
+        // Profiles are filtered later, the menu is dropped then
+        assert!(parse_config(r#"menu "Games" { back "Back"; }"#, ParseOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_weight() {
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"menu "A" { weight 1; weight 2; program "B" "b"; }"#),
+            "weight is defined more than once"
+        );
+        assert_eq!(
+            error(r#"program "A" "a" weight=1 { weight 2; }"#),
+            "weight is defined more than once"
+        );
+        assert_eq!(
+            error(r#"program "A" "a" weight="high""#),
+            "weight should be an integer"
+        );
+        assert_eq!(
+            error(r#"open "A" "/" { weight 1.5; }"#),
+            format!(
+                "argument should be an integer between {} and {}",
+                i64::MIN,
+                i64::MAX
+            )
+        );
+    }
+
+    #[test]
+    fn test_hooks() {
+        let config = r#"
+            hooks {
+                pre-launch "swaymsg" "workspace" "back_and_forth"
+                post-launch "tracker"
+            }
+            menu "A" {
+                hooks { post-launch "other-tracker"; }
+                program "B" "b"
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert!(parsed.entries_only);
+        assert_eq!(
+            parsed.hooks.pre_launch,
+            Some(vec![
+                "swaymsg".to_string(),
+                "workspace".to_string(),
+                "back_and_forth".to_string()
+            ])
+        );
+        assert_eq!(parsed.hooks.post_launch, Some(vec!["tracker".to_string()]));
+        let ItemContents::Menu(menu) = &parsed.items[0].contents else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(menu.hooks.pre_launch, None);
+        assert_eq!(
+            menu.hooks.post_launch,
+            Some(vec!["other-tracker".to_string()])
+        );
+
+        let error = parse_config(
+            r#"hooks { on-launch "x"; }; program "B" "b""#,
+            ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "unexpected node in hooks: on-launch");
+    }
+
+    #[test]
+    fn test_label_command() {
+        let config =
+            r#"program "Battery ({})" "gnome-power-statistics" { label-command "cat" "~/bat"; }"#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(
+            parsed.items[0].label_command,
+            Some(vec!["cat".to_string(), "~/bat".to_string()])
+        );
+
+        let error = parse_config(
+            r#"program "A {}" "a" { label-command "date"; label-command "uptime"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "label-command is defined more than once");
+    }
+
+    #[test]
+    fn test_duplicate_nodes() {
+        let config = r#"menu "A" { fuzzel-args "--lines=5"; program "B" { command "b"; command "c"; }; fuzzel-args "--lines=9"; }"#;
+        let error = parse_config(config, ParseOptions::default()).unwrap_err();
+        let ConfigErrors(reports) = error.downcast_ref::<ConfigErrors>().unwrap();
+        let labels: Vec<Vec<usize>> = reports
+            .iter()
+            .map(|report| {
+                report
+                    .labels()
+                    .unwrap()
+                    .map(|label| label.offset())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                [
+                    config.find("command \"b\"").unwrap(),
+                    config.find("command \"c\"").unwrap()
+                ],
+                [
+                    config.find("fuzzel-args").unwrap(),
+                    config.rfind("fuzzel-args").unwrap()
+                ]
+            ]
+        );
+        assert_eq!(
+            reports[0].help().unwrap().to_string(),
+            "remove one of them, with --lenient the later one is used"
+        );
+
+        // Lenient mode keeps the later one
+        let options = ParseOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        let parsed = parse_config(config, options).unwrap();
+        let ItemContents::Menu(menu) = &parsed.items[0].contents else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(menu.fuzzel_args, ["--lines=9"]);
+        let ItemContents::Program(program) = &menu.items[0].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(program.command, ["c"]);
+
+        // Top-level nodes too, like they are in config.kdl
+        let config = r#"
+            defaults { fuzzel-args "--lines=5"; }
+            power-commands { reboot "reboot"; }
+            defaults { fuzzel-args "--lines=9"; }
+            power-commands { reboot "systemctl" "reboot"; }
+        "#;
+        let error = parse_config(config, ParseOptions::default()).unwrap_err();
+        let ConfigErrors(reports) = error.downcast_ref::<ConfigErrors>().unwrap();
+        let labels: Vec<Vec<usize>> = reports
+            .iter()
+            .map(|report| {
+                report
+                    .labels()
+                    .unwrap()
+                    .map(|label| label.offset())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                [
+                    config.find("defaults").unwrap(),
+                    config.rfind("defaults").unwrap()
+                ],
+                [
+                    config.find("power-commands").unwrap(),
+                    config.rfind("power-commands").unwrap()
+                ]
+            ]
+        );
+        let parsed = parse_config(config, options).unwrap();
+        assert_eq!(parsed.defaults.fuzzel_args, ["--lines=9"]);
+        assert_eq!(
+            parsed.defaults.power_commands,
+            [(
+                PowerAction::Reboot,
+                vec!["systemctl".to_string(), "reboot".to_string()]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_power_items() {
+        let config = r#"
+            power-commands {
+                power-off "loginctl" "poweroff"
+            }
+            power-off
+            reboot "Restart" {
+                confirm #false
+            }
+            hibernate {
+                icon "weather-snow"
+                confirm
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(
+            parsed.defaults.power_commands,
+            [(
+                PowerAction::PowerOff,
+                vec!["loginctl".to_string(), "poweroff".to_string()]
+            )]
+        );
+        let power = |index: usize| {
+            let item = &parsed.items[index];
+            let ItemContents::Power(power) = &item.contents else {
+                panic!("Expected power item");
+            };
+            (
+                item.name.as_str(),
+                item.icon.as_deref(),
+                power.action,
+                power.confirm.as_deref(),
+            )
+        };
+        assert_eq!(
+            power(0),
+            (
+                "Shut down",
+                Some("system-shutdown"),
+                PowerAction::PowerOff,
+                Some("Shut down now?")
+            )
+        );
+        assert_eq!(
+            power(1),
+            ("Restart", Some("system-reboot"), PowerAction::Reboot, None)
+        );
+        assert_eq!(
+            power(2),
+            (
+                "Hibernate",
+                Some("weather-snow"),
+                PowerAction::Hibernate,
+                Some("Are you sure?")
+            )
+        );
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"power-commands { shutdown "poweroff"; }"#),
+            "unexpected node in power-commands: shutdown"
+        );
+        assert_eq!(
+            error(r#"lock { command "swaylock"; }"#),
+            "unexpected node in lock: command"
+        );
+        assert_eq!(
+            error("menu \"Power\" { power-commands { lock \"swaylock\"; }; }"),
+            "unexpected node in menu: power-commands"
+        );
+    }
+
+    #[test]
+    fn test_files_menu() {
+        let config = r#"
+            files-menu "Screenshots" "/tmp/screenshots" {
+                icon "camera"
+                open-with "imv"
+                pattern "*.png"
+                sort "mtime"
+                limit 50
+            }
+            files-menu "Downloads" "/tmp/downloads"
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(parsed.items[0].icon.as_deref(), Some("camera"));
+        let ItemContents::FilesMenu(screenshots) = &parsed.items[0].contents else {
+            panic!("Expected files-menu item");
+        };
+        assert_eq!(screenshots.dir, "/tmp/screenshots");
+        assert_eq!(
+            screenshots.open_with.as_deref(),
+            Some(&["imv".to_string()][..])
+        );
+        assert_eq!(screenshots.pattern.as_deref(), Some("*.png"));
+        assert_eq!(screenshots.sort, FileSort::Mtime);
+        assert_eq!(screenshots.limit, Some(50));
+        let ItemContents::FilesMenu(downloads) = &parsed.items[1].contents else {
+            panic!("Expected files-menu item");
+        };
+        assert_eq!(downloads.sort, FileSort::Name);
+        assert!(downloads.open_with.is_none());
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"files-menu "A""#),
+            "files-menu should have exactly one directory"
+        );
+        assert_eq!(
+            error(r#"files-menu "A" "/tmp" { sort "size"; }"#),
+            "unknown value for sort"
+        );
+        assert_eq!(
+            error(r#"files-menu "A" "/tmp" { limit 0; }"#),
+            "argument should be an integer between 1 and 4294967295"
+        );
+    }
+
+    #[test]
+    fn test_type_annotations() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("test-type-annotations");
+        std::fs::create_dir_all(&dir).unwrap();
+        let icon = dir.join("foo.png");
+        std::fs::write(&icon, "").unwrap();
+        let home = crate::config::home().unwrap();
+        let config = format!(
+            r#"
+                icon-dir (path)"{dir}"
+                opener (sh)"xdg-open \"$1\"" 
+                program "Path" {{
+                    icon (path)"{icon}"
+                    command (path)"~" (raw)"$NOT_SET"
+                }}
+                program "Raw" icon=(raw)"$NOT_SET" {{
+                    command (sh)"pactl set-sink-volume @DEFAULT_SINK@ +5% && echo $NOT_SET"
+                }}
+                program "Inline" (sh)"echo $NOT_SET | wl-copy" icon=(path)"{icon}"
+                program "Plain" "foo" (raw)"${{NOT_SET}}"
+                open "Home" (path)"~"
+                open "Literal" (raw)"$NOT_SET"
+                files-menu "Files" (path)"{dir}" {{ open-with (raw)"$NOT_SET"; }}
+            "#,
+            dir = dir.display(),
+            icon = icon.display(),
+        );
+        let parsed = parse_config(&config, ParseOptions::default()).unwrap();
+        assert_eq!(parsed.icon_dirs[0].path, dir);
+        assert_eq!(
+            parsed.opener.as_deref().unwrap(),
+            ["sh", "-c", "xdg-open \"$1\""]
+        );
+        let program = |index: usize| match &parsed.items[index].contents {
+            ItemContents::Program(program) => program,
+            _ => panic!("Expected program item"),
+        };
+        assert_eq!(
+            parsed.items[0].icon.as_deref(),
+            Some(icon.to_str().unwrap())
+        );
+        assert_eq!(program(0).command, [home.as_str(), "$NOT_SET"]);
+        assert_eq!(parsed.items[1].icon.as_deref(), Some("$NOT_SET"));
+        assert_eq!(
+            program(1).command,
+            [
+                "sh",
+                "-c",
+                "pactl set-sink-volume @DEFAULT_SINK@ +5% && echo $NOT_SET"
+            ]
+        );
+        assert_eq!(program(2).command, ["sh", "-c", "echo $NOT_SET | wl-copy"]);
+        assert_eq!(
+            parsed.items[2].icon.as_deref(),
+            Some(icon.to_str().unwrap())
+        );
+        assert_eq!(program(3).command, ["foo", "${NOT_SET}"]);
+        assert!(matches!(&parsed.items[4].contents, ItemContents::Open(target) if *target == home));
+        assert!(
+            matches!(&parsed.items[5].contents, ItemContents::Open(target) if target == "$NOT_SET")
+        );
+        let ItemContents::FilesMenu(files) = &parsed.items[6].contents else {
+            panic!("Expected files-menu item");
+        };
+        assert_eq!(files.dir, dir.display().to_string());
+        assert_eq!(files.open_with.as_deref().unwrap(), ["$NOT_SET"]);
+
+        let error = |config: &str| {
+            let error = parse_config(config, ParseOptions::default()).unwrap_err();
+            (error.to_string(), error.help().unwrap().to_string())
+        };
+        let missing = dir.join("missing.png");
+        assert_eq!(
+            error(&format!(
+                r#"program "A" "a" {{ icon (path)"{}"; }}"#,
+                missing.display()
+            )),
+            (
+                format!("{} doesn't exist", missing.display()),
+                "(path) checks that it exists, remove it to skip the check".to_string()
+            )
+        );
+        assert_eq!(
+            error(r#"program "A" "a" { icon (sh)"foo"; }"#),
+            (
+                "unsupported type annotation: (sh)".to_string(),
+                "supported here: (path), (raw)".to_string()
+            )
+        );
+        assert_eq!(
+            error(r#"program "A" { command (shell)"foo"; }"#),
+            (
+                "unsupported type annotation: (shell)".to_string(),
+                "supported here: (path), (sh), (raw)".to_string()
+            )
+        );
+        assert_eq!(
+            error(r#"program (raw)"A" "a""#),
+            (
+                "unsupported type annotation: (raw)".to_string(),
+                "this value can't have one, remove it".to_string()
+            )
+        );
+        assert_eq!(
+            error(r#"program "A" { command (sh)"foo" "bar"; }"#).0,
+            "a (sh) command should be its only argument"
+        );
+        assert_eq!(
+            error(r#"program "A" cmd="foo" (sh)"bar""#).0,
+            "a (sh) command can't be used with cmd"
+        );
+    }
+
+    #[test]
+    fn test_uff_version() {
+        let current = CONFIG_VERSIONS.end();
+        let config = format!("uff-version \"{current}\"\nmenu \"A\" {{ program \"B\" \"b\"; }}");
+        let parsed = parse_config(&config, ParseOptions::default()).unwrap();
+        // It doesn't stop the config being a collection of entries
+        assert!(parsed.entries_only);
+        let config = format!("uff-version {current}; program \"B\" \"b\"");
+        assert!(parse_config(&config, ParseOptions::default()).is_ok());
+        assert!(
+            parse_global_config(&format!("uff-version {current}"), ParseOptions::default()).is_ok()
+        );
+
+        // A newer config fails on its version, not the nodes it adds
+        let config = format!("uff-version {}; new-node; program \"B\" \"b\"", current + 1);
+        let error = parse_config(&config, ParseOptions::default()).unwrap_err();
+        assert_eq!(error.to_string(), "this config requires a newer uff");
+        assert!(error.help().unwrap().to_string().starts_with(&format!(
+            "uff {} reads config versions up to {current}",
+            env!("CARGO_PKG_VERSION")
+        )));
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"uff-version "two"; program "B" "b""#),
+            "uff-version should be a version number"
+        );
+        assert_eq!(
+            error(r#"uff-version 0; program "B" "b""#),
+            "uff-version should be a version number"
+        );
+        assert_eq!(
+            error(r#"menu "A" { uff-version 1; program "B" "b"; }"#),
+            "unexpected node in menu: uff-version"
+        );
+    }
+
+    #[test]
+    fn test_recent_files() {
+        let config = r#"
+            recent-files "Recent" {
+                limit 5
+                open-with "xdg-open"
+            }
+            recent-files "More recent"
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::RecentFiles(recent) = &parsed.items[0].contents else {
+            panic!("Expected recent-files item");
+        };
+        assert_eq!(recent.limit, 5);
+        assert_eq!(
+            recent.open_with.as_deref(),
+            Some(&["xdg-open".to_string()][..])
+        );
+        let ItemContents::RecentFiles(recent) = &parsed.items[1].contents else {
+            panic!("Expected recent-files item");
+        };
+        assert_eq!(recent.limit, 15);
+        assert!(recent.open_with.is_none());
+
+        let error = parse_config(
+            r#"recent-files "A" { pattern "*.pdf"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unexpected node in recent-files: pattern"
+        );
+    }
+
+    #[test]
+    fn test_input() {
+        let config = r#"
+            input "Search" url-encode=#true {
+                icon "system-search"
+                command "xdg-open" "https://ddg.gg/?q={}"
+                prompt "search: "
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(parsed.items[0].icon.as_deref(), Some("system-search"));
+        let ItemContents::Input(input) = &parsed.items[0].contents else {
+            panic!("Expected input item");
+        };
+        assert_eq!(input.command, ["xdg-open", "https://ddg.gg/?q={}"]);
+        assert_eq!(input.prompt.as_deref(), Some("search: "));
+        assert!(input.url_encode);
+        assert!(!input.password);
+        assert_eq!(input.pass_via, None);
+
+        let config = r#"
+            input "Unlock vault" {
+                command "vault-unlock"
+                password #true
+                pass-via "env:VAULT_PASSWORD"
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::Input(input) = &parsed.items[0].contents else {
+            panic!("Expected input item");
+        };
+        assert!(input.password);
+        assert_eq!(
+            input.pass_via,
+            Some(PassVia::Env("VAULT_PASSWORD".to_string()))
+        );
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"input "A" { prompt "a: "; }"#),
+            "input should have a command"
+        );
+        assert_eq!(
+            error(r#"input "A" { command "a"; history "b"; }"#),
+            "unexpected node in input: history"
+        );
+        assert_eq!(
+            error(r#"input "A" encode=#true { command "a"; }"#),
+            "unexpected named parameter on input: encode"
+        );
+        assert_eq!(
+            error(r#"input "A" { command "a"; pass-via "env:"; }"#),
+            "unknown value for pass-via"
+        );
+    }
+
+    #[test]
+    fn test_multi_select() {
+        let config = r#"
+            menu "Each" {
+                multi-select #true
+                program "Firefox" "firefox"
+                program "Foot" "foot"
+            }
+            menu "Args" {
+                multi-select "args"
+                run-command "mpv" "--"
+                program "Lo-fi" "https://example.com/lofi"
+            }
+            menu "Single" {
+                multi-select #false
+                program "Mpv" "mpv"
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let menu = |index: usize| {
+            let ItemContents::Menu(menu) = &parsed.items[index].contents else {
+                panic!("Expected menu item");
+            };
+            menu.multi_select.clone()
+        };
+        assert_eq!(menu(0), Some(MultiSelect::Each));
+        assert_eq!(
+            menu(1),
+            Some(MultiSelect::Args(vec!["mpv".to_string(), "--".to_string()]))
+        );
+        assert_eq!(menu(2), None);
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(
+                r#"menu "A" { multi-select #true; program "a" "a"; menu "b" { program "c" "c"; }; }"#
+            ),
+            "a multi-select menu can only hold programs, found menu"
+        );
+        assert_eq!(
+            error(r#"menu "A" { multi-select #true; program "a" "a" { confirm; }; }"#),
+            "items in a multi-select menu can't ask for confirmation"
+        );
+        // Power items that ask by default have to turn it off
+        assert_eq!(
+            error(r#"menu "A" { multi-select #true; program "a" "a"; reboot; }"#),
+            "items in a multi-select menu can't ask for confirmation"
+        );
+        parse_config(
+            r#"menu "A" { multi-select #true; power-off { confirm #false; }; suspend; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            error(r#"menu "A" { multi-select "args"; program "a" "a"; }"#),
+            "multi-select \"args\" needs a run-command"
+        );
+        assert_eq!(
+            error(r#"menu "A" { run-command "a"; program "a" "a"; }"#),
+            "run-command is only used by multi-select menus"
+        );
+    }
+
+    #[test]
+    fn test_try() {
+        let config = r#"
+            program "Terminal" {
+                try required=#true {
+                    command "foot"
+                    command "alacritty" "-e" "fish"
+                }
+                on "copy" {
+                    try {
+                        command "wl-copy"
+                    }
+                }
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        let ItemContents::Program(ref program) = parsed.items[0].contents else {
+            panic!("Expected program item");
+        };
+        assert!(program.command.is_empty());
+        let fallbacks = program.fallbacks.as_ref().unwrap();
+        assert_eq!(
+            fallbacks.commands,
+            [vec!["foot"], vec!["alacritty", "-e", "fish"]]
+        );
+        assert!(fallbacks.required);
+        let fallbacks = parsed.items[0].actions[0].1.fallbacks.as_ref().unwrap();
+        assert_eq!(fallbacks.commands, [vec!["wl-copy"]]);
+        assert!(!fallbacks.required);
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"program "A" "a" { try { command "b"; }; }"#),
+            "program has both a try block and a command"
+        );
+        assert_eq!(
+            error(r#"program "A" { chain { command "a"; }; try { command "b"; }; }"#),
+            "program has both a try block and a chain"
+        );
+        assert_eq!(
+            error(r#"program "A" { try {}; }"#),
+            "try should have commands"
+        );
+        assert_eq!(
+            error(r#"program "A" { try "a"; }"#),
+            "try should not have any arguments"
+        );
+        assert_eq!(
+            error(r#"program "A" { try { chain { command "a"; }; }; }"#),
+            "unexpected node in try: chain"
+        );
+    }
+
+    #[test]
+    fn test_desktop() {
+        let config = r#"
+            defaults {
+                terminal "foot" "-e"
+            }
+            program "Firefox" {
+                desktop "firefox.desktop"
+                on "private" {
+                    desktop "~/apps/firefox-private.desktop"
+                }
+            }
+            program "Htop" "btop" {
+                desktop "htop.desktop"
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(
+            parsed.defaults.terminal.as_deref(),
+            Some(&["foot".to_string(), "-e".to_string()][..])
+        );
+        let ItemContents::Program(ref program) = parsed.items[0].contents else {
+            panic!("Expected program item");
+        };
+        assert!(program.command.is_empty());
+        assert_eq!(program.desktop.as_deref(), Some("firefox.desktop"));
+        assert_eq!(
+            parsed.items[0].actions[0].1.desktop.as_deref(),
+            Some("~/apps/firefox-private.desktop")
+        );
+        let ItemContents::Program(ref program) = parsed.items[1].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(program.command, ["btop"]);
+
+        let error = parse_config(
+            r#"program "A" { desktop "a.desktop"; desktop "b.desktop"; }"#,
+            ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("desktop"));
+    }
+
+    #[test]
+    fn test_display_text() {
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"program "a\u{0}b" "a""#),
+            "item names can't contain a NUL byte"
+        );
+        assert_eq!(
+            error(r#"program "a\u{1f}b" "a""#),
+            "item names can't contain a 0x1f byte"
+        );
+        assert_eq!(
+            error(r#"program "a\nb" "a""#),
+            "item names can't contain a newline"
+        );
+        assert_eq!(
+            error(r#"menu "a\nb" { program "a" "a"; }"#),
+            "item names can't contain a newline"
+        );
+        assert_eq!(
+            error(r#"reboot "a\u{1f}b""#),
+            "item names can't contain a 0x1f byte"
+        );
+        assert_eq!(
+            error(r#"program "a" "a" { description "x\u{0}y"; }"#),
+            "descriptions can't contain a NUL byte"
+        );
+        assert_eq!(
+            error(r#"program "a" "a" { description "x\ny"; }"#),
+            "descriptions can't contain a newline"
+        );
+        // Commands are never shown, so they can contain anything
+        assert!(parse_config(r#"program "a" "printf" "a\nb""#, ParseOptions::default()).is_ok());
+
+        let lenient = ParseOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        let parsed =
+            parse_config(r#"program "a\nb" "a" { description "x\ny"; }"#, lenient).unwrap();
+        assert_eq!(parsed.items[0].name, "a\u{2424}b");
+        assert_eq!(parsed.items[0].description.as_deref(), Some("x\u{2424}y"));
+        assert!(parse_config(r#"program "a\u{0}b" "a""#, lenient).is_err());
+        assert!(parse_config(r#"program "a\u{1f}b" "a""#, lenient).is_err());
+    }
+
+    #[test]
+    fn test_auto_size() {
+        let config = r#"
+            auto-size #true
+            program "a" "a"
+            menu "Limited" {
+                auto-size #true max-lines=8 max-width=40 padding=2
+                program "a" "a"
+            }
+            menu "Off" {
+                auto-size #false
+                program "a" "a"
+            }
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert_eq!(parsed.auto_size, Some(Some(AutoSize::default())));
+        let submenu = |index: usize| {
+            let ItemContents::Menu(ref menu) = parsed.items[index].contents else {
+                panic!("Expected menu item");
+            };
+            menu.auto_size
+        };
+        assert_eq!(
+            submenu(1),
+            Some(Some(AutoSize {
+                max_lines: 8,
+                max_width: 40,
+                padding: 2
+            }))
+        );
+        assert_eq!(submenu(2), Some(None));
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"auto-size #true max-lines=0; program "a" "a""#),
+            "max-lines should be an integer between 1 and 100"
+        );
+        assert_eq!(
+            error(r#"auto-size #true padding=-1; program "a" "a""#),
+            "padding should be an integer between 0 and 100"
+        );
+        assert_eq!(
+            error(r#"auto-size #true lines=5; program "a" "a""#),
+            "unexpected named parameter on auto-size: lines"
+        );
     }
 }
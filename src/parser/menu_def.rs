@@ -0,0 +1,272 @@
+//! `menu-def` nodes and the `menu-ref` items that place them, expanded into
+//! ordinary menus before the menus are parsed so each place gets its own copy.
+
+use kdl::{KdlDocument, KdlNode};
+use miette::{LabeledSpan, Result, miette};
+
+use super::{
+    Diagnostics, already_defined, children, is_item_node, many_arguments, one_argument,
+    only_parameters, positional,
+};
+
+/// Nodes that can be given more than once, so ones on a reference are added
+/// to the definition's instead of replacing them.
+const REPEATABLE_NODES: &[&str] = &["on", "profiles", "icon-dir"];
+
+struct Definition<'a> {
+    name: String,
+    node: &'a KdlNode,
+    used: bool,
+}
+
+/// Takes the definitions out of the top level and replaces every `menu-ref`
+/// with a menu holding the definition's nodes.
+pub(super) fn expand_menu_refs(doc: &mut KdlDocument, diagnostics: &mut Diagnostics) {
+    let top_level = doc.clone();
+    let mut definitions: Vec<Definition> = Vec::new();
+    diagnostics.each_node(&top_level, |node, _| {
+        if node.name().value() == "menu-def" {
+            let name = one_argument(node)?;
+            only_parameters(node, &[])?;
+            children(node)?;
+            if let Some(first) = definitions.iter().find(|first| first.name == name) {
+                return Err(already_defined(first.node, node));
+            }
+            definitions.push(Definition {
+                name,
+                node,
+                used: false,
+            });
+        }
+        Ok(())
+    });
+    doc.nodes_mut()
+        .retain(|node| node.name().value() != "menu-def");
+
+    expand_refs(doc, &mut definitions, &mut Vec::new(), diagnostics);
+
+    for definition in definitions.iter().filter(|definition| !definition.used) {
+        diagnostics.lint(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("defined here".to_string()),
+                definition.node.span()
+            )],
+            help = format!(
+                "place it with menu-ref \"Name\" \"{}\", or remove it",
+                definition.name
+            ),
+            "menu-def {} is never used",
+            definition.name,
+        ));
+    }
+}
+
+/// `stack` holds the definitions being expanded, to catch ones that contain themselves.
+fn expand_refs(
+    doc: &mut KdlDocument,
+    definitions: &mut [Definition],
+    stack: &mut Vec<String>,
+    diagnostics: &mut Diagnostics,
+) {
+    // A reference that can't be expanded is dropped so it's only reported once
+    doc.nodes_mut().retain_mut(|node| {
+        if node.name().value() != "menu-ref" {
+            if let Some(children) = node.children_mut() {
+                expand_refs(children, definitions, stack, diagnostics);
+            }
+            return true;
+        }
+        let result = instantiate(node, definitions, stack, diagnostics);
+        diagnostics
+            .check(result)
+            .flatten()
+            .map(|menu| *node = menu)
+            .is_some()
+    });
+}
+
+/// `None` if a reference inside the definition failed, which has already been reported.
+fn instantiate(
+    node: &KdlNode,
+    definitions: &mut [Definition],
+    stack: &mut Vec<String>,
+    diagnostics: &mut Diagnostics,
+) -> Result<Option<KdlNode>> {
+    let arguments = many_arguments(node)?;
+    let entries = positional(node);
+    if arguments.len() != 2 {
+        let span = entries[0].span();
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("here".to_string()),
+                entries.get(2).map_or_else(
+                    || (span.offset() + span.len(), 0).into(),
+                    |extra| extra.span()
+                ),
+            )],
+            help = "menu-ref takes the menu's name and then the name of a menu-def",
+            "menu-ref should have exactly two arguments",
+        ));
+    }
+    only_parameters(node, &["id"])?;
+
+    let Some(definition) = definitions
+        .iter_mut()
+        .find(|definition| definition.name == arguments[1])
+    else {
+        let known: Vec<&str> = definitions
+            .iter()
+            .map(|definition| definition.name.as_str())
+            .collect();
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entries[1].span()
+            )],
+            help = if known.is_empty() {
+                "define it at the top level with menu-def \"name\" { ... }".to_string()
+            } else {
+                format!("defined menus: {}", known.join(", "))
+            },
+            "unknown menu-def: {}",
+            arguments[1],
+        ));
+    };
+    definition.used = true;
+    if stack.contains(&definition.name) {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("referenced here".to_string()),
+                node.span()
+            )],
+            help = "a menu can't contain itself, move the menu-ref out of it",
+            "menu-def {} contains itself",
+            definition.name,
+        ));
+    }
+
+    let name = definition.name.clone();
+    let mut body = definition.node.children().unwrap().clone();
+    // Nodes on the reference replace the definition's, so each place can give
+    // the menu its own icon or description
+    if let Some(extra) = node.children() {
+        for extra in extra.nodes() {
+            let replaceable = !is_item_node(extra.name().value())
+                && !REPEATABLE_NODES.contains(&extra.name().value());
+            if replaceable {
+                body.nodes_mut()
+                    .retain(|own| own.name().value() != extra.name().value());
+            }
+            body.nodes_mut().push(extra.clone());
+        }
+    }
+    stack.push(name);
+    let reported = diagnostics.count();
+    expand_refs(&mut body, definitions, stack, diagnostics);
+    stack.pop();
+    if diagnostics.count() != reported {
+        return Ok(None);
+    }
+
+    let mut menu = KdlNode::new("menu");
+    menu.set_span(node.span());
+    menu.name_mut().set_span(node.name().span());
+    menu.entries_mut().push(entries[0].clone());
+    menu.entries_mut().extend(
+        node.entries()
+            .iter()
+            .filter(|entry| entry.name().is_some())
+            .cloned(),
+    );
+    menu.set_children(body);
+    Ok(Some(menu))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{ItemContents, ParseOptions, parse_config};
+
+    #[test]
+    fn test_menu_refs() {
+        let config = r#"
+            menu-def "power" {
+                icon "system-shutdown"
+                power-off "Shut down"
+                program "Lock" "swaylock" id="lock"
+            }
+            menu "Apps" {
+                program "Firefox" "firefox"
+                menu-ref "Power" "power" id="apps-power" {
+                    icon "battery"
+                    reboot "Reboot"
+                }
+            }
+            menu-ref "Power" "power"
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+        assert!(parsed.entries_only);
+
+        let ItemContents::Menu(apps) = &parsed.items[0].contents else {
+            panic!("Expected menu item");
+        };
+        let power = &apps.items[1];
+        assert_eq!(power.name, "Power");
+        assert_eq!(power.id.as_deref(), Some("apps-power"));
+        assert_eq!(power.icon.as_deref(), Some("battery"));
+        let ItemContents::Menu(menu) = &power.contents else {
+            panic!("Expected menu item");
+        };
+        let names: Vec<&str> = menu.items.iter().map(|item| item.name.as_str()).collect();
+        assert_eq!(names, ["Shut down", "Lock", "Reboot"]);
+
+        // Each place gets its own copy, without the other's additions
+        let power = &parsed.items[1];
+        assert_eq!(power.icon.as_deref(), Some("system-shutdown"));
+        let ItemContents::Menu(menu) = &power.contents else {
+            panic!("Expected menu item");
+        };
+        assert_eq!(menu.items.len(), 2);
+        assert_eq!(menu.items[1].id.as_deref(), Some("lock"));
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(r#"menu-ref "Power" "power""#),
+            "unknown menu-def: power"
+        );
+        assert_eq!(
+            error(r#"menu-def "a" { menu-ref "A" "a"; }; menu-ref "A" "a""#),
+            "menu-def a contains itself"
+        );
+        assert_eq!(
+            error(
+                r#"menu-def "a" { menu-ref "B" "b"; }; menu-def "b" { menu-ref "A" "a"; }
+                menu-ref "A" "a""#
+            ),
+            "menu-def a contains itself"
+        );
+        assert_eq!(
+            error(r#"menu-def "a" { reboot "R"; }; menu-def "a" { reboot "R"; }"#),
+            "menu-def is defined more than once"
+        );
+        assert_eq!(
+            error(r#"menu-def "a" { reboot "R"; }; menu-ref "A""#),
+            "menu-ref should have exactly two arguments"
+        );
+
+        // Unused definitions are only a warning, unless --strict
+        let unused = r#"menu-def "a" { reboot "R"; }; program "B" "b""#;
+        assert!(parse_config(unused, ParseOptions::default()).is_ok());
+        let strict = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            parse_config(unused, strict).unwrap_err().to_string(),
+            "menu-def a is never used"
+        );
+    }
+}
@@ -0,0 +1,349 @@
+//! `template` nodes and the `use` nodes that fill them in, expanded into ordinary
+//! items before the menus are parsed.
+
+use kdl::{KdlDocument, KdlNode, KdlValue};
+use miette::{LabeledSpan, Result, miette};
+
+use super::{
+    Diagnostics, already_defined, children, many_arguments, only_parameters, positional,
+    string_parameter,
+};
+
+struct Template<'a> {
+    name: String,
+    /// The item it becomes, `program` or `menu`.
+    kind: String,
+    node: &'a KdlNode,
+}
+
+/// Takes the templates out of the top level and replaces every `use` with the item it describes.
+pub(super) fn expand_templates(doc: &mut KdlDocument, diagnostics: &mut Diagnostics) {
+    let definitions = doc.clone();
+    let mut templates: Vec<Template> = Vec::new();
+    diagnostics.each_node(&definitions, |node, _| {
+        if node.name().value() == "template" {
+            let template = parse_template(node)?;
+            if let Some(first) = templates.iter().find(|first| first.name == template.name) {
+                return Err(already_defined(first.node, node));
+            }
+            templates.push(template);
+        }
+        Ok(())
+    });
+    doc.nodes_mut()
+        .retain(|node| node.name().value() != "template");
+
+    expand_uses(doc, &templates, &mut Vec::new(), diagnostics);
+}
+
+fn parse_template(node: &KdlNode) -> Result<Template<'_>> {
+    let arguments = many_arguments(node)?;
+    if let Some(extra) = positional(node).get(1) {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("remove this".to_string()),
+                extra.span()
+            )],
+            help = "parameters are given by use nodes, write them as ${name} in the body",
+            "template should have exactly one argument",
+        ));
+    }
+    only_parameters(node, &["kind"])?;
+    let kind = string_parameter(node, "kind")?.unwrap_or_else(|| "program".to_string());
+    if !matches!(kind.as_str(), "program" | "menu") {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                node.entry("kind").unwrap().span()
+            )],
+            help = "expected one of: program, menu",
+            "unknown template kind",
+        ));
+    }
+    children(node)?;
+
+    Ok(Template {
+        name: arguments[0].clone(),
+        kind,
+        node,
+    })
+}
+
+/// `stack` holds the templates being expanded, to catch ones that use themselves.
+fn expand_uses(
+    doc: &mut KdlDocument,
+    templates: &[Template],
+    stack: &mut Vec<String>,
+    diagnostics: &mut Diagnostics,
+) {
+    // A use that can't be expanded is dropped so it's only reported once
+    doc.nodes_mut().retain_mut(|node| {
+        if node.name().value() != "use" {
+            if let Some(children) = node.children_mut() {
+                expand_uses(children, templates, stack, diagnostics);
+            }
+            return true;
+        }
+        let result = instantiate(node, templates, stack, diagnostics);
+        diagnostics
+            .check(result)
+            .flatten()
+            .map(|item| *node = item)
+            .is_some()
+    });
+}
+
+/// `None` if a use inside the template failed, which has already been reported.
+fn instantiate(
+    node: &KdlNode,
+    templates: &[Template],
+    stack: &mut Vec<String>,
+    diagnostics: &mut Diagnostics,
+) -> Result<Option<KdlNode>> {
+    let arguments = many_arguments(node)?;
+    let entries = positional(node);
+    if arguments.len() != 2 {
+        let span = entries[0].span();
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("here".to_string()),
+                entries.get(2).map_or_else(
+                    || (span.offset() + span.len(), 0).into(),
+                    |extra| extra.span()
+                ),
+            )],
+            help = "use takes a template name, the item's name and parameters like arg=\"value\"",
+            "use should have exactly two arguments",
+        ));
+    }
+
+    let Some(template) = templates
+        .iter()
+        .find(|template| template.name == arguments[0])
+    else {
+        let known: Vec<&str> = templates
+            .iter()
+            .map(|template| template.name.as_str())
+            .collect();
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("this".to_string()),
+                entries[0].span()
+            )],
+            help = if known.is_empty() {
+                "define it at the top level with template \"name\" { ... }".to_string()
+            } else {
+                format!("defined templates: {}", known.join(", "))
+            },
+            "unknown template: {}",
+            arguments[0],
+        ));
+    };
+    if stack.contains(&template.name) {
+        return Err(miette!(
+            labels = vec![LabeledSpan::new_primary_with_span(
+                Some("used here".to_string()),
+                node.span()
+            )],
+            "template {} uses itself",
+            template.name,
+        ));
+    }
+
+    let mut parameters = Vec::new();
+    for entry in node.entries() {
+        if let Some(name) = entry.name() {
+            let value = string_parameter(node, name.value())?.unwrap();
+            parameters.push((name.value().to_owned(), value));
+        }
+    }
+
+    let mut body = template.node.children().unwrap().clone();
+    substitute(&mut body, &parameters, node, &template.name)?;
+    // Extra nodes on the use go after the template's, so they can override it
+    if let Some(extra) = node.children() {
+        body.nodes_mut().extend(extra.nodes().iter().cloned());
+    }
+    stack.push(template.name.clone());
+    let reported = diagnostics.count();
+    expand_uses(&mut body, templates, stack, diagnostics);
+    stack.pop();
+    if diagnostics.count() != reported {
+        return Ok(None);
+    }
+
+    let mut item = KdlNode::new(template.kind.as_str());
+    item.set_span(node.span());
+    item.name_mut().set_span(node.name().span());
+    item.entries_mut().push(entries[1].clone());
+    item.set_children(body);
+    Ok(Some(item))
+}
+
+/// Fills in the `${name}` placeholders in every string of the body.
+fn substitute(
+    doc: &mut KdlDocument,
+    parameters: &[(String, String)],
+    use_node: &KdlNode,
+    template: &str,
+) -> Result<()> {
+    for node in doc.nodes_mut() {
+        for entry in node.entries_mut() {
+            let Some(text) = entry.value().as_string() else {
+                continue;
+            };
+            let text = fill_placeholders(text, parameters).map_err(|name| {
+                miette!(
+                    labels = vec![
+                        LabeledSpan::new_primary_with_span(
+                            Some(format!("needs {name}=\"...\"")),
+                            use_node.span()
+                        ),
+                        LabeledSpan::new_with_span(Some("used here".to_string()), entry.span()),
+                    ],
+                    "missing parameter {name} for template {template}",
+                )
+            })?;
+            entry.set_value(KdlValue::String(text));
+        }
+        if let Some(children) = node.children_mut() {
+            substitute(children, parameters, use_node, template)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `${name}` with the parameter's value. Placeholders without lowercase
+/// letters are left alone as environment variables, as is anything after `$$`.
+/// Returns the name of a missing parameter as the error.
+fn fill_placeholders(text: &str, parameters: &[(String, String)]) -> Result<String, String> {
+    let mut filled = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            filled.push_str("$$");
+            rest = after;
+            continue;
+        }
+        if let Some(inner) = rest.strip_prefix("${")
+            && let Some(end) = inner.find('}')
+        {
+            let name = &inner[..end];
+            if let Some((_, value)) = parameters.iter().find(|(parameter, _)| parameter == name) {
+                filled.push_str(value);
+                rest = &inner[end + 1..];
+                continue;
+            }
+            if name.chars().any(|c| c.is_ascii_lowercase()) {
+                return Err(name.to_owned());
+            }
+        }
+        filled.push('$');
+        rest = &rest[1..];
+    }
+    filled.push_str(rest);
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ItemContents, ParseOptions, parse_config};
+
+    #[test]
+    fn test_fill_placeholders() {
+        let parameters = [("arg".to_string(), "https://example.com".to_string())];
+        assert_eq!(
+            fill_placeholders("--url=${arg}", &parameters).unwrap(),
+            "--url=https://example.com"
+        );
+        assert_eq!(
+            fill_placeholders("$HOME ${HOME} $${arg} 5$", &parameters).unwrap(),
+            "$HOME ${HOME} $${arg} 5$"
+        );
+        assert_eq!(
+            fill_placeholders("${arg}${other}", &parameters).unwrap_err(),
+            "other"
+        );
+    }
+
+    #[test]
+    fn test_templates() {
+        let config = r#"
+            template "stream" {
+                icon "mpv"
+                command "mpv" "--fs" "${arg}"
+            }
+            template "host" kind="menu" {
+                program "SSH" "foot" "ssh" "${host}"
+                use "stream" "Camera" arg="rtsp://${host}/live"
+            }
+            menu "Streams" {
+                use "stream" "Lo-fi" arg="https://lofi.example" {
+                    profiles "home"
+                }
+            }
+            use "host" "Server" host="server.lan"
+        "#;
+        let parsed = parse_config(config, ParseOptions::default()).unwrap();
+
+        let ItemContents::Menu(streams) = &parsed.items[0].contents else {
+            panic!("Expected menu item");
+        };
+        let lofi = &streams.items[0];
+        assert_eq!(lofi.name, "Lo-fi");
+        assert_eq!(lofi.icon.as_deref(), Some("mpv"));
+        assert_eq!(lofi.profiles, ["home"]);
+        let ItemContents::Program(program) = &lofi.contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(program.command, ["mpv", "--fs", "https://lofi.example"]);
+
+        let server = &parsed.items[1];
+        assert_eq!(server.name, "Server");
+        let ItemContents::Menu(server) = &server.contents else {
+            panic!("Expected menu item");
+        };
+        let ItemContents::Program(camera) = &server.items[1].contents else {
+            panic!("Expected program item");
+        };
+        assert_eq!(camera.command, ["mpv", "--fs", "rtsp://server.lan/live"]);
+
+        let error = |config: &str| {
+            parse_config(config, ParseOptions::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error(r#"use "nope" "Name""#), "unknown template: nope");
+        assert_eq!(
+            error(r#"template "t" { command "${arg}"; }; use "t" "Name""#),
+            "missing parameter arg for template t"
+        );
+        assert_eq!(
+            error(r#"template "t" kind="menu" { use "t" "Again"; }; use "t" "Name""#),
+            "template t uses itself"
+        );
+        assert_eq!(
+            error(r#"template "t" { command "a"; }; template "t" { command "b"; }"#),
+            "template is defined more than once"
+        );
+
+        let config = r#"template "t" { command "${arg}"; }
+use "t" "Name""#;
+        let error = parse_config(config, ParseOptions::default()).unwrap_err();
+        let labels: Vec<usize> = error
+            .labels()
+            .unwrap()
+            .map(|label| label.offset())
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                config.find("use").unwrap(),
+                config.find("\"${arg}\"").unwrap()
+            ]
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use log::{info, warn};
+use std::{
+    ffi::OsString,
+    io::{ErrorKind, Write},
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{self, ComputedMenu, ComputedProgram, ComputedRecentFiles},
+    desktop, dynamic,
+};
+
+/// Shown instead of the files when there are none, selecting it goes back.
+const NO_RECENT_FILES: &str = "no recent files";
+
+/// A file from `recently-used.xbel`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecentFile {
+    pub path: PathBuf,
+    pub mime_type: Option<String>,
+    /// When it was last used, as an ISO 8601 timestamp in UTC.
+    modified: String,
+}
+
+/// `recently-used.xbel` in `$XDG_DATA_HOME`, where GTK apps record the files
+/// they open.
+fn xbel_path() -> PathBuf {
+    desktop::data_dirs()[0].join("recently-used.xbel")
+}
+
+/// The most recently used files that still exist, newest first. A missing or
+/// unreadable list has no files.
+pub fn list_files(recent_files: &ComputedRecentFiles) -> Vec<RecentFile> {
+    read_files(&xbel_path(), recent_files.limit)
+}
+
+fn read_files(path: &Path, limit: usize) -> Vec<RecentFile> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            info!("{} does not exist", path.display());
+            return Vec::new();
+        }
+        Err(error) => {
+            warn!("failed to read {}: {error}", path.display());
+            return Vec::new();
+        }
+    };
+    let mut files: Vec<RecentFile> = parse_xbel(&contents)
+        .into_iter()
+        .filter(|file| file.path.is_file())
+        .collect();
+    // The timestamps all use the same format, so they sort as text
+    files.sort_by(|a, b| b.modified.cmp(&a.modified));
+    files.truncate(limit);
+    files
+}
+
+/// Reads the local files out of the `bookmark` elements. This is only as much
+/// XML as GLib writes, which is all that reads and writes the file in practice.
+fn parse_xbel(contents: &str) -> Vec<RecentFile> {
+    let mut files = Vec::new();
+    let mut rest = contents;
+    while let Some(start) = rest.find("<bookmark") {
+        rest = &rest[start + "<bookmark".len()..];
+        // Skips <bookmark:applications> and the like
+        if !rest.starts_with(|character: char| character.is_whitespace()) {
+            continue;
+        }
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..tag_end];
+        let body = if tag.ends_with('/') {
+            ""
+        } else {
+            let body_end = rest.find("</bookmark>").unwrap_or(rest.len());
+            &rest[tag_end..body_end.max(tag_end)]
+        };
+        rest = &rest[tag_end..];
+
+        let Some(path) = attribute(tag, "href").and_then(|href| file_path(&href)) else {
+            continue;
+        };
+        let modified = attribute(tag, "modified")
+            .or_else(|| attribute(tag, "visited"))
+            .or_else(|| attribute(tag, "added"))
+            .unwrap_or_default();
+        let mime_type = body
+            .find("<mime:mime-type")
+            .and_then(|start| attribute(&body[start..], "type"));
+        files.push(RecentFile {
+            path,
+            mime_type,
+            modified,
+        });
+    }
+    files
+}
+
+/// The value of `name="..."` in a start tag, with entities decoded.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+    let mut rest = tag;
+    loop {
+        let start = rest.find(name)?;
+        let before = rest[..start].chars().next_back();
+        rest = &rest[start + name.len()..];
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        let Some(value) = rest.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            return None;
+        }
+        let value = &value[1..];
+        return Some(decode_entities(&value[..value.find(quote)?]));
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map_or_else(
+                    || entity.strip_prefix('#')?.parse().ok(),
+                    |hex| u32::from_str_radix(hex, 16).ok(),
+                )
+                .and_then(char::from_u32),
+        };
+        match character {
+            Some(character) => {
+                decoded.push(character);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The path of a local `file://` uri, other uris have none.
+fn file_path(uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix("file://")?;
+    let path = rest.strip_prefix("localhost").unwrap_or(rest);
+    if !path.starts_with('/') {
+        return None; // on another host
+    }
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = (bytes[index] == b'%')
+            .then(|| std::str::from_utf8(bytes.get(index + 1..index + 3)?).ok())
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if let Some(byte) = escaped {
+            decoded.push(byte);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    Some(PathBuf::from(OsString::from_vec(decoded)))
+}
+
+/// Shows each file's name with the icon for its MIME type, in the same order as
+/// `files` so fuzzel's index can be used to look them up.
+pub fn build_menu(recent_files: &ComputedRecentFiles, files: &[RecentFile]) -> ComputedMenu {
+    let mut input = Vec::new();
+    if files.is_empty() {
+        writeln!(&mut input, "{NO_RECENT_FILES}").unwrap();
+    }
+    for file in files {
+        let name = file.path.file_name().unwrap_or_default().to_string_lossy();
+        write!(&mut input, "{}", config::sanitize_entry(&name)).unwrap();
+        // Icon themes name MIME type icons like text-plain
+        if let Some(mime_type) = &file.mime_type {
+            let icon = mime_type.replace('/', "-");
+            write!(&mut input, "\0icon\x1f{}", config::sanitize_entry(&icon)).unwrap();
+        }
+        input.push(b'\n');
+    }
+    ComputedMenu {
+        input,
+        ..dynamic::build_menu(&recent_files.args, &[])
+    }
+}
+
+/// The opener with the file's path appended.
+pub fn program_for_file(recent_files: &ComputedRecentFiles, file: &RecentFile) -> ComputedProgram {
+    let mut command = recent_files.open_with.clone();
+    command.push(file.path.display().to_string());
+    ComputedProgram {
+        command,
+        env: recent_files.env.clone(),
+        ..recent_files.defaults.program()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ComputedDefaults;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_xbel() {
+        let files = parse_xbel(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<xbel version="1.0"
+      xmlns:bookmark="http://www.freedesktop.org/standards/desktop-bookmarks"
+      xmlns:mime="http://www.freedesktop.org/standards/shared-mime-info"
+>
+  <bookmark href="file:///home/me/a%20b%26c.pdf" added="2024-01-01T10:00:00.000000Z" modified="2024-03-01T10:00:00.000000Z" visited="2024-01-01T10:00:00.000000Z">
+    <info>
+      <metadata owner="http://freedesktop.org">
+        <mime:mime-type type="application/pdf"/>
+        <bookmark:applications>
+          <bookmark:application name="Papers" exec="&apos;papers %u&apos;" modified="2024-03-01T10:00:00Z" count="2"/>
+        </bookmark:applications>
+      </metadata>
+    </info>
+  </bookmark>
+  <bookmark href="https://example.com/" modified="2024-04-01T10:00:00Z"/>
+  <bookmark href="file:///tmp/Tom&amp;Jerry.txt" modified="2024-02-01T10:00:00Z"/>
+</xbel>"#,
+        );
+        assert_eq!(
+            files,
+            [
+                RecentFile {
+                    path: PathBuf::from("/home/me/a b&c.pdf"),
+                    mime_type: Some("application/pdf".to_string()),
+                    modified: "2024-03-01T10:00:00.000000Z".to_string(),
+                },
+                RecentFile {
+                    path: PathBuf::from("/tmp/Tom&Jerry.txt"),
+                    mime_type: None,
+                    modified: "2024-02-01T10:00:00Z".to_string(),
+                },
+            ]
+        );
+
+        assert_eq!(
+            file_path("file:///a%2Fb%ZZ"),
+            Some(PathBuf::from("/a/b%ZZ"))
+        );
+        assert_eq!(file_path("file://localhost/a"), Some(PathBuf::from("/a")));
+        assert_eq!(file_path("file://other/a"), None);
+        assert_eq!(decode_entities("&lt;&#65;&#x42;&nope; &"), "<AB&nope; &");
+    }
+
+    #[test]
+    fn test_recent_files() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("test-recent-files");
+        std::fs::create_dir_all(&dir).unwrap();
+        let xbel = dir.join("recently-used.xbel");
+        assert!(read_files(&xbel, 10).is_empty());
+
+        let mut contents = String::from("<xbel>\n");
+        for (name, day) in [
+            ("old.txt", 1),
+            ("new.png", 3),
+            ("gone.txt", 4),
+            ("mid.txt", 2),
+        ] {
+            if name != "gone.txt" {
+                std::fs::write(dir.join(name), "").unwrap();
+            }
+            let mime_type = if name.ends_with(".png") {
+                "image/png"
+            } else {
+                "text/plain"
+            };
+            contents.push_str(&format!(
+                "<bookmark href=\"file://{}\" modified=\"2024-01-0{day}T00:00:00Z\">\
+                 <mime:mime-type type=\"{mime_type}\"/></bookmark>\n",
+                dir.join(name).display()
+            ));
+        }
+        contents.push_str("</xbel>\n");
+        std::fs::write(&xbel, contents).unwrap();
+
+        // Newest first, without files that were deleted since
+        let files = read_files(&xbel, 2);
+        let names: Vec<_> = files
+            .iter()
+            .map(|file| {
+                file.path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        assert_eq!(names, ["new.png", "mid.txt"]);
+
+        let recent_files = ComputedRecentFiles {
+            args: Vec::new(),
+            limit: 2,
+            open_with: vec!["xdg-open".to_string()],
+            env: Vec::new(),
+            defaults: ComputedDefaults::default(),
+        };
+        let menu = build_menu(&recent_files, &files);
+        assert_eq!(
+            menu.input,
+            b"new.png\0icon\x1fimage-png\nmid.txt\0icon\x1ftext-plain\n"
+        );
+        assert_eq!(
+            program_for_file(&recent_files, &files[0]).command,
+            [
+                "xdg-open".to_string(),
+                dir.join("new.png").display().to_string()
+            ]
+        );
+        assert_eq!(build_menu(&recent_files, &[]).input, b"no recent files\n");
+    }
+}
@@ -0,0 +1,238 @@
+use log::{LevelFilter, debug};
+use miette::{Context, IntoDiagnostic, NamedSource, Result, ensure, miette};
+use std::{
+    ffi::OsString,
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config,
+    parser::{self, GlobalConfig, ParseOptions, Settings},
+};
+
+/// `config.kdl` in the same directory as the presets.
+pub fn global_config_path(config_dir: &Path) -> PathBuf {
+    let mut path = config_dir.to_path_buf();
+    path.push(env!("CARGO_BIN_NAME"));
+    path.push("config.kdl");
+    path
+}
+
+/// Reads and parses the global config, along with its contents so they can be
+/// hashed into each preset's cache. A missing file is the same as an empty one.
+pub fn read_global_config(path: &Path, options: ParseOptions) -> Result<(GlobalConfig, String)> {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            return Ok((GlobalConfig::default(), String::new()));
+        }
+        Err(error) => {
+            return Err(error)
+                .into_diagnostic()
+                .with_context(|| format!("failed to read {}", path.display()));
+        }
+    };
+    let global = parser::parse_global_config(&source, options)
+        .wrap_err("failed to parse global config")
+        .map_err(|report| {
+            report.with_source_code(NamedSource::new(path.display().to_string(), source.clone()))
+        })?;
+    Ok((global, source))
+}
+
+/// Picks the value a setting ends up with. A CLI flag wins over the
+/// environment, which wins over `config.kdl`, which wins over the built-in default.
+pub fn resolve<T>(cli: Option<T>, env: Option<T>, settings: Option<T>, default: T) -> T {
+    cli.or(env).or(settings).unwrap_or(default)
+}
+
+/// The log level from `--log-level`, `UFF_LOG_LEVEL` or the `log-level` setting,
+/// `info` by default.
+pub fn log_level(cli: Option<&str>, settings: &Settings) -> Result<LevelFilter> {
+    let parse = |level: &str, source: &str| {
+        level
+            .parse::<LevelFilter>()
+            .map_err(|_| miette!("invalid log level in {source}: {level:?}"))
+    };
+    let cli = cli.map(|level| parse(level, "--log-level")).transpose()?;
+    let env = std::env::var("UFF_LOG_LEVEL")
+        .ok()
+        .filter(|level| !level.is_empty())
+        .map(|level| parse(&level, "UFF_LOG_LEVEL"))
+        .transpose()?;
+    Ok(resolve(cli, env, settings.log_level, LevelFilter::Info))
+}
+
+/// The fuzzel to run, from `$UFF_FUZZEL` or the `fuzzel-path` setting and
+/// otherwise found in `$PATH`. It's checked before any menu is shown, so a wrong
+/// path fails straight away.
+pub fn fuzzel_path(settings: &Settings) -> Result<PathBuf> {
+    let env = std::env::var("UFF_FUZZEL")
+        .ok()
+        .filter(|path| !path.is_empty());
+    let path = resolve(
+        None,
+        env,
+        settings.fuzzel_path.clone(),
+        "fuzzel".to_string(),
+    );
+    let path = find_executable(&path, std::env::var_os("PATH"))?;
+    debug!("using fuzzel at {}", path.display());
+    Ok(path)
+}
+
+/// Expands `~` and variables in the path, or looks it up in `search_path` if
+/// it's only a name.
+pub fn find_executable(path: &str, search_path: Option<OsString>) -> Result<PathBuf> {
+    let expanded = config::expand_env_vars(path)
+        .map_err(|name| miette!("{path} uses ${name}, which isn't set"))?;
+    let expanded = config::expand_tilde(&expanded);
+    if !expanded.contains('/') {
+        return search_path
+            .iter()
+            .flat_map(std::env::split_paths)
+            .map(|dir| dir.join(&expanded))
+            .find(|candidate| is_executable(candidate))
+            .ok_or_else(|| {
+                miette!(
+                    help = "install it, or set fuzzel-path in config.kdl",
+                    "{expanded} was not found in PATH"
+                )
+            });
+    }
+    let path = PathBuf::from(expanded);
+    ensure!(path.exists(), "{} does not exist", path.display());
+    ensure!(is_executable(&path), "{} is not executable", path.display());
+    Ok(path)
+}
+
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve(Some(1), Some(2), Some(3), 4), 1);
+        assert_eq!(resolve(None, Some(2), Some(3), 4), 2);
+        assert_eq!(resolve(None, None, Some(3), 4), 3);
+        assert_eq!(resolve(None, None, None, 4), 4);
+
+        let settings = Settings {
+            log_level: Some(LevelFilter::Debug),
+            ..Default::default()
+        };
+        assert_eq!(
+            log_level(Some("WARN"), &settings).unwrap(),
+            LevelFilter::Warn
+        );
+        assert_eq!(log_level(None, &settings).unwrap(), LevelFilter::Debug);
+        assert_eq!(
+            log_level(None, &Settings::default()).unwrap(),
+            LevelFilter::Info
+        );
+        assert_eq!(
+            log_level(Some("loud"), &settings).unwrap_err().to_string(),
+            "invalid log level in --log-level: \"loud\""
+        );
+    }
+
+    #[test]
+    fn test_find_executable() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("test-find-executable");
+        std::fs::create_dir_all(&dir).unwrap();
+        let fuzzel = dir.join("fuzzel");
+        std::fs::write(&fuzzel, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&fuzzel, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let plain = dir.join("plain");
+        std::fs::write(&plain, "").unwrap();
+        std::fs::set_permissions(&plain, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let search_path =
+            || Some(std::env::join_paths(["/nonexistent", dir.to_str().unwrap()]).unwrap());
+        assert_eq!(find_executable("fuzzel", search_path()).unwrap(), fuzzel);
+        assert_eq!(
+            find_executable(fuzzel.to_str().unwrap(), None).unwrap(),
+            fuzzel
+        );
+        assert_eq!(
+            find_executable("~/fuzzel", None).unwrap_err().to_string(),
+            format!("{}/fuzzel does not exist", config::home().unwrap())
+        );
+        assert_eq!(
+            find_executable(plain.to_str().unwrap(), None)
+                .unwrap_err()
+                .to_string(),
+            format!("{} is not executable", plain.display())
+        );
+        assert_eq!(
+            find_executable("plain", search_path())
+                .unwrap_err()
+                .to_string(),
+            "plain was not found in PATH"
+        );
+        assert_eq!(
+            find_executable("$UFF_TEST_UNSET/fuzzel", None)
+                .unwrap_err()
+                .to_string(),
+            "$UFF_TEST_UNSET/fuzzel uses $UFF_TEST_UNSET, which isn't set"
+        );
+    }
+
+    #[test]
+    fn test_read_global_config() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("test-global-config");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.kdl");
+
+        let (global, source) = read_global_config(&path, ParseOptions::default()).unwrap();
+        assert!(global.settings.opener.is_none());
+        assert!(source.is_empty());
+
+        std::fs::write(
+            &path,
+            r#"
+            settings {
+                opener "handlr" "open"
+                log-level "debug"
+                stale-while-revalidate #true
+                cache-compression #false
+            }
+            defaults {
+                fuzzel-args "--lines" "5"
+            }
+            "#,
+        )
+        .unwrap();
+        let (global, source) = read_global_config(&path, ParseOptions::default()).unwrap();
+        assert_eq!(
+            global.settings.opener,
+            Some(vec!["handlr".to_string(), "open".to_string()])
+        );
+        assert_eq!(global.settings.log_level, Some(LevelFilter::Debug));
+        assert_eq!(global.settings.stale_while_revalidate, Some(true));
+        assert_eq!(global.settings.cache_compression, Some(false));
+        assert_eq!(global.defaults.fuzzel_args, ["--lines", "5"]);
+        assert!(source.contains("handlr"));
+
+        let typo = r#"settings { openr "handlr"; }"#;
+        std::fs::write(&path, typo).unwrap();
+        assert!(read_global_config(&path, ParseOptions::default()).is_err());
+        let error = parser::parse_global_config(typo, ParseOptions::default()).unwrap_err();
+        assert_eq!(error.to_string(), "unknown setting: openr");
+        assert_eq!(error.help().unwrap().to_string(), "did you mean opener?");
+
+        let error =
+            parser::parse_global_config(r#"menu "A" { }"#, ParseOptions::default()).unwrap_err();
+        assert_eq!(error.to_string(), "unexpected node in config.kdl: menu");
+    }
+}